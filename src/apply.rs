@@ -0,0 +1,365 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Deserialize;
+use std::{cmp::Ordering, path::Path};
+
+use crate::checker::{self, InstallOptions, Integration};
+use crate::scope::{self, Scope};
+use crate::{info, server, state};
+
+/// Keys of the desired state for what vsdown does not manage, refused by
+/// name rather than as unknown, with what is missing.
+const UNSUPPORTED_KEYS: &[(&str, &str)] = &[
+    ("pin", "vsdown does not hold versions, set version instead"),
+    (
+        "wrapper-flags",
+        "the launcher is a symlink to the editor, not a wrapper passing flags",
+    ),
+    ("extensions", "vsdown does not manage extensions"),
+];
+
+/// The desired state `vsdown apply` brings the installation to. Keys left
+/// out keep whatever is installed.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Desired {
+    /// "desktop" or "server".
+    #[serde(alias = "product")]
+    variant: Option<String>,
+    /// Only "stable", the one channel vsdown installs from.
+    channel: Option<String>,
+    /// "latest" or a version.
+    version: Option<String>,
+    /// Name of the launcher in /usr/bin, "" for none.
+    launcher: Option<String>,
+    sandbox: Option<bool>,
+    coexist: Option<bool>,
+    /// "system" or "user".
+    metadata_scope: Option<String>,
+    pin: Option<toml::Value>,
+    wrapper_flags: Option<toml::Value>,
+    extensions: Option<toml::Value>,
+}
+
+impl Desired {
+    fn has(&self, key: &str) -> bool {
+        match key {
+            "pin" => self.pin.is_some(),
+            "wrapper-flags" => self.wrapper_flags.is_some(),
+            "extensions" => self.extensions.is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Reads and validates the desired state at `p`.
+fn read(p: &Path) -> Result<Desired> {
+    let s = std::fs::read_to_string(p)
+        .map_err(|e| anyhow!("Failed to read {}: {}.", p.display(), e))?;
+    let desired = toml::from_str::<Desired>(&s)
+        .map_err(|e| anyhow!("Failed to parse {}: {}.", p.display(), e))?;
+    if let Some((key, why)) = UNSUPPORTED_KEYS.iter().find(|(key, _)| desired.has(key)) {
+        bail!("{}: {} is not supported, {}.", p.display(), key, why);
+    }
+    match desired.channel.as_deref() {
+        None | Some("stable") => (),
+        Some(other) => bail!(
+            "{}: channel must be stable, the only one vsdown installs, not {}.",
+            p.display(),
+            other
+        ),
+    }
+    match desired.variant.as_deref() {
+        None | Some("desktop") => (),
+        Some("server") => {
+            if desired.launcher.is_some()
+                || desired.sandbox.is_some()
+                || desired.coexist.is_some()
+                || desired.metadata_scope.is_some()
+            {
+                bail!(
+                    "{}: launcher, sandbox, coexist and metadata-scope only apply to the desktop variant.",
+                    p.display()
+                );
+            }
+        }
+        Some(other) => bail!(
+            "{}: variant must be desktop or server, not {}.",
+            p.display(),
+            other
+        ),
+    }
+    if let Some(v) = desired.version.as_deref().filter(|v| *v != "latest") {
+        if semver::Version::parse(v).is_err() {
+            bail!(
+                "{}: version must be latest or a version such as 1.70.0, not {}.",
+                p.display(),
+                v
+            );
+        }
+    }
+    if let Some(name) = desired.launcher.as_deref().filter(|n| !n.is_empty()) {
+        checker::parse_bin_name(name).map_err(|e| anyhow!("{}: launcher {}.", p.display(), e))?;
+    }
+    if let Some(s) = &desired.metadata_scope {
+        parse_scope(s).map_err(|e| anyhow!("{}: {}", p.display(), e))?;
+    }
+
+    Ok(desired)
+}
+
+fn parse_scope(s: &str) -> Result<Scope> {
+    match s {
+        "system" => Ok(Scope::System),
+        "user" => Ok(Scope::User),
+        other => bail!("metadata-scope must be system or user, not {}.", other),
+    }
+}
+
+/// What of the installation `apply` compares with the desired state.
+#[derive(Clone, Debug, PartialEq)]
+struct Current {
+    version: Option<String>,
+    integration: Integration,
+}
+
+impl Current {
+    fn read(server: bool) -> Self {
+        Current {
+            version: if server {
+                server::installed_version()
+            } else {
+                state::recorded_version()
+            },
+            integration: Integration::recorded(),
+        }
+    }
+}
+
+/// Brings the installation to the state declared in `p`, or only lists the
+/// changes with `dry_run`. Applying the same file again changes nothing.
+/// Returns how many changes there were.
+pub fn apply(p: &Path, dry_run: bool) -> Result<usize> {
+    let desired = read(p)?;
+    if state::interrupted_install().is_some() {
+        bail!("An installation was interrupted, run `vsdown install --resume` or `vsdown install --abort` first.");
+    }
+    let server = desired.variant.as_deref() == Some("server");
+    let target = match desired.version.as_deref().filter(|v| *v != "latest") {
+        Some(v) => v.to_string(),
+        None => checker::get_lastest_version()?,
+    };
+    let (changes, options) = plan(p, &desired, &Current::read(server), &target)?;
+
+    for change in &changes {
+        println!(
+            "{} {}",
+            style(if dry_run { "would:" } else { "apply:" })
+                .cyan()
+                .bold(),
+            change
+        );
+    }
+    if changes.is_empty() {
+        info!("The installation already is as {} declares.", p.display());
+        return Ok(0);
+    }
+    if dry_run {
+        return Ok(changes.len());
+    }
+    // A reinstall applies the integration changes along with the version.
+    if server {
+        server::install(&options, true)?;
+    } else {
+        checker::install_vscode(&options)?;
+    }
+
+    Ok(changes.len())
+}
+
+/// The changes bringing `current` to `desired`, `target` being the version
+/// it asks for, and the options of the install making them.
+fn plan(
+    p: &Path,
+    desired: &Desired,
+    current: &Current,
+    target: &str,
+) -> Result<(Vec<String>, InstallOptions)> {
+    let mut changes = Vec::new();
+    match &current.version {
+        None => changes.push(format!("install {}", target)),
+        Some(c) if c == target => (),
+        Some(c) => match checker::compare_versions(c, target) {
+            Some(Ordering::Greater) => changes.push(format!("downgrade {} -> {}", c, target)),
+            _ => changes.push(format!("upgrade {} -> {}", c, target)),
+        },
+    }
+    let pinned = desired.version.as_deref().filter(|v| *v != "latest");
+    let mut options = InstallOptions {
+        version: pinned.map(|v| v.to_string()),
+        force: true,
+        unpack_jobs: 1,
+        ..Default::default()
+    };
+    let integration = &current.integration;
+    if let Some(name) = &desired.launcher {
+        let name = Some(name.clone()).filter(|n| !n.is_empty());
+        if name != integration.bin_name {
+            changes.push(match &name {
+                Some(name) => format!("launcher {}", name),
+                None => "no launcher".to_string(),
+            });
+        }
+        options.no_symlink = name.is_none();
+        options.bin_name = name;
+    }
+    if let Some(sandbox) = desired.sandbox {
+        if sandbox != integration.sandbox {
+            changes.push(format!("sandbox {}", if sandbox { "on" } else { "off" }));
+        }
+        options.sandbox_setup = Some(sandbox);
+    }
+    if let Some(coexist) = desired.coexist {
+        match (coexist, integration.coexist) {
+            (true, false) => changes.push("coexist on".to_string()),
+            (false, true) => bail!("Coexisting installs cannot be turned back into regular ones, remove it and apply {} again.", p.display()),
+            _ => (),
+        }
+        options.coexist = coexist;
+    }
+    if let Some(s) = &desired.metadata_scope {
+        let scope = parse_scope(s)?;
+        if scope::data_dir(scope)? != integration.data_dir {
+            changes.push(format!("metadata scope {}", s));
+        }
+        options.metadata_scope = Some(scope);
+    }
+
+    Ok((changes, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn desired(toml: &str) -> Result<(tempfile::NamedTempFile, Desired)> {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut f, toml.as_bytes()).unwrap();
+        let desired = read(f.path())?;
+
+        Ok((f, desired))
+    }
+
+    fn error(toml: &str) -> String {
+        match desired(toml) {
+            Ok(_) => panic!("{} was accepted", toml),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// The installation as an install with `options` of `target` leaves it,
+    /// going by the integration `checker::install()` resolves. The install
+    /// itself only works on the fixed system paths, so the tests compare
+    /// plans rather than applying them.
+    fn installed(current: &Current, options: &InstallOptions, target: &str) -> Current {
+        Current {
+            version: Some(target.to_string()),
+            integration: current.integration.with(options).unwrap(),
+        }
+    }
+
+    fn current(version: Option<&str>, bin_name: Option<&str>, sandbox: bool) -> Current {
+        Current {
+            version: version.map(|v| v.to_string()),
+            integration: Integration {
+                sandbox,
+                coexist: false,
+                data_dir: PathBuf::from(scope::SYSTEM_DATA_DIR),
+                bin_name: bin_name.map(|n| n.to_string()),
+                cli_name: None,
+            },
+        }
+    }
+
+    #[test]
+    fn refuses_unmanaged_keys() {
+        assert!(error("extensions = [\"ms-python.python\"]")
+            .ends_with(": extensions is not supported, vsdown does not manage extensions."));
+        assert!(error("pin = true").contains(": pin is not supported"));
+        assert!(error("wrapper-flags = [\"--disable-gpu\"]")
+            .contains(": wrapper-flags is not supported"));
+        assert!(error("channel = \"insiders\"")
+            .ends_with(": channel must be stable, the only one vsdown installs, not insiders."));
+        assert!(error("editor = \"code\"").contains("unknown field `editor`"));
+    }
+
+    #[test]
+    fn takes_product_for_variant() {
+        let (_f, d) = desired("product = \"server\"\nchannel = \"stable\"").unwrap();
+        assert_eq!(d.variant.as_deref(), Some("server"));
+        assert!(error("product = \"server\"\nlauncher = \"code\"")
+            .ends_with("only apply to the desktop variant."));
+    }
+
+    #[test]
+    fn applying_twice_changes_nothing() {
+        let (f, d) = desired(
+            "version = \"1.70.0\"\nlauncher = \"code\"\nsandbox = true\ncoexist = false\nmetadata-scope = \"system\"",
+        )
+        .unwrap();
+        let before = current(Some("1.71.2"), None, false);
+        let (changes, options) = plan(f.path(), &d, &before, "1.70.0").unwrap();
+        assert_eq!(
+            changes,
+            ["downgrade 1.71.2 -> 1.70.0", "launcher code", "sandbox on"]
+        );
+        assert_eq!(options.version.as_deref(), Some("1.70.0"));
+
+        let after = installed(&before, &options, "1.70.0");
+        let (changes, _) = plan(f.path(), &d, &after, "1.70.0").unwrap();
+        assert!(changes.is_empty(), "{:?}", changes);
+    }
+
+    #[test]
+    fn installs_latest_once() {
+        let (f, d) = desired("version = \"latest\"").unwrap();
+        let before = current(None, Some("code"), true);
+        let (changes, options) = plan(f.path(), &d, &before, "1.80.0").unwrap();
+        assert_eq!(changes, ["install 1.80.0"]);
+        assert_eq!(options.version, None);
+        let after = installed(&before, &options, "1.80.0");
+        assert!(plan(f.path(), &d, &after, "1.80.0").unwrap().0.is_empty());
+        // Until a newer release is out.
+        assert_eq!(
+            plan(f.path(), &d, &after, "1.81.0").unwrap().0,
+            ["upgrade 1.80.0 -> 1.81.0"]
+        );
+    }
+
+    #[test]
+    fn coexisting_twice_changes_nothing() {
+        let (f, d) = desired("coexist = true").unwrap();
+        let before = current(Some("1.80.0"), Some("code"), true);
+        let (changes, options) = plan(f.path(), &d, &before, "1.80.0").unwrap();
+        assert_eq!(changes, ["coexist on"]);
+
+        let after = installed(&before, &options, "1.80.0");
+        assert_eq!(
+            after.integration.bin_name.as_deref(),
+            Some(checker::COEXIST_BIN_NAME)
+        );
+        assert!(plan(f.path(), &d, &after, "1.80.0").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn refuses_to_undo_coexist() {
+        let (f, d) = desired("coexist = false").unwrap();
+        let mut coexisting = current(Some("1.80.0"), Some("code"), true);
+        coexisting.integration.coexist = true;
+        assert!(plan(f.path(), &d, &coexisting, "1.80.0")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Coexisting installs cannot be turned back"));
+    }
+}
@@ -0,0 +1,411 @@
+use anyhow::{anyhow, bail, Result};
+use clap::ArgEnum;
+use console::style;
+use flate2::bufread::GzDecoder;
+use progress_streams::ProgressReader;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    io::{self, BufRead, Read},
+    path::PathBuf,
+};
+
+use crate::{
+    checksum, config, debug, info,
+    progress::{self, ThrottledProgress},
+    state, units,
+};
+
+const CACHE_DIRECTORY: &str = "/var/cache/vsdown/";
+/// zstd level for `--compress-cache zstd`, favouring ratio as archives are
+/// compressed once and kept.
+const ZSTD_LEVEL: i32 = 19;
+
+/// Where archives are cached, `--cache-dir` or `CACHE_DIRECTORY`.
+pub fn directory() -> PathBuf {
+    config::get()
+        .cache_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(CACHE_DIRECTORY))
+}
+
+/// How cached archives are stored on disk. Entries are always addressed by
+/// the SHA-256 of the archive as downloaded, whatever their storage, and
+/// checked against that of what they store.
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// As downloaded
+    None,
+    /// Unpacked from gzip and recompressed with zstd, smaller when many
+    /// versions are retained
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+/// Metadata stored next to each cached archive.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheEntry {
+    pub version: String,
+    pub os: String,
+    pub sha256: String,
+    pub size: u64,
+    /// The commit the build was made from, if it was known when caching.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// How the archive is stored, `sha256` and `size` being of the archive
+    /// before that.
+    #[serde(default)]
+    pub compression: Compression,
+    /// The SHA-256 of the tar a zstd entry holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tar_sha256: Option<String>,
+}
+
+impl CacheEntry {
+    fn stem(&self) -> String {
+        entry_stem(&self.version, &self.os)
+    }
+
+    pub fn archive_path(&self) -> PathBuf {
+        match self.compression {
+            Compression::None => directory().join(format!("{}.tar.gz", self.stem())),
+            Compression::Zstd => directory().join(format!("{}.tar.zst", self.stem())),
+        }
+    }
+
+    pub fn metadata_path(&self) -> PathBuf {
+        directory().join(format!("{}.json", self.stem()))
+    }
+}
+
+fn entry_stem(version: &str, os: &str) -> String {
+    format!("code-{}-{}", version, os)
+}
+
+/// Every readable cache entry, in no particular order.
+pub fn entries() -> Vec<CacheEntry> {
+    let dir = match std::fs::read_dir(directory()) {
+        Ok(dir) => dir,
+        Err(_) => return Vec::new(),
+    };
+
+    dir.filter_map(|i| i.ok())
+        .map(|i| i.path())
+        .filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+        .filter_map(|p| {
+            let s = std::fs::read_to_string(&p).ok()?;
+            serde_json::from_str::<CacheEntry>(&s).ok()
+        })
+        .collect()
+}
+
+pub fn lookup(version: &str, os: &str) -> Option<CacheEntry> {
+    entries()
+        .into_iter()
+        .find(|i| i.version == version && i.os == os)
+}
+
+fn compare(a: &CacheEntry, b: &CacheEntry) -> Ordering {
+    match (
+        semver::Version::parse(&a.version),
+        semver::Version::parse(&b.version),
+    ) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.version.cmp(&b.version),
+    }
+}
+
+/// The cached entry for `os` with the highest version.
+pub fn newest(os: &str) -> Option<CacheEntry> {
+    entries().into_iter().filter(|i| i.os == os).max_by(compare)
+}
+
+/// The entries for `os` other than `current`, newest first. The newest
+/// entry stands in for `current` when that is not cached.
+fn others(os: &str, current: Option<&str>) -> Vec<CacheEntry> {
+    let mut entries = entries()
+        .into_iter()
+        .filter(|i| i.os == os)
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| compare(b, a));
+    let current = match current.filter(|v| entries.iter().any(|i| i.version == *v)) {
+        Some(v) => v.to_string(),
+        None => match entries.first() {
+            Some(i) => i.version.clone(),
+            None => return entries,
+        },
+    };
+    entries.retain(|i| i.version != current);
+
+    entries
+}
+
+/// The previous versions for `os` kept for rolling back from `current`.
+pub fn retained(os: &str, current: Option<&str>, retain: u64) -> Vec<CacheEntry> {
+    others(os, current)
+        .into_iter()
+        .take(retain as usize)
+        .collect()
+}
+
+/// The entries for `os` beyond what `retained()` keeps.
+pub fn excess(os: &str, current: Option<&str>, retain: u64) -> Vec<CacheEntry> {
+    others(os, current)
+        .into_iter()
+        .skip(retain as usize)
+        .collect()
+}
+
+/// Removes the entries for `os` beyond `current` and the `retain` newest
+/// previous versions, so lowering the retention takes effect on the next
+/// install. With `--compress-cache zstd`, the retained versions cached as
+/// downloaded are recompressed.
+pub fn prune(os: &str, current: &str, retain: u64) -> Result<()> {
+    for i in excess(os, Some(current), retain) {
+        info!(
+            "Removing cached Visual Studio Code {} ({}), beyond the {} version(s) retained for rollback.",
+            i.version,
+            units::Bytes(i.size),
+            retain
+        );
+        remove(&i)?;
+    }
+    if config::get().compress_cache == Compression::Zstd {
+        for i in retained(os, Some(current), retain) {
+            if i.compression == Compression::None {
+                recompress(&i)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores an entry cached as downloaded with zstd instead.
+fn recompress(entry: &CacheEntry) -> Result<()> {
+    let buf = load(entry, false)?;
+    info!(
+        "Recompressing cached Visual Studio Code {} with zstd ...",
+        entry.version
+    );
+    let compressed = CacheEntry {
+        compression: Compression::Zstd,
+        ..entry.clone()
+    };
+    write(compressed, &buf)?;
+    // The metadata now names the compressed archive.
+    std::fs::remove_file(entry.archive_path())?;
+
+    Ok(())
+}
+
+/// Writes the archive `buf` of `entry` in its storage format, then the
+/// metadata, so the entry only exists once the archive is complete. Returns
+/// the entry as written.
+fn write(mut entry: CacheEntry, buf: &[u8]) -> Result<CacheEntry> {
+    let stored = encode(&mut entry, buf)?;
+    state::write_atomic(&entry.archive_path(), &stored)?;
+    state::write_atomic(&entry.metadata_path(), &serde_json::to_vec(&entry)?)?;
+
+    Ok(entry)
+}
+
+/// What is stored of the archive `buf` of `entry`. zstd entries hold the
+/// tar, as gzip leaves next to nothing for zstd to compress further, and
+/// record its SHA-256 in `entry`.
+fn encode<'a>(entry: &mut CacheEntry, buf: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    match entry.compression {
+        Compression::None => Ok(Cow::Borrowed(buf)),
+        Compression::Zstd => {
+            let mut tar = Vec::new();
+            GzDecoder::new(buf)
+                .read_to_end(&mut tar)
+                .map_err(|e| anyhow!("Failed to decompress the archive: {}.", e))?;
+            entry.tar_sha256 = Some(checksum::sha256_bytes(&tar));
+            Ok(Cow::Owned(zstd::encode_all(&*tar, ZSTD_LEVEL)?))
+        }
+    }
+}
+
+/// The archive or tar `stored` for `entry`, and the SHA-256 it must have.
+fn decode<'a>(entry: &'a CacheEntry, stored: Vec<u8>) -> io::Result<(Vec<u8>, &'a str)> {
+    match entry.compression {
+        Compression::None => Ok((stored, entry.sha256.as_str())),
+        Compression::Zstd => Ok((
+            zstd::decode_all(&*stored)?,
+            entry.tar_sha256.as_deref().unwrap_or_default(),
+        )),
+    }
+}
+
+/// Reads the tar in `r`, gunzipping it unless it is the bare tar `load()`
+/// returns for a zstd entry.
+pub fn decompressed<'a, R: BufRead + 'a>(mut r: R) -> io::Result<Box<dyn Read + 'a>> {
+    if is_gzip(r.fill_buf()?) {
+        Ok(Box::new(GzDecoder::new(r)))
+    } else {
+        Ok(Box::new(r))
+    }
+}
+
+/// Whether `buf` starts as gzip does.
+pub fn is_gzip(buf: &[u8]) -> bool {
+    buf.starts_with(&[0x1f, 0x8b])
+}
+
+/// Caches `buf` as the archive of `version`. Older archives for the same os
+/// are left to `prune()` once the install went through.
+pub fn store(version: &str, os: &str, buf: &[u8], commit: Option<&str>) -> Result<CacheEntry> {
+    std::fs::create_dir_all(directory())?;
+    let entry = CacheEntry {
+        version: version.to_string(),
+        os: os.to_string(),
+        sha256: checksum::sha256_bytes(buf),
+        size: buf.len() as u64,
+        commit: commit.map(|c| c.to_string()),
+        compression: config::get().compress_cache,
+        tar_sha256: None,
+    };
+    // A previous entry of the version may be stored differently.
+    if let Some(previous) = lookup(version, os) {
+        remove(&previous)?;
+    }
+
+    write(entry, buf)
+}
+
+pub fn remove(entry: &CacheEntry) -> Result<()> {
+    for p in [entry.archive_path(), entry.metadata_path()] {
+        if p.exists() {
+            std::fs::remove_file(p)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a cached archive, re-hashing it against its metadata unless `trust`
+/// is set. That is the archive as downloaded, or the tar it held for a zstd
+/// entry, which `decompressed()` reads either way. Corrupted entries are
+/// removed before returning an error.
+pub fn load(entry: &CacheEntry, trust: bool) -> Result<Vec<u8>> {
+    let path = entry.archive_path();
+    let stored = std::fs::read(&path)
+        .map_err(|e| anyhow!("Failed to read cached archive {}: {}.", path.display(), e))?;
+    let (buf, expected) = match decode(entry, stored) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            remove(entry)?;
+            bail!(
+                "Cached archive {} is corrupted ({}) and has been removed.",
+                path.display(),
+                e
+            );
+        }
+    };
+    if trust {
+        debug!(
+            "Trusting cached archive {} without hashing it.",
+            path.display()
+        );
+        return Ok(buf);
+    }
+
+    info!("Verifying cached archive {} ...", path.display());
+    let progress_bar = progress::bar(
+        buf.len() as u64,
+        "{spinner} [{bar:25.cyan/blue}] {bytes}/{total_bytes} (eta {eta})",
+    );
+    let mut throttle = ThrottledProgress::new(&progress_bar, "verify-cache");
+    let reader = ProgressReader::new(&*buf, |progress: usize| {
+        throttle.inc(progress as u64);
+    });
+    let sha256 = checksum::sha256_reader(reader)?;
+    drop(throttle);
+    progress_bar.finish_and_clear();
+
+    if sha256 != expected {
+        remove(entry)?;
+        bail!(
+            "Cached archive {} is corrupted (expected sha256 {}, got {}) and has been removed.",
+            path.display(),
+            expected,
+            sha256
+        );
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpack::tests::{archive, Spec};
+
+    fn entry(compression: Compression) -> CacheEntry {
+        CacheEntry {
+            version: "1.0.0".to_string(),
+            os: "linux-x64".to_string(),
+            sha256: String::new(),
+            size: 0,
+            commit: None,
+            compression,
+            tar_sha256: None,
+        }
+    }
+
+    #[test]
+    fn zstd_entries_are_smaller() {
+        let text = (0..2000)
+            .map(|i| format!("export const line{} = require('./module{}');\n", i, i % 7))
+            .collect::<String>();
+        let buf = archive(&[
+            Spec::Dir(b"VSCode-linux-x64", 0o755),
+            Spec::File(b"VSCode-linux-x64/a.js", text.as_bytes(), 0o644),
+            Spec::File(b"VSCode-linux-x64/b.js", text.as_bytes(), 0o644),
+        ]);
+
+        let mut packed = entry(Compression::Zstd);
+        let stored = encode(&mut packed, &buf).unwrap().into_owned();
+        assert!(
+            stored.len() < buf.len(),
+            "{} >= {}",
+            stored.len(),
+            buf.len()
+        );
+
+        let (tar, expected) = decode(&packed, stored).unwrap();
+        assert!(!is_gzip(&tar));
+        assert_eq!(checksum::sha256_bytes(&tar), expected);
+        let mut gunzipped = Vec::new();
+        GzDecoder::new(&*buf).read_to_end(&mut gunzipped).unwrap();
+        assert_eq!(tar, gunzipped);
+        let mut read = Vec::new();
+        decompressed(&*tar).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, tar);
+    }
+
+    #[test]
+    fn plain_entries_are_as_downloaded() {
+        let buf = archive(&[Spec::File(b"VSCode-linux-x64/code", b"x", 0o755)]);
+        let mut plain = entry(Compression::None);
+        plain.sha256 = checksum::sha256_bytes(&buf);
+        let stored = encode(&mut plain, &buf).unwrap().into_owned();
+        assert_eq!(stored, buf);
+        assert_eq!(plain.tar_sha256, None);
+
+        let (read, expected) = decode(&plain, stored).unwrap();
+        assert!(is_gzip(&read));
+        assert_eq!(checksum::sha256_bytes(&read), expected);
+        let mut tar = Vec::new();
+        decompressed(&*read).unwrap().read_to_end(&mut tar).unwrap();
+        assert!(!is_gzip(&tar) && !tar.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::checker::{self, CheckOptions, UpdateStatus};
+use crate::server;
+use crate::table::{Format, Table};
+
+/// Channels checked at the same time by `check --all`.
+const MAX_PARALLEL_CHECKS: usize = 4;
+
+struct Channel {
+    name: &'static str,
+    is_installed: fn() -> bool,
+    check: fn(&CheckOptions) -> Result<UpdateStatus>,
+}
+
+/// Every product vsdown can manage.
+const CHANNELS: &[Channel] = &[
+    Channel {
+        name: "desktop",
+        is_installed: checker::is_installed,
+        check: checker::check_update,
+    },
+    Channel {
+        name: "server",
+        is_installed: server::is_installed,
+        check: server::check_update,
+    },
+];
+
+/// The result of one channel, either a status or why it could not be told.
+#[derive(Serialize)]
+pub struct ChannelStatus {
+    pub channel: &'static str,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub status: Option<UpdateStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Checks every installed channel, a few at a time. A failing channel is
+/// reported with its error and does not affect the others. Results are in
+/// the order of `CHANNELS`.
+pub fn check_all(options: &CheckOptions) -> Vec<ChannelStatus> {
+    let installed = CHANNELS
+        .iter()
+        .filter(|c| (c.is_installed)())
+        .collect::<Vec<_>>();
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..installed.len().min(MAX_PARALLEL_CHECKS) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let channel = match installed.get(i) {
+                    Some(c) => c,
+                    None => break,
+                };
+                let result = (channel.check)(options);
+                results
+                    .lock()
+                    .expect("a check worker panicked")
+                    .push((i, channel.name, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().expect("a check worker panicked");
+    results.sort_by_key(|(i, _, _)| *i);
+
+    results
+        .into_iter()
+        .map(|(_, channel, result)| match result {
+            Ok(status) => ChannelStatus {
+                channel,
+                status: Some(status),
+                error: None,
+            },
+            Err(e) => ChannelStatus {
+                channel,
+                status: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect()
+}
+
+/// Columns of `check --all`, in the order `--format plain` keeps.
+const COLUMNS: &[&str] = &["CHANNEL", "INSTALLED", "LATEST", "STATUS"];
+
+pub fn print_table(results: &[ChannelStatus], format: Format) {
+    if results.is_empty() {
+        if format == Format::Table {
+            println!("No Visual Studio Code channel is installed by vsdown.");
+        }
+        return;
+    }
+    let mut table = Table::new(COLUMNS);
+    for r in results {
+        table.row(match &r.status {
+            Some(s) => vec![
+                r.channel.to_string(),
+                s.current_version.clone().unwrap_or_else(|| "-".to_string()),
+                s.latest_version.clone(),
+                if s.update_available {
+                    style("update available").yellow().bold().to_string()
+                } else if s.ahead {
+                    style("ahead").cyan().to_string()
+                } else {
+                    style("up to date").green().to_string()
+                },
+            ],
+            None => vec![
+                r.channel.to_string(),
+                "-".to_string(),
+                "-".to_string(),
+                format!(
+                    "{} {}",
+                    style("error:").red().bold(),
+                    r.error.as_deref().unwrap_or_default()
+                ),
+            ],
+        });
+    }
+    table.print(format);
+}
+
+/// Fails with the first error once every result has been printed.
+pub fn first_error(results: &[ChannelStatus]) -> Result<()> {
+    match results
+        .iter()
+        .find_map(|r| r.error.as_ref().map(|e| (r.channel, e)))
+    {
+        Some((channel, e)) => Err(anyhow!("Checking {} failed: {}", channel, e)),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+use crate::timings::{self, Phase};
+
+/// Returns the lowercase hex SHA-256 digest of everything `r` yields.
+pub fn sha256_reader<R: Read>(mut r: R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let n = timings::measure(Phase::Hash, || std::io::copy(&mut r, &mut hasher))?;
+    timings::add_bytes(Phase::Hash, n);
+
+    Ok(hex(&hasher.finalize()))
+}
+
+pub fn sha256_bytes(buf: &[u8]) -> String {
+    let digest = timings::measure(Phase::Hash, || Sha256::digest(buf));
+    timings::add_bytes(Phase::Hash, buf.len() as u64);
+
+    hex(&digest)
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a `sha256:HEX` digest argument, returning the lowercase hex digest.
+pub fn parse_sha256(s: &str) -> Result<String> {
+    let hex = match s.split_once(':') {
+        Some(("sha256", hex)) => hex,
+        Some((algorithm, _)) => bail!(
+            "unsupported digest algorithm {}, expected sha256",
+            algorithm
+        ),
+        None => bail!("expected a digest in the form sha256:HEX"),
+    };
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("a sha256 digest must be 64 hexadecimal characters");
+    }
+
+    Ok(hex.to_ascii_lowercase())
+}
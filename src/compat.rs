@@ -0,0 +1,174 @@
+use anyhow::{anyhow, bail, Result};
+use clap::ArgEnum;
+use console::style;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::Stdio,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::checker::INSTALL_DIR;
+use crate::{debug, elf, info, user, warn};
+
+/// Where the system C and C++ runtime libraries are looked up.
+const LIBRARY_DIRECTORIES: &[&str] = &[
+    "/usr/lib",
+    "/usr/lib64",
+    "/lib",
+    "/lib64",
+    "/usr/lib/x86_64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+];
+
+/// (library name, soname, symbol version prefix)
+const RUNTIMES: &[(&str, &str, &str)] = &[
+    ("glibc", "libc.so.6", "GLIBC_"),
+    ("libstdc++", "libstdc++.so.6", "GLIBCXX_"),
+];
+
+/// Checks that the system libraries provide every glibc and libstdc++ symbol
+/// version `code` needs, so an upgrade never replaces a working install with
+/// one which cannot start.
+pub fn check(code: &Path, version: &str) -> Result<()> {
+    info!("Checking C library compatibility ...");
+    let needed = elf::read_dynstr(code)?;
+    for (name, soname, prefix) in RUNTIMES {
+        let required = match max_version(&needed, prefix) {
+            Some(v) => v,
+            None => continue,
+        };
+        let provided = match find_library(soname)
+            .and_then(|p| elf::read_dynstr(&p).ok())
+            .and_then(|s| max_version(&s, prefix))
+        {
+            Some(v) => v,
+            None => {
+                warn!(
+                    "Could not determine the {} version of this system, skipping its compatibility check.",
+                    name
+                );
+                continue;
+            }
+        };
+        debug!(
+            "{}: requires {}, system provides {}",
+            name,
+            format_version(&required),
+            format_version(&provided)
+        );
+        if required > provided {
+            bail!(
+                "Visual Studio Code {} requires {} >= {} but this system has {}. Pass --skip-compat-check to install it anyway.",
+                version,
+                name,
+                format_version(&required),
+                format_version(&provided)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The highest `<prefix><version>` string in a string table, e.g. 2.28 for
+/// GLIBC_2.28.
+fn max_version(strtab: &[u8], prefix: &str) -> Option<Vec<u32>> {
+    strtab
+        .split(|b| *b == 0)
+        .filter_map(|s| std::str::from_utf8(s).ok())
+        .filter_map(|s| s.strip_prefix(prefix))
+        .filter_map(|v| {
+            v.split('.')
+                .map(|i| i.parse::<u32>().ok())
+                .collect::<Option<Vec<_>>>()
+        })
+        .max()
+}
+
+fn find_library(soname: &str) -> Option<PathBuf> {
+    LIBRARY_DIRECTORIES
+        .iter()
+        .map(|d| Path::new(d).join(soname))
+        .find(|p| p.exists())
+}
+
+fn format_version(v: &[u32]) -> String {
+    v.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// What a failed `--health-check` does to the install.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum HealthCheck {
+    /// Do not run it
+    Off,
+    /// Report the failure and go on
+    Warn,
+    /// Fail the install, leaving the new release installed
+    Fail,
+}
+
+/// Seconds `--health-check` waits for the editor by default.
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: u64 = 30;
+const HEALTH_CHECK_POLL: Duration = Duration::from_millis(100);
+
+/// Runs `command` through the shell, `code --version` of the installed tree
+/// by default, as the target user and fails unless it exits within
+/// `timeout` printing the installed `version`. The error carries what the
+/// command printed.
+pub fn health_check(command: Option<&str>, version: &str, timeout: Duration) -> Result<()> {
+    let default = format!("{}/bin/code --version", INSTALL_DIR);
+    let command = command.unwrap_or(&default);
+    info!("Checking that Visual Studio Code starts: {} ...", command);
+    let mut child = user::target()?
+        .command("sh")
+        .args(["-c", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run {}: {}.", command, e))?;
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(HEALTH_CHECK_POLL);
+    };
+    let mut output = String::new();
+    if let Some(mut out) = child.stdout.take() {
+        let _ = out.read_to_string(&mut output);
+    }
+    let printed = output.clone();
+    if let Some(mut err) = child.stderr.take() {
+        let _ = err.read_to_string(&mut output);
+    }
+    let output = output.trim();
+    match status {
+        None => bail!(
+            "{} did not finish within {} seconds. Output:\n{}",
+            command,
+            timeout.as_secs(),
+            output
+        ),
+        Some(s) if !s.success() => bail!("{} failed ({}). Output:\n{}", command, s, output),
+        Some(_) if printed.lines().next().map(str::trim) != Some(version) => bail!(
+            "{} did not print version {}. Output:\n{}",
+            command,
+            version,
+            output
+        ),
+        Some(_) => debug!("{} printed {}", command, version),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, bail, Result};
+use clap::Command;
+use clap_complete::Shell;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::{cache, checker, state, user};
+
+/// Options whose values are release versions, completed dynamically.
+const VERSION_OPTIONS: &[&str] = &["--version", "--skip-if-marker"];
+
+/// Prints the completion script for `shell`.
+pub fn print_completions(shell: Shell, command: &mut Command) {
+    print!("{}", script(shell, command));
+}
+
+/// The completion script for `shell`, extended so version-valued options
+/// are completed by `vsdown __complete-versions`.
+fn script(shell: Shell, command: &mut Command) -> String {
+    let mut buf = Vec::new();
+    clap_complete::generate(shell, command, "vsdown", &mut buf);
+    let mut script = String::from_utf8_lossy(&buf).into_owned();
+    let options = VERSION_OPTIONS.join(" ");
+    match shell {
+        Shell::Bash => {
+            script.push_str(&format!(
+                r#"
+_vsdown_with_versions() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    for opt in {options}; do
+        if [[ "$prev" == "$opt" ]]; then
+            COMPREPLY=($(compgen -W "$(vsdown __complete-versions 2>/dev/null)" -- "$cur"))
+            return 0
+        fi
+    done
+    _vsdown "$@"
+}}
+complete -F _vsdown_with_versions -o bashdefault -o default vsdown
+"#,
+                options = options
+            ));
+        }
+        Shell::Zsh => {
+            for opt in VERSION_OPTIONS {
+                // clap_complete leaves the value action empty: `--opt=[help]:NAME: `
+                let marker = format!("'{}=[", opt);
+                let mut out = String::new();
+                for line in script.lines() {
+                    if line.trim_start().starts_with(&marker) && line.ends_with(": ' \\") {
+                        out.push_str(&line.replacen(": ' \\", ":_vsdown_versions' \\", 1));
+                    } else {
+                        out.push_str(line);
+                    }
+                    out.push('\n');
+                }
+                script = out;
+            }
+            script = script.replacen(
+                "#compdef vsdown\n",
+                "#compdef vsdown\n\n_vsdown_versions() {\n    compadd -- ${(f)\"$(vsdown __complete-versions 2>/dev/null)\"}\n}\n",
+                1,
+            );
+        }
+        Shell::Fish => {
+            for opt in VERSION_OPTIONS {
+                script.push_str(&format!(
+                    "complete -c vsdown -n \"__fish_seen_subcommand_from install\" -l {} -r -f -a \"(vsdown __complete-versions 2>/dev/null)\"\n",
+                    opt.trim_start_matches('-')
+                ));
+            }
+        }
+        _ => {}
+    }
+
+    script
+}
+
+/// The shell named by `$SHELL`.
+fn detect_shell() -> Result<Shell> {
+    let var = std::env::var("SHELL").unwrap_or_default();
+    let name = Path::new(&var)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    name.parse::<Shell>()
+        .map_err(|_| anyhow!("Could not tell the shell from $SHELL ({:?}), name it.", var))
+}
+
+/// Where `shell` looks for the completions of vsdown: the system-wide
+/// directory for root, below `home` otherwise.
+fn install_path(shell: Shell, home: Option<&Path>) -> Result<PathBuf> {
+    let data = |rel: &str| -> Result<PathBuf> {
+        Ok(match home {
+            None => Path::new("/usr/share").join(rel),
+            Some(home) => std::env::var_os("XDG_DATA_HOME")
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".local/share"))
+                .join(rel),
+        })
+    };
+    let path = match shell {
+        Shell::Bash => data("bash-completion/completions/vsdown")?,
+        Shell::Zsh => data("zsh/site-functions/_vsdown")?,
+        Shell::Fish => match home {
+            None => data("fish/vendor_completions.d/vsdown.fish")?,
+            Some(home) => std::env::var_os("XDG_CONFIG_HOME")
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".config"))
+                .join("fish/completions/vsdown.fish"),
+        },
+        other => bail!(
+            "Installing completions for {} is not supported, print them instead.",
+            other
+        ),
+    };
+
+    Ok(path)
+}
+
+/// Writes the completion script for `shell`, `$SHELL` by default, where the
+/// shell finds it: system-wide as root, for the invoking user otherwise.
+/// Returns the path written.
+pub fn install(shell: Option<Shell>, command: &mut Command) -> Result<PathBuf> {
+    let shell = match shell {
+        Some(shell) => shell,
+        None => detect_shell()?,
+    };
+    let home = if unsafe { libc::geteuid() } == 0 {
+        None
+    } else {
+        Some(user::target()?.home)
+    };
+    let path = install_path(shell, home.as_deref())?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("Failed to create {}: {}.", dir.display(), e))?;
+    }
+    std::fs::write(&path, script(shell, command))
+        .map_err(|e| anyhow!("Failed to write {}: {}.", path.display(), e))?;
+
+    Ok(path)
+}
+
+/// Prints known release versions, newest first, from local records only:
+/// the last upstream version list, cached archives, and the installed one.
+pub fn print_versions() {
+    let mut versions = state::remote_versions().map(|(_, v)| v).unwrap_or_default();
+    versions.extend(cache::entries().into_iter().map(|e| e.version));
+    if let Ok(v) = checker::get_current_version() {
+        versions.push(v);
+    }
+    let mut seen = HashSet::new();
+    versions.retain(|v| !v.is_empty() && seen.insert(v.clone()));
+    versions.sort_by(
+        |a, b| match (semver::Version::parse(a), semver::Version::parse(b)) {
+            (Ok(a), Ok(b)) => b.cmp(&a),
+            _ => b.cmp(a),
+        },
+    );
+    for v in versions {
+        println!("{}", v);
+    }
+}
@@ -0,0 +1,542 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::checker::VersionSource;
+use crate::{cache, netfs, pin, progress, proxy, state, units, warn};
+
+pub const CONFIG_PATH: &str = "/etc/vsdown/config.toml";
+pub const ARCH_MAP_PATH: &str = "/etc/vsdown/arch-map.toml";
+
+/// Architectures Microsoft publishes builds for, see `checker::get_arch()`.
+const OFFICIAL_ARCHES: &[&str] = &["x86_64", "aarch64"];
+
+const DEFAULT_MIRROR_TTL: u64 = 24 * 60 * 60;
+const DEFAULT_RETRY_AFTER_CAP: u64 = 5 * 60;
+const DEFAULT_THROTTLE_BUDGET: u64 = 30 * 60;
+const DEFAULT_DAEMON_IDLE_TIMEOUT: u64 = 5 * 60;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The on-disk layout of `CONFIG_PATH` and the user configuration file.
+/// Every key is optional so the user file only overrides what it sets.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    mirrors: Option<Vec<String>>,
+    mirror_ttl: Option<u64>,
+    max_parallel_mirrors: Option<u64>,
+    pins: Option<Vec<String>>,
+    proxy_rules: Option<Vec<String>>,
+    retry_after_cap: Option<u64>,
+    throttle_budget: Option<u64>,
+    verify_after_days: Option<u64>,
+    daemon_idle_timeout: Option<u64>,
+    retain: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    units: Option<String>,
+    state_fs: Option<String>,
+}
+
+/// Renders `v` as a TOML array of strings.
+fn toml_list(v: &[String]) -> String {
+    let items = v.iter().map(|i| format!("{:?}", i)).collect::<Vec<_>>();
+    format!("[{}]", items.join(", "))
+}
+
+impl ConfigFile {
+    /// Returns None if `p` does not exist.
+    fn read(p: &Path) -> Result<Option<Self>> {
+        if !p.exists() {
+            return Ok(None);
+        }
+        let s = std::fs::read_to_string(p)
+            .map_err(|e| anyhow!("Failed to read {}: {}.", p.display(), e))?;
+        let file = toml::from_str::<ConfigFile>(&s)
+            .map_err(|e| anyhow!("Failed to parse {}: {}.", p.display(), e))?;
+
+        Ok(Some(file))
+    }
+
+    /// Renders the value of `key` the way it would be written in TOML.
+    fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "mirrors" => self.mirrors.as_deref().map(toml_list),
+            "mirror-ttl" => self.mirror_ttl.map(|v| v.to_string()),
+            "daemon-idle-timeout" => self.daemon_idle_timeout.map(|v| v.to_string()),
+            "max-parallel-mirrors" => self.max_parallel_mirrors.map(|v| v.to_string()),
+            "pins" => self.pins.as_deref().map(toml_list),
+            "proxy-rules" => self.proxy_rules.as_deref().map(toml_list),
+            "retry-after-cap" => self.retry_after_cap.map(|v| v.to_string()),
+            "throttle-budget" => self.throttle_budget.map(|v| v.to_string()),
+            "verify-after-days" => self.verify_after_days.map(|v| v.to_string()),
+            "retain" => self.retain.as_deref().map(toml_list),
+            "exclude" => self.exclude.as_deref().map(toml_list),
+            "units" => self.units.as_ref().map(|v| format!("{:?}", v)),
+            "state-fs" => self.state_fs.as_ref().map(|v| format!("{:?}", v)),
+            _ => None,
+        }
+    }
+
+    /// Applies the user file on top of the system one.
+    fn merge(self, user: ConfigFile) -> ConfigFile {
+        ConfigFile {
+            mirrors: user.mirrors.or(self.mirrors),
+            mirror_ttl: user.mirror_ttl.or(self.mirror_ttl),
+            max_parallel_mirrors: user.max_parallel_mirrors.or(self.max_parallel_mirrors),
+            pins: user.pins.or(self.pins),
+            proxy_rules: user.proxy_rules.or(self.proxy_rules),
+            retry_after_cap: user.retry_after_cap.or(self.retry_after_cap),
+            throttle_budget: user.throttle_budget.or(self.throttle_budget),
+            verify_after_days: user.verify_after_days.or(self.verify_after_days),
+            daemon_idle_timeout: user.daemon_idle_timeout.or(self.daemon_idle_timeout),
+            retain: user.retain.or(self.retain),
+            exclude: user.exclude.or(self.exclude),
+            units: user.units.or(self.units),
+            state_fs: user.state_fs.or(self.state_fs),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum KeyType {
+    Integer,
+    String,
+    StringList,
+}
+
+/// Every key `config set` accepts, see `ConfigFile`.
+const KEYS: &[(&str, KeyType, &str)] = &[
+    (
+        "mirrors",
+        KeyType::StringList,
+        "download URL templates, comma separated",
+    ),
+    (
+        "mirror-ttl",
+        KeyType::Integer,
+        "seconds to remember the selected mirror",
+    ),
+    (
+        "max-parallel-mirrors",
+        KeyType::Integer,
+        "mirrors raced at once when selecting one, 1 probing them in turn",
+    ),
+    (
+        "pins",
+        KeyType::StringList,
+        "pinned certificate keys as HOST=sha256:HEX, comma separated",
+    ),
+    (
+        "proxy-rules",
+        KeyType::StringList,
+        "proxies by host as PATTERN=URL or PATTERN=direct, first match wins, comma separated",
+    ),
+    (
+        "retry-after-cap",
+        KeyType::Integer,
+        "longest wait in seconds a Retry-After header is honoured for",
+    ),
+    (
+        "throttle-budget",
+        KeyType::Integer,
+        "seconds a download may spend waiting for a throttling server in total",
+    ),
+    (
+        "verify-after-days",
+        KeyType::Integer,
+        "days after which check reminds to run verify, 0 never",
+    ),
+    (
+        "daemon-idle-timeout",
+        KeyType::Integer,
+        "seconds the D-Bus service waits for a call before exiting, 0 never",
+    ),
+    (
+        "retain",
+        KeyType::StringList,
+        "previous versions cached for rollback as CHANNEL=N, comma separated",
+    ),
+    (
+        "exclude",
+        KeyType::StringList,
+        "glob patterns of release contents not to install, comma separated",
+    ),
+    (
+        "units",
+        KeyType::String,
+        "binary (KiB, MiB) or decimal (kB, MB) units for sizes and speeds",
+    ),
+    (
+        "state-fs",
+        KeyType::String,
+        "auto, or local or network to override detecting the filesystem of the state directory",
+    ),
+];
+
+/// Built-in values of keys which have one, as rendered by `ConfigFile::get()`.
+fn default_value(key: &str) -> Option<String> {
+    match key {
+        "mirror-ttl" => Some(DEFAULT_MIRROR_TTL.to_string()),
+        "retry-after-cap" => Some(DEFAULT_RETRY_AFTER_CAP.to_string()),
+        "throttle-budget" => Some(DEFAULT_THROTTLE_BUDGET.to_string()),
+        "daemon-idle-timeout" => Some(DEFAULT_DAEMON_IDLE_TIMEOUT.to_string()),
+        "units" => Some("\"binary\"".to_string()),
+        "state-fs" => Some("\"auto\"".to_string()),
+        _ => None,
+    }
+}
+
+/// `$XDG_CONFIG_HOME/vsdown/config.toml`, falling back to `~/.config`.
+pub fn user_config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+
+    Some(base.join("vsdown/config.toml"))
+}
+
+#[derive(Debug)]
+pub struct Config {
+    /// Overrides from `std::env::consts::ARCH` to the `os` value of the
+    /// download URL, consulted before the built-in table.
+    pub arch_map: HashMap<String, String>,
+    /// Download URL templates to choose from, `{os}` is substituted.
+    pub mirrors: Vec<String>,
+    /// Seconds a mirror choice is remembered before probing again.
+    pub mirror_ttl: u64,
+    /// Mirrors whose first chunk is raced at once when selecting one, 1
+    /// probing them one after the other.
+    pub max_parallel_mirrors: usize,
+    /// A mirror forced from the command line, skipping selection.
+    pub mirror: Option<String>,
+    /// Overrides `hooks::HOOKS_DIRECTORY`.
+    pub hooks_dir: Option<PathBuf>,
+    /// Minimum TLS version for every request, "1.2" or "1.3".
+    pub tls_min: Option<String>,
+    /// Fail a download on a 4xx response rather than trying the next mirror.
+    pub fail_fast_on_4xx: bool,
+    /// Rebuild the desktop database and icon cache after changing metadata.
+    pub metadata_refresh: bool,
+    /// Accepted SPKI SHA-256 digests by host, see `pin::check()`.
+    pub pins: HashMap<String, Vec<String>>,
+    /// Proxies by host, overriding the environment's, see `proxy::Rule`.
+    pub proxy_rules: Vec<proxy::Rule>,
+    pub progress_style: progress::Style,
+    /// Look up proxy and mirror credentials in netrc.
+    pub netrc: bool,
+    /// Longest Retry-After delay honoured, in seconds.
+    pub retry_after_cap: u64,
+    /// Total seconds a download may wait for throttling servers.
+    pub throttle_budget: u64,
+    pub state_format: state::Format,
+    /// Which source the latest version is asked of first.
+    pub version_source: VersionSource,
+    /// Overrides `cache::CACHE_DIRECTORY`.
+    pub cache_dir: Option<PathBuf>,
+    /// How newly cached archives are stored.
+    pub compress_cache: cache::Compression,
+    /// Where `--dump-download` saves the body of each download response.
+    pub dump_download: Option<PathBuf>,
+    /// Days without a clean `verify` after which `check` reminds of it, 0
+    /// never.
+    pub verify_after_days: u64,
+    /// Seconds `vsdown daemon` stays up without calls, 0 for ever.
+    pub daemon_idle_timeout: u64,
+    /// Previous versions kept in the cache for rolling back, by channel.
+    pub retain: HashMap<String, u64>,
+    /// Glob patterns of release contents not to install, see `exclude::Exclude`.
+    pub exclude: Vec<String>,
+    /// Which multiples sizes and speeds are shown in.
+    pub units: units::System,
+    /// Whether the state directory is taken to be on a network filesystem.
+    pub state_fs: netfs::Detection,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            arch_map: HashMap::new(),
+            mirrors: Vec::new(),
+            mirror_ttl: DEFAULT_MIRROR_TTL,
+            max_parallel_mirrors: 1,
+            mirror: None,
+            hooks_dir: None,
+            tls_min: None,
+            fail_fast_on_4xx: false,
+            metadata_refresh: true,
+            pins: HashMap::new(),
+            proxy_rules: Vec::new(),
+            progress_style: progress::Style::Auto,
+            netrc: true,
+            retry_after_cap: DEFAULT_RETRY_AFTER_CAP,
+            throttle_budget: DEFAULT_THROTTLE_BUDGET,
+            state_format: state::Format::Json,
+            version_source: VersionSource::UpdateApi,
+            cache_dir: None,
+            compress_cache: cache::Compression::None,
+            dump_download: None,
+            verify_after_days: 0,
+            daemon_idle_timeout: DEFAULT_DAEMON_IDLE_TIMEOUT,
+            retain: HashMap::new(),
+            exclude: Vec::new(),
+            units: units::System::Binary,
+            state_fs: netfs::Detection::Auto,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(arch_map: Option<&Path>) -> Result<Self> {
+        let file = load_files()?;
+        let arch_map = match arch_map {
+            Some(p) => load_arch_map(p)?,
+            None if Path::new(ARCH_MAP_PATH).exists() => load_arch_map(Path::new(ARCH_MAP_PATH))?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            arch_map,
+            mirrors: file.mirrors.unwrap_or_default(),
+            mirror_ttl: file.mirror_ttl.unwrap_or(DEFAULT_MIRROR_TTL),
+            max_parallel_mirrors: file.max_parallel_mirrors.unwrap_or(1).max(1) as usize,
+            pins: pin::parse(&file.pins.unwrap_or_default())?,
+            proxy_rules: proxy::parse(&file.proxy_rules.unwrap_or_default())?,
+            retry_after_cap: file.retry_after_cap.unwrap_or(DEFAULT_RETRY_AFTER_CAP),
+            throttle_budget: file.throttle_budget.unwrap_or(DEFAULT_THROTTLE_BUDGET),
+            verify_after_days: file.verify_after_days.unwrap_or(0),
+            daemon_idle_timeout: file
+                .daemon_idle_timeout
+                .unwrap_or(DEFAULT_DAEMON_IDLE_TIMEOUT),
+            retain: parse_retain(&file.retain.unwrap_or_default())?,
+            exclude: file.exclude.unwrap_or_default(),
+            units: match &file.units {
+                Some(s) => units::System::parse(s)?,
+                None => units::System::Binary,
+            },
+            state_fs: match &file.state_fs {
+                Some(s) => netfs::Detection::parse(s)?,
+                None => netfs::Detection::Auto,
+            },
+            ..Default::default()
+        })
+    }
+}
+
+/// Parses `retain` entries of the form CHANNEL=N.
+fn parse_retain(entries: &[String]) -> Result<HashMap<String, u64>> {
+    let mut retain = HashMap::new();
+    for entry in entries {
+        let (channel, n) = entry
+            .split_once('=')
+            .and_then(|(c, n)| Some((c.trim(), n.trim().parse::<u64>().ok()?)))
+            .ok_or_else(|| anyhow!("Invalid retain entry {}: expected CHANNEL=N.", entry))?;
+        retain.insert(channel.to_string(), n);
+    }
+
+    Ok(retain)
+}
+
+/// How many previous versions of `channel` the cache keeps for rolling back,
+/// none unless configured.
+pub fn retention(channel: &str) -> u64 {
+    get().retain.get(channel).copied().unwrap_or(0)
+}
+
+/// Fails unless `s` is a configuration file vsdown would load, reporting
+/// errors as being in `p`.
+pub fn validate(s: &str, p: &Path) -> Result<()> {
+    let file = toml::from_str::<ConfigFile>(s)
+        .map_err(|e| anyhow!("Failed to parse {}: {}.", p.display(), e))?;
+    pin::parse(&file.pins.unwrap_or_default())?;
+    proxy::parse(&file.proxy_rules.unwrap_or_default())?;
+    parse_retain(&file.retain.unwrap_or_default())?;
+    if let Some(s) = &file.units {
+        units::System::parse(s)?;
+    }
+    if let Some(s) = &file.state_fs {
+        netfs::Detection::parse(s)?;
+    }
+
+    Ok(())
+}
+
+fn load_files() -> Result<ConfigFile> {
+    let system = ConfigFile::read(Path::new(CONFIG_PATH))?.unwrap_or_default();
+    let user = match user_config_path() {
+        Some(p) => ConfigFile::read(&p)?.unwrap_or_default(),
+        None => ConfigFile::default(),
+    };
+
+    Ok(system.merge(user))
+}
+
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .expect("configuration is initialized twice");
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+fn load_arch_map(p: &Path) -> Result<HashMap<String, String>> {
+    let s = std::fs::read_to_string(p)
+        .map_err(|e| anyhow!("Failed to read arch map {}: {}.", p.display(), e))?;
+
+    parse_arch_map(&s, p)
+}
+
+/// Parses and checks the contents of the arch map `p`.
+pub fn parse_arch_map(s: &str, p: &Path) -> Result<HashMap<String, String>> {
+    let map: HashMap<String, String> = toml::from_str(s)
+        .map_err(|e| anyhow!("Failed to parse arch map {}: {}.", p.display(), e))?;
+    for (arch, os) in &map {
+        if os.is_empty()
+            || !os
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            bail!(
+                "Invalid arch map entry in {}: {} = \"{}\" (expected an os value such as \"linux-x64\").",
+                p.display(),
+                arch,
+                os
+            );
+        }
+        if OFFICIAL_ARCHES.contains(&arch.as_str()) {
+            warn!(
+                "Arch map {} overrides the officially supported architecture {} with {}.",
+                p.display(),
+                arch,
+                os
+            );
+        }
+    }
+
+    Ok(map)
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The file `config set`/`unset` edit: the system file for root, the user
+/// file otherwise.
+fn editable_path() -> Result<PathBuf> {
+    if is_root() {
+        return Ok(PathBuf::from(CONFIG_PATH));
+    }
+
+    user_config_path()
+        .ok_or_else(|| anyhow!("Cannot locate the user configuration file, HOME is not set."))
+}
+
+fn key_type(key: &str) -> Result<KeyType> {
+    KEYS.iter()
+        .find(|(k, _, _)| *k == key)
+        .map(|(_, t, _)| *t)
+        .ok_or_else(|| {
+            anyhow!(
+                "Unknown configuration key {}. Valid keys are: {}.",
+                key,
+                KEYS.iter()
+                    .map(|(k, _, _)| *k)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+/// Prints the effective value of `key`.
+pub fn get_key(key: &str) -> Result<()> {
+    key_type(key)?;
+    match load_files()?.get(key).or_else(|| default_value(key)) {
+        Some(v) => println!("{}", v),
+        None => bail!("{} is not set.", key),
+    }
+
+    Ok(())
+}
+
+/// Sets `key` in the editable configuration file, keeping everything else in
+/// it (comments included) as it is.
+pub fn set_key(key: &str, value: &str) -> Result<()> {
+    let item = match key_type(key)? {
+        KeyType::Integer => {
+            let v = value
+                .parse::<u64>()
+                .map_err(|_| anyhow!("{} must be a non-negative integer.", key))?;
+            toml_edit::value(v as i64)
+        }
+        KeyType::String => toml_edit::value(value),
+        KeyType::StringList => {
+            let mut array = toml_edit::Array::new();
+            for i in value.split(',').map(|i| i.trim()).filter(|i| !i.is_empty()) {
+                array.push(i);
+            }
+            toml_edit::value(array)
+        }
+    };
+    edit(|doc| {
+        doc[key] = item;
+    })
+}
+
+pub fn unset_key(key: &str) -> Result<()> {
+    key_type(key)?;
+    edit(|doc| {
+        doc.as_table_mut().remove(key);
+    })
+}
+
+/// Prints every key with its effective value and where it comes from.
+pub fn list_keys() -> Result<()> {
+    let system = ConfigFile::read(Path::new(CONFIG_PATH))?;
+    let user_path = user_config_path();
+    let user = match &user_path {
+        Some(p) => ConfigFile::read(p)?,
+        None => None,
+    };
+    for (key, _, description) in KEYS {
+        let (value, origin) = if let Some(v) = user.as_ref().and_then(|f| f.get(key)) {
+            (v, user_path.as_ref().unwrap().display().to_string())
+        } else if let Some(v) = system.as_ref().and_then(|f| f.get(key)) {
+            (v, CONFIG_PATH.to_string())
+        } else if let Some(v) = default_value(key) {
+            (v, "default".to_string())
+        } else {
+            ("(unset)".to_string(), "default".to_string())
+        };
+        println!("{} = {}", key, value);
+        eprintln!("  {} ({})", style(description).dim(), origin);
+    }
+
+    Ok(())
+}
+
+fn edit<F: FnOnce(&mut toml_edit::Document)>(f: F) -> Result<()> {
+    let p = editable_path()?;
+    let s = if p.exists() {
+        std::fs::read_to_string(&p)
+            .map_err(|e| anyhow!("Failed to read {}: {}.", p.display(), e))?
+    } else {
+        String::new()
+    };
+    let mut doc = s
+        .parse::<toml_edit::Document>()
+        .map_err(|e| anyhow!("Failed to parse {}: {}.", p.display(), e))?;
+    f(&mut doc);
+    let s = doc.to_string();
+    // Never write a file vsdown itself would then refuse to load.
+    validate(&s, &p).map_err(|e| anyhow!("Refusing to write {}: {}", p.display(), e))?;
+
+    state::write_atomic(&p, s.as_bytes())
+}
@@ -0,0 +1,123 @@
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use crate::{checker::CURRENT_VERSION_DIRECTORY, state, sysext};
+
+const ISSUE_URL: &str = "https://github.com/AOSC-Archive/vsdown/issues";
+
+/// Replaces the default panic output with a short message pointing to a
+/// crash report written to disk.
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "(non-string panic payload)".to_string());
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+            .unwrap_or_else(|| "unknown".to_string());
+        let report = report(&message, &location);
+        let mut stderr = std::io::stderr();
+        // Nothing here may panic again, so every error is ignored.
+        let _ = match write_report(&report) {
+            Some(p) => writeln!(
+                stderr,
+                "vsdown crashed unexpectedly. A report was written to {}.\nPlease file an issue at {} and attach it.",
+                p.display(),
+                ISSUE_URL
+            ),
+            None => writeln!(
+                stderr,
+                "vsdown crashed unexpectedly and could not save a report.\nPlease file an issue at {} including the following:\n\n{}",
+                ISSUE_URL, report
+            ),
+        };
+    }));
+}
+
+fn report(message: &str, location: &str) -> String {
+    let os = sysext::read_os_release()
+        .ok()
+        .and_then(|r| r.get("PRETTY_NAME").cloned())
+        .unwrap_or_else(|| "unknown".to_string());
+    let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    let mut s = String::new();
+    let _ = writeln!(s, "vsdown {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(s, "Command: {}", redacted_args().join(" "));
+    let _ = writeln!(s, "OS: {} ({})", os, std::env::consts::ARCH);
+    let _ = writeln!(s, "Kernel: {}", kernel);
+    let _ = writeln!(s, "Time: {}", state::now());
+    let _ = writeln!(s, "Panic: {}", message);
+    let _ = writeln!(s, "Location: {}", location);
+    let backtrace = Backtrace::capture();
+    match backtrace.status() {
+        BacktraceStatus::Captured => {
+            let _ = writeln!(s, "\nBacktrace:\n{}", backtrace);
+        }
+        _ => {
+            let _ = writeln!(s, "\nBacktrace: unavailable, rerun with RUST_BACKTRACE=1");
+        }
+    }
+
+    s
+}
+
+/// The command line with option values and arguments beyond the subcommand
+/// replaced, as they may contain URLs, paths or user names.
+fn redacted_args() -> Vec<String> {
+    let mut seen_subcommand = false;
+    std::env::args_os()
+        .skip(1)
+        .map(|a| {
+            let a = a.to_string_lossy();
+            if a.starts_with('-') {
+                match a.split_once('=') {
+                    Some((name, _)) => format!("{}=<redacted>", name),
+                    None => a.to_string(),
+                }
+            } else if !seen_subcommand {
+                seen_subcommand = true;
+                a.to_string()
+            } else {
+                "<redacted>".to_string()
+            }
+        })
+        .collect()
+}
+
+/// Returns where the report was written, trying the system state directory
+/// first and the user's one next.
+fn write_report(report: &str) -> Option<PathBuf> {
+    let name = format!("crash-{}-{}.txt", state::now(), std::process::id());
+    for dir in crash_directories() {
+        let p = dir.join(&name);
+        if std::fs::create_dir_all(&dir).is_ok() && std::fs::write(&p, report).is_ok() {
+            return Some(p);
+        }
+    }
+
+    None
+}
+
+pub fn crash_directories() -> Vec<PathBuf> {
+    let mut dirs = vec![Path::new(CURRENT_VERSION_DIRECTORY).join("crash")];
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/state")));
+    if let Some(base) = state_home {
+        dirs.push(base.join("vsdown/crash"));
+    }
+
+    dirs
+}
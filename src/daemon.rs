@@ -0,0 +1,504 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use zbus::{
+    blocking::Connection, dbus_interface, fdo, zvariant::Value, MessageHeader, SignalContext,
+};
+
+use crate::checker::{self, CheckOptions, InstallOptions};
+use crate::{debug, error, events, info, lock, state, warn};
+
+/// The well-known name of the service.
+pub const BUS_NAME: &str = "org.aosc.vsdown";
+const OBJECT_PATH: &str = "/org/aosc/vsdown";
+/// The polkit action installs and removals are authorised against, see
+/// res/org.aosc.vsdown.policy.
+const POLKIT_ACTION: &str = "org.aosc.vsdown.manage";
+/// Lets polkit ask the caller's session to authenticate.
+const POLKIT_ALLOW_USER_INTERACTION: u32 = 1;
+/// How often the idle timeout is checked.
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// What the methods run, stubbed in tests, which cannot install on the
+/// system they run on.
+#[derive(Clone, Copy)]
+struct Operations {
+    check_update: fn() -> Result<String>,
+    install: fn(bool) -> Result<String>,
+    remove: fn(bool) -> Result<String>,
+}
+
+const OPERATIONS: Operations = Operations {
+    check_update,
+    install,
+    remove,
+};
+
+struct Service {
+    conn: Connection,
+    ops: Operations,
+    /// Whether the service is on the system bus, where callers of installs
+    /// and removals are checked with polkit.
+    system: bool,
+    /// Set while an install or removal runs.
+    busy: Arc<AtomicBool>,
+    last_call: Arc<Mutex<Instant>>,
+}
+
+impl Service {
+    fn touch(&self) {
+        *self.last_call.lock().expect("a method call panicked") = Instant::now();
+    }
+
+    /// Runs `op` for `sender` on a thread of its own, so that the call
+    /// returns at once and progress can be signalled meanwhile, then signals
+    /// `Finished` with its outcome.
+    fn spawn(
+        &self,
+        operation: &'static str,
+        sender: Option<String>,
+        op: impl FnOnce() -> Result<String> + Send + 'static,
+    ) -> fdo::Result<()> {
+        self.touch();
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return Err(fdo::Error::Failed(
+                "Another vsdown operation is in progress.".to_string(),
+            ));
+        }
+        let conn = self.conn.clone();
+        let system = self.system;
+        let busy = self.busy.clone();
+        let last_call = self.last_call.clone();
+        std::thread::spawn(move || {
+            let result = authorize(&conn, system, sender.as_deref()).and_then(|()| op());
+            let (success, message) = match result {
+                Ok(message) => {
+                    info!("{}", message);
+                    (true, message)
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    (false, e.to_string())
+                }
+            };
+            let signalled = SignalContext::new(conn.inner(), OBJECT_PATH).and_then(|ctxt| {
+                zbus::block_on(Service::finished(&ctxt, operation, success, &message))
+            });
+            if let Err(e) = signalled {
+                warn!("Failed to signal the end of the {}: {}", operation, e);
+            }
+            *last_call.lock().expect("a method call panicked") = Instant::now();
+            busy.store(false, Ordering::SeqCst);
+        });
+
+        Ok(())
+    }
+}
+
+#[dbus_interface(name = "org.aosc.vsdown1")]
+impl Service {
+    /// What `vsdown check --json` prints.
+    fn check_update(&self) -> fdo::Result<String> {
+        self.touch();
+        (self.ops.check_update)().map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Installs the latest release unless it already is, or anyway with
+    /// `force`, which also goes on while Visual Studio Code runs.
+    fn install(&self, force: bool, #[zbus(header)] header: MessageHeader<'_>) -> fdo::Result<()> {
+        let op = self.ops.install;
+        self.spawn("install", sender(&header), move || op(force))
+    }
+
+    /// Removes the installation, with `force` while Visual Studio Code runs.
+    fn remove(&self, force: bool, #[zbus(header)] header: MessageHeader<'_>) -> fdo::Result<()> {
+        let op = self.ops.remove;
+        self.spawn("remove", sender(&header), move || op(force))
+    }
+
+    #[dbus_interface(property)]
+    fn busy(&self) -> bool {
+        self.busy.load(Ordering::SeqCst)
+    }
+
+    /// Empty when Visual Studio Code is not installed.
+    #[dbus_interface(property)]
+    fn installed_version(&self) -> String {
+        checker::get_installed_version().unwrap_or_default()
+    }
+
+    /// A `--progress-json` event.
+    #[dbus_interface(signal)]
+    async fn progress(ctxt: &SignalContext<'_>, event: &str) -> zbus::Result<()>;
+
+    /// The outcome of an `Install` or `Remove`, `operation` naming which.
+    #[dbus_interface(signal)]
+    async fn finished(
+        ctxt: &SignalContext<'_>,
+        operation: &str,
+        success: bool,
+        message: &str,
+    ) -> zbus::Result<()>;
+}
+
+fn sender(header: &MessageHeader<'_>) -> Option<String> {
+    header.sender().ok().flatten().map(|s| s.to_string())
+}
+
+/// What `vsdown check --json` prints.
+fn check_update() -> Result<String> {
+    let options = CheckOptions {
+        network_extras: true,
+        explain: false,
+    };
+
+    Ok(serde_json::to_string(&checker::check_update(&options)?)?)
+}
+
+/// Installs as `vsdown install --yes` does, under the operation lock.
+fn install(force: bool) -> Result<String> {
+    let _lock = lock::acquire()?;
+    if state::interrupted_install().is_some() {
+        bail!("An installation was interrupted, run `vsdown install --resume` or `vsdown install --abort` first.");
+    }
+    if !force && checker::update_checker().is_ok() {
+        return Ok("You have already installed the latest Visual Studio Code release!".to_string());
+    }
+    let options = InstallOptions {
+        yes: true,
+        force,
+        unpack_jobs: 1,
+        ..Default::default()
+    };
+    checker::install_vscode(&options)?;
+
+    Ok("Visual Studio Code has been successfully installed!".to_string())
+}
+
+/// Removes as `vsdown remove` does, under the operation lock.
+fn remove(force: bool) -> Result<String> {
+    let _lock = lock::acquire()?;
+    checker::remove_vscode(force, None)?;
+
+    Ok("Visual Studio Code has been successfully uninstalled!".to_string())
+}
+
+/// Asks polkit whether `sender` may install and remove, letting it
+/// authenticate first. On the session bus, callers are the user running the
+/// service anyway.
+fn authorize(conn: &Connection, system: bool, sender: Option<&str>) -> Result<()> {
+    if !system {
+        return Ok(());
+    }
+    let sender = sender.ok_or_else(|| anyhow!("The caller has no bus name to authorise."))?;
+    let subject = HashMap::from([("name", Value::from(sender))]);
+    let details = HashMap::<&str, &str>::new();
+    let reply = conn
+        .call_method(
+            Some("org.freedesktop.PolicyKit1"),
+            "/org/freedesktop/PolicyKit1/Authority",
+            Some("org.freedesktop.PolicyKit1.Authority"),
+            "CheckAuthorization",
+            &(
+                ("system-bus-name", subject),
+                POLKIT_ACTION,
+                details,
+                POLKIT_ALLOW_USER_INTERACTION,
+                "",
+            ),
+        )
+        .map_err(|e| anyhow!("Failed to ask polkit for authorisation: {}.", e))?;
+    let (authorized, _, _) = reply.body::<(bool, bool, HashMap<String, String>)>()?;
+    if !authorized {
+        bail!("{} is not authorised for {}.", sender, POLKIT_ACTION);
+    }
+
+    Ok(())
+}
+
+/// Turns the lines of JSON events `events` writes into `Progress` signals.
+struct Signals {
+    ctxt: SignalContext<'static>,
+    line: Vec<u8>,
+}
+
+impl Write for Signals {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line = self.line.drain(..=end).collect::<Vec<_>>();
+            let event = String::from_utf8_lossy(&line[..end]);
+            // Nobody listening is no reason to stop signalling.
+            if let Err(e) = zbus::block_on(Service::progress(&self.ctxt, &event)) {
+                debug!("Failed to signal progress: {}", e);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Registers the service running `ops` on `conn` and owns `BUS_NAME`,
+/// returning the flag set while it is busy and the time of the last call.
+fn serve(
+    conn: &Connection,
+    system: bool,
+    ops: Operations,
+) -> Result<(Arc<AtomicBool>, Arc<Mutex<Instant>>)> {
+    let busy = Arc::new(AtomicBool::new(false));
+    let last_call = Arc::new(Mutex::new(Instant::now()));
+    conn.object_server().at(
+        OBJECT_PATH,
+        Service {
+            conn: conn.clone(),
+            ops,
+            system,
+            busy: busy.clone(),
+            last_call: last_call.clone(),
+        },
+    )?;
+    conn.request_name(BUS_NAME)?;
+
+    Ok((busy, last_call))
+}
+
+/// Serves `BUS_NAME` on the system bus, or the session bus unless `system`,
+/// until no call came for `idle_timeout` seconds (0 for ever).
+pub fn run(system: bool, idle_timeout: u64) -> Result<()> {
+    let bus = if system { "system" } else { "session" };
+    let conn = match system {
+        true => Connection::system(),
+        false => Connection::session(),
+    }
+    .map_err(|e| anyhow!("Failed to connect to the {} bus: {}.", bus, e))?;
+    events::start_writer(Box::new(Signals {
+        ctxt: SignalContext::new(conn.inner(), OBJECT_PATH)?,
+        line: Vec::new(),
+    }))?;
+    let (busy, last_call) = serve(&conn, system, OPERATIONS)
+        .map_err(|e| anyhow!("Failed to own {} on the {} bus: {}.", BUS_NAME, bus, e))?;
+    info!("Serving {} on the {} bus.", BUS_NAME, bus);
+    loop {
+        std::thread::sleep(IDLE_POLL);
+        if idle_timeout == 0 || busy.load(Ordering::SeqCst) {
+            continue;
+        }
+        let idle = last_call.lock().expect("a method call panicked").elapsed();
+        if idle >= Duration::from_secs(idle_timeout) {
+            info!("No calls for {} seconds, exiting.", idle_timeout);
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+    use zbus::blocking::{ConnectionBuilder, Proxy};
+
+    const INTERFACE: &str = "org.aosc.vsdown1";
+    /// Stand-ins for operations which would change or query the system.
+    const STUBS: Operations = Operations {
+        check_update: || Ok(r#"{"installed":"1.0.0"}"#.to_string()),
+        install: |force| Ok(format!("Installed, force {}.", force)),
+        remove: |_| Err(anyhow!("Nothing to remove.")),
+    };
+
+    /// A session bus of its own, for as long as it lives.
+    struct Bus {
+        daemon: Child,
+        address: String,
+        _dir: tempfile::TempDir,
+    }
+
+    impl Bus {
+        /// None when dbus-daemon is not installed.
+        fn start() -> Option<Self> {
+            let dir = tempfile::tempdir().unwrap();
+            let config = dir.path().join("bus.conf");
+            std::fs::write(
+                &config,
+                format!(
+                    "<busconfig>\
+                       <type>session</type>\
+                       <listen>unix:dir={}</listen>\
+                       <auth>EXTERNAL</auth>\
+                       <policy context=\"default\">\
+                         <allow send_destination=\"*\" eavesdrop=\"true\"/>\
+                         <allow eavesdrop=\"true\"/>\
+                         <allow own=\"*\"/>\
+                       </policy>\
+                     </busconfig>",
+                    dir.path().display()
+                ),
+            )
+            .unwrap();
+            let mut daemon = match Command::new("dbus-daemon")
+                .arg(format!("--config-file={}", config.display()))
+                .args(["--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(daemon) => daemon,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+                Err(e) => panic!("Failed to start dbus-daemon: {}", e),
+            };
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+
+            Some(Self {
+                daemon,
+                address: address.trim().to_string(),
+                _dir: dir,
+            })
+        }
+
+        fn connect(&self) -> Connection {
+            ConnectionBuilder::address(self.address.as_str())
+                .unwrap()
+                .build()
+                .unwrap()
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[test]
+    fn serves_the_interface() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => {
+                eprintln!("dbus-daemon is not installed, skipping.");
+                return;
+            }
+        };
+        let server = bus.connect();
+        let (busy, _) = serve(&server, false, STUBS).unwrap();
+        let client = bus.connect();
+        let proxy = Proxy::new(&client, BUS_NAME, OBJECT_PATH, INTERFACE).unwrap();
+
+        assert!(!proxy.get_property::<bool>("Busy").unwrap());
+        assert_eq!(
+            proxy.get_property::<String>("InstalledVersion").unwrap(),
+            checker::get_installed_version().unwrap_or_default()
+        );
+
+        // Nothing is started while another operation runs.
+        busy.store(true, Ordering::SeqCst);
+        assert!(proxy.get_property::<bool>("Busy").unwrap());
+        for method in ["Install", "Remove"] {
+            match proxy.call::<_, _, ()>(method, &(false,)) {
+                Err(zbus::Error::MethodError(name, detail, _)) => {
+                    assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.Failed");
+                    assert_eq!(
+                        detail.as_deref(),
+                        Some("Another vsdown operation is in progress.")
+                    );
+                }
+                other => panic!("{} returned {:?}", method, other),
+            }
+        }
+        // Nor can a second service take over the name.
+        let second = bus.connect();
+        assert!(serve(&second, false, STUBS).is_err());
+    }
+
+    #[test]
+    fn signals_progress() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => {
+                eprintln!("dbus-daemon is not installed, skipping.");
+                return;
+            }
+        };
+        let server = bus.connect();
+        serve(&server, false, STUBS).unwrap();
+        let client = bus.connect();
+        let proxy = Proxy::new(&client, BUS_NAME, OBJECT_PATH, INTERFACE).unwrap();
+        let mut signals = proxy.receive_signal("Progress").unwrap();
+
+        let mut out = Signals {
+            ctxt: SignalContext::new(server.inner(), OBJECT_PATH).unwrap(),
+            line: Vec::new(),
+        };
+        let event = events::Event::Progress {
+            task: "download".to_string(),
+            done: 1,
+            total: Some(2),
+        };
+        let line = format!("{}\n", serde_json::to_string(&event).unwrap());
+        // Split across writes, as a buffered writer may.
+        let (head, tail) = line.split_at(10);
+        out.write_all(head.as_bytes()).unwrap();
+        out.write_all(tail.as_bytes()).unwrap();
+
+        let signal = signals.next().unwrap();
+        let body = signal.body::<String>().unwrap();
+        assert_eq!(serde_json::from_str::<events::Event>(&body).unwrap(), event);
+    }
+
+    #[test]
+    fn runs_operations() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => {
+                eprintln!("dbus-daemon is not installed, skipping.");
+                return;
+            }
+        };
+        let server = bus.connect();
+        let (busy, _) = serve(&server, false, STUBS).unwrap();
+        let client = bus.connect();
+        let proxy = Proxy::new(&client, BUS_NAME, OBJECT_PATH, INTERFACE).unwrap();
+        let mut finished = proxy.receive_signal("Finished").unwrap();
+
+        let status = proxy.call::<_, _, String>("CheckUpdate", &()).unwrap();
+        assert_eq!(status, r#"{"installed":"1.0.0"}"#);
+
+        let expected = [
+            ("Install", ("install", true, "Installed, force true.")),
+            ("Remove", ("remove", false, "Nothing to remove.")),
+        ];
+        for (method, outcome) in expected {
+            proxy.call::<_, _, ()>(method, &(true,)).unwrap();
+            let signal = finished.next().unwrap();
+            let (operation, success, message) = signal.body::<(String, bool, String)>().unwrap();
+            assert_eq!((operation.as_str(), success, message.as_str()), outcome);
+            // Cleared right after signalling.
+            let start = Instant::now();
+            while busy.load(Ordering::SeqCst) {
+                assert!(
+                    start.elapsed() < Duration::from_secs(5),
+                    "{} stayed busy",
+                    method
+                );
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
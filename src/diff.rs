@@ -0,0 +1,215 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    path::{Component, PathBuf},
+};
+
+use crate::checker::{self, InstallOptions};
+use crate::manifest::{self, FileRecord};
+use crate::{cache, checksum, info, verify, warn};
+
+/// What installing `to` over the current installation would change, each
+/// list sorted by path relative to `INSTALL_DIR`.
+#[derive(Serialize)]
+pub struct Diff {
+    /// The installed version, None without a manifest.
+    pub from: Option<String>,
+    pub to: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub replaced: Vec<String>,
+    /// Files changed or added locally since the install, which would be lost.
+    pub clobbered: Vec<String>,
+}
+
+/// Compares the manifest of the current installation and the state of its
+/// files with the archive of `version`, the latest release by default. The
+/// archive comes from the cache, the latest one is downloaded (and cached)
+/// if needed. Nothing is unpacked.
+pub fn diff(version: Option<&str>, jobs: usize) -> Result<Diff> {
+    let arch = checker::get_arch()?;
+    let (buf, to) = match version {
+        Some(v) => {
+            let entry = cache::lookup(v, &arch).ok_or_else(|| {
+                anyhow!(
+                    "Visual Studio Code {} is not cached, only the latest release can be downloaded for diff.",
+                    v
+                )
+            })?;
+            (cache::load(&entry, false)?, v.to_string())
+        }
+        None => {
+            let release = checker::fetch_release(&arch, &InstallOptions::default())?;
+            (release.buf, release.version)
+        }
+    };
+    info!("Reading the file list of Visual Studio Code {} ...", to);
+    let target = index(&buf)?;
+    let manifest = manifest::read()?;
+    let clobbered = if manifest.is_some() {
+        local_changes(jobs)?
+    } else {
+        warn!("There is no manifest of the installed files, local changes cannot be detected.");
+        Vec::new()
+    };
+    let current = manifest.as_ref().map(|m| &m.files);
+    let in_current = |p: &String| current.map_or(false, |c| c.contains_key(p));
+
+    Ok(Diff {
+        from: manifest.as_ref().map(|m| m.version.clone()),
+        to,
+        added: target.keys().filter(|p| !in_current(p)).cloned().collect(),
+        removed: current
+            .map(|c| {
+                c.keys()
+                    .filter(|p| !target.contains_key(*p))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default(),
+        replaced: target
+            .iter()
+            .filter(|(p, r)| {
+                current
+                    .and_then(|c| c.get(*p))
+                    .map_or(false, |c| !c.same_contents(r))
+            })
+            .map(|(p, _)| p.clone())
+            .collect(),
+        clobbered,
+    })
+}
+
+/// Installed files which no longer match the manifest or are not part of
+/// it, sorted by path.
+pub fn local_changes(jobs: usize) -> Result<Vec<String>> {
+    let report = verify::verify(jobs)?;
+    let mut changes = report.modified;
+    changes.extend(report.missing);
+    changes.extend(report.unexpected);
+    changes.sort();
+
+    Ok(changes)
+}
+
+/// Records every file of a release archive, keyed by the path it would be
+/// installed at relative to `INSTALL_DIR`.
+pub fn index(buf: &[u8]) -> Result<BTreeMap<String, FileRecord>> {
+    records(buf, true)
+}
+
+/// `index()` without the SHA-256 of regular files, for comparisons of sizes
+/// and kinds, which then only decompress the archive.
+pub fn listing(buf: &[u8]) -> Result<BTreeMap<String, FileRecord>> {
+    records(buf, false)
+}
+
+fn records(buf: &[u8], hashed: bool) -> Result<BTreeMap<String, FileRecord>> {
+    let mut files = BTreeMap::new();
+    let mut tar = tar::Archive::new(cache::decompressed(buf)?);
+    for entry in tar.entries()? {
+        let entry = entry?;
+        // Strip the top-level VSCode-<os> directory.
+        let rel = entry
+            .path()?
+            .components()
+            .skip(1)
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<PathBuf>();
+        if rel.as_os_str().is_empty() {
+            continue;
+        }
+        let rel = manifest::encode_path(&rel);
+        let header = entry.header();
+        let kind = header.entry_type();
+        let record = if kind.is_symlink() {
+            FileRecord {
+                sha256: None,
+                link: entry.link_name()?.map(|l| manifest::encode_path(&l)),
+                size: 0,
+                mode: None,
+            }
+        } else if kind.is_hard_link() {
+            let target = entry
+                .link_name()?
+                .map(|l| l.components().skip(1).collect::<PathBuf>())
+                .and_then(|l| files.get(&manifest::encode_path(&l)).cloned());
+            match target {
+                Some(record) => record,
+                None => continue,
+            }
+        } else if kind.is_file() {
+            let size = header.size()?;
+            let mode = header.mode()? & 0o7777;
+            FileRecord {
+                sha256: if hashed {
+                    Some(checksum::sha256_reader(entry)?)
+                } else {
+                    None
+                },
+                link: None,
+                size,
+                mode: Some(mode),
+            }
+        } else {
+            continue;
+        };
+        files.insert(rel, record);
+    }
+
+    Ok(files)
+}
+
+pub fn print_diff(diff: &Diff) {
+    println!(
+        "{} -> {}",
+        diff.from.as_deref().unwrap_or("(unknown)"),
+        diff.to
+    );
+    for p in &diff.added {
+        println!("{} {}", style("added:").green().bold(), p);
+    }
+    for p in &diff.removed {
+        println!("{} {}", style("removed:").red().bold(), p);
+    }
+    for p in &diff.replaced {
+        println!("{} {}", style("replaced:").cyan().bold(), p);
+    }
+    for p in &diff.clobbered {
+        println!("{} {}", style("clobbered:").yellow().bold(), p);
+    }
+    info!(
+        "{} added, {} removed and {} replaced files.",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.replaced.len()
+    );
+    if !diff.clobbered.is_empty() {
+        warn!(
+            "{} locally changed files would be lost by the upgrade.",
+            diff.clobbered.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpack::tests::{archive, Spec};
+
+    #[test]
+    fn lists_without_hashing() {
+        let buf = archive(&[
+            Spec::Dir(b"VSCode-linux-x64", 0o755),
+            Spec::File(b"VSCode-linux-x64/code", b"#!/bin/sh\n", 0o755),
+        ]);
+        let (hashed, listed) = (index(&buf).unwrap(), listing(&buf).unwrap());
+        assert_eq!(hashed.keys().collect::<Vec<_>>(), ["code"]);
+        assert_eq!(listed.keys().collect::<Vec<_>>(), ["code"]);
+        assert!(hashed["code"].sha256.is_some());
+        assert_eq!(listed["code"].sha256, None);
+        assert_eq!(listed["code"].size, hashed["code"].size);
+    }
+}
@@ -0,0 +1,431 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use progress_streams::ProgressReader;
+use reqwest::{
+    blocking::Response,
+    header::{HeaderMap, CONTENT_RANGE, ETAG, IF_RANGE, RANGE},
+    StatusCode,
+};
+use std::{
+    io::Read,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::timings::{self, Phase};
+use crate::{
+    config, debug, http, info, mirror,
+    progress::{self, ThrottledProgress},
+    stats, units, warn,
+};
+
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Without Retry-After, throttled retries back off up to 2^5 seconds.
+const MAX_BACKOFF_STEPS: u32 = 5;
+/// Most of an error response `--dump-download` saves.
+const MAX_DUMPED_ERROR_BODY: u64 = 16 * 1024 * 1024;
+
+macro_rules! make_progress_bar {
+    ($msg:expr) => {
+        concat!(
+            "{spinner} [{bar:25.cyan/blue}] ",
+            $msg,
+            " ({bytes_per_sec}, eta {eta})"
+        )
+    };
+}
+
+/// Everything received so far, carried across retries, and across endpoints
+/// when the archive will be checked.
+#[derive(Default)]
+struct Partial {
+    buf: Vec<u8>,
+    /// Strong ETag of the entity `buf` is a prefix of. Resuming always sends
+    /// it as If-Range, so an endpoint serving different content replies with
+    /// the full body instead of a range which would not fit.
+    etag: Option<String>,
+    /// Time spent waiting for throttling servers, against `throttle-budget`.
+    throttled: Duration,
+}
+
+/// The start of the archive, received from `url` before the download, e.g.
+/// by the mirror race. The download resumes from it when `url` is the first
+/// endpoint tried.
+pub struct Head {
+    pub url: String,
+    pub buf: Vec<u8>,
+    /// Strong ETag of the entity `buf` is a prefix of, see `Partial::etag`.
+    pub etag: String,
+}
+
+/// A 4xx response, which `--fail-fast-on-4xx` does not try other endpoints
+/// after.
+#[derive(Debug)]
+struct ClientError(StatusCode);
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server responded with {}", self.0)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+enum AttemptError {
+    /// Worth trying the same endpoint again.
+    Retriable(anyhow::Error),
+    /// Move on to the next endpoint.
+    Fatal(anyhow::Error),
+    /// 429 or 503, try again after the Retry-After delay if there was one.
+    Throttled(StatusCode, Option<Duration>),
+}
+
+/// Downloads the archive from the first of `urls` that works, retrying each
+/// a few times and resuming partial transfers where possible. Nothing checks
+/// that endpoints serve the same archive, so a transfer is only resumed on
+/// the endpoint it started on.
+pub fn fetch(urls: &[String]) -> Result<Vec<u8>> {
+    fetch_from(urls, None)
+}
+
+/// `fetch()`, resuming from `head` if it was received from the first of
+/// `urls`.
+pub fn fetch_from(urls: &[String], head: Option<Head>) -> Result<Vec<u8>> {
+    fetch_indexed(urls, head, false).map(|(buf, _)| buf)
+}
+
+/// Like `fetch()`, but moves on to the next endpoint when `check` rejects
+/// the archive one served instead of failing, as a bad mirror will keep
+/// serving bad bytes. Endpoints which did are deprioritized for the rest of
+/// the run, see `mirror::mark_bad()`. As `check` would reject an archive
+/// pieced together from different ones, transfers are resumed across
+/// endpoints serving the same ETag, starting from `head` as `fetch_from()`
+/// does.
+pub fn fetch_checked<F: Fn(&[u8]) -> Result<()>>(
+    urls: &[String],
+    mut head: Option<Head>,
+    check: F,
+) -> Result<Vec<u8>> {
+    checked(urls, |urls| fetch_indexed(urls, head.take(), true), check)
+}
+
+/// `fetch_checked()` with `fetch` downloading from the first endpoint of
+/// those given that works.
+fn checked<G, F>(urls: &[String], mut fetch: G, check: F) -> Result<Vec<u8>>
+where
+    G: FnMut(&[String]) -> Result<(Vec<u8>, usize)>,
+    F: Fn(&[u8]) -> Result<()>,
+{
+    let mut start = 0;
+    loop {
+        let (buf, i) = fetch(&urls[start..]).map(|(buf, i)| (buf, start + i))?;
+        let e = match check(&buf) {
+            Ok(()) => return Ok(buf),
+            Err(e) => e,
+        };
+        mirror::mark_bad(&urls[i]);
+        // Those before it have already failed.
+        let next = match urls.get(i + 1) {
+            Some(next) => next,
+            None => return Err(anyhow!("{} No other endpoint is left to try.", e)),
+        };
+        warn!(
+            "{} served a bad archive ({}), switching to {} ...",
+            http::redact(&urls[i]),
+            e,
+            http::redact(next)
+        );
+        start = i + 1;
+    }
+}
+
+/// Returns the archive and the index of the endpoint which served it.
+/// Partial transfers are carried over to the next endpoint only when
+/// `checked`, the archive then being checked against a published or
+/// `--checksum` digest.
+fn fetch_indexed(urls: &[String], head: Option<Head>, checked: bool) -> Result<(Vec<u8>, usize)> {
+    let mut partial = Partial::default();
+    if let Some(head) = head.filter(|h| urls.first() == Some(&h.url)) {
+        debug!(
+            "Resuming from the {} already received.",
+            units::Bytes(head.buf.len() as u64)
+        );
+        partial.buf = head.buf;
+        partial.etag = Some(head.etag);
+    }
+    let mut last_error = None;
+    stats::record(|c| c.downloads_attempted += 1);
+    for (i, url) in urls.iter().enumerate() {
+        if i > 0 && !checked && !partial.buf.is_empty() {
+            debug!(
+                "Not resuming the {} received on {}, nothing checks it serves the same archive.",
+                units::Bytes(partial.buf.len() as u64),
+                http::redact(url)
+            );
+            partial.buf.clear();
+            partial.etag = None;
+        }
+        match fetch_with_retries(url, &mut partial) {
+            Ok(served_by) => {
+                info!("Downloaded from {}.", http::redact(&served_by));
+                stats::record(|c| c.downloads_completed += 1);
+                return Ok((partial.buf, i));
+            }
+            Err(e) if e.is::<ClientError>() && config::get().fail_fast_on_4xx => {
+                return Err(e);
+            }
+            Err(e) if i + 1 < urls.len() => {
+                warn!(
+                    "Download from {} failed: {}, trying the next endpoint ...",
+                    http::redact(url),
+                    e
+                );
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow!("No download endpoint is configured.")))
+}
+
+fn fetch_with_retries(url: &str, partial: &mut Partial) -> Result<String> {
+    let mut attempt = 0;
+    // Waiting out throttling does not use up retries, only the wait budget.
+    let mut throttled = 0;
+    loop {
+        match fetch_once(url, partial) {
+            Ok(served_by) => return Ok(served_by),
+            Err(AttemptError::Throttled(status, retry_after)) => {
+                let cap = Duration::from_secs(config::get().retry_after_cap);
+                let budget = Duration::from_secs(config::get().throttle_budget);
+                let delay = retry_after
+                    .unwrap_or(RETRY_DELAY * 2u32.pow(throttled.min(MAX_BACKOFF_STEPS)))
+                    .min(cap);
+                if partial.throttled + delay > budget {
+                    return Err(anyhow!(
+                        "server kept responding with {} after waiting {}s in total, giving up (see throttle-budget)",
+                        status,
+                        partial.throttled.as_secs()
+                    ));
+                }
+                throttled += 1;
+                partial.throttled += delay;
+                warn!(
+                    "Server responded with {}, waiting {}s before trying again{} ...",
+                    status,
+                    delay.as_secs(),
+                    if partial.buf.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" from {}", units::Bytes(partial.buf.len() as u64))
+                    }
+                );
+                progress::countdown(&format!("Throttled ({})", status), delay);
+            }
+            Err(AttemptError::Retriable(e)) if attempt + 1 < RETRIES => {
+                let delay = RETRY_DELAY * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "{}, retrying in {}s ({}/{}) ...",
+                    e,
+                    delay.as_secs(),
+                    attempt,
+                    RETRIES - 1
+                );
+                thread::sleep(delay);
+            }
+            Err(AttemptError::Retriable(e)) | Err(AttemptError::Fatal(e)) => return Err(e),
+        }
+    }
+}
+
+/// The ETag of a response if it is strong, the only kind If-Range accepts.
+pub fn strong_etag(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.starts_with("W/"))
+        .map(|v| v.to_string())
+}
+
+/// Returns the URL which finally served the archive, after redirects.
+fn fetch_once(url: &str, partial: &mut Partial) -> Result<String, AttemptError> {
+    let mut request = http::client().map_err(AttemptError::Fatal)?.get(url);
+    let mut offset = 0;
+    if let Some(etag) = &partial.etag {
+        if !partial.buf.is_empty() {
+            offset = partial.buf.len();
+            request = request
+                .header(RANGE, format!("bytes={}-", offset))
+                .header(IF_RANGE, etag);
+        }
+    }
+    let start = Instant::now();
+    let mut r = timings::measure(Phase::Connect, || http::send(request)).map_err(|e| {
+        // Transport errors may be transient, pin mismatches are not.
+        if e.is::<reqwest::Error>() {
+            AttemptError::Retriable(e)
+        } else {
+            AttemptError::Fatal(e)
+        }
+    })?;
+    debug!(
+        "{} responded in {}.",
+        http::redact(url),
+        units::Elapsed(start.elapsed())
+    );
+    let status = r.status();
+    if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = http::retry_after(r.headers());
+        discard(r);
+        return Err(AttemptError::Throttled(status, retry_after));
+    }
+    if status.is_server_error() {
+        discard(r);
+        return Err(AttemptError::Retriable(anyhow!(
+            "server responded with {}",
+            status
+        )));
+    }
+    if status.is_client_error() {
+        discard(r);
+        return Err(AttemptError::Fatal(ClientError(status).into()));
+    }
+    if !status.is_success() {
+        discard(r);
+        return Err(AttemptError::Fatal(anyhow!(
+            "server responded with {}",
+            status
+        )));
+    }
+    let resumed = offset > 0
+        && status == StatusCode::PARTIAL_CONTENT
+        && r.headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with(&format!("bytes {}-", offset)))
+            .unwrap_or(false);
+    if !resumed {
+        offset = 0;
+        partial.buf.clear();
+        partial.etag = strong_etag(r.headers());
+    }
+
+    let served_by = r.url().to_string();
+    let length = r.content_length();
+    let progress_bar = progress::bar(
+        offset as u64 + length.unwrap_or(0),
+        make_progress_bar!("{bytes}/{total_bytes}"),
+    );
+    progress_bar.set_position(offset as u64);
+    progress_bar.enable_steady_tick(500);
+    let mut throttle = ThrottledProgress::new(&progress_bar, "download");
+    // Archives have no size ceiling, but one announcing its length may not
+    // run past it.
+    let limit = length.map_or(u64::MAX, |l| l + 1);
+    let mut reader = ProgressReader::new((&mut r).take(limit), |progress: usize| {
+        throttle.inc(progress as u64);
+    });
+    let result = timings::measure(Phase::Download, || reader.read_to_end(&mut partial.buf));
+    drop(reader);
+    drop(throttle);
+    progress_bar.finish_and_clear();
+    // A resumed transfer is saved whole, as it would be checked.
+    dump(&served_by, status, &partial.buf);
+    let received = (partial.buf.len() - offset) as u64;
+    stats::record(|c| c.bytes_downloaded += received);
+    timings::add_bytes(Phase::Download, received);
+    result.map_err(|e| AttemptError::Retriable(e.into()))?;
+    if let Some(length) = length {
+        if received < length {
+            return Err(AttemptError::Retriable(anyhow!(
+                "connection closed after {} of {} bytes",
+                received,
+                length
+            )));
+        }
+        if received > length {
+            return Err(AttemptError::Fatal(anyhow!(
+                "server sent more than the {} bytes it announced",
+                length
+            )));
+        }
+    }
+
+    Ok(served_by)
+}
+
+/// Saves `body`, as `url` sent it with `status`, to the `--dump-download`
+/// file if one was given, before anything decodes or checks it. Each
+/// response replaces the previous one.
+fn dump(url: &str, status: StatusCode, body: &[u8]) {
+    let p = match &config::get().dump_download {
+        Some(p) => p,
+        None => return,
+    };
+    match std::fs::write(p, body) {
+        Ok(()) => info!(
+            "Saved the {} response of {} ({}) to {}.",
+            status,
+            http::redact(url),
+            units::Bytes(body.len() as u64),
+            p.display()
+        ),
+        Err(e) => warn!("Failed to save the response to {}: {}", p.display(), e),
+    }
+}
+
+/// Drops the body of a response which is not used, saving it first with
+/// `--dump-download`, as error pages are what it is for.
+fn discard(r: Response) {
+    if config::get().dump_download.is_none() {
+        http::discard(r);
+        return;
+    }
+    let url = r.url().to_string();
+    let status = r.status();
+    let mut body = Vec::new();
+    if let Err(e) = r.take(MAX_DUMPED_ERROR_BODY).read_to_end(&mut body) {
+        debug!(
+            "Failed to read the {} response of {}: {}",
+            status,
+            http::redact(&url),
+            e
+        );
+    }
+    dump(&url, status, &body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn moves_on_past_bad_archives() {
+        let urls = ["a", "b", "c", "d"].map(|u| format!("https://{}.example/", u));
+        let asked = RefCell::new(Vec::new());
+        // The first endpoint fails, the second serves a bad archive.
+        let fetch = |urls: &[String]| {
+            asked.borrow_mut().push(urls.to_vec());
+            Ok(match asked.borrow().len() {
+                1 => (b"bad".to_vec(), 1),
+                _ => (b"good".to_vec(), 0),
+            })
+        };
+        let check = |buf: &[u8]| match buf {
+            b"good" => Ok(()),
+            _ => Err(anyhow!("bad checksum")),
+        };
+        assert_eq!(checked(&urls, fetch, check).unwrap(), b"good");
+        // Neither the endpoint which failed nor the bad one is tried again.
+        assert_eq!(*asked.borrow(), [urls.to_vec(), urls[2..].to_vec()]);
+
+        let e = checked(&urls, |_| Ok((b"bad".to_vec(), 3)), check).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "bad checksum No other endpoint is left to try."
+        );
+    }
+}
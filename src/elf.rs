@@ -0,0 +1,115 @@
+use anyhow::{bail, Result};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// Enough of the ELF header to read `e_machine`.
+pub const HEADER_LEN: usize = 20;
+
+const SHT_STRTAB: u32 = 3;
+/// Upper bound for the tables read by `read_dynstr()`, so a damaged file
+/// cannot make us allocate without limit.
+const MAX_TABLE_SIZE: u64 = 64 * 1024 * 1024;
+
+pub const EM_ARM: u16 = 40;
+pub const EM_X86_64: u16 = 62;
+pub const EM_AARCH64: u16 = 183;
+pub const EM_RISCV: u16 = 243;
+
+/// Returns `e_machine` from the start of an ELF file.
+pub fn machine(header: &[u8]) -> Result<u16> {
+    if header.len() < HEADER_LEN || &header[..4] != ELF_MAGIC {
+        bail!("not an ELF file");
+    }
+    let bytes = [header[18], header[19]];
+    let machine = match header[5] {
+        1 => u16::from_le_bytes(bytes),
+        2 => u16::from_be_bytes(bytes),
+        _ => bail!("unknown ELF byte order"),
+    };
+
+    Ok(machine)
+}
+
+/// Maps a `std::env::consts::ARCH` value to its `e_machine`.
+pub fn machine_for_arch(arch: &str) -> Option<u16> {
+    match arch {
+        "x86_64" => Some(EM_X86_64),
+        "aarch64" => Some(EM_AARCH64),
+        "arm" => Some(EM_ARM),
+        "riscv64" => Some(EM_RISCV),
+        _ => None,
+    }
+}
+
+pub fn machine_name(machine: u16) -> String {
+    match machine {
+        EM_X86_64 => "x86_64".to_string(),
+        EM_AARCH64 => "aarch64".to_string(),
+        EM_ARM => "arm".to_string(),
+        EM_RISCV => "riscv64".to_string(),
+        m => format!("unknown machine {}", m),
+    }
+}
+
+/// Reads the `.dynstr` section of a 64-bit little-endian ELF file, which holds
+/// among others the names of the symbol versions it needs or defines.
+pub fn read_dynstr(p: &Path) -> Result<Vec<u8>> {
+    let mut f = File::open(p)?;
+    let mut header = [0u8; 64];
+    f.read_exact(&mut header)?;
+    if &header[..4] != ELF_MAGIC {
+        bail!("not an ELF file");
+    }
+    if header[4] != 2 || header[5] != 1 {
+        bail!("only 64-bit little-endian ELF files are supported");
+    }
+    let shoff = u64::from_le_bytes(<[u8; 8]>::try_from(&header[0x28..0x30])?);
+    let shentsize = u16::from_le_bytes([header[0x3a], header[0x3b]]) as u64;
+    let shnum = u16::from_le_bytes([header[0x3c], header[0x3d]]) as u64;
+    let shstrndx = u16::from_le_bytes([header[0x3e], header[0x3f]]) as u64;
+    if shentsize < 0x28 || shstrndx >= shnum {
+        bail!("malformed section header table");
+    }
+    let sections = read_at(&mut f, shoff, shentsize * shnum)?;
+    let section = |i: u64| {
+        let s = &sections[(i * shentsize) as usize..];
+        let name = u32::from_le_bytes([s[0], s[1], s[2], s[3]]);
+        let kind = u32::from_le_bytes([s[4], s[5], s[6], s[7]]);
+        let offset = u64::from_le_bytes([
+            s[0x18], s[0x19], s[0x1a], s[0x1b], s[0x1c], s[0x1d], s[0x1e], s[0x1f],
+        ]);
+        let size = u64::from_le_bytes([
+            s[0x20], s[0x21], s[0x22], s[0x23], s[0x24], s[0x25], s[0x26], s[0x27],
+        ]);
+        (name, kind, offset, size)
+    };
+    let (_, _, names_offset, names_size) = section(shstrndx);
+    let names = read_at(&mut f, names_offset, names_size)?;
+    for i in 0..shnum {
+        let (name, kind, offset, size) = section(i);
+        let name = names
+            .get(name as usize..)
+            .and_then(|n| n.split(|b| *b == 0).next())
+            .unwrap_or_default();
+        if kind == SHT_STRTAB && name == b".dynstr" {
+            return read_at(&mut f, offset, size);
+        }
+    }
+
+    bail!("no .dynstr section")
+}
+
+fn read_at(f: &mut File, offset: u64, size: u64) -> Result<Vec<u8>> {
+    if size > MAX_TABLE_SIZE {
+        bail!("section of {} bytes is too large", size);
+    }
+    let mut buf = vec![0u8; size as usize];
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
@@ -0,0 +1,224 @@
+use anyhow::{bail, Result};
+use console::style;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::Write,
+    os::unix::io::{FromRawFd, RawFd},
+    sync::Mutex,
+};
+
+use crate::debug;
+
+/// Version of the `--progress-json` event schema. It is bumped whenever an
+/// event changes incompatibly; adding event types or fields is not breaking.
+/// docs/progress-protocol.ndjson has an example of every event.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol `--json-protocol` can ask for.
+pub const OLDEST_PROTOCOL_VERSION: u32 = 1;
+
+/// Every `event` a run may produce.
+pub const EVENT_TYPES: &[&str] = &["handshake", "progress"];
+
+/// Where progress goes, set once reporting is enabled. None after the
+/// reader went away.
+static STREAM: OnceCell<Mutex<Option<Stream>>> = OnceCell::new();
+
+struct Stream {
+    out: Box<dyn Write + Send>,
+    /// NDJSON events with `--progress-json`, `TASK FRACTION` lines otherwise.
+    json: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Always the first event.
+    Handshake {
+        vsdown_version: String,
+        protocol: u32,
+        events: Vec<String>,
+    },
+    /// Progress of a long running task: "download", "verify-cache",
+    /// "unpack" or "remove", the latter counting files rather than bytes.
+    /// `total` is missing when the size is not known in advance.
+    Progress {
+        task: String,
+        done: u64,
+        total: Option<u64>,
+    },
+}
+
+/// Enables progress reporting for `--progress-json`, in protocol
+/// `requested` (the newest by default), and `--progress-fd`. Events go to
+/// `fd` if given, stdout otherwise; without `json` only progress fractions
+/// are written to `fd`.
+pub fn start(json: bool, requested: Option<u32>, fd: Option<RawFd>) -> Result<()> {
+    if !json && fd.is_none() {
+        return Ok(());
+    }
+    let protocol = requested.unwrap_or(PROTOCOL_VERSION);
+    if !(OLDEST_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol) {
+        bail!(
+            "JSON protocol {} is not supported, this vsdown speaks versions {} to {}.",
+            protocol,
+            OLDEST_PROTOCOL_VERSION,
+            PROTOCOL_VERSION
+        );
+    }
+    let out: Box<dyn Write + Send> = match fd {
+        Some(fd) => Box::new(open_fd(fd)?),
+        None => Box::new(std::io::stdout()),
+    };
+    if STREAM.set(Mutex::new(Some(Stream { out, json }))).is_err() {
+        panic!("progress reporting is started twice");
+    }
+    if json {
+        emit(&Event::Handshake {
+            vsdown_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol,
+            events: EVENT_TYPES.iter().map(|t| t.to_string()).collect(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Enables JSON progress events to `out` for the rest of the process, as
+/// the D-Bus service does to turn them into signals. There is no handshake,
+/// the service being introspectable instead.
+pub fn start_writer(out: Box<dyn Write + Send>) -> Result<()> {
+    if STREAM
+        .set(Mutex::new(Some(Stream { out, json: true })))
+        .is_err()
+    {
+        bail!("Progress reporting is already enabled, drop --progress-json and --progress-fd.");
+    }
+
+    Ok(())
+}
+
+/// Takes over `fd`, inherited from the parent, after checking it is open
+/// for writing.
+fn open_fd(fd: RawFd) -> Result<File> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        bail!("File descriptor {} given to --progress-fd is not open.", fd);
+    }
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        bail!(
+            "File descriptor {} given to --progress-fd is not open for writing.",
+            fd
+        );
+    }
+    // Hooks and the launched commands have no business writing to it.
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub fn enabled() -> bool {
+    STREAM.get().is_some()
+}
+
+/// Writes `event` as a line of JSON, or a fraction line for progress events
+/// of a known size, if reporting is enabled.
+pub fn emit(event: &Event) {
+    let stream = match STREAM.get() {
+        Some(stream) => stream,
+        None => return,
+    };
+    let mut stream = stream.lock().expect("a progress writer panicked");
+    let s = match stream.as_mut() {
+        Some(s) => s,
+        None => return,
+    };
+    let line = match event {
+        _ if s.json => serde_json::to_string(event).expect("events are serializable"),
+        Event::Progress {
+            task,
+            done,
+            total: Some(total),
+        } => format!("{} {:.4}", task, *done as f64 / *total as f64),
+        _ => return,
+    };
+    // A frontend which went away is no reason to abort the operation.
+    let result = writeln!(s.out, "{}", line).and_then(|_| s.out.flush());
+    if let Err(e) = result {
+        debug!("No longer reporting progress: {}", e);
+        *stream = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLES: &str = include_str!("../docs/progress-protocol.ndjson");
+
+    fn examples() -> Vec<Event> {
+        EXAMPLES
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap_or_else(|e| panic!("{}: {}", l, e)))
+            .collect()
+    }
+
+    #[test]
+    fn examples_parse() {
+        let events = examples();
+        match &events[0] {
+            Event::Handshake {
+                protocol, events, ..
+            } => {
+                assert_eq!(*protocol, PROTOCOL_VERSION);
+                assert_eq!(events, EVENT_TYPES);
+            }
+            other => panic!("{:?} comes before the handshake", other),
+        }
+        assert!(events[1..]
+            .iter()
+            .all(|e| !matches!(e, Event::Handshake { .. })));
+    }
+
+    #[test]
+    fn examples_cover_every_event() {
+        for event in EVENT_TYPES {
+            let tag = format!("{{\"event\":\"{}\"", event);
+            assert!(
+                EXAMPLES.lines().any(|l| l.starts_with(&tag)),
+                "no example of {}",
+                event
+            );
+        }
+        assert!(examples()
+            .iter()
+            .any(|e| matches!(e, Event::Progress { total: None, .. })));
+    }
+
+    #[test]
+    fn round_trips() {
+        for (line, event) in EXAMPLES.lines().zip(examples()) {
+            assert_eq!(serde_json::to_string(&event).unwrap(), line);
+            let back = serde_json::from_str::<Event>(&serde_json::to_string(&event).unwrap());
+            assert_eq!(back.unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn tolerates_new_fields() {
+        let event = serde_json::from_str::<Event>(
+            r#"{"event":"progress","task":"unpack","done":1,"total":2,"rate":3.5}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            event,
+            Event::Progress {
+                task: "unpack".to_string(),
+                done: 1,
+                total: Some(2),
+            }
+        );
+        assert!(serde_json::from_str::<Event>(r#"{"event":"unknown"}"#).is_err());
+    }
+}
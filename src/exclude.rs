@@ -0,0 +1,254 @@
+use anyhow::{bail, Result};
+use std::path::{Component, Path};
+
+/// Glob patterns of release contents not to install, matched against whole
+/// paths relative to the top of the release (e.g. `locales/de.pak`, without
+/// the `VSCode-linux-x64/` directory). `*` and `?` do not match `/`, `**`
+/// matches any number of directories, and `[...]` a set of characters. A
+/// pattern matching a directory excludes everything in it.
+#[derive(Default, Debug)]
+pub struct Exclude {
+    patterns: Vec<Vec<Token>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `**` as a whole path component, matching zero or more of them.
+    Globstar,
+    /// `[...]`, `[!...]` negated.
+    Class(Vec<(char, char)>, bool),
+}
+
+impl Exclude {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| parse(p))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the archive entry `path` is excluded. The leading release
+    /// directory is stripped first, so the release directory itself never is.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let components = path
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .skip(1);
+        let mut rel = String::new();
+        for c in components {
+            if !rel.is_empty() {
+                rel.push('/');
+            }
+            rel.push_str(&c.as_os_str().to_string_lossy());
+            // Checking every ancestor excludes the contents of a matching
+            // directory, whatever order the archive lists them in.
+            let chars = rel.chars().collect::<Vec<_>>();
+            if self.patterns.iter().any(|p| matches(p, &chars)) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+fn parse(pattern: &str) -> Result<Vec<Token>> {
+    let pattern = pattern.trim_matches('/');
+    if pattern.is_empty() {
+        bail!("Invalid exclude pattern: it is empty.");
+    }
+    let chars = pattern.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let starts = i == 0 || chars[i - 1] == '/';
+                let ends = i + 2 == chars.len() || chars[i + 2] == '/';
+                if !starts || !ends {
+                    bail!(
+                        "Invalid exclude pattern {}: ** must be a whole path component.",
+                        pattern
+                    );
+                }
+                tokens.push(Token::Globstar);
+                // The slash after `**` belongs to it, so `a/**/b` matches `a/b`.
+                i += if i + 2 < chars.len() { 3 } else { 2 };
+                continue;
+            }
+            '*' => tokens.push(Token::Star),
+            '?' => tokens.push(Token::Any),
+            '[' => {
+                let mut j = i + 1;
+                let negated = matches!(chars.get(j), Some('!') | Some('^'));
+                if negated {
+                    j += 1;
+                }
+                let mut ranges = Vec::new();
+                // A `]` right after the opening bracket is a member.
+                let start = j;
+                while j < chars.len() && (chars[j] != ']' || j == start) {
+                    if chars.get(j + 1) == Some(&'-') && j + 2 < chars.len() && chars[j + 2] != ']'
+                    {
+                        ranges.push((chars[j], chars[j + 2]));
+                        j += 3;
+                    } else {
+                        ranges.push((chars[j], chars[j]));
+                        j += 1;
+                    }
+                }
+                if j >= chars.len() {
+                    bail!("Invalid exclude pattern {}: unclosed [.", pattern);
+                }
+                tokens.push(Token::Class(ranges, negated));
+                i = j;
+            }
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                tokens.push(Token::Char(chars[i]));
+            }
+            c => tokens.push(Token::Char(c)),
+        }
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+/// Matches `path` as a whole against `tokens`.
+fn matches(tokens: &[Token], path: &[char]) -> bool {
+    match tokens.first() {
+        None => path.is_empty(),
+        Some(Token::Globstar) => {
+            // Zero components, or skip one and try again.
+            if matches(&tokens[1..], path) {
+                return true;
+            }
+            match path.iter().position(|c| *c == '/') {
+                Some(i) => matches(tokens, &path[i + 1..]),
+                // The last component, which `**` at the end also takes.
+                None => tokens.len() == 1,
+            }
+        }
+        Some(Token::Star) => (0..=path.len())
+            .take_while(|i| *i == 0 || path[i - 1] != '/')
+            .any(|i| matches(&tokens[1..], &path[i..])),
+        Some(token) => match path.first() {
+            Some(c) if *c != '/' || *token == Token::Char('/') => {
+                let ok = match token {
+                    Token::Char(t) => t == c,
+                    Token::Any => true,
+                    Token::Class(ranges, negated) => {
+                        ranges.iter().any(|(lo, hi)| lo <= c && c <= hi) != *negated
+                    }
+                    Token::Star | Token::Globstar => unreachable!(),
+                };
+                ok && matches(&tokens[1..], &path[1..])
+            }
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, path: &str) -> bool {
+        let chars = path.chars().collect::<Vec<_>>();
+        matches(&parse(pattern).unwrap(), &chars)
+    }
+
+    fn error(pattern: &str) -> String {
+        parse(pattern).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn globstar_matches_no_components() {
+        assert!(is_match("a/**/b", "a/b"));
+        assert!(is_match("a/**/b", "a/x/b"));
+        assert!(is_match("a/**/b", "a/x/y/b"));
+        assert!(!is_match("a/**/b", "a/xb"));
+        assert!(!is_match("a/**/b", "ab"));
+        assert!(is_match("**/de.pak", "de.pak"));
+        assert!(is_match("**/de.pak", "locales/de.pak"));
+    }
+
+    #[test]
+    fn trailing_globstar_takes_everything() {
+        assert!(is_match("resources/**", "resources/app"));
+        assert!(is_match("resources/**", "resources/app/package.json"));
+        // The directory itself is left, though nothing in it is.
+        assert!(!is_match("resources/**", "resources"));
+        assert!(!is_match("resources/**", "resourcesx"));
+        assert!(is_match("**", "a/b/c"));
+    }
+
+    #[test]
+    fn star_stays_in_a_component() {
+        assert!(is_match("locales/*.pak", "locales/de.pak"));
+        assert!(!is_match("locales/*.pak", "locales/x/de.pak"));
+        assert!(!is_match("*", "a/b"));
+        assert!(is_match("*", "a"));
+        assert!(!is_match("a?b", "a/b"));
+        assert!(is_match("a?b", "a.b"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(is_match("[]]", "]"));
+        assert!(is_match("[]a]", "a"));
+        assert!(!is_match("[]a]", "b"));
+        assert!(is_match("[!x]", "y"));
+        assert!(!is_match("[!x]", "x"));
+        assert!(!is_match("[!x]", "/"));
+        assert!(is_match("[^x]", "y"));
+        assert!(is_match("[!]]", "a"));
+        assert!(!is_match("[!]]", "]"));
+        assert!(is_match("[a-c]", "b"));
+        assert!(!is_match("[a-c]", "d"));
+        assert!(is_match("[a-]", "-"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(is_match("\\*", "*"));
+        assert!(!is_match("\\*", "a"));
+        assert!(is_match("\\[a]", "[a]"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert_eq!(error("[ab"), "Invalid exclude pattern [ab: unclosed [.");
+        assert_eq!(error("[]"), "Invalid exclude pattern []: unclosed [.");
+        assert_eq!(
+            error("a**"),
+            "Invalid exclude pattern a**: ** must be a whole path component."
+        );
+        assert_eq!(
+            error("**b"),
+            "Invalid exclude pattern **b: ** must be a whole path component."
+        );
+        assert_eq!(error("/"), "Invalid exclude pattern: it is empty.");
+    }
+
+    #[test]
+    fn excludes_directory_contents() {
+        let exclude = Exclude::new(&["locales".to_string()]).unwrap();
+        assert!(exclude.is_excluded(Path::new("VSCode-linux-x64/locales")));
+        assert!(exclude.is_excluded(Path::new("./VSCode-linux-x64/locales/de.pak")));
+        assert!(!exclude.is_excluded(Path::new("VSCode-linux-x64/resources")));
+        assert!(!exclude.is_excluded(Path::new("VSCode-linux-x64")));
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::Result;
+use console::style;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{cache, checker::VSCODE_PATH, config, crash, debug, info, state, units, warn};
+
+/// What `run()` removes, by category.
+struct Category {
+    name: &'static str,
+    paths: Vec<PathBuf>,
+}
+
+/// Removes superseded cache entries, files in the cache that belong to no
+/// entry, staging directories left by interrupted installs and crash reports
+/// older than `crash_age`, then prints how much space each category took.
+/// With `dry_run`, only prints what would be removed.
+pub fn run(crash_age: Duration, dry_run: bool) -> Result<()> {
+    let categories = [
+        Category {
+            name: "Cache",
+            paths: cache_garbage(),
+        },
+        Category {
+            name: "Staging directories",
+            paths: staging_directories(),
+        },
+        Category {
+            name: "Crash reports",
+            paths: old_crash_reports(crash_age),
+        },
+    ];
+    let mut total = 0;
+    for category in &categories {
+        let mut size = 0;
+        let mut count = 0;
+        for p in &category.paths {
+            let usage = disk_usage(p);
+            if dry_run {
+                println!("Would remove {} ({})", p.display(), units::Bytes(usage));
+            } else {
+                debug!("Removing {} ...", p.display());
+                let result = if p.is_dir() {
+                    std::fs::remove_dir_all(p)
+                } else {
+                    std::fs::remove_file(p)
+                };
+                if let Err(e) = result {
+                    warn!("Failed to remove {}: {}", p.display(), e);
+                    continue;
+                }
+            }
+            size += usage;
+            count += 1;
+        }
+        total += size;
+        println!(
+            "{}: {} item(s), {}",
+            category.name,
+            count,
+            units::Bytes(size)
+        );
+    }
+    if dry_run {
+        info!("{} would be reclaimed.", units::Bytes(total));
+    } else {
+        info!("{} reclaimed.", units::Bytes(total));
+    }
+
+    Ok(())
+}
+
+/// Entries beyond the installed version and those retained for rolling back
+/// from it, and files matching no entry at all (e.g. archives whose metadata
+/// was lost).
+fn cache_garbage() -> Vec<PathBuf> {
+    let current = state::recorded_version();
+    let retain = config::retention("desktop");
+    let mut oses = cache::entries()
+        .into_iter()
+        .map(|i| i.os)
+        .collect::<Vec<_>>();
+    oses.sort();
+    oses.dedup();
+    let excess = oses
+        .iter()
+        .flat_map(|os| cache::excess(os, current.as_deref(), retain))
+        .flat_map(|i| [i.archive_path(), i.metadata_path()])
+        .collect::<Vec<_>>();
+    let keep = cache::entries()
+        .into_iter()
+        .flat_map(|i| [i.archive_path(), i.metadata_path()])
+        .filter(|p| !excess.contains(p))
+        .collect::<Vec<_>>();
+    let dir = match std::fs::read_dir(cache::directory()) {
+        Ok(dir) => dir,
+        Err(_) => return Vec::new(),
+    };
+
+    dir.filter_map(|i| i.ok())
+        .map(|i| i.path())
+        .filter(|p| !keep.contains(p))
+        .collect()
+}
+
+/// `VSCode-<os>` directories unpacked next to the installation. The
+/// operation lock is held, so no install is using them.
+fn staging_directories() -> Vec<PathBuf> {
+    let dir = match std::fs::read_dir(VSCODE_PATH) {
+        Ok(dir) => dir,
+        Err(_) => return Vec::new(),
+    };
+
+    dir.filter_map(|i| i.ok())
+        .filter(|i| i.file_name().to_string_lossy().starts_with("VSCode-"))
+        .map(|i| i.path())
+        .filter(|p| p.is_dir())
+        .collect()
+}
+
+fn old_crash_reports(age: Duration) -> Vec<PathBuf> {
+    let cutoff = SystemTime::now()
+        .checked_sub(age)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    crash::crash_directories()
+        .into_iter()
+        .filter_map(|d| std::fs::read_dir(d).ok())
+        .flatten()
+        .filter_map(|i| i.ok())
+        .filter(|i| {
+            i.metadata()
+                .and_then(|m| m.modified())
+                .map(|t| t < cutoff)
+                .unwrap_or(false)
+        })
+        .map(|i| i.path())
+        .collect()
+}
+
+/// The apparent size of `p`, recursively for directories.
+pub fn disk_usage(p: &Path) -> u64 {
+    let metadata = match std::fs::symlink_metadata(p) {
+        Ok(m) => m,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    std::fs::read_dir(p)
+        .map(|dir| {
+            dir.filter_map(|i| i.ok())
+                .map(|i| disk_usage(&i.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,56 @@
+use anyhow::Result;
+use console::style;
+use std::{collections::BTreeMap, fs::File, path::Path};
+
+use crate::checker::{self, INSTALL_DIR};
+use crate::{checksum, warn};
+
+/// Files of the installed tree whose hashes are reported, relative to
+/// `INSTALL_DIR`. Missing ones are skipped, as not every release ships all.
+const KEY_FILES: &[&str] = &[
+    "code",
+    "bin/code",
+    "chrome-sandbox",
+    "chrome_crashpad_handler",
+    "libEGL.so",
+    "libGLESv2.so",
+    "libffmpeg.so",
+    "libvk_swiftshader.so",
+    "libvulkan.so.1",
+    "resources/app/package.json",
+    "resources/app/product.json",
+    "resources/app/out/main.js",
+];
+
+/// SHA-256 digests of the key files of the installed tree, by full path.
+pub fn report() -> Result<BTreeMap<String, String>> {
+    if !Path::new(INSTALL_DIR).join("code").is_file() {
+        return Err(checker::not_installed_at(INSTALL_DIR));
+    }
+    let mut hashes = BTreeMap::new();
+    for name in KEY_FILES {
+        let path = Path::new(INSTALL_DIR).join(name);
+        if !path.is_file() {
+            continue;
+        }
+        match File::open(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(checksum::sha256_reader)
+        {
+            Ok(hash) => {
+                hashes.insert(path.display().to_string(), hash);
+            }
+            Err(e) => warn!("Could not hash {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Prints the hashes in `sha256sum` format, so they can be checked with
+/// `sha256sum -c`.
+pub fn print_report(hashes: &BTreeMap<String, String>) {
+    for (path, hash) in hashes {
+        println!("{}  {}", hash, path);
+    }
+}
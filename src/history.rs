@@ -0,0 +1,69 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Write};
+
+use crate::checker::CURRENT_VERSION_DIRECTORY;
+use crate::state::now;
+
+const HISTORY_FILENAME: &str = "history.jsonl";
+
+/// One line of the history file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Event {
+    /// Unix seconds.
+    pub timestamp: u64,
+    /// `install`, `remove` or `cleanup-legacy`.
+    pub action: String,
+    pub version: String,
+    /// SHA-256 of the installed archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// What the archive was verified against, e.g. `operator-supplied`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_source: Option<String>,
+    /// The commit the installed build was made from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Milliseconds spent in each `timings::Phase`, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub timings: BTreeMap<String, u64>,
+}
+
+impl Event {
+    pub fn new(action: &str, version: &str) -> Self {
+        Self {
+            timestamp: now(),
+            action: action.to_string(),
+            version: version.to_string(),
+            sha256: None,
+            hash_source: None,
+            commit: None,
+            timings: BTreeMap::new(),
+        }
+    }
+}
+
+/// Appends `event` to the history file, one JSON object per line.
+pub fn append(event: &Event) -> Result<()> {
+    std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}{}", CURRENT_VERSION_DIRECTORY, HISTORY_FILENAME))?;
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    f.write_all(&line)?;
+
+    Ok(())
+}
+
+/// Every readable event, oldest first.
+pub fn read() -> Vec<Event> {
+    std::fs::read_to_string(format!("{}{}", CURRENT_VERSION_DIRECTORY, HISTORY_FILENAME))
+        .map(|s| {
+            s.lines()
+                .filter_map(|l| serde_json::from_str::<Event>(l).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,91 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{config, debug, info, warn};
+
+pub const HOOKS_DIRECTORY: &str = "/etc/vsdown/hooks";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    PreInstall,
+    PostInstall,
+    PreRemove,
+    PostRemove,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::PreInstall => "pre-install",
+            Stage::PostInstall => "post-install",
+            Stage::PreRemove => "pre-remove",
+            Stage::PostRemove => "post-remove",
+        }
+    }
+
+    fn is_pre(self) -> bool {
+        matches!(self, Stage::PreInstall | Stage::PreRemove)
+    }
+}
+
+/// Runs the executables in `<hooks dir>/<stage>.d/` in lexical order with
+/// `env` plus `VSDOWN_HOOK` set. A failing pre-* hook aborts the operation,
+/// failing post-* hooks are only reported.
+pub fn run(stage: Stage, env: &[(&str, &str)]) -> Result<()> {
+    let base = config::get()
+        .hooks_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(HOOKS_DIRECTORY));
+    let dir = base.join(format!("{}.d", stage.name()));
+    let mut hooks = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|i| i.ok())
+            .map(|i| i.path())
+            .filter(|p| is_executable(p))
+            .collect::<Vec<_>>(),
+        Err(_) => {
+            debug!("No {} hooks in {}.", stage.name(), dir.display());
+            return Ok(());
+        }
+    };
+    hooks.sort();
+
+    for hook in hooks {
+        info!("Running {} hook {} ...", stage.name(), hook.display());
+        let result = Command::new(&hook)
+            .envs(env.iter().copied())
+            .env("VSDOWN_HOOK", stage.name())
+            .status()
+            .map_err(|e| anyhow!("{}", e))
+            .and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(anyhow!("exited with {}", status))
+                }
+            });
+        match result {
+            Ok(()) => {}
+            Err(e) if stage.is_pre() => {
+                bail!("Hook {} failed: {}, aborting.", hook.display(), e)
+            }
+            Err(e) => {
+                warn!("Hook {} failed: {}.", hook.display(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_executable(p: &Path) -> bool {
+    match std::fs::metadata(p) {
+        Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
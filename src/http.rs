@@ -0,0 +1,777 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use once_cell::sync::OnceCell;
+use reqwest::{
+    blocking::{Client, Request, RequestBuilder, Response},
+    header::{
+        HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+        LOCATION, RETRY_AFTER,
+    },
+    redirect, tls, Proxy, StatusCode, Url,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    io::Read,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{config, debug, logger, netrc, pin, progress, proxy, units, warn};
+
+/// The same limit reqwest applies when following redirects itself.
+const MAX_REDIRECTS: usize = 10;
+
+/// Idle connections are kept this long, enough to span the version check,
+/// mirror probing and every retry of a download.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+/// Error bodies up to this size are read to the end so their connection
+/// can be reused, larger ones are not worth it.
+const MAX_DISCARD: u64 = 64 * 1024;
+/// Release metadata and version lists are a few KiB, anything past this
+/// is not one and is not buffered.
+pub const MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+/// Response headers `--trace-http` logs.
+const TRACED_HEADERS: &[HeaderName] = &[CONTENT_LENGTH, CONTENT_TYPE, ETAG, RETRY_AFTER];
+/// Query parameters whose values `--trace-http` hides, by a part of their
+/// lowercased name, as signed mirror URLs carry credentials there.
+const SECRET_PARAMETERS: &[&str] = &[
+    "token",
+    "sig",
+    "key",
+    "secret",
+    "password",
+    "auth",
+    "credential",
+];
+
+const ONLINE_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+const ONLINE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+static CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// The client every HTTP request vsdown makes goes through, built on first
+/// use from the configuration.
+pub fn client() -> Result<&'static Client> {
+    CLIENT.get_or_try_init(build)
+}
+
+/// Sends a request built from `client()`. With certificate pins configured,
+/// redirects are followed here instead of by reqwest so that the certificate
+/// of every pinned host along the way is checked.
+pub fn send(request: RequestBuilder) -> Result<Response> {
+    let client = client()?;
+    let mut request = request.build()?;
+    authorize(&mut request)?;
+    let pins = &config::get().pins;
+    if pins.is_empty() {
+        return execute(client, request);
+    }
+
+    follow(client, request, pins)
+}
+
+/// Executes `request` on a client not following redirects, following them
+/// here and checking every response against `pins`.
+fn follow(
+    client: &Client,
+    mut request: Request,
+    pins: &HashMap<String, Vec<String>>,
+) -> Result<Response> {
+    for _ in 0..MAX_REDIRECTS {
+        let next = request.try_clone();
+        let r = execute(client, request)?;
+        pin::check(&r, pins)?;
+        let location = match r.headers().get(LOCATION).and_then(|v| v.to_str().ok()) {
+            Some(location) if r.status().is_redirection() => r.url().join(location)?,
+            _ => return Ok(r),
+        };
+        request = next
+            .ok_or_else(|| anyhow!("Cannot follow a redirect to {}.", redact(location.as_str())))?;
+        *request.url_mut() = location;
+        // Credentials are for the host they were looked up for.
+        request.headers_mut().remove(AUTHORIZATION);
+        authorize(&mut request)?;
+    }
+
+    bail!("Too many redirects.")
+}
+
+/// Like `send()`, but waits out 429 and 503 responses carrying Retry-After,
+/// for the metadata queries which otherwise fail on the first one. Each wait
+/// is capped at retry-after-cap and all of them at throttle-budget; the
+/// response is returned as is once they are exceeded or the server gave no
+/// delay.
+pub fn send_with_retry_after(request: RequestBuilder) -> Result<Response> {
+    let cap = Duration::from_secs(config::get().retry_after_cap);
+    let budget = Duration::from_secs(config::get().throttle_budget);
+    let mut request = request;
+    let mut waited = Duration::ZERO;
+    loop {
+        let next = request.try_clone();
+        let r = send(request)?;
+        let status = r.status();
+        if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+            return Ok(r);
+        }
+        let (next, delay) = match (next, retry_after(r.headers())) {
+            (Some(next), Some(delay)) if waited + delay.min(cap) <= budget => {
+                (next, delay.min(cap))
+            }
+            _ => return Ok(r),
+        };
+        warn!(
+            "{} responded with {}, waiting {}s before trying again ...",
+            redact(r.url().as_str()),
+            status,
+            delay.as_secs()
+        );
+        discard(r);
+        progress::countdown(&format!("Throttled ({})", status), delay);
+        waited += delay;
+        request = next;
+    }
+}
+
+/// Attaches the credentials netrc lists for the host of `request`, for
+/// mirrors requiring basic auth. They are only sent over HTTPS.
+fn authorize(request: &mut Request) -> Result<()> {
+    let host = match request.url().host_str() {
+        Some(host) => host,
+        None => return Ok(()),
+    };
+    let credentials = match netrc::host_credentials(host)? {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    if request.url().scheme() != "https" {
+        debug!(
+            "Not sending netrc credentials for {} over plain HTTP.",
+            host
+        );
+        return Ok(());
+    }
+    debug!("Using netrc credentials for {}.", host);
+    let token = base64::encode(format!("{}:{}", credentials.login, credentials.password));
+    let mut value = HeaderValue::from_str(&format!("Basic {}", token))?;
+    value.set_sensitive(true);
+    request.headers_mut().insert(AUTHORIZATION, value);
+
+    Ok(())
+}
+
+/// Replaces the password of `url`, if any, for logging.
+pub fn redact(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut u) if u.password().is_some() => {
+            let _ = u.set_password(Some("redacted"));
+            u.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Executes `request`, logging the method, URLs, status, timing and
+/// `TRACED_HEADERS` of the exchange with `--trace-http`. Bodies and request
+/// headers, which carry credentials, are never logged.
+fn execute(client: &Client, request: Request) -> Result<Response> {
+    if !logger::is_tracing_http() {
+        return Ok(client.execute(request).map_err(scrub)?);
+    }
+    let method = request.method().clone();
+    let url = request.url().clone();
+    let start = Instant::now();
+    let result = client.execute(request).map_err(scrub);
+    let elapsed = start.elapsed();
+    match &result {
+        Ok(r) => {
+            let mut line = format!(
+                "{} {} -> {} in {:?}",
+                method,
+                trace_url(&url),
+                r.status(),
+                elapsed
+            );
+            if r.url() != &url {
+                line.push_str(&format!(" from {}", trace_url(r.url())));
+            }
+            for name in TRACED_HEADERS {
+                if let Some(v) = r.headers().get(name).and_then(|v| v.to_str().ok()) {
+                    line.push_str(&format!(", {}: {}", name, v));
+                }
+            }
+            trace(&line);
+        }
+        Err(e) => {
+            // The error names the URL too, which must be as redacted.
+            let mut message = e.to_string();
+            if let Some(u) = e.url() {
+                message = message.replace(u.as_str(), &trace_url(u));
+            }
+            trace(&format!(
+                "{} {} failed after {:?}: {}",
+                method,
+                trace_url(&url),
+                elapsed,
+                message
+            ))
+        }
+    }
+
+    Ok(result?)
+}
+
+fn trace(line: &str) {
+    eprintln!("{} {}", style("http:").dim().bold(), line);
+}
+
+/// `url` without credentials in its user info or query.
+fn trace_url(url: &Url) -> String {
+    let mut url = url.clone();
+    if !url.username().is_empty() || url.password().is_some() {
+        let _ = url.set_username("redacted");
+        let _ = url.set_password(None);
+    }
+    if url.query().is_some() {
+        let pairs = url
+            .query_pairs()
+            .map(|(k, v)| {
+                let lower = k.to_ascii_lowercase();
+                if SECRET_PARAMETERS.iter().any(|s| lower.contains(s)) {
+                    (k.into_owned(), "redacted".to_string())
+                } else {
+                    (k.into_owned(), v.into_owned())
+                }
+            })
+            .collect::<Vec<_>>();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    url.to_string()
+}
+
+/// reqwest errors include the request URL, drop it if it carries a password.
+fn scrub(e: reqwest::Error) -> reqwest::Error {
+    if e.url().and_then(Url::password).is_some() {
+        e.without_url()
+    } else {
+        e
+    }
+}
+
+/// Waits up to `timeout` for the host of `url` to answer, returning whether
+/// it did.
+pub fn wait_online(url: &str, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match reachable(url) {
+            Ok(()) => return true,
+            Err(e) => debug!("Network is not available yet: {}", e),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        thread::sleep(ONLINE_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Sends a HEAD request to the host of `url` with `client()`, so through the
+/// proxy its requests would take. Any response means it is reachable.
+pub fn reachable(url: &str) -> Result<()> {
+    probe(client()?, url)
+}
+
+fn probe(client: &Client, url: &str) -> Result<()> {
+    let root = Url::parse(url)?.join("/")?;
+    client.head(root).timeout(ONLINE_PROBE_TIMEOUT).send()?;
+
+    Ok(())
+}
+
+/// The delay a 429 or 503 response asks for, given in seconds or as an
+/// HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = parse_http_date(value)?;
+
+    Some(Duration::from_secs(at.saturating_sub(crate::state::now())))
+}
+
+/// Parses an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT` into Unix
+/// time, the only format servers are supposed to send.
+fn parse_http_date(s: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = s.split_whitespace().skip(1);
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = MONTHS.iter().position(|m| Some(*m) == parts.next())? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|t| t.parse::<i64>().ok());
+    let (h, m, sec) = (time.next()??, time.next()??, time.next()??);
+    if parts.next() != Some("GMT") {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    u64::try_from(days * 86400 + h * 3600 + m * 60 + sec).ok()
+}
+
+/// Reads the rest of a response nobody is interested in, which returns its
+/// connection to the pool instead of closing it.
+pub fn discard(r: Response) {
+    let _ = std::io::copy(&mut r.take(MAX_DISCARD), &mut std::io::sink());
+}
+
+/// Parses the JSON body of a small response such as release metadata,
+/// reading at most `MAX_METADATA_SIZE` of it.
+pub fn json<T: DeserializeOwned>(r: Response) -> Result<T> {
+    let url = redact(r.url().as_str());
+    let too_large = || {
+        anyhow!(
+            "The response from {} is too large, over {}.",
+            url,
+            units::Bytes(MAX_METADATA_SIZE)
+        )
+    };
+    if r.content_length().map_or(false, |l| l > MAX_METADATA_SIZE) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    r.take(MAX_METADATA_SIZE + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_METADATA_SIZE {
+        return Err(too_large());
+    }
+
+    serde_json::from_slice(&body).map_err(|e| anyhow!("Invalid response from {}: {}.", url, e))
+}
+
+fn build() -> Result<Client> {
+    // Connections are pooled per host, and reusing one also saves the TLS
+    // handshake.
+    let mut builder = Client::builder()
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE);
+    let tls_min = config::get().tls_min.as_deref();
+    if let Some(v) = tls_min {
+        let version = match v {
+            "1.2" => tls::Version::TLS_1_2,
+            "1.3" => tls::Version::TLS_1_3,
+            _ => return Err(anyhow!("Unsupported minimum TLS version {}.", v)),
+        };
+        builder = builder.min_tls_version(version);
+    }
+    if !config::get().pins.is_empty() {
+        builder = builder.tls_info(true).redirect(redirect::Policy::none());
+    }
+    for proxy in proxies()? {
+        builder = builder.proxy(proxy);
+    }
+
+    builder.build().map_err(|e| match tls_min {
+        Some(v) => anyhow!(
+            "Failed to set up the HTTP client with a minimum TLS version of {}, the TLS backend may not support it: {}.",
+            v,
+            e
+        ),
+        None => anyhow!("Failed to set up the HTTP client: {}.", e),
+    })
+}
+
+/// The proxies reqwest would take from the environment, with credentials
+/// from netrc attached, preceded by the `proxy-rules` of the configuration.
+/// Empty when there are no rules and netrc has no credentials for the
+/// environment's proxies, leaving reqwest to its own lookup; keeping the
+/// password out of `*_proxy` variables keeps it out of process listings.
+fn proxies() -> Result<Vec<Proxy>> {
+    let mut env = Vec::new();
+    let mut authenticated = false;
+    for (scheme, vars) in [
+        (
+            "http",
+            ["http_proxy", "HTTP_PROXY", "all_proxy", "ALL_PROXY"],
+        ),
+        (
+            "https",
+            ["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"],
+        ),
+    ] {
+        let value = match vars
+            .iter()
+            .find_map(|v| std::env::var(v).ok().filter(|v| !v.is_empty()))
+        {
+            Some(value) => value,
+            None => continue,
+        };
+        let url = proxy::parse_url(&value)
+            .map_err(|e| anyhow!("Invalid {} proxy {}: {}.", scheme, redact(&value), e))?;
+        let (url, found) = with_netrc_credentials(url)?;
+        authenticated |= found;
+        env.push((scheme, url));
+    }
+    let rules = config::get()
+        .proxy_rules
+        .iter()
+        .map(|rule| {
+            let proxy = match &rule.proxy {
+                Some(url) => Some(with_netrc_credentials(url.clone())?.0),
+                None => None,
+            };
+            Ok(proxy::Rule {
+                proxy,
+                ..rule.clone()
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if rules.is_empty() && !authenticated {
+        return Ok(Vec::new());
+    }
+
+    let no_proxy = no_proxy();
+    Ok(vec![Proxy::custom(move |target| {
+        choose(&rules, &no_proxy, &env, target)
+    })])
+}
+
+/// The proxy for `target`: that of the first of `rules` matching it, none
+/// when `no_proxy` exempts it, or that of `env` for its scheme.
+fn choose(
+    rules: &[proxy::Rule],
+    no_proxy: &[proxy::Pattern],
+    env: &[(&str, Url)],
+    target: &Url,
+) -> Option<Url> {
+    let host = target.host_str().unwrap_or_default();
+    if let Some(rule) = proxy::rule_for(rules, target) {
+        debug!("Proxy rule {} applies to {}.", rule, host);
+        return rule.proxy.clone();
+    }
+    if bypasses_proxy(no_proxy, target) {
+        debug!("{} is exempted from the proxy by no_proxy.", host);
+        return None;
+    }
+    let url = env
+        .iter()
+        .find(|(scheme, _)| *scheme == target.scheme())
+        .map(|(_, url)| url.clone());
+    if let Some(url) = &url {
+        debug!(
+            "No proxy rule applies to {}, using {} from the environment.",
+            host,
+            redact(url.as_str())
+        );
+    }
+    url
+}
+
+/// `url` of a proxy with the credentials netrc has for its host, unless it
+/// names a user already, and whether there were any.
+fn with_netrc_credentials(mut url: Url) -> Result<(Url, bool)> {
+    let credentials = match url.host_str() {
+        Some(host) if url.username().is_empty() => netrc::proxy_credentials(host)?,
+        _ => None,
+    };
+    let found = credentials.is_some();
+    if let Some(c) = credentials {
+        // Proxy URLs always have a host, which is all these need.
+        let _ = url.set_username(&c.login);
+        let _ = url.set_password(Some(&c.password));
+    }
+
+    Ok((url, found))
+}
+
+/// Entries of `no_proxy`, where suffixes, addresses and networks as
+/// ADDRESS/PREFIX are understood.
+fn no_proxy() -> Vec<proxy::Pattern> {
+    let value = ["no_proxy", "NO_PROXY"]
+        .iter()
+        .find_map(|v| std::env::var(v).ok())
+        .unwrap_or_default();
+    parse_no_proxy(&value)
+}
+
+fn parse_no_proxy(value: &str) -> Vec<proxy::Pattern> {
+    value
+        .split(',')
+        .filter(|e| !e.trim().is_empty())
+        .filter_map(|e| match proxy::Pattern::parse(e) {
+            Ok(pattern) => Some(pattern),
+            Err(err) => {
+                debug!("Ignoring the no_proxy entry {}: {}", e, err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether `no_proxy` exempts `url`, matching `*`, the host itself and its
+/// parent domains the way curl does, as well as addresses in networks.
+fn bypasses_proxy(no_proxy: &[proxy::Pattern], url: &Url) -> bool {
+    no_proxy.iter().any(|p| p.matches(url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Certificate;
+    use std::{io::Write, net::TcpListener};
+
+    use crate::checksum;
+
+    /// A TLS server for localhost on a port of its own, answering every
+    /// request with what `respond` makes of its path.
+    struct Server {
+        port: u16,
+        /// The DER certificate it serves.
+        certificate: Vec<u8>,
+    }
+
+    impl Server {
+        fn start<F: Fn(&str) -> String + Send + 'static>(respond: F) -> Self {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let certificate = cert.serialize_der().unwrap();
+            let pem = format!(
+                "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                base64::encode(&certificate)
+                    .as_bytes()
+                    .chunks(64)
+                    .map(|l| std::str::from_utf8(l).unwrap())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+            let identity = native_tls::Identity::from_pkcs8(
+                pem.as_bytes(),
+                cert.serialize_private_key_pem().as_bytes(),
+            )
+            .unwrap();
+            let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream.ok().and_then(|s| acceptor.accept(s).ok()) {
+                        Some(s) => s,
+                        None => continue,
+                    };
+                    let mut buf = [0; 4096];
+                    let n = stream.read(&mut buf).unwrap_or(0);
+                    let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    let _ = stream.write_all(respond(path).as_bytes());
+                    let _ = stream.shutdown();
+                }
+            });
+
+            Server { port, certificate }
+        }
+
+        /// The pin of its certificate key.
+        fn pin(&self) -> String {
+            checksum::sha256_bytes(pin::spki(&self.certificate).unwrap())
+        }
+    }
+
+    fn ok() -> String {
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_string()
+    }
+
+    fn redirect(to: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            to
+        )
+    }
+
+    /// A client set up as `build()` does with pins, trusting `servers`. The
+    /// certificates are only for localhost, and also served on 127.0.0.1.
+    fn client(servers: &[&Server]) -> Client {
+        let mut builder = Client::builder()
+            .no_proxy()
+            .tls_info(true)
+            .redirect(redirect::Policy::none())
+            .danger_accept_invalid_hostnames(true);
+        for server in servers {
+            builder =
+                builder.add_root_certificate(Certificate::from_der(&server.certificate).unwrap());
+        }
+
+        builder.build().unwrap()
+    }
+
+    fn get(client: &Client, url: &str, pins: &[(&str, String)]) -> Result<Response> {
+        let pins = pins
+            .iter()
+            .map(|(host, pin)| (host.to_string(), vec![pin.clone()]))
+            .collect();
+
+        follow(client, client.get(url).build()?, &pins)
+    }
+
+    #[test]
+    fn accepts_a_matching_pin() {
+        let server = Server::start(|_| ok());
+        let url = format!("https://localhost:{}/", server.port);
+        let r = get(&client(&[&server]), &url, &[("localhost", server.pin())]).unwrap();
+        assert_eq!(r.status(), StatusCode::OK);
+        assert_eq!(r.text().unwrap(), "ok");
+    }
+
+    #[test]
+    fn refuses_a_mismatched_pin() {
+        let server = Server::start(|_| ok());
+        let url = format!("https://localhost:{}/", server.port);
+        let e = get(&client(&[&server]), &url, &[("localhost", "0".repeat(64))]).unwrap_err();
+        assert!(
+            e.to_string().starts_with(&format!(
+                "The certificate key of localhost (sha256:{}) matches none of its pins.",
+                server.pin()
+            )),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn checks_pins_after_redirects() {
+        let target = Server::start(|_| ok());
+        let location = format!("https://localhost:{}/final", target.port);
+        let origin = Server::start(move |_| redirect(&location));
+        let client = client(&[&origin, &target]);
+        // The origin is not pinned, the host it redirects to is.
+        let url = format!("https://127.0.0.1:{}/", origin.port);
+
+        let r = get(&client, &url, &[("localhost", target.pin())]).unwrap();
+        assert_eq!(r.url().port(), Some(target.port));
+        assert_eq!(r.text().unwrap(), "ok");
+
+        let e = get(&client, &url, &[("localhost", origin.pin())]).unwrap_err();
+        assert!(e.to_string().contains("matches none of its pins"), "{}", e);
+    }
+
+    #[test]
+    fn refuses_plain_http_for_pinned_hosts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(ok().as_bytes());
+            }
+        });
+        let client = client(&[]);
+        let url = format!("http://localhost:{}/", port);
+        let e = get(&client, &url, &[("localhost", "0".repeat(64))]).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("Refusing plain HTTP response from localhost"));
+        assert!(get(&client, &url, &[]).is_ok());
+    }
+
+    #[test]
+    fn parses_no_proxy() {
+        let patterns = parse_no_proxy("localhost, .internal.example,10.0.0.0/8,,[::1], bad/99 ,");
+        let shown = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            shown,
+            ["localhost", "internal.example", "10.0.0.0/8", "::1"]
+        );
+        assert!(parse_no_proxy("").is_empty());
+    }
+
+    #[test]
+    fn chooses_proxies() {
+        let env = [
+            ("http", Url::parse("http://env.example:3128/").unwrap()),
+            ("https", Url::parse("http://env.example:3129/").unwrap()),
+        ];
+        let rules = proxy::parse(&[
+            "update.code.visualstudio.com=direct".to_string(),
+            "192.168.0.0/16=http://lan.example:8080".to_string(),
+        ])
+        .unwrap();
+        let no_proxy = parse_no_proxy(".internal.example,10.0.0.0/8,::1,192.168.1.1");
+        let chosen = |target: &str| {
+            choose(&rules, &no_proxy, &env, &Url::parse(target).unwrap()).map(|u| u.to_string())
+        };
+
+        assert_eq!(chosen("https://update.code.visualstudio.com/api"), None);
+        // Rules come before no_proxy.
+        assert_eq!(
+            chosen("http://192.168.1.1:8000/").as_deref(),
+            Some("http://lan.example:8080/")
+        );
+        assert_eq!(chosen("https://mirror.internal.example:8443/"), None);
+        assert_eq!(chosen("http://10.20.30.40/"), None);
+        assert_eq!(chosen("http://[::1]:8080/"), None);
+        assert_eq!(
+            chosen("https://az764295.vo.msecnd.net/").as_deref(),
+            Some("http://env.example:3129/")
+        );
+        assert_eq!(
+            chosen("http://[::2]/").as_deref(),
+            Some("http://env.example:3128/")
+        );
+        assert_eq!(
+            choose(
+                &[],
+                &[],
+                &env[..1],
+                &Url::parse("https://example.com/").unwrap()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn probes_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                let _ = tx.send(request.lines().next().unwrap_or_default().to_string());
+                // Any status will do.
+                let _ = stream.write_all(
+                    b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            }
+        });
+        let proxied = Client::builder()
+            .proxy(Proxy::all(format!("http://127.0.0.1:{}", port)).unwrap())
+            .build()
+            .unwrap();
+
+        // Not resolvable, so only reachable through the proxy.
+        probe(&proxied, "http://metadata.invalid/api/versions/{version}").unwrap();
+        assert_eq!(rx.recv().unwrap(), "HEAD http://metadata.invalid/ HTTP/1.1");
+        assert!(probe(&client(&[]), "http://metadata.invalid/").is_err());
+    }
+
+    #[test]
+    fn takes_proxies_without_a_scheme() {
+        let url = proxy::parse_url("proxy.example:3128").unwrap();
+        assert_eq!(url.as_str(), "http://proxy.example:3128/");
+        let url = proxy::parse_url("socks5://[::1]:1080").unwrap();
+        assert_eq!(url.scheme(), "socks5");
+        assert_eq!(url.host_str(), Some("[::1]"));
+        assert_eq!(url.port(), Some(1080));
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::path::{Path, PathBuf};
+
+use crate::{checker, gc, history, info, logger, state, units, warn};
+
+/// A location an older vsdown wrote to and the current one no longer uses.
+struct LegacyPath {
+    /// A path, or a `prefix*` matching entries of a directory.
+    pattern: &'static str,
+    what: &'static str,
+    /// Whether the path is obsolete yet, e.g. once its migration has run.
+    obsolete: fn() -> bool,
+}
+
+/// Every historical location, in the order migrations made them obsolete.
+/// Future migrations append to it.
+const LEGACY_PATHS: &[LegacyPath] = &[
+    LegacyPath {
+        pattern: "/usr/lib/VSCode-linux-*",
+        what: "release unpacked by an interrupted install",
+        obsolete: no_interrupted_install,
+    },
+    LegacyPath {
+        pattern: "/var/lib/vsdown/current_version",
+        what: "version file, replaced by state.json",
+        obsolete: state::migrated,
+    },
+    LegacyPath {
+        pattern: "/var/lib/vsdown/bin_name",
+        what: "launcher name, replaced by state.json",
+        obsolete: state::migrated,
+    },
+    LegacyPath {
+        pattern: "/var/lib/vsdown/no-sandbox",
+        what: "sandbox choice, replaced by state.json",
+        obsolete: state::migrated,
+    },
+    LegacyPath {
+        pattern: "/var/lib/vsdown/origin",
+        what: "release origin, replaced by state.json",
+        obsolete: state::migrated,
+    },
+];
+
+/// An unpacked release may still be resumed from.
+fn no_interrupted_install() -> bool {
+    state::interrupted_install().is_none()
+}
+
+fn expand(pattern: &str) -> Vec<PathBuf> {
+    let prefix = match pattern.strip_suffix('*') {
+        Some(prefix) => prefix,
+        None => {
+            let p = PathBuf::from(pattern);
+            return match std::fs::symlink_metadata(&p) {
+                Ok(_) => vec![p],
+                Err(_) => Vec::new(),
+            };
+        }
+    };
+    let (dir, name) = match prefix.rsplit_once('/') {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+    let mut paths = std::fs::read_dir(dir)
+        .map(|d| {
+            d.filter_map(|i| i.ok())
+                .filter(|i| i.file_name().to_string_lossy().starts_with(name))
+                .map(|i| i.path())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    paths.sort();
+
+    paths
+}
+
+/// Lists what older vsdown versions left behind with its size, and removes
+/// it once confirmed or with `yes`. With `dry_run`, only lists it.
+pub fn cleanup(dry_run: bool, yes: bool) -> Result<()> {
+    let mut found = Vec::new();
+    for legacy in LEGACY_PATHS {
+        if !(legacy.obsolete)() {
+            continue;
+        }
+        for p in expand(legacy.pattern) {
+            let size = gc::disk_usage(&p);
+            println!("{} ({}): {}", p.display(), units::Bytes(size), legacy.what);
+            found.push((p, size));
+        }
+    }
+    if found.is_empty() {
+        info!("Nothing left behind by older vsdown versions.");
+        return Ok(());
+    }
+    let total = found.iter().map(|(_, size)| size).sum::<u64>();
+    if dry_run {
+        info!("{} would be reclaimed.", units::Bytes(total));
+        return Ok(());
+    }
+    if !yes && !logger::confirm(&format!("Remove {} path(s)?", found.len())) {
+        bail!("Nothing was removed. Run with --yes to remove without asking.");
+    }
+    let mut failed = 0;
+    for (p, _) in &found {
+        if let Err(e) = remove(p) {
+            warn!("Failed to remove {}: {}", p.display(), e);
+            failed += 1;
+        }
+    }
+    let version = checker::get_current_version().unwrap_or_default();
+    if let Err(e) = history::append(&history::Event::new("cleanup-legacy", &version)) {
+        warn!("Failed to record the cleanup in the history: {}", e);
+    }
+    if failed > 0 {
+        return Err(anyhow!("Failed to remove {} path(s).", failed));
+    }
+    info!("{} reclaimed.", units::Bytes(total));
+
+    Ok(())
+}
+
+/// Removes `p` without following it if it is a symlink.
+fn remove(p: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(p)?.is_dir() {
+        std::fs::remove_dir_all(p)
+    } else {
+        std::fs::remove_file(p)
+    }
+}
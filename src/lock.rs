@@ -0,0 +1,224 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::checker::CURRENT_VERSION_DIRECTORY;
+use crate::{debug, info, netfs, units, warn};
+
+const LOCK_FILENAME: &str = "lock";
+/// Created exclusively in place of flock on network filesystems, holding
+/// the PID and host of its owner.
+const EXCLUSIVE_LOCK_FILENAME: &str = "lock.excl";
+
+/// Held for the duration of an operation changing the installation, the
+/// cache or the state; released when dropped.
+pub struct Lock {
+    _file: File,
+    /// The lock file to remove when released, if it was created exclusively.
+    exclusive: Option<PathBuf>,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Some(p) = &self.exclusive {
+            if let Err(e) = std::fs::remove_file(p) {
+                warn!("Failed to remove {}: {}", p.display(), e);
+            }
+        }
+    }
+}
+
+/// How often a held lock is tried again while waiting for it.
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Takes the operation lock, failing at once if another vsdown process
+/// holds it.
+pub fn acquire() -> Result<Lock> {
+    acquire_within(Duration::ZERO)
+}
+
+/// Takes the operation lock, waiting up to `timeout` for another vsdown
+/// process holding it to finish.
+pub fn acquire_within(timeout: Duration) -> Result<Lock> {
+    acquire_in(
+        Path::new(CURRENT_VERSION_DIRECTORY),
+        netfs::state_on_network(),
+        timeout,
+    )
+}
+
+/// Takes the lock of `dir`, created exclusively rather than with flock when
+/// `dir` is on a `network` filesystem.
+fn acquire_in(dir: &Path, network: bool, timeout: Duration) -> Result<Lock> {
+    std::fs::create_dir_all(dir)?;
+    let p = if network {
+        dir.join(EXCLUSIVE_LOCK_FILENAME)
+    } else {
+        dir.join(LOCK_FILENAME)
+    };
+    let start = Instant::now();
+    let mut waiting = false;
+    let file = loop {
+        let held = if network {
+            create_exclusive(&p)?
+        } else {
+            flock_file(&p)?
+        };
+        if let Some(file) = held {
+            break file;
+        }
+        if start.elapsed() >= timeout {
+            if waiting {
+                bail!(
+                    "Another vsdown operation is still in progress after {} seconds.",
+                    timeout.as_secs()
+                );
+            }
+            bail!("Another vsdown operation is in progress.");
+        }
+        if !waiting {
+            info!(
+                "Another vsdown operation is in progress, waiting up to {} seconds for it to finish...",
+                timeout.as_secs()
+            );
+            waiting = true;
+        }
+        std::thread::sleep(RETRY_INTERVAL.min(timeout.saturating_sub(start.elapsed())));
+    };
+    if waiting {
+        debug!("Took the lock after {}.", units::Elapsed(start.elapsed()));
+    }
+
+    Ok(Lock {
+        _file: file,
+        exclusive: if network { Some(p) } else { None },
+    })
+}
+
+/// Takes the flock on `p`, returning None if another process holds it.
+fn flock_file(p: &Path) -> Result<Option<File>> {
+    let file = File::create(p).map_err(|e| anyhow!("Failed to open {}: {}.", p.display(), e))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            bail!("Failed to lock {}: {}.", p.display(), e);
+        }
+        return Ok(None);
+    }
+
+    Ok(Some(file))
+}
+
+/// Creates `p` with O_EXCL, which NFS honours where flock may not reach the
+/// server, returning None if another process holds it. A lock file left by
+/// a process of this host which is gone is taken over.
+fn create_exclusive(p: &Path) -> Result<Option<File>> {
+    let owner = format!("{}@{}", std::process::id(), hostname());
+    match netfs::retry_stale(|| OpenOptions::new().write(true).create_new(true).open(p)) {
+        Ok(mut file) => {
+            file.write_all(owner.as_bytes())?;
+            Ok(Some(file))
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            let held_by = std::fs::read_to_string(p).unwrap_or_default();
+            let stale = held_by.trim().split_once('@').map_or(false, |(pid, host)| {
+                host == hostname() && !Path::new(&format!("/proc/{}", pid)).exists()
+            });
+            if !stale {
+                return Ok(None);
+            }
+            warn!(
+                "Removing {} left by process {}, which is gone.",
+                p.display(),
+                held_by.trim()
+            );
+            std::fs::remove_file(p)
+                .map_err(|e| anyhow!("Failed to remove {}: {}.", p.display(), e))?;
+            create_exclusive(p)
+        }
+        Err(e) => bail!("Failed to create {}: {}.", p.display(), e),
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netfs::Detection;
+
+    /// Takes the lock of `dir` as on a network filesystem.
+    fn acquire_networked(dir: &Path, timeout: Duration) -> Result<Lock> {
+        acquire_in(dir, netfs::on_network(Detection::Network, dir), timeout)
+    }
+
+    #[test]
+    fn locks_with_an_exclusive_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join(EXCLUSIVE_LOCK_FILENAME);
+        let lock = acquire_networked(dir.path(), Duration::ZERO).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&p).unwrap(),
+            format!("{}@{}", std::process::id(), hostname())
+        );
+        assert!(!dir.path().join(LOCK_FILENAME).exists());
+        drop(lock);
+        assert!(!p.exists());
+    }
+
+    #[test]
+    fn refuses_a_held_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = acquire_networked(dir.path(), Duration::ZERO).unwrap();
+        let e = acquire_networked(dir.path(), Duration::ZERO).err().unwrap();
+        assert_eq!(e.to_string(), "Another vsdown operation is in progress.");
+
+        // Until it is released.
+        let releasing = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(lock);
+        });
+        acquire_networked(dir.path(), Duration::from_secs(10)).unwrap();
+        releasing.join().unwrap();
+    }
+
+    #[test]
+    fn takes_over_the_lock_of_a_dead_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join(EXCLUSIVE_LOCK_FILENAME);
+        // Beyond the largest PID Linux hands out.
+        std::fs::write(&p, format!("{}@{}", 1 << 23, hostname())).unwrap();
+        let lock = acquire_networked(dir.path(), Duration::ZERO).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&p).unwrap(),
+            format!("{}@{}", std::process::id(), hostname())
+        );
+        drop(lock);
+
+        // Whether a process of another host is gone cannot be told.
+        std::fs::write(&p, format!("{}@{}-other", 1 << 23, hostname())).unwrap();
+        assert!(acquire_networked(dir.path(), Duration::ZERO).is_err());
+        assert!(p.exists());
+    }
+
+    #[test]
+    fn locks_locally_with_flock() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = netfs::on_network(Detection::Local, dir.path());
+        let lock = acquire_in(dir.path(), network, Duration::ZERO).unwrap();
+        assert!(dir.path().join(LOCK_FILENAME).exists());
+        assert!(acquire_in(dir.path(), network, Duration::ZERO).is_err());
+        drop(lock);
+        acquire_in(dir.path(), network, Duration::ZERO).unwrap();
+    }
+}
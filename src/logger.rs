@@ -1,3 +1,35 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static TRACE_HTTP: AtomicBool = AtomicBool::new(false);
+static VERBOSE_UNPACK: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+pub fn set_trace_http(trace: bool) {
+    TRACE_HTTP.store(trace, Ordering::Relaxed);
+}
+
+/// Whether `--trace-http` asked for the metadata of every HTTP exchange.
+pub fn is_tracing_http() -> bool {
+    TRACE_HTTP.load(Ordering::Relaxed)
+}
+
+pub fn set_verbose_unpack(verbose: bool) {
+    VERBOSE_UNPACK.store(verbose, Ordering::Relaxed);
+}
+
+/// Whether `--verbose-unpack` asked for every extracted path to be logged.
+pub fn is_verbose_unpack() -> bool {
+    VERBOSE_UNPACK.load(Ordering::Relaxed)
+}
+
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)+) => {
@@ -22,6 +54,16 @@ macro_rules! error {
     };
 }
 
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        if $crate::logger::is_verbose() {
+            eprint!("{} ", style("debug:").dim().bold());
+            eprintln!($($arg)+);
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! color_bool {
     ($x:expr) => {
@@ -32,3 +74,21 @@ macro_rules! color_bool {
         }
     };
 }
+
+/// Asks a yes/no question on the terminal, defaulting to no. Returns false
+/// without asking when there is no one to answer.
+pub fn confirm(prompt: &str) -> bool {
+    if !console::user_attended_stderr() {
+        return false;
+    }
+    let term = console::Term::stderr();
+    eprint!(
+        "{} {} [y/N] ",
+        console::style("confirm:").magenta().bold(),
+        prompt
+    );
+    match term.read_line() {
+        Ok(answer) => matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"),
+        Err(_) => false,
+    }
+}
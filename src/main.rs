@@ -1,14 +1,156 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgEnum, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use console::style;
+use serde::Serialize;
+use std::{fmt::Display, path::PathBuf, time::Duration};
 
-use crate::checker::install_vscode;
+use crate::checker::{install_vscode, CachedVerdict, CheckOptions, InstallOptions};
 
+mod apply;
+mod cache;
+mod channels;
 mod checker;
+mod checksum;
+mod compat;
+mod completions;
+mod config;
+mod crash;
+mod daemon;
+mod diff;
+mod download;
+mod elf;
+mod events;
+mod exclude;
+mod gc;
+mod hashes;
+mod history;
+mod hooks;
+mod http;
+mod legacy;
+mod lock;
 mod logger;
+mod manifest;
+mod media;
+mod migrate;
+mod mirror;
+mod netfs;
+mod netrc;
+mod notice;
+mod output;
+mod packages;
+mod perms;
+mod pin;
+mod preflight;
+mod progress;
+mod proxy;
+mod release;
+mod remove_report;
+mod running;
+mod scope;
+mod server;
+mod state;
+mod stats;
+mod sysext;
+mod table;
+mod timings;
+mod units;
+mod unpack;
+mod user;
+mod userns;
+mod verify;
+mod which;
+
+/// Exit code when the network stayed unavailable, EX_TEMPFAIL from sysexits.h.
+const EXIT_NETWORK_UNAVAILABLE: i32 = 75;
+/// Exit code when an upgrade or removal was refused because Visual Studio
+/// Code is running.
+const EXIT_EDITOR_RUNNING: i32 = 10;
+/// Exit code of `install --detailed-exit-codes` when there was nothing to do.
+const EXIT_UP_TO_DATE: i32 = 2;
+/// Exit code when the requested version is not published, as opposed to a
+/// failure worth retrying.
+const EXIT_NOT_FOUND: i32 = 3;
+/// Exit code of `check --all` when any channel has an update, as with
+/// `dnf check-update`.
+const EXIT_UPDATE_AVAILABLE: i32 = 100;
+/// Exit code of `check --cached-only` when the last check is missing or stale.
+const EXIT_UNKNOWN: i32 = 4;
+/// Exit code of `install --if-needed --version` when a newer version is
+/// installed.
+const EXIT_NEWER_INSTALLED: i32 = 5;
 
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct Args {
+    /// TOML file mapping architectures to download os values
+    #[clap(long, global = true)]
+    arch_map: Option<PathBuf>,
+    /// Download from this URL template instead of the configured mirrors
+    #[clap(long, global = true)]
+    mirror: Option<String>,
+    /// Race the first chunk of up to N mirrors and download from the fastest
+    #[clap(long, global = true, value_name = "N")]
+    max_parallel_mirrors: Option<usize>,
+    /// Directory of pre-/post-operation hook scripts
+    #[clap(long, global = true)]
+    hooks_dir: Option<PathBuf>,
+    /// Cache downloaded archives here instead of /var/cache/vsdown
+    #[clap(long, global = true, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Store cached archives as downloaded, or their tar with zstd to save space
+    #[clap(
+        long,
+        global = true,
+        arg_enum,
+        value_name = "FORMAT",
+        default_value = "none"
+    )]
+    compress_cache: cache::Compression,
+    /// Give up on a 4xx response instead of trying the next mirror
+    #[clap(long, global = true)]
+    fail_fast_on_4xx: bool,
+    /// Minimum TLS version to accept
+    #[clap(long, global = true, possible_values = ["1.2", "1.3"])]
+    tls_min: Option<String>,
+    /// Do not rebuild the desktop database and icon cache, leaving it to the caller
+    #[clap(long, global = true)]
+    no_metadata_refresh: bool,
+    /// How to show the progress of downloads and unpacking
+    #[clap(long, global = true, arg_enum, default_value = "auto")]
+    progress_style: progress::Style,
+    /// Write NDJSON progress events to stdout, for frontends
+    #[clap(long, global = true)]
+    progress_json: bool,
+    /// Use this version of the --progress-json protocol [default: the newest]
+    #[clap(long, global = true, value_name = "N", requires = "progress-json")]
+    json_protocol: Option<u32>,
+    /// Write progress to this inherited file descriptor instead of stdout
+    #[clap(long, global = true, value_name = "N")]
+    progress_fd: Option<i32>,
+    /// Do not read proxy and mirror credentials from ~/.netrc or $NETRC
+    #[clap(long, global = true)]
+    no_netrc: bool,
+    /// Where to record the installed release, legacy keeps older vsdown working
+    #[clap(long, global = true, arg_enum, default_value = "json")]
+    state_format: state::Format,
+    /// Wait up to SECONDS for another vsdown operation to finish instead of failing at once
+    #[clap(long, global = true, value_name = "SECONDS", default_value = "0")]
+    state_lock_timeout: u64,
+    /// Where to learn the latest version, falling back to the other source if it is unreachable
+    #[clap(long, global = true, arg_enum, default_value = "update-api")]
+    channel_version_source: checker::VersionSource,
+    /// Print debugging information
+    #[clap(short, long, global = true)]
+    verbose: bool,
+    /// Log the method, URL, status, timing and key headers of every HTTP request, never bodies or credentials
+    #[clap(long, global = true)]
+    trace_http: bool,
+    /// Log the path of every file as it is extracted, instead of the unpack progress bar
+    #[clap(long, global = true)]
+    verbose_unpack: bool,
+    /// [debug] Save the raw body of the last download response to PATH, error pages included, for bug reports
+    #[clap(long, global = true, value_name = "PATH", hide_short_help = true)]
+    dump_download: Option<PathBuf>,
     #[clap(subcommand)]
     subcommand: VsdownCommand,
 }
@@ -21,56 +163,1552 @@ enum VsdownCommand {
     Check(Check),
     /// Remove vscode
     Remove(Remove),
+    /// Serve update checks, installs and removals to desktop frontends over D-Bus
+    Daemon(Daemon),
+    /// Bring the installation to the state declared in a TOML file
+    Apply(Apply),
+    /// Swap in an upgrade left pending because Visual Studio Code kept the installed tree busy
+    FinishUpgrade(FinishUpgrade),
+    /// Build a systemd-sysext extension containing vscode
+    BuildSysext(BuildSysext),
+    /// Manage the configuration file
+    Config(ConfigArgs),
+    /// Save the configuration and install preferences to a file, for another machine
+    Export(Export),
+    /// Restore what export saved, for the next install and check to use
+    Import(Import),
+    /// Show what a command resolves to and whether vsdown manages it
+    Which(Which),
+    /// Show the release date, downloads and checksums of a release
+    Info(Info),
+    /// Check whether a URL serves Visual Studio Code releases, before using it as a mirror
+    VerifyUrl(VerifyUrl),
+    /// Preview which files an upgrade would add, remove, replace or clobber
+    Diff(Diff),
+    /// Check the installed files against the manifest recorded when installing them
+    Verify(Verify),
+    /// Restore the expected permissions of the installed files
+    FixPerms(FixPerms),
+    /// Inspect what vsdown has recorded about the installation
+    State(StateArgs),
+    /// Recreate only the launcher in /usr/bin, leaving the installed tree alone
+    RefreshSymlink,
+    /// Remove superseded caches, leftover staging directories and old crash reports
+    Gc(Gc),
+    /// Remove what older vsdown versions left behind
+    CleanupLegacy(CleanupLegacy),
+    /// Show how much vsdown downloaded and saved each month
+    Stats(Stats),
+    /// Print SHA-256 hashes of key files of the installed tree
+    Hashes(Hashes),
+    /// Install a profile.d snippet announcing pending updates at login
+    GenerateLoginNotice(GenerateLoginNotice),
+    /// Print a shell completion script
+    Completions(Completions),
+    #[clap(name = "__complete-versions", hide = true)]
+    CompleteVersions,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum Variant {
+    /// The desktop editor
+    Desktop,
+    /// The standalone CLI, providing remote tunnels
+    Server,
+}
+
+impl Variant {
+    fn is_installed(self) -> bool {
+        match self {
+            Variant::Desktop => checker::is_installed(),
+            Variant::Server => server::is_installed(),
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
+#[clap(
+    after_help = "EXIT STATUS:\n    0     Upgraded, or up to date without --detailed-exit-codes\n    1     Failed\n    2     Up to date, with --detailed-exit-codes\n    CODE  Up to date, with --on-up-to-date CODE\n    3     The --version is not published\n    5     A newer version is installed, with --if-needed --version\n    10    Deferred because Visual Studio Code is running\n    75    The network stayed unavailable"
+)]
 struct Install {
+    /// Reinstall even if up to date, and upgrade while Visual Studio Code is running
     #[clap(short = 'f', long)]
     force: bool,
+    /// Only check write access to the installation paths, then exit
+    #[clap(long)]
+    check_perms: bool,
+    /// Check the downloaded code binary is built for the expected architecture
+    #[clap(long)]
+    verify_downloaded_arch: bool,
+    /// Skip the architecture check, which is on by default for --arch-map overrides
+    #[clap(long, conflicts_with = "verify-downloaded-arch")]
+    no_verify_downloaded_arch: bool,
+    /// Install the newest cached release without network access
+    #[clap(long)]
+    offline: bool,
+    /// Do not re-hash cached archives before reusing them
+    #[clap(long)]
+    trust_cache: bool,
+    /// Do nothing if the success marker already records this version
+    #[clap(long, value_name = "VERSION")]
+    skip_if_marker: Option<String>,
+    /// Only download the release into DIR as vscode-VERSION-OS.tar.gz, without installing it
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = &["record-only", "from-url", "from-file", "offline", "resume", "abort", "variant"]
+    )]
+    download_only: Option<PathBuf>,
+    /// With --download-only, fetch for these architectures (e.g. linux-x64,linux-arm64) instead of this machine's
+    #[clap(
+        long,
+        value_name = "OS",
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        requires = "download-only",
+        parse(try_from_str = media::parse_arch)
+    )]
+    arch: Vec<String>,
+    /// With --download-only, pick the architectures to fetch from a list
+    #[clap(long, requires = "download-only", conflicts_with = "arch")]
+    select_arch_interactive: bool,
+    /// Only install when absent or outdated, never asking, for provisioning scripts; with --version, ensure exactly that version
+    #[clap(long, conflicts_with_all = &["force", "record-only", "resume", "abort"])]
+    if_needed: bool,
+    /// With --if-needed --version, install the version even over a newer one
+    #[clap(long, requires_all = &["if-needed", "version"])]
+    allow_downgrade: bool,
+    /// Print how long each phase took at the end, as --verbose always does
+    #[clap(long)]
+    timings: bool,
+    /// Download the archive from this URL instead of the mirrors
+    #[clap(long, value_name = "URL", requires = "version")]
+    from_url: Option<String>,
+    /// Install this local archive instead of downloading one
+    #[clap(long, value_name = "PATH", requires = "version", conflicts_with_all = &["from-url", "offline"])]
+    from_file: Option<PathBuf>,
+    /// Refuse to install unless the archive has this digest, given as sha256:HEX
+    #[clap(long, parse(try_from_str = checksum::parse_sha256))]
+    checksum: Option<String>,
+    /// Switch to the next mirror when one serves an archive failing --checksum
+    #[clap(long, requires = "checksum")]
+    retry_mirrors_on_checksum_fail: bool,
+    /// Allow --from-url without --checksum
+    #[clap(long)]
+    no_verify: bool,
+    /// Install this version instead of the latest, or the version to record for --from-url and --from-file installs
+    #[clap(long)]
+    version: Option<String>,
+    /// When --version is not published, list the closest versions that are
+    #[clap(long, requires = "version")]
+    suggest: bool,
+    /// Do not check the release against the system C libraries
+    #[clap(long)]
+    skip_compat_check: bool,
+    /// Do not make chrome-sandbox setuid root, launch with --no-sandbox instead
+    #[clap(long, conflicts_with = "sandbox-setup")]
+    no_sandbox_setup: bool,
+    /// Set up chrome-sandbox again after a --no-sandbox-setup install
+    #[clap(long)]
+    sandbox_setup: bool,
+    /// Wait up to SECONDS for the network to come up before downloading
+    #[clap(long, value_name = "SECONDS", conflicts_with = "offline")]
+    wait_online: Option<u64>,
+    /// Do not create a launcher in /usr/bin, e.g. when it is managed by other tools
+    #[clap(long, conflicts_with = "bin-name")]
+    no_symlink: bool,
+    /// Name of the launcher in /usr/bin [default: vscode, or the previous name]
+    #[clap(long, value_name = "NAME", parse(try_from_str = checker::parse_bin_name))]
+    bin_name: Option<String>,
+    /// Also symlink the tree's CLI helper (bin/code) into /usr/bin as NAME, for `code .` in terminals [default NAME: code]
+    #[clap(
+        long,
+        value_name = "NAME",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = checker::DEFAULT_CLI_NAME,
+        parse(try_from_str = checker::parse_bin_name)
+    )]
+    cli_symlink: Option<String>,
+    /// Remove the CLI helper symlink an earlier --cli-symlink installed
+    #[clap(long, conflicts_with = "cli-symlink")]
+    no_cli_symlink: bool,
+    /// Write unpacked files on N threads [default: one per CPU, up to 4]
+    #[clap(long, value_name = "N")]
+    concurrent_unpack: Option<Option<usize>>,
+    /// Finish an interrupted installation, reusing the cached archive
+    #[clap(long, conflicts_with = "abort")]
+    resume: bool,
+    /// When resuming, unpack the release again from the start instead of keeping what the interrupted extraction wrote
+    #[clap(long, conflicts_with = "abort")]
+    fresh_extract: bool,
+    /// Roll back an interrupted installation
+    #[clap(long)]
+    abort: bool,
+    /// Overwrite locally changed files without asking
+    #[clap(short, long)]
+    yes: bool,
+    /// What to install
+    #[clap(long, arg_enum, default_value = "desktop")]
+    variant: Variant,
+    /// Leave paths owned by a packaged Visual Studio Code alone, installing the launcher as code-latest and the desktop files under names of their own
+    #[clap(long, conflicts_with = "variant")]
+    coexist: bool,
+    /// Install the desktop entry and icon system-wide or for the user only, kept by later upgrades
+    #[clap(long, arg_enum, value_name = "SCOPE", conflicts_with = "variant")]
+    metadata_scope: Option<scope::Scope>,
+    /// Keep this many previous versions cached for rolling back, instead of the configured retain
+    #[clap(long, value_name = "N", conflicts_with = "variant")]
+    retain: Option<u64>,
+    /// Do not install release contents matching GLOB, relative to the top of the release, e.g. 'locales/*.pak'
+    #[clap(
+        long,
+        value_name = "GLOB",
+        multiple_occurrences = true,
+        conflicts_with = "variant"
+    )]
+    exclude: Vec<String>,
+    /// Take over a tree extracted to /usr/lib/vscode by hand: record its version and files and add the launcher, without downloading
+    #[clap(
+        long,
+        conflicts_with_all = &["from-url", "from-file", "offline", "variant", "resume", "abort", "exclude"]
+    )]
+    record_only: bool,
+    /// Take over a tree at /usr/lib/vscode vsdown did not install, as --record-only does, before upgrading it
+    #[clap(long, conflicts_with_all = &["replace", "record-only", "variant"])]
+    adopt: bool,
+    /// Run `code --version` as the target user after installing, failing the install (or only warning) unless it prints the installed version
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "MODE",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "fail"
+    )]
+    health_check: Option<compat::HealthCheck>,
+    /// Shell command the health check runs instead, which must print the installed version first
+    #[clap(long, value_name = "CMD", requires = "health-check")]
+    health_check_command: Option<String>,
+    /// Seconds the health check waits for the command [default: 30]
+    #[clap(long, value_name = "SECONDS", requires = "health-check")]
+    health_check_timeout: Option<u64>,
+    /// Delete a tree at /usr/lib/vscode vsdown did not install and install over it
+    #[clap(long, conflicts_with_all = &["record-only", "variant"])]
+    replace: bool,
+    /// Install the newest cached release when the network is unreachable, even if it may not be the latest
+    #[clap(
+        long,
+        conflicts_with_all = &["from-url", "from-file", "offline", "version", "variant", "record-only"]
+    )]
+    prefer_cache_even_if_stale: bool,
+    /// Exit with 2 instead of 0 when already up to date
+    #[clap(long)]
+    detailed_exit_codes: bool,
+    /// Exit with CODE (0-255) instead of 0 when already up to date
+    #[clap(long, value_name = "CODE", parse(try_from_str = parse_exit_code), conflicts_with = "detailed-exit-codes")]
+    on_up_to_date: Option<i32>,
+    /// Print the outcome as a final line of JSON: upgraded, up-to-date, deferred or failed
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Parser, Debug)]
+#[clap(
+    after_help = "EXIT STATUS:\n    0     Checked\n    1     Failed\n    CODE  Up to date, with --on-up-to-date CODE\n    4     The last check is missing or stale, with --cached-only\n    75    The network stayed unavailable\n    100   An update is available, with --cached-only or --all"
+)]
+struct Check {
+    /// Print the result as JSON
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+    /// Do not resolve the download URL and size of a pending update
+    #[clap(long)]
+    no_network_extras: bool,
+    /// Explain how the result was determined
+    #[clap(long)]
+    explain: bool,
+    /// Wait up to SECONDS for the network to come up before checking
+    #[clap(long, value_name = "SECONDS")]
+    wait_online: Option<u64>,
+    /// Record a pending update in /run/vsdown/update-available for the login notice
+    #[clap(long)]
+    write_flag: bool,
+    /// Check every installed channel (desktop and server) at once
+    #[clap(long, conflicts_with_all = &["write-flag", "explain"])]
+    all: bool,
+    /// Print the --all table fitted to the terminal, as tab-separated columns for scripts, or as JSON
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "FORMAT",
+        requires = "all",
+        conflicts_with = "json"
+    )]
+    format: Option<table::Format>,
+    /// Remind to run verify when the installation was not verified for N days [default: the verify-after-days key]
+    #[clap(long, value_name = "N")]
+    verify_after_days: Option<u64>,
+    /// Do not remind to run verify
+    #[clap(long, conflicts_with = "verify-after-days")]
+    no_verify_reminder: bool,
+    /// Exit with CODE (0-255) instead of 0 when already up to date, with --all when every channel is
+    #[clap(long, value_name = "CODE", parse(try_from_str = parse_exit_code))]
+    on_up_to_date: Option<i32>,
+    /// Answer from the last check without the network, only through the exit code
+    #[clap(
+        long,
+        conflicts_with_all = &["json", "explain", "wait-online", "write-flag", "all"]
+    )]
+    cached_only: bool,
+    /// With --cached-only, treat a last check older than SECONDS as unknown
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value = "86400",
+        requires = "cached-only"
+    )]
+    max_age: u64,
+}
+#[derive(Parser, Debug)]
+struct Remove {
+    /// Remove the installation at this directory instead of the recorded one
+    #[clap(long, conflicts_with = "variant")]
+    path: Option<PathBuf>,
+    /// What to remove
+    #[clap(long, arg_enum, default_value = "desktop")]
+    variant: Variant,
+    /// Do not ask for confirmation
+    #[clap(short, long)]
+    yes: bool,
+    /// Look for the desktop entry and icon in this scope instead of where they were installed
+    #[clap(long, arg_enum, value_name = "SCOPE", conflicts_with_all = &["path", "variant"])]
+    metadata_scope: Option<scope::Scope>,
+    /// Exit with an error when there is nothing to remove
+    #[clap(long)]
+    fail_if_not_installed: bool,
+    /// Only list what would be removed and the user data that would be kept
+    #[clap(long, conflicts_with_all = &["path", "variant"])]
+    dry_run: bool,
+    /// Print the --dry-run report as JSON
+    #[clap(long, requires = "dry-run")]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Parser, Debug)]
+struct BuildSysext {
+    /// Output path, either an image (*.raw) or a directory
+    #[clap(short, long)]
+    output: PathBuf,
+    /// Filesystem for image outputs (erofs or squashfs)
+    #[clap(long)]
+    fs: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct Which {
+    /// Command to resolve
+    #[clap(default_value = "vscode")]
+    name: String,
+    /// Print the result as JSON
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Parser, Debug)]
+struct Info {
+    /// Version to describe [default: the latest release]
+    version: Option<String>,
+    /// When the version is not published, list the closest versions that are
+    #[clap(long)]
+    suggest: bool,
+    /// Print the metadata as JSON
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Parser, Debug)]
+struct VerifyUrl {
+    /// URL or mirror URL template, `{os}` is substituted for this system
+    url: String,
+    /// Download the whole archive and check its contents and the published checksum too
+    #[clap(long)]
+    full: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Diff {
+    /// Compare with this cached version instead of the latest release
+    #[clap(long)]
+    version: Option<String>,
+    /// Hash installed files on N threads [default: one per CPU, up to 8]
+    #[clap(short, long, value_name = "N")]
+    jobs: Option<usize>,
+    /// Print the result as JSON
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Parser, Debug)]
+struct Verify {
+    /// Hash files on N threads [default: one per CPU, up to 8]
+    #[clap(short, long, value_name = "N")]
+    jobs: Option<usize>,
+    /// Record the installed tree as it is now as the manifest, instead of checking it
+    #[clap(long, conflicts_with = "json")]
+    rebuild_manifest: bool,
+    /// Compare the file list and sizes with the released archive, if cached, instead of the manifest
+    #[clap(long, conflicts_with = "rebuild-manifest")]
+    against_upstream: bool,
+    /// Print the report as JSON
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Parser, Debug)]
+struct FixPerms {
+    /// Only show what would be changed
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Gc {
+    /// Only show what would be removed
+    #[clap(long)]
+    dry_run: bool,
+    /// Remove crash reports older than this many days
+    #[clap(long, value_name = "DAYS", default_value = "30")]
+    crash_age: u64,
+}
+
+#[derive(Parser, Debug)]
+struct CleanupLegacy {
+    /// Only show what would be removed
+    #[clap(long)]
+    dry_run: bool,
+    /// Do not ask for confirmation
+    #[clap(short, long)]
+    yes: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Stats {
+    /// Print the statistics as JSON
+    #[clap(long)]
+    json: bool,
+    /// Print a table fitted to the terminal, tab-separated columns for scripts, or JSON
+    #[clap(long, arg_enum, value_name = "FORMAT", conflicts_with = "json")]
+    format: Option<table::Format>,
+    #[clap(flatten)]
+    output: OutputArgs,
+    /// Delete the recorded statistics
+    #[clap(long, conflicts_with = "json")]
+    reset: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Hashes {
+    /// Print the hashes as a JSON object keyed by path
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Parser, Debug)]
+struct OutputArgs {
+    /// Also write the --json result to this file, replacing it atomically
+    #[clap(long, value_name = "PATH", requires = "json")]
+    output: Option<PathBuf>,
+    /// Write the --json result only to the --output file
+    #[clap(long, requires = "output")]
+    json_only_file: bool,
+}
+
+#[derive(Parser, Debug)]
+struct GenerateLoginNotice {
+    /// Uninstall the snippet instead
+    #[clap(long)]
+    remove: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Completions {
+    /// The shell, $SHELL by default with --install
+    #[clap(arg_enum, required_unless_present = "install")]
+    shell: Option<Shell>,
+    /// Write the script where the shell loads it from instead, for all users as root
+    #[clap(long)]
+    install: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Export {
+    /// The file to write
+    file: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct Daemon {
+    /// Serve org.aosc.vsdown on D-Bus, the only mode so far
+    #[clap(long, required = true)]
+    dbus: bool,
+    /// Use the session bus instead of the system bus, without polkit, for testing frontends
+    #[clap(long)]
+    session: bool,
+    /// Exit after this many seconds without calls, 0 never [default: the daemon-idle-timeout key]
+    #[clap(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+struct Apply {
+    /// TOML file declaring variant, version, launcher, sandbox, coexist and metadata-scope
+    file: PathBuf,
+    /// Only list the changes applying it would make
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Parser, Debug)]
+struct FinishUpgrade {
+    /// Go on while Visual Studio Code is running
+    #[clap(short, long)]
+    force: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Import {
+    /// A file written by export
+    file: PathBuf,
+    /// Replace configuration files with other contents
+    #[clap(short, long)]
+    force: bool,
+}
+
+#[derive(Parser, Debug)]
+struct StateArgs {
+    #[clap(subcommand)]
+    action: StateAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum StateAction {
+    /// Print the recorded paths of the installed tree, e.g. for backup tools, without reading the tree
+    ExportManifest(ExportManifest),
 }
 
 #[derive(Parser, Debug)]
-struct Check;
+struct ExportManifest {
+    /// List only files and symlinks
+    #[clap(long, conflicts_with = "dirs-only")]
+    files_only: bool,
+    /// List only directories
+    #[clap(long)]
+    dirs_only: bool,
+    /// Print types, sizes and hashes as JSON instead of one path per line
+    #[clap(long)]
+    json: bool,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
 #[derive(Parser, Debug)]
-struct Remove;
+struct ConfigArgs {
+    #[clap(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the effective value of a key
+    Get { key: String },
+    /// Set a key in the configuration file
+    Set { key: String, value: String },
+    /// Remove a key from the configuration file
+    Unset { key: String },
+    /// List every key with its value and origin
+    List,
+}
 
 fn main() {
+    crash::install_hook();
     let args = Args::parse();
+    logger::set_verbose(args.verbose);
+    logger::set_trace_http(args.trace_http);
+    logger::set_verbose_unpack(args.verbose_unpack);
+    match config::Config::load(args.arch_map.as_deref()) {
+        Ok(mut c) => {
+            c.mirror = args.mirror.clone();
+            if let Some(n) = args.max_parallel_mirrors {
+                c.max_parallel_mirrors = n.max(1);
+            }
+            c.hooks_dir = args.hooks_dir.clone();
+            c.cache_dir = args.cache_dir.clone();
+            c.compress_cache = args.compress_cache;
+            c.dump_download = args.dump_download.clone();
+            c.tls_min = args.tls_min.clone();
+            c.fail_fast_on_4xx = args.fail_fast_on_4xx;
+            c.metadata_refresh = !args.no_metadata_refresh;
+            c.progress_style = args.progress_style;
+            c.netrc = !args.no_netrc;
+            c.state_format = args.state_format;
+            c.version_source = args.channel_version_source;
+            config::init(c);
+        }
+        // The config subcommand must keep working to repair a broken file.
+        Err(e) if matches!(args.subcommand, VsdownCommand::Config(_)) => {
+            warn!("{}", e);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = events::start(args.progress_json, args.json_protocol, args.progress_fd) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    // Operations changing the installation, the cache or the state must not
+    // run concurrently.
+    let needs_lock = match &args.subcommand {
+        VsdownCommand::Install(install) => !install.check_perms,
+        VsdownCommand::Remove(remove) => !remove.dry_run,
+        VsdownCommand::FixPerms(fix_perms) => !fix_perms.dry_run,
+        VsdownCommand::Verify(verify) => verify.rebuild_manifest,
+        VsdownCommand::RefreshSymlink => true,
+        VsdownCommand::Import(_) => true,
+        VsdownCommand::FinishUpgrade(_) => true,
+        VsdownCommand::Apply(apply) => !apply.dry_run,
+        VsdownCommand::Gc(_) => true,
+        VsdownCommand::CleanupLegacy(cleanup) => !cleanup.dry_run,
+        _ => false,
+    };
+    let _lock = if needs_lock {
+        match lock::acquire_within(Duration::from_secs(args.state_lock_timeout)) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
     match args.subcommand {
-        VsdownCommand::Install(Install { force }) => {
-            if force {
-                if let Err(e) = install_vscode() {
-                    error!("{}", e);
+        VsdownCommand::Install(install) => {
+            let options = InstallOptions {
+                verify_arch: install.verify_downloaded_arch,
+                skip_verify_arch: install.no_verify_downloaded_arch,
+                offline: install.offline,
+                trust_cache: install.trust_cache,
+                from_url: install.from_url.clone(),
+                from_file: install.from_file.clone(),
+                checksum: install.checksum.clone(),
+                retry_mirrors_on_checksum_fail: install.retry_mirrors_on_checksum_fail,
+                no_verify: install.no_verify,
+                version: install.version.clone(),
+                skip_compat_check: install.skip_compat_check,
+                sandbox_setup: if install.no_sandbox_setup {
+                    Some(false)
+                } else if install.sandbox_setup {
+                    Some(true)
+                } else {
+                    None
+                },
+                bin_name: install.bin_name.clone(),
+                no_symlink: install.no_symlink,
+                cli_name: install.cli_symlink.clone(),
+                no_cli_symlink: install.no_cli_symlink,
+                unpack_jobs: match install.concurrent_unpack {
+                    Some(Some(n)) => n,
+                    Some(None) => unpack::default_jobs(),
+                    None => 1,
+                },
+                resume: install.resume,
+                fresh_extract: install.fresh_extract,
+                abort: install.abort,
+                yes: install.yes || install.if_needed,
+                force: install.force,
+                coexist: install.coexist,
+                metadata_scope: install.metadata_scope,
+                retain: install.retain,
+                suggest: install.suggest,
+                exclude: install.exclude.clone(),
+                replace: install.replace,
+                health_check: install.health_check,
+                health_check_command: install.health_check_command.clone(),
+                health_check_timeout: install.health_check_timeout,
+            };
+            if install.check_perms {
+                if !preflight::check_permissions() {
+                    error!("Some installation paths are not writable.");
                     std::process::exit(1);
+                }
+                info!("All installation paths are writable.");
+                return;
+            }
+            if let Some(dir) = &install.download_only {
+                let arches = if install.select_arch_interactive {
+                    media::select_interactive()
+                } else if install.arch.is_empty() {
+                    checker::get_arch().map(|os| vec![os])
                 } else {
-                    info!("Visual Studio Code has been successfully installed!");
+                    Ok(install.arch.clone())
+                };
+                let result = arches.and_then(|arches| media::download(dir, &arches, &options));
+                if install.timings || logger::is_verbose() {
+                    timings::print();
                 }
-            } else if let Err(e) = checker::update_checker() {
-                info!("{}", e);
-                if let Err(e) = install_vscode() {
+                if let Err(e) = result {
                     error!("{}", e);
-                    std::process::exit(1);
+                    std::process::exit(exit_code(&e));
+                }
+                return;
+            }
+            let out = open_output(install.json, &install.output);
+            if let Some(seconds) = install.wait_online {
+                if let Err(e) = wait_online(seconds) {
+                    if let Some(out) = out {
+                        emit_json(out, &InstallResult::new(&Err(anyhow::anyhow!("{}", e))));
+                    }
+                    error!("{}", e);
+                    std::process::exit(EXIT_NETWORK_UNAVAILABLE);
+                }
+            }
+            let result = run_install(&install, &options);
+            if install.timings || logger::is_verbose() {
+                timings::print();
+            }
+            let code =
+                install_exit_code(&result, install.detailed_exit_codes, install.on_up_to_date);
+            if let Some(out) = out {
+                emit_json(out, &InstallResult::new(&result));
+            }
+            match result {
+                Err(e) if e.is::<running::EditorRunning>() => warn!("{}", e),
+                Err(e) => error!("{}", e),
+                Ok(_) => (),
+            }
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        VsdownCommand::Check(Check {
+            json,
+            output,
+            no_network_extras,
+            explain,
+            wait_online: wait_online_seconds,
+            write_flag,
+            all,
+            verify_after_days,
+            no_verify_reminder,
+            format,
+            on_up_to_date,
+            cached_only,
+            max_age,
+        }) => {
+            if cached_only {
+                std::process::exit(cached_exit_code(
+                    checker::cached_check(max_age),
+                    on_up_to_date,
+                ));
+            }
+            let out = open_output(json || format == Some(table::Format::Json), &output);
+            if let Some(seconds) = wait_online_seconds {
+                if let Err(e) = wait_online(seconds) {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(EXIT_NETWORK_UNAVAILABLE);
+                }
+            }
+            let options = CheckOptions {
+                network_extras: !no_network_extras,
+                explain,
+            };
+            if all {
+                let results = channels::check_all(&options);
+                if let Some(out) = out {
+                    emit_json(out, &results);
                 } else {
-                    info!("Visual Studio Code has been successfully installed!");
+                    channels::print_table(&results, format.unwrap_or(table::Format::Table));
                 }
+                if let Err(e) = channels::first_error(&results) {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                if results
+                    .iter()
+                    .any(|r| r.status.as_ref().map_or(false, |s| s.update_available))
+                {
+                    std::process::exit(EXIT_UPDATE_AVAILABLE);
+                }
+                if let Some(code) = on_up_to_date {
+                    std::process::exit(code);
+                }
+                return;
+            }
+            match checker::check_update(&options) {
+                Ok(status) => {
+                    checker::record_check(&status);
+                    if write_flag {
+                        let pending = status
+                            .update_available
+                            .then(|| status.latest_version.as_str());
+                        if let Err(e) = notice::write_flag(pending) {
+                            warn!("Failed to update the login notice flag: {}", e);
+                        }
+                    }
+                    if let Some(out) = out {
+                        emit_json(out, &status);
+                    } else {
+                        checker::print_update_status(&status);
+                        checker::print_retained(&status);
+                    }
+                    let days = verify_after_days.unwrap_or(config::get().verify_after_days);
+                    if !no_verify_reminder && days > 0 {
+                        verify::remind_if_due(days);
+                    }
+                    let up_to_date = status.current_version.is_some() && !status.update_available;
+                    if let Some(code) = on_up_to_date.filter(|_| up_to_date) {
+                        std::process::exit(code);
+                    }
+                }
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        VsdownCommand::Remove(Remove {
+            dry_run: true,
+            json,
+            output,
+            ..
+        }) => {
+            let out = open_output(json, &output);
+            let report = remove_report::report();
+            if let Some(out) = out {
+                emit_json(out, &report);
             } else {
-                info!("You have already installed the latest Visual Studio Code release!");
+                remove_report::print_report(&report);
             }
         }
-        VsdownCommand::Check(_) => {
-            if let Err(e) = checker::update_checker() {
-                info!("{}", e);
+        VsdownCommand::Remove(Remove {
+            path: Some(path),
+            yes,
+            ..
+        }) => {
+            if let Err(e) = checker::remove_path(&path, yes) {
+                error!("{}", e);
+                std::process::exit(1);
             } else {
-                info!("You have already installed the latest Visual Studio Code release!");
+                info!("{} has been successfully removed!", path.display());
+            }
+        }
+        VsdownCommand::Remove(Remove {
+            variant,
+            fail_if_not_installed,
+            path: None,
+            ..
+        }) if !variant.is_installed() => {
+            info!("Nothing to remove (Visual Studio Code is not installed by vsdown).");
+            if fail_if_not_installed {
+                std::process::exit(1);
             }
         }
-        VsdownCommand::Remove(_) => {
-            if let Err(e) = checker::remove_vscode() {
+        VsdownCommand::Remove(Remove {
+            variant: Variant::Server,
+            ..
+        }) => {
+            if let Err(e) = server::remove() {
                 error!("{}", e);
                 std::process::exit(1);
+            } else {
+                info!("Visual Studio Code CLI has been successfully uninstalled!");
+            }
+        }
+        VsdownCommand::Remove(Remove {
+            yes,
+            metadata_scope,
+            ..
+        }) => {
+            if let Err(e) = checker::remove_vscode(yes, metadata_scope) {
+                error!("{}", e);
+                std::process::exit(exit_code(&e));
             } else {
                 info!("Visual Studio Code has been successfully uninstalled!");
             }
         }
+        VsdownCommand::BuildSysext(BuildSysext { output, fs }) => {
+            if let Err(e) = sysext::build_sysext(&output, fs.as_deref(), &InstallOptions::default())
+            {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        VsdownCommand::Config(ConfigArgs { action }) => {
+            let result = match action {
+                ConfigAction::Get { key } => config::get_key(&key),
+                ConfigAction::Set { key, value } => config::set_key(&key, &value),
+                ConfigAction::Unset { key } => config::unset_key(&key),
+                ConfigAction::List => config::list_keys(),
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        VsdownCommand::Which(Which { name, json, output }) => {
+            let out = open_output(json, &output);
+            match which::resolve(&name) {
+                Ok(r) => {
+                    if let Some(out) = out {
+                        emit_json(out, &r);
+                    } else {
+                        which::print_resolution(&r);
+                    }
+                    if !r.managed {
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        VsdownCommand::Info(Info {
+            version,
+            suggest,
+            json,
+            output,
+        }) => {
+            let out = open_output(json, &output);
+            match release::info(version.as_deref(), suggest) {
+                Ok(info) => {
+                    if let Some(out) = out {
+                        emit_json(out, &info);
+                    } else {
+                        release::print_info(&info);
+                    }
+                }
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(exit_code(&e));
+                }
+            }
+        }
+        VsdownCommand::VerifyUrl(VerifyUrl { url, full }) => match mirror::verify_url(&url, full) {
+            Ok(true) => info!("{} looks like a valid download source.", url),
+            Ok(false) => {
+                error!("{} does not look like a valid download source.", url);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        VsdownCommand::Diff(Diff {
+            version,
+            jobs,
+            json,
+            output,
+        }) => {
+            let out = open_output(json, &output);
+            match diff::diff(
+                version.as_deref(),
+                jobs.unwrap_or_else(manifest::default_jobs),
+            ) {
+                Ok(d) => {
+                    if let Some(out) = out {
+                        emit_json(out, &d);
+                    } else {
+                        diff::print_diff(&d);
+                    }
+                }
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        VsdownCommand::Verify(Verify {
+            jobs,
+            rebuild_manifest: true,
+            ..
+        }) => match verify::rebuild_manifest(jobs.unwrap_or_else(manifest::default_jobs)) {
+            Ok(n) => info!("Recorded {} installed files as the manifest.", n),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        VsdownCommand::Verify(Verify {
+            jobs,
+            json,
+            output,
+            against_upstream,
+            ..
+        }) => {
+            let out = open_output(json, &output);
+            match against_upstream.then(verify::verify_upstream).transpose() {
+                Ok(Some(Some(report))) => {
+                    if let Some(out) = out {
+                        emit_json(out, &report);
+                    } else {
+                        verify::print_upstream_report(&report);
+                    }
+                    if !report.is_clean() {
+                        std::process::exit(1);
+                    }
+                    return;
+                }
+                Ok(Some(None)) => warn!("The release archive of the installed version is not cached, verifying against the manifest instead."),
+                Ok(None) => (),
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            match verify::verify(jobs.unwrap_or_else(manifest::default_jobs)) {
+                Ok(report) => {
+                    if let Some(out) = out {
+                        emit_json(out, &report);
+                    } else {
+                        verify::print_report(&report);
+                    }
+                    if !report.is_clean() {
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        VsdownCommand::Export(Export { file }) => match migrate::export(&file) {
+            Ok(()) => info!("Exported the vsdown configuration to {}.", file.display()),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        VsdownCommand::Daemon(Daemon {
+            session,
+            idle_timeout,
+            ..
+        }) => {
+            let idle_timeout = idle_timeout.unwrap_or(config::get().daemon_idle_timeout);
+            if let Err(e) = daemon::run(!session, idle_timeout) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        VsdownCommand::Apply(Apply { file, dry_run }) => match apply::apply(&file, dry_run) {
+            Ok(n) if n > 0 && !dry_run => info!("Applied {} changes from {}.", n, file.display()),
+            Ok(_) => (),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(exit_code(&e));
+            }
+        },
+        VsdownCommand::FinishUpgrade(FinishUpgrade { force }) => {
+            match checker::finish_upgrade(force) {
+                Ok(version) => info!(
+                    "Visual Studio Code {} has been successfully installed!",
+                    version
+                ),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(exit_code(&e));
+                }
+            }
+        }
+        VsdownCommand::Import(Import { file, force }) => match migrate::import(&file, force) {
+            Ok(()) => {
+                info!("Run `vsdown check` or `vsdown install` to apply the imported configuration.")
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        VsdownCommand::State(StateArgs {
+            action: StateAction::ExportManifest(export),
+        }) => {
+            let out = open_output(export.json, &export.output);
+            let m = match manifest::read() {
+                Ok(Some(m)) => m,
+                Ok(None) => {
+                    let e = anyhow::anyhow!(
+                        "There is no manifest of the installed files. {}",
+                        manifest::MISSING_HINT
+                    );
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let only = if export.files_only {
+                Some(manifest::Kind::File)
+            } else if export.dirs_only {
+                Some(manifest::Kind::Directory)
+            } else {
+                None
+            };
+            let entries = manifest::entries(&m, only);
+            if let Some(out) = out {
+                emit_json(out, &entries);
+            } else {
+                for e in &entries {
+                    println!("{}", e.path.display());
+                }
+            }
+        }
+        VsdownCommand::FixPerms(FixPerms { dry_run }) => match perms::fix(dry_run) {
+            Ok(0) => info!("All permissions are as expected."),
+            Ok(n) if dry_run => info!("{} paths would be fixed.", n),
+            Ok(n) => info!("Fixed the permissions of {} paths.", n),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        VsdownCommand::RefreshSymlink => match checker::refresh_launcher() {
+            Ok(p) => info!("Recreated {}.", p.display()),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        VsdownCommand::Gc(Gc { dry_run, crash_age }) => {
+            if let Err(e) = gc::run(Duration::from_secs(crash_age * 24 * 60 * 60), dry_run) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        VsdownCommand::CleanupLegacy(CleanupLegacy { dry_run, yes }) => {
+            if let Err(e) = legacy::cleanup(dry_run, yes) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        VsdownCommand::Stats(Stats { reset: true, .. }) => {
+            if let Err(e) = stats::reset() {
+                error!("Failed to reset the statistics: {}", e);
+                std::process::exit(1);
+            }
+            info!("Statistics have been reset.");
+        }
+        VsdownCommand::Stats(Stats {
+            json,
+            format,
+            output,
+            ..
+        }) => {
+            let out = open_output(json || format == Some(table::Format::Json), &output);
+            let stats = stats::read();
+            if let Some(out) = out {
+                emit_json(out, &stats);
+            } else {
+                stats::print(&stats, format.unwrap_or(table::Format::Table));
+            }
+        }
+        VsdownCommand::Hashes(Hashes { json, output }) => {
+            let out = open_output(json, &output);
+            match hashes::report() {
+                Ok(hashes) => {
+                    if let Some(out) = out {
+                        emit_json(out, &hashes);
+                    } else {
+                        hashes::print_report(&hashes);
+                    }
+                }
+                Err(e) => {
+                    emit_json_error(out, &e);
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        VsdownCommand::GenerateLoginNotice(GenerateLoginNotice { remove }) => {
+            if let Err(e) = notice::generate(remove) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        VsdownCommand::Completions(Completions {
+            shell,
+            install: true,
+        }) => match completions::install(shell, &mut Args::command()) {
+            Ok(path) => info!("Wrote the completions to {}.", path.display()),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        VsdownCommand::Completions(Completions { shell, .. }) => {
+            if let Some(shell) = shell {
+                completions::print_completions(shell, &mut Args::command());
+            }
+        }
+        VsdownCommand::CompleteVersions => completions::print_versions(),
+    }
+}
+
+/// How `install` ended, in its exit status and `--json` result.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum Outcome {
+    Upgraded,
+    UpToDate,
+    /// Postponed because Visual Studio Code is running.
+    Deferred,
+    Failed,
+}
+
+/// The final line `install --json` prints.
+#[derive(Serialize)]
+struct InstallResult {
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl InstallResult {
+    fn new(result: &anyhow::Result<Outcome>) -> Self {
+        match result {
+            Ok(outcome) => Self {
+                outcome: *outcome,
+                error: None,
+            },
+            Err(e) => Self {
+                outcome: if e.is::<running::EditorRunning>() {
+                    Outcome::Deferred
+                } else {
+                    Outcome::Failed
+                },
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+/// Installs or upgrades whatever `install` asks for, if anything.
+fn run_install(install: &Install, options: &InstallOptions) -> anyhow::Result<Outcome> {
+    if install.skip_if_marker.is_some() && install.skip_if_marker == state::success_marker_version()
+    {
+        stats::record(|c| c.up_to_date_skips += 1);
+        info!(
+            "Visual Studio Code {} has already been installed successfully, skipping.",
+            install.skip_if_marker.as_deref().unwrap_or_default()
+        );
+        return Ok(Outcome::UpToDate);
+    }
+    if install.if_needed {
+        let installed = if install.variant == Variant::Server {
+            server::installed_version()
+        } else {
+            checker::get_installed_version()
+                .ok()
+                .or_else(state::recorded_version)
+        };
+        match (&installed, &install.version) {
+            (Some(installed), Some(version)) if installed == version => {
+                stats::record(|c| c.up_to_date_skips += 1);
+                info!(
+                    "Visual Studio Code {} is already installed, nothing to do.",
+                    version
+                );
+                return Ok(Outcome::UpToDate);
+            }
+            (Some(installed), Some(version))
+                if !install.allow_downgrade
+                    && checker::compare_versions(installed, version)
+                        == Some(std::cmp::Ordering::Greater) =>
+            {
+                return Err(checker::NewerInstalled {
+                    installed: installed.clone(),
+                    requested: version.clone(),
+                }
+                .into());
+            }
+            // Absent or outdated, in which case the paths below install,
+            // or without --version, where they stop at an up to date one.
+            _ => (),
+        }
+    }
+    if install.adopt && checker::foreign_tree() {
+        // The tree is adopted as whatever version it is, --version is the
+        // one to upgrade to.
+        let version = checker::record_only(&InstallOptions {
+            version: None,
+            ..options.clone()
+        })?;
+        info!("Visual Studio Code {} is now managed by vsdown.", version);
+    }
+    if install.record_only {
+        let version = checker::record_only(options)?;
+        info!("Visual Studio Code {} is now managed by vsdown.", version);
+    } else if install.variant == Variant::Server {
+        server::install(options, install.force)?;
+    } else if install.resume || install.abort || state::interrupted_install().is_some() {
+        checker::recover_interrupted(options)?;
+    } else if install.force
+        || install.offline
+        || install.from_url.is_some()
+        || install.from_file.is_some()
+    {
+        install_vscode(options)?;
+        info!("Visual Studio Code has been successfully installed!");
+    } else if let Some(version) = &install.version {
+        if state::recorded_version().as_ref() == Some(version) {
+            stats::record(|c| c.up_to_date_skips += 1);
+            info!("Visual Studio Code {} is already installed.", version);
+            return Ok(Outcome::UpToDate);
+        }
+        install_vscode(options)?;
+        info!("Visual Studio Code has been successfully installed!");
+    } else if install.prefer_cache_even_if_stale
+        && http::reachable(checker::version_source_url()).is_err()
+    {
+        match checker::install_newest_cached(options)? {
+            Some(version) => warn!(
+                "Installed the newest cached Visual Studio Code {}, which could not be confirmed against upstream and may not be the latest available.",
+                version
+            ),
+            None => {
+                stats::record(|c| c.up_to_date_skips += 1);
+                warn!("No release newer than the installed one is cached. Whether it is the latest available could not be confirmed against upstream.");
+                return Ok(Outcome::UpToDate);
+            }
+        }
+    } else if let Err(e) = checker::update_checker() {
+        info!("{}", e);
+        install_vscode(options)?;
+        info!("Visual Studio Code has been successfully installed!");
+    } else {
+        stats::record(|c| c.up_to_date_skips += 1);
+        info!("You have already installed the latest Visual Studio Code release!");
+        return Ok(Outcome::UpToDate);
+    }
+
+    Ok(Outcome::Upgraded)
+}
+
+/// Fails unless the host of the version source becomes reachable within
+/// `seconds`. Callers exit with `EXIT_NETWORK_UNAVAILABLE` then.
+fn wait_online(seconds: u64) -> Result<(), String> {
+    wait_online_for(checker::version_source_url(), seconds)
+}
+
+fn wait_online_for(url: &str, seconds: u64) -> Result<(), String> {
+    if !http::wait_online(url, Duration::from_secs(seconds)) {
+        return Err(format!(
+            "The network did not become available within {} seconds.",
+            seconds
+        ));
+    }
+
+    Ok(())
+}
+
+/// Opens the destinations of a `--json` result, exiting if the `--output`
+/// file cannot be created.
+fn open_output(json: bool, args: &OutputArgs) -> Option<output::JsonOutput> {
+    if !json {
+        return None;
+    }
+    match output::JsonOutput::open(args.output.as_deref(), !args.json_only_file) {
+        Ok(out) => Some(out),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn emit_json<T: Serialize>(out: output::JsonOutput, value: &T) {
+    if let Err(e) = out.write(value) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Records the error of a failed command in its `--output` file.
+fn emit_json_error(out: Option<output::JsonOutput>, e: &dyn Display) {
+    if let Some(Err(e)) = out.map(|out| out.write_error(&e.to_string())) {
+        warn!("{}", e);
+    }
+}
+
+/// Validates an `--on-up-to-date` exit code.
+fn parse_exit_code(s: &str) -> Result<i32, String> {
+    s.parse::<u8>()
+        .map(i32::from)
+        .map_err(|_| format!("{} is not an exit code from 0 to 255", s))
+}
+
+/// The exit code of `install` ending in `result`.
+fn install_exit_code(
+    result: &anyhow::Result<Outcome>,
+    detailed: bool,
+    on_up_to_date: Option<i32>,
+) -> i32 {
+    match result {
+        Ok(Outcome::UpToDate) if detailed => EXIT_UP_TO_DATE,
+        Ok(Outcome::UpToDate) => on_up_to_date.unwrap_or(0),
+        Ok(_) => 0,
+        Err(e) => exit_code(e),
+    }
+}
+
+/// The exit code of `check --cached-only` finding `verdict`.
+fn cached_exit_code(verdict: CachedVerdict, on_up_to_date: Option<i32>) -> i32 {
+    match verdict {
+        CachedVerdict::UpdateAvailable => EXIT_UPDATE_AVAILABLE,
+        CachedVerdict::UpToDate => on_up_to_date.unwrap_or(0),
+        CachedVerdict::Unknown => EXIT_UNKNOWN,
+    }
+}
+
+/// The exit code for a failed install or removal.
+fn exit_code(e: &anyhow::Error) -> i32 {
+    if e.is::<running::EditorRunning>() {
+        EXIT_EDITOR_RUNNING
+    } else if e.is::<release::NotFound>() {
+        EXIT_NOT_FOUND
+    } else if e.is::<checker::NewerInstalled>() {
+        EXIT_NEWER_INSTALLED
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn install_exit_codes() {
+        assert_eq!(install_exit_code(&Ok(Outcome::Upgraded), true, Some(7)), 0);
+        assert_eq!(install_exit_code(&Ok(Outcome::UpToDate), false, None), 0);
+        assert_eq!(install_exit_code(&Ok(Outcome::UpToDate), false, Some(7)), 7);
+        assert_eq!(install_exit_code(&Ok(Outcome::UpToDate), true, Some(7)), 2);
+        assert_eq!(
+            install_exit_code(&Err(anyhow::anyhow!("failed")), true, None),
+            1
+        );
+    }
+
+    #[test]
+    fn error_exit_codes() {
+        let not_found = release::NotFound {
+            version: "1.0.0".to_string(),
+            os: None,
+            suggestions: Vec::new(),
+        };
+        assert_eq!(exit_code(&not_found.into()), 3);
+        let newer = checker::NewerInstalled {
+            installed: "1.2.0".to_string(),
+            requested: "1.1.0".to_string(),
+        };
+        assert_eq!(exit_code(&newer.into()), 5);
+        let running = running::EditorRunning {
+            what: "upgrading".to_string(),
+            pids: vec![1],
+            flag: "--force",
+        };
+        let e = anyhow::Error::from(running);
+        assert_eq!(install_exit_code(&Err(e), true, None), 10);
+        // Context added on the way up keeps the code.
+        let e = anyhow::Error::from(release::NotFound {
+            version: "1.0.0".to_string(),
+            os: Some("linux-x64".to_string()),
+            suggestions: Vec::new(),
+        })
+        .context("while installing");
+        assert_eq!(e.to_string(), "while installing");
+        assert_eq!(exit_code(&e), 3);
+    }
+
+    #[test]
+    fn cached_check_exit_codes() {
+        assert_eq!(
+            cached_exit_code(CachedVerdict::UpdateAvailable, Some(7)),
+            100
+        );
+        assert_eq!(cached_exit_code(CachedVerdict::UpToDate, None), 0);
+        assert_eq!(cached_exit_code(CachedVerdict::UpToDate, Some(7)), 7);
+        assert_eq!(cached_exit_code(CachedVerdict::Unknown, Some(7)), 4);
+    }
+
+    #[test]
+    fn network_unavailable() {
+        // Nothing listens on port 1 of the loopback.
+        let e = wait_online_for("http://127.0.0.1:1/", 0).unwrap_err();
+        assert_eq!(e, "The network did not become available within 0 seconds.");
+        assert_eq!(EXIT_NETWORK_UNAVAILABLE, 75);
+    }
+
+    #[test]
+    fn exit_codes_are_documented() {
+        let help = |name: &str| {
+            let mut command = Args::command();
+            let mut help = Vec::new();
+            command
+                .find_subcommand_mut(name)
+                .unwrap()
+                .write_long_help(&mut help)
+                .unwrap();
+            String::from_utf8(help).unwrap()
+        };
+        let install = help("install");
+        for code in [
+            EXIT_UP_TO_DATE,
+            EXIT_NOT_FOUND,
+            EXIT_NEWER_INSTALLED,
+            EXIT_EDITOR_RUNNING,
+            EXIT_NETWORK_UNAVAILABLE,
+        ] {
+            assert!(install.contains(&format!("    {:<5} ", code)), "{}", code);
+        }
+        let check = help("check");
+        for code in [
+            EXIT_UNKNOWN,
+            EXIT_NETWORK_UNAVAILABLE,
+            EXIT_UPDATE_AVAILABLE,
+        ] {
+            assert!(check.contains(&format!("    {:<5} ", code)), "{}", code);
+        }
     }
 }
@@ -0,0 +1,444 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs::File,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::checker::{CURRENT_VERSION_DIRECTORY, INSTALL_DIR};
+use crate::{checksum, progress, state};
+
+const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Told when there is no manifest to work from, which installs made before
+/// vsdown recorded one lack.
+pub const MISSING_HINT: &str =
+    "Installs made by older vsdown versions have none, run `vsdown verify --rebuild-manifest` to record the installed tree.";
+
+/// Hashing workers used unless `--jobs` says otherwise: one per CPU, up to
+/// this many, as more mostly contend for the disk.
+const MAX_DEFAULT_JOBS: usize = 8;
+
+/// Every file of the installed tree as it was right after installing it.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    /// By path relative to `INSTALL_DIR`.
+    pub files: BTreeMap<String, FileRecord>,
+    /// Sizes of the files of the release `--exclude` left out on purpose, by
+    /// the same relative path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub omitted: BTreeMap<String, u64>,
+    /// Symlinks installed outside `INSTALL_DIR`, the launcher and the CLI
+    /// helper, by absolute path with their targets.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub links: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct FileRecord {
+    /// SHA-256 of the contents of regular files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Target of symlinks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    pub size: u64,
+    /// Permission bits of regular files, missing from older manifests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+impl FileRecord {
+    /// Whether the contents are the same, whatever the permissions.
+    pub fn same_contents(&self, other: &FileRecord) -> bool {
+        self.sha256 == other.sha256 && self.link == other.link && self.size == other.size
+    }
+}
+
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(MAX_DEFAULT_JOBS)
+}
+
+/// Records every file under `root` for `version`.
+pub fn build(root: &Path, version: &str, jobs: usize) -> Result<Manifest> {
+    let mut paths = Vec::new();
+    walk(root, Path::new(""), &mut paths)?;
+    let files = records(root, &paths, jobs)
+        .into_iter()
+        .map(|(p, r)| r.map(|r| (p, r)))
+        .collect::<Result<_>>()?;
+
+    Ok(Manifest {
+        version: version.to_string(),
+        files,
+        omitted: BTreeMap::new(),
+        links: BTreeMap::new(),
+    })
+}
+
+/// Renders `p` as a manifest path: UTF-8 as it is, with backslashes doubled
+/// and other bytes as `\xNN`, as tar allows names JSON strings cannot hold.
+pub fn encode_path(p: &Path) -> String {
+    let mut s = String::new();
+    let mut rest = p.as_os_str().as_bytes();
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(valid) => (valid, 0),
+            Err(e) => (
+                // Up to valid_up_to() is UTF-8.
+                std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap_or_default(),
+                e.error_len().unwrap_or(rest.len() - e.valid_up_to()),
+            ),
+        };
+        s.push_str(&valid.replace('\\', "\\\\"));
+        let end = valid.len() + invalid;
+        for b in &rest[valid.len()..end] {
+            s.push_str(&format!("\\x{:02x}", b));
+        }
+        rest = &rest[end..];
+    }
+
+    s
+}
+
+/// The path `encode_path()` rendered as `s`. Other backslashes are kept as
+/// they are, as manifests written before paths were escaped have them.
+pub fn decode_path(s: &str) -> PathBuf {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            if bytes.get(i + 1) == Some(&b'\\') {
+                out.push(b'\\');
+                i += 2;
+                continue;
+            }
+            if bytes.get(i + 1) == Some(&b'x') {
+                let hex = bytes
+                    .get(i + 2..i + 4)
+                    .and_then(|h| std::str::from_utf8(h).ok());
+                if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    out.push(b);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    PathBuf::from(OsStr::from_bytes(&out))
+}
+
+/// The relative paths of the files and symlinks under `root`, sorted, as
+/// `encode_path()` renders them.
+pub fn walk(root: &Path, rel: &Path, paths: &mut Vec<String>) -> Result<()> {
+    let mut entries = std::fs::read_dir(root.join(rel))?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let rel = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            walk(root, &rel, paths)?;
+        } else {
+            paths.push(encode_path(&rel));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the record of each of `paths` under `root` on `jobs` threads. The
+/// result is keyed by path, so it does not depend on which thread finished
+/// first.
+pub fn records(root: &Path, paths: &[String], jobs: usize) -> BTreeMap<String, Result<FileRecord>> {
+    let bar = progress::bar(
+        paths.len() as u64,
+        "{spinner} [{bar:25.cyan/blue}] {pos}/{len} files (eta {eta})",
+    );
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(BTreeMap::new());
+    thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let p = match paths.get(i) {
+                    Some(p) => p,
+                    None => break,
+                };
+                let record = record(&root.join(decode_path(p)));
+                bar.inc(1);
+                results
+                    .lock()
+                    .expect("a hashing worker panicked")
+                    .insert(p.clone(), record);
+            });
+        }
+    });
+    bar.finish_and_clear();
+
+    results.into_inner().expect("a hashing worker panicked")
+}
+
+fn record(p: &Path) -> Result<FileRecord> {
+    let metadata = std::fs::symlink_metadata(p)
+        .map_err(|e| anyhow!("Failed to read {}: {}.", p.display(), e))?;
+    if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(p)?;
+        return Ok(FileRecord {
+            sha256: None,
+            link: Some(encode_path(&target)),
+            size: 0,
+            mode: None,
+        });
+    }
+    let f = File::open(p).map_err(|e| anyhow!("Failed to open {}: {}.", p.display(), e))?;
+
+    Ok(FileRecord {
+        sha256: Some(checksum::sha256_reader(f)?),
+        link: None,
+        size: metadata.len(),
+        mode: Some(metadata.permissions().mode() & 0o7777),
+    })
+}
+
+pub fn path() -> PathBuf {
+    Path::new(CURRENT_VERSION_DIRECTORY).join(MANIFEST_FILENAME)
+}
+
+pub fn write(manifest: &Manifest) -> Result<()> {
+    state::write_atomic(&path(), &serde_json::to_vec(manifest)?)
+}
+
+/// The manifest of the current install, None if there is none.
+pub fn read() -> Result<Option<Manifest>> {
+    let p = path();
+    let s = match std::fs::read(&p) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow!("Failed to read {}: {}.", p.display(), e)),
+    };
+    let manifest = serde_json::from_slice(&s)
+        .map_err(|e| anyhow!("Failed to parse {}: {}.", p.display(), e))?;
+
+    Ok(Some(manifest))
+}
+
+pub fn remove() -> Result<()> {
+    let p = path();
+    if p.exists() {
+        std::fs::remove_file(p)?;
+    }
+
+    Ok(())
+}
+
+/// What a manifest entry is.
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Symlink,
+    /// Recorded implicitly, by the paths below it.
+    Directory,
+}
+
+/// A path of the installed tree as `state export-manifest` lists it.
+#[derive(Serialize)]
+pub struct Entry {
+    /// Absolute, below `INSTALL_DIR`.
+    #[serde(serialize_with = "serialize_lossy")]
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Every path `manifest` accounts for, `INSTALL_DIR` and the directories
+/// leading to its files included, sorted. `only` keeps entries of that kind,
+/// symlinks counting as files.
+pub fn entries(manifest: &Manifest, only: Option<Kind>) -> Vec<Entry> {
+    let root = Path::new(INSTALL_DIR);
+    let mut dirs = BTreeSet::new();
+    dirs.insert(PathBuf::new());
+    for p in manifest.files.keys() {
+        dirs.extend(decode_path(p).ancestors().skip(1).map(Path::to_path_buf));
+    }
+    let mut entries = Vec::new();
+    if only != Some(Kind::File) {
+        entries.extend(dirs.into_iter().map(|d| Entry {
+            path: root.join(d),
+            kind: Kind::Directory,
+            size: None,
+            sha256: None,
+            link: None,
+        }));
+    }
+    if only != Some(Kind::Directory) {
+        entries.extend(manifest.files.iter().map(|(p, r)| {
+            Entry {
+                path: root.join(decode_path(p)),
+                kind: if r.link.is_some() {
+                    Kind::Symlink
+                } else {
+                    Kind::File
+                },
+                size: Some(r.size),
+                sha256: r.sha256.clone(),
+                link: r
+                    .link
+                    .as_deref()
+                    .map(|l| decode_path(l).to_string_lossy().into_owned()),
+            }
+        }));
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    entries
+}
+
+/// Writes `p` with bytes which are not UTF-8 replaced, as JSON strings
+/// cannot hold them.
+fn serialize_lossy<S: serde::Serializer>(p: &Path, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&p.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes of every value, in an order of their own.
+    fn bytes(n: usize) -> Vec<u8> {
+        let mut x = 0x2545_f491_u32;
+        (0..n)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    fn path(b: &[u8]) -> PathBuf {
+        PathBuf::from(OsStr::from_bytes(b))
+    }
+
+    #[test]
+    fn encodes_paths_losslessly() {
+        let noise = bytes(4096);
+        let mut names = noise.chunks(37).map(<[u8]>::to_vec).collect::<Vec<_>>();
+        names.extend([
+            b"caf\xc3\xa9".to_vec(),
+            b"\\x41".to_vec(),
+            b"\\\\x41\\".to_vec(),
+            b"a\xffb\\xff".to_vec(),
+            b"\xe2\x82".to_vec(),
+            (0..=255).collect(),
+        ]);
+        for name in names {
+            let encoded = encode_path(&path(&name));
+            assert_eq!(decode_path(&encoded), path(&name), "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn encodes_paths_readably() {
+        assert_eq!(
+            encode_path(Path::new("resources/app/package.json")),
+            "resources/app/package.json"
+        );
+        assert_eq!(encode_path(&path(b"caf\xc3\xa9")), "caf\u{e9}");
+        assert_eq!(encode_path(&path(b"caf\xe9")), "caf\\xe9");
+        // A name spelling an escape is not taken for one.
+        assert_eq!(encode_path(Path::new("\\x41")), "\\\\x41");
+        assert_eq!(decode_path("\\\\x41"), Path::new("\\x41"));
+        assert_eq!(decode_path("\\x41"), Path::new("A"));
+        // As older manifests have them.
+        assert_eq!(decode_path("a\\b\\x4"), Path::new("a\\b\\x4"));
+    }
+
+    #[test]
+    fn exports_decoded_paths() {
+        let record = FileRecord {
+            sha256: None,
+            link: Some(encode_path(&path(b"caf\xe9"))),
+            size: 0,
+            mode: None,
+        };
+        let manifest = Manifest {
+            version: "1.0.0".to_string(),
+            files: [(encode_path(&path(b"bin/caf\xe9")), record)].into(),
+            omitted: BTreeMap::new(),
+            links: BTreeMap::new(),
+        };
+        let files = entries(&manifest, Some(Kind::File));
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].path,
+            Path::new(INSTALL_DIR).join(path(b"bin/caf\xe9"))
+        );
+        assert_eq!(files[0].link.as_deref(), Some("caf\u{fffd}"));
+        let json = serde_json::to_value(&files[0]).unwrap();
+        assert_eq!(json["path"], format!("{}/bin/caf\u{fffd}", INSTALL_DIR));
+
+        let dirs = entries(&manifest, Some(Kind::Directory));
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[1].path, Path::new(INSTALL_DIR).join("bin"));
+    }
+
+    #[test]
+    fn records_do_not_depend_on_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let noise = bytes(1 << 16);
+        for i in 0..200 {
+            let sub = dir.path().join(format!("d{}/e{}", i % 7, i % 3));
+            std::fs::create_dir_all(&sub).unwrap();
+            let p = sub.join(format!("f{}", i));
+            std::fs::write(&p, &noise[..i * 317 % noise.len()]).unwrap();
+            std::fs::set_permissions(
+                &p,
+                std::fs::Permissions::from_mode(0o600 + i as u32 % 0o200),
+            )
+            .unwrap();
+            if i % 10 == 0 {
+                std::os::unix::fs::symlink(format!("f{}", i), sub.join(format!("l{}", i))).unwrap();
+            }
+        }
+        std::fs::write(dir.path().join(path(b"caf\xe9")), b"latin-1").unwrap();
+        let mut paths = Vec::new();
+        walk(dir.path(), Path::new(""), &mut paths).unwrap();
+        assert_eq!(paths.len(), 221);
+        // A file gone between the walk and the hashing is an error either way.
+        paths.push("missing".to_string());
+
+        let with_jobs = |jobs| {
+            records(dir.path(), &paths, jobs)
+                .into_iter()
+                .map(|(p, r)| (p, r.map_err(|e| e.to_string())))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let serial = with_jobs(1);
+        assert_eq!(serial.len(), paths.len());
+        assert!(serial["missing"].is_err());
+        assert_eq!(serial, with_jobs(8));
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::path::{Path, PathBuf};
+
+use crate::checker::{self, InstallOptions};
+use crate::table::{Format, Table};
+use crate::{cache, checksum, info, state, units, warn};
+
+/// The download os values of the architectures Visual Studio Code is built
+/// for, offered by `--select-arch-interactive`.
+pub const KNOWN_ARCHES: &[&str] = &["linux-x64", "linux-arm64", "linux-armhf"];
+
+const COLUMNS: &[&str] = &["ARCH", "VERSION", "SIZE", "SHA256", "FILE"];
+
+/// Validates an `--arch` value: a download os value such as linux-arm64, or
+/// the machine name it is for.
+pub fn parse_arch(s: &str) -> Result<String> {
+    let os = match s {
+        "x86_64" | "amd64" => "linux-x64",
+        "aarch64" | "arm64" => "linux-arm64",
+        "armv7l" | "armhf" => "linux-armhf",
+        os if os.starts_with("linux-") => os,
+        other => bail!(
+            "{} is neither a download os value such as linux-arm64 nor a known machine name",
+            other
+        ),
+    };
+
+    Ok(os.to_string())
+}
+
+/// Asks on the terminal which of `KNOWN_ARCHES` to fetch.
+pub fn select_interactive() -> Result<Vec<String>> {
+    if !console::user_attended_stderr() {
+        bail!("--select-arch-interactive needs a terminal, pass the architectures with --arch instead.");
+    }
+    for (i, os) in KNOWN_ARCHES.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, os);
+    }
+    eprint!(
+        "{} Architectures to fetch, as numbers separated by commas, or all: ",
+        style("select:").magenta().bold()
+    );
+    let answer = console::Term::stderr().read_line()?;
+    let answer = answer.trim();
+    if answer == "all" {
+        return Ok(KNOWN_ARCHES.iter().map(|os| os.to_string()).collect());
+    }
+    let mut arches = Vec::new();
+    for item in answer.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let os = item
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|i| KNOWN_ARCHES.get(i))
+            .ok_or_else(|| anyhow!("{} is not one of the numbers listed.", item))?;
+        if !arches.iter().any(|a| a == os) {
+            arches.push(os.to_string());
+        }
+    }
+    if arches.is_empty() {
+        bail!("No architecture was selected.");
+    }
+
+    Ok(arches)
+}
+
+struct Fetched {
+    version: String,
+    size: u64,
+    sha256: String,
+    path: PathBuf,
+}
+
+/// Fetches `version` for `os` through the cache, verified against the
+/// published checksum, and saves it in `dir`.
+fn fetch(dir: &Path, os: &str, version: &str, options: &InstallOptions) -> Result<Fetched> {
+    let checker::Release { buf, version, .. } = checker::fetch_pinned(os, version, options)?;
+    let sha256 = checksum::sha256_bytes(&buf);
+    // A cache entry stored with zstd only has the tar left.
+    let extension = if cache::is_gzip(&buf) {
+        "tar.gz"
+    } else {
+        "tar"
+    };
+    let path = dir.join(format!("vscode-{}-{}.{}", version, os, extension));
+    state::write_atomic(&path, &buf)
+        .map_err(|e| anyhow!("Failed to write {}: {}.", path.display(), e))?;
+
+    Ok(Fetched {
+        version,
+        size: buf.len() as u64,
+        sha256,
+        path,
+    })
+}
+
+/// Saves the release for each of `arches` in `dir`, for installation media
+/// covering several architectures, and prints what was fetched. Fails only
+/// when every architecture did.
+pub fn download(dir: &Path, arches: &[String], options: &InstallOptions) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| anyhow!("Failed to create {}: {}.", dir.display(), e))?;
+    // Every architecture gets the same version, even if one is published
+    // while the others download.
+    let version = match &options.version {
+        Some(v) => v.clone(),
+        None => checker::get_lastest_version()?,
+    };
+    let mut results = Vec::new();
+    for os in arches {
+        info!("Fetching Visual Studio Code {} for {} ...", version, os);
+        let result = fetch(dir, os, &version, options);
+        if let Err(e) = &result {
+            warn!("Failed to fetch {}: {}", os, e);
+        }
+        results.push((os, result));
+    }
+
+    let mut table = Table::new(COLUMNS);
+    for (os, result) in &results {
+        table.row(match result {
+            Ok(f) => vec![
+                os.to_string(),
+                f.version.clone(),
+                units::Bytes(f.size).to_string(),
+                f.sha256.clone(),
+                f.path.display().to_string(),
+            ],
+            Err(e) => vec![
+                os.to_string(),
+                version.clone(),
+                "-".to_string(),
+                "-".to_string(),
+                format!("{} {}", style("error:").red().bold(), e),
+            ],
+        });
+    }
+    table.print(Format::Table);
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    if failed == results.len() {
+        bail!("No architecture could be fetched.");
+    }
+    if failed > 0 {
+        warn!(
+            "{} of {} architectures could not be fetched.",
+            failed,
+            results.len()
+        );
+    } else {
+        info!(
+            "Fetched Visual Studio Code {} for {} architectures into {}.",
+            version,
+            results.len(),
+            dir.display()
+        );
+    }
+
+    Ok(())
+}
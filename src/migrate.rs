@@ -0,0 +1,145 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::checker;
+use crate::config::{self, ARCH_MAP_PATH, CONFIG_PATH};
+use crate::scope::{self, Scope, SYSTEM_DATA_DIR};
+use crate::{info, state, warn};
+
+/// Bumped whenever `Bundle` changes incompatibly. Other versions are refused.
+const SCHEMA_VERSION: u32 = 1;
+
+/// The settings `vsdown export` carries to another machine. The editor
+/// tree, the cache, the installed version and its history, hooks and
+/// credentials are left out.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Bundle {
+    schema: u32,
+    /// The vsdown which exported it, for information only.
+    vsdown_version: String,
+    exported_at: u64,
+    /// `CONFIG_PATH` as written, mirrors and certificate pins included.
+    config: Option<String>,
+    /// `ARCH_MAP_PATH` as written.
+    arch_map: Option<String>,
+    preferences: Preferences,
+}
+
+/// How installs integrate with the system, as recorded by the last one.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Preferences {
+    bin_name: Option<String>,
+    sandbox: bool,
+    coexist: bool,
+    metadata_scope: String,
+}
+
+fn read_optional(p: &str) -> Result<Option<String>> {
+    match std::fs::read_to_string(p) {
+        Ok(s) => Ok(Some(s)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Failed to read {}: {}.", p, e)),
+    }
+}
+
+/// Writes the configuration, the arch map and the integration preferences
+/// to `p`.
+pub fn export(p: &Path) -> Result<()> {
+    let state = state::load();
+    let bundle = Bundle {
+        schema: SCHEMA_VERSION,
+        vsdown_version: env!("CARGO_PKG_VERSION").to_string(),
+        exported_at: state::now(),
+        config: read_optional(CONFIG_PATH)?,
+        arch_map: read_optional(ARCH_MAP_PATH)?,
+        preferences: Preferences {
+            bin_name: state.bin_name,
+            sandbox: state.sandbox,
+            coexist: state.coexist,
+            metadata_scope: if state.metadata_dir == SYSTEM_DATA_DIR {
+                "system".to_string()
+            } else {
+                "user".to_string()
+            },
+        },
+    };
+    std::fs::write(p, serde_json::to_vec_pretty(&bundle)?)
+        .map_err(|e| anyhow!("Failed to write {}: {}.", p.display(), e))?;
+
+    Ok(())
+}
+
+/// Restores what `export()` wrote to `p`. Existing files with other
+/// contents are only replaced with `force`. The preferences are skipped
+/// while Visual Studio Code is installed, as they would change how the
+/// existing installation is managed.
+pub fn import(p: &Path, force: bool) -> Result<()> {
+    let s = std::fs::read(p).map_err(|e| anyhow!("Failed to read {}: {}.", p.display(), e))?;
+    let value = serde_json::from_slice::<serde_json::Value>(&s)
+        .map_err(|e| anyhow!("{} is not a vsdown export: {}.", p.display(), e))?;
+    match value.get("schema").and_then(|v| v.as_u64()) {
+        Some(v) if v == SCHEMA_VERSION as u64 => (),
+        Some(v) => bail!(
+            "{} has schema version {}, this vsdown only imports version {}.",
+            p.display(),
+            v,
+            SCHEMA_VERSION
+        ),
+        None => bail!("{} is not a vsdown export.", p.display()),
+    }
+    let bundle = serde_json::from_value::<Bundle>(value)
+        .map_err(|e| anyhow!("{} is not a valid vsdown export: {}.", p.display(), e))?;
+
+    // Check everything before writing anything.
+    if let Some(s) = &bundle.config {
+        config::validate(s, Path::new(CONFIG_PATH))?;
+    }
+    if let Some(s) = &bundle.arch_map {
+        config::parse_arch_map(s, Path::new(ARCH_MAP_PATH))?;
+    }
+    let scope = match bundle.preferences.metadata_scope.as_str() {
+        "system" => Scope::System,
+        "user" => Scope::User,
+        other => bail!("Unknown metadata scope {} in {}.", other, p.display()),
+    };
+    if let Some(name) = &bundle.preferences.bin_name {
+        checker::parse_bin_name(name)?;
+    }
+    let files = [
+        (CONFIG_PATH, bundle.config.as_deref()),
+        (ARCH_MAP_PATH, bundle.arch_map.as_deref()),
+    ];
+    for (path, contents) in files {
+        let existing = read_optional(path)?;
+        match (existing, contents) {
+            (Some(existing), Some(contents)) if existing != contents && !force => bail!(
+                "{} exists and differs from the imported one, pass --force to replace it.",
+                path
+            ),
+            _ => (),
+        }
+    }
+
+    for (path, contents) in files {
+        if let Some(contents) = contents {
+            state::write_atomic(Path::new(path), contents.as_bytes())?;
+            info!("Imported {}.", path);
+        }
+    }
+    if checker::is_installed() {
+        warn!("Not importing the launcher, sandbox and metadata preferences, which would change how the current installation is managed. Pass them to the next install instead.");
+    } else {
+        let prefs = &bundle.preferences;
+        state::write_bin_name(prefs.bin_name.as_deref())?;
+        state::write_sandbox_setup(prefs.sandbox)?;
+        state::write_coexist(prefs.coexist)?;
+        state::write_metadata_dir(&scope::data_dir(scope)?)?;
+        info!("Imported the launcher, sandbox and metadata preferences for the next install.");
+    }
+
+    Ok(())
+}
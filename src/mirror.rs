@@ -0,0 +1,449 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use std::{
+    io::Read,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::checker::{self, CURRENT_VERSION_DIRECTORY, DOWNLOAD_VSCODE_URL};
+use crate::download::{self, Head};
+use crate::state::{self, now};
+use crate::{checksum, color_bool, config, debug, http, release, units, warn};
+
+/// Equivalent official download URLs, tried in order when the official
+/// download is selected.
+const OFFICIAL_ENDPOINTS: &[&str] = &[
+    DOWNLOAD_VSCODE_URL,
+    "https://update.code.visualstudio.com/latest/{os}/stable",
+];
+const MIRROR_CHOICE_FILENAME: &str = "mirror";
+
+/// Endpoints which served a bad archive during this run.
+static BAD_URLS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+const PROBE_SIZE: u64 = 256 * 1024;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How much of the first chunk a racing mirror reads before checking
+/// whether another one has already won.
+const RACE_READ_SIZE: u64 = 16 * 1024;
+/// Releases are well above this, anything smaller is likely an error page.
+const MIN_ARCHIVE_SIZE: u64 = 50 * 1024 * 1024;
+const ARCHIVE_CONTENT_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-tar",
+    "application/x-compressed-tar",
+    "application/octet-stream",
+    "binary/octet-stream",
+];
+
+/// Substitutes the `{os}` placeholder of a mirror URL template.
+pub fn expand(template: &str, os: &str) -> String {
+    template.replace("{os}", os)
+}
+
+/// Expands a mirror URL template into the URLs to try for it.
+fn endpoints(template: &str, os: &str) -> Vec<String> {
+    if template == DOWNLOAD_VSCODE_URL {
+        OFFICIAL_ENDPOINTS.iter().map(|i| expand(i, os)).collect()
+    } else {
+        vec![expand(template, os)]
+    }
+}
+
+/// Mirror URL templates in the order they should be tried when nothing is
+/// known about their speed.
+fn candidates() -> Vec<String> {
+    let config = config::get();
+    if let Some(m) = &config.mirror {
+        return vec![m.clone()];
+    }
+    if config.mirrors.is_empty() {
+        return vec![DOWNLOAD_VSCODE_URL.to_string()];
+    }
+
+    config.mirrors.clone()
+}
+
+/// Returns the download URL to use without probing, for requests which are
+/// too small to justify it (e.g. resolving the download size).
+pub fn preferred_url(os: &str) -> String {
+    let candidates = candidates();
+    let template = match read_choice() {
+        Some(c) if candidates.contains(&c) => c,
+        _ => candidates[0].clone(),
+    };
+
+    expand(&template, os)
+}
+
+/// Returns every candidate download URL, fastest mirror first and those
+/// which served a bad archive during this run last, with the start of the
+/// archive if racing mirrors already received it. Candidates are probed
+/// unless a mirror is forced or a remembered choice is still fresh.
+pub fn ordered_urls(os: &str) -> (Vec<String>, Option<Head>) {
+    let (mut urls, head) = probed_urls(os);
+    let bad = BAD_URLS.lock().expect("mirror state is poisoned");
+    // Stable, so the order is otherwise kept.
+    urls.sort_by_key(|u| bad.contains(u));
+
+    (urls, head)
+}
+
+/// Remembers for the rest of the run that `url` served an archive failing
+/// its checksum.
+pub fn mark_bad(url: &str) {
+    let mut bad = BAD_URLS.lock().expect("mirror state is poisoned");
+    if !bad.iter().any(|u| u == url) {
+        bad.push(url.to_string());
+    }
+}
+
+fn probed_urls(os: &str) -> (Vec<String>, Option<Head>) {
+    let mut candidates = candidates();
+    if candidates.len() == 1 {
+        return (endpoints(&candidates[0], os), None);
+    }
+    if let Some(c) = read_choice() {
+        if let Some(pos) = candidates.iter().position(|i| *i == c) {
+            debug!("Using remembered mirror choice {}.", c);
+            let c = candidates.remove(pos);
+            candidates.insert(0, c);
+            return (
+                candidates.iter().flat_map(|i| endpoints(i, os)).collect(),
+                None,
+            );
+        }
+    }
+    let max = config::get().max_parallel_mirrors;
+    if max > 1 {
+        return raced_urls(candidates, os, max);
+    }
+
+    let mut scored = candidates
+        .into_iter()
+        .map(|i| {
+            let speed = match probe(&expand(&i, os)) {
+                Ok(speed) => {
+                    debug!("Mirror {}: {}", i, units::Rate(speed));
+                    speed
+                }
+                Err(e) => {
+                    debug!("Mirror {}: probe failed: {}", i, e);
+                    0.0
+                }
+            };
+            (i, speed)
+        })
+        .collect::<Vec<_>>();
+    // Stable sort, so mirrors that failed to probe keep their configured order.
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    if scored[0].1 > 0.0 {
+        debug!("Selected mirror {}.", scored[0].0);
+        if let Err(e) = write_choice(&scored[0].0) {
+            debug!("Could not remember mirror choice: {}", e);
+        }
+    }
+
+    (
+        scored.iter().flat_map(|(i, _)| endpoints(i, os)).collect(),
+        None,
+    )
+}
+
+/// Races the first chunk of the archive across up to `max` candidates at a
+/// time, batch after batch until one delivers it, and puts the winner first
+/// with the chunk it delivered, for the download to go on from. If every
+/// race fails the configured order is kept.
+fn raced_urls(mut candidates: Vec<String>, os: &str, max: usize) -> (Vec<String>, Option<Head>) {
+    let winner = candidates.chunks(max).find_map(|batch| race(batch, os));
+    let mut head = None;
+    match winner.and_then(|(w, h)| Some((candidates.iter().position(|i| *i == w)?, h))) {
+        Some((pos, h)) => {
+            let w = candidates.remove(pos);
+            if let Err(e) = write_choice(&w) {
+                debug!("Could not remember mirror choice: {}", e);
+            }
+            candidates.insert(0, w);
+            head = h;
+        }
+        None => debug!("No mirror won the race, trying them in the configured order."),
+    }
+
+    (
+        candidates.iter().flat_map(|i| endpoints(i, os)).collect(),
+        head,
+    )
+}
+
+/// Fetches the first chunk from every mirror of `batch` at once and returns
+/// the first to finish, with the chunk if the download can resume from it.
+/// The others stop reading as soon as it does.
+fn race(batch: &[String], os: &str) -> Option<(String, Option<Head>)> {
+    let done = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    for template in batch {
+        let url = expand(template, os);
+        let (template, done, tx) = (template.clone(), done.clone(), tx.clone());
+        std::thread::spawn(move || {
+            let _ = tx.send((template, first_chunk(&url, &done)));
+        });
+    }
+    // Receiving ends once every racer has reported.
+    drop(tx);
+    for (template, result) in rx {
+        match result {
+            Ok((elapsed, head)) => {
+                done.store(true, Ordering::Relaxed);
+                debug!("Mirror {} won the race in {:.2?}.", template, elapsed);
+                return Some((template, head));
+            }
+            Err(e) => debug!("Mirror {}: race failed: {}", template, e),
+        }
+    }
+
+    None
+}
+
+/// Fetches the first chunk of the archive, unless `cancelled` is set in the
+/// meantime, and returns how long that took. The chunk is returned as well
+/// when the response had a strong ETag to resume it with.
+fn first_chunk(url: &str, cancelled: &AtomicBool) -> Result<(Duration, Option<Head>)> {
+    let start = Instant::now();
+    let mut r = http::send(
+        http::client()?
+            .get(url)
+            .timeout(PROBE_TIMEOUT)
+            .header(RANGE, format!("bytes=0-{}", PROBE_SIZE - 1)),
+    )?
+    .error_for_status()?;
+    let etag = download::strong_etag(r.headers());
+    let mut buf = Vec::new();
+    while (buf.len() as u64) < PROBE_SIZE {
+        if cancelled.load(Ordering::Relaxed) {
+            // Dropping the response closes the losing connection.
+            bail!("another mirror was faster");
+        }
+        // A server ignoring the range sends more than the chunk.
+        let left = (PROBE_SIZE - buf.len() as u64).min(RACE_READ_SIZE);
+        let n = (&mut r).take(left).read_to_end(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+    }
+    if buf.is_empty() {
+        bail!("empty response");
+    }
+    let elapsed = start.elapsed();
+    let head = etag.map(|etag| Head {
+        url: url.to_string(),
+        buf,
+        etag,
+    });
+
+    Ok((elapsed, head))
+}
+
+/// Fetches the first few hundred KiB of the archive and returns the observed
+/// throughput in bytes per second, connection setup included.
+fn probe(url: &str) -> Result<f64> {
+    let start = Instant::now();
+    let r = http::send(
+        http::client()?
+            .get(url)
+            .timeout(PROBE_TIMEOUT)
+            .header(RANGE, format!("bytes=0-{}", PROBE_SIZE - 1)),
+    )?
+    .error_for_status()?;
+    // Servers ignoring the range would send the whole archive. Reading one
+    // byte more reaches the end of a ranged response, which lets the
+    // connection be reused for the download.
+    let read = std::io::copy(&mut r.take(PROBE_SIZE + 1), &mut std::io::sink())?;
+    let elapsed = start.elapsed().as_secs_f64();
+    if read == 0 {
+        return Err(anyhow!("empty response"));
+    }
+
+    Ok(read as f64 / elapsed.max(f64::EPSILON))
+}
+
+fn read_choice() -> Option<String> {
+    let mut s = String::new();
+    std::fs::File::open(format!(
+        "{}{}",
+        CURRENT_VERSION_DIRECTORY, MIRROR_CHOICE_FILENAME
+    ))
+    .ok()?
+    .read_to_string(&mut s)
+    .ok()?;
+    let (timestamp, url) = s.trim().split_once(' ')?;
+    let age = now().checked_sub(timestamp.parse().ok()?)?;
+    if age > config::get().mirror_ttl {
+        return None;
+    }
+
+    Some(url.to_string())
+}
+
+fn write_choice(url: &str) -> Result<()> {
+    state::write_atomic(
+        &Path::new(CURRENT_VERSION_DIRECTORY).join(MIRROR_CHOICE_FILENAME),
+        format!("{} {}\n", now(), url).as_bytes(),
+    )
+}
+
+/// Checks whether `template` serves release archives for this system,
+/// fetching only the start of the archive unless `full` is set, which also
+/// compares it with the checksum the update API publishes. Nothing on the
+/// system is changed. Returns whether every check passed.
+pub fn verify_url(template: &str, full: bool) -> Result<bool> {
+    let os = checker::get_arch()?;
+    let url = expand(template, &os);
+    println!("URL: {}", http::redact(&url));
+    let mut r = http::send(http::client()?.get(&url).header(RANGE, "bytes=0-511"))?;
+    let status = r.status();
+    if !status.is_success() {
+        bail!("{} responded with {}.", http::redact(&url), status);
+    }
+    if r.url().as_str() != url {
+        println!("Served by: {}", http::redact(r.url().as_str()));
+    }
+    let content_type = r
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    // The total size is only in Content-Range when the range was honoured.
+    let size = r
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit_once('/'))
+        .and_then(|(_, total)| total.parse::<u64>().ok())
+        .or_else(|| r.content_length().filter(|_| status != 206));
+    let mut prefix = Vec::new();
+    (&mut r).take(512).read_to_end(&mut prefix)?;
+    drop(r);
+
+    let gzip = prefix.starts_with(&[0x1f, 0x8b]);
+    let type_ok = content_type
+        .as_deref()
+        .map(|t| ARCHIVE_CONTENT_TYPES.iter().any(|a| t.starts_with(a)))
+        .unwrap_or(false);
+    let size_ok = size.map(|s| s >= MIN_ARCHIVE_SIZE).unwrap_or(false);
+    println!("Gzip data: {}", color_bool!(gzip));
+    println!(
+        "Archive content type: {} ({})",
+        color_bool!(type_ok),
+        content_type.as_deref().unwrap_or("none")
+    );
+    println!(
+        "Plausible size: {} ({})",
+        color_bool!(size_ok),
+        size.map(|s| units::Bytes(s).to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    );
+    let mut ok = gzip && type_ok && size_ok;
+    if full {
+        let buf = download::fetch(&[url])?;
+        let arch_ok = match checker::verify_archive_arch(&buf, &os) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("{}", e);
+                false
+            }
+        };
+        println!("Release for {}: {}", os, color_bool!(arch_ok));
+        let sha256 = checksum::sha256_bytes(&buf);
+        println!("SHA-256: {}", sha256);
+        // Mirrors serve the latest release, which the update API has the
+        // checksum of.
+        let version = checker::get_lastest_version()?;
+        let published = release::query(&version, &os)?.sha256hash;
+        let hash_ok = match checksum_matches(&sha256, published.as_deref()) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("{}", e);
+                false
+            }
+        };
+        println!("Published SHA-256 of {}: {}", version, color_bool!(hash_ok));
+        ok &= arch_ok && hash_ok;
+    }
+
+    Ok(ok)
+}
+
+/// Whether `sha256` is the `published` one, failing as well when none is.
+fn checksum_matches(sha256: &str, published: Option<&str>) -> Result<()> {
+    match published {
+        Some(published) if published.eq_ignore_ascii_case(sha256) => Ok(()),
+        Some(published) => bail!(
+            "The archive has SHA-256 {}, but {} was published for the latest release; the mirror may be outdated or corrupt.",
+            sha256,
+            published
+        ),
+        None => bail!("The update API publishes no SHA-256 for the latest release to compare with."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_published_checksums() {
+        let sha256 = checksum::sha256_bytes(b"archive");
+        assert!(checksum_matches(&sha256, Some(&sha256)).is_ok());
+        assert!(checksum_matches(&sha256, Some(&sha256.to_ascii_uppercase())).is_ok());
+
+        let other = checksum::sha256_bytes(b"other");
+        let e = checksum_matches(&sha256, Some(&other)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "The archive has SHA-256 {}, but {} was published for the latest release; the mirror may be outdated or corrupt.",
+                sha256, other
+            )
+        );
+        assert_eq!(
+            checksum_matches(&sha256, None).unwrap_err().to_string(),
+            "The update API publishes no SHA-256 for the latest release to compare with."
+        );
+    }
+
+    #[test]
+    fn keeps_the_winning_chunk() {
+        use std::{io::Write, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for (mut stream, etag) in listener.incoming().flatten().zip(["\"v1\"", "W/\"v1\""]) {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                let response = format!(
+                    "HTTP/1.1 206 Partial Content\r\nETag: {}\r\nContent-Range: bytes 0-4/10\r\nContent-Length: 5\r\nConnection: close\r\n\r\nstart",
+                    etag
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        let url = format!("http://127.0.0.1:{}/archive", port);
+        let chunk = || first_chunk(&url, &AtomicBool::new(false)).unwrap().1;
+
+        let head = chunk().unwrap();
+        assert_eq!(
+            (head.url.as_str(), head.buf.as_slice()),
+            (url.as_str(), &b"start"[..])
+        );
+        assert_eq!(head.etag, "\"v1\"");
+        // Nothing could check a resumption against a weak ETag.
+        assert!(chunk().is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path, thread, time::Duration};
+
+use crate::checker::CURRENT_VERSION_DIRECTORY;
+use crate::{config, debug};
+
+/// `statfs` f_type values of filesystems whose locking and renames are not
+/// those of a local one: NFS, SMB, CIFS, SMB2, Ceph, AFS, Coda and FUSE,
+/// which sshfs and most cluster filesystems are mounted through. Compared
+/// as u32, as `f_type` is an i32 on 32-bit targets such as armhf, where the
+/// SMB magics would not match sign-extended.
+const NETWORK_MAGICS: &[u32] = &[
+    0x6969,
+    0x517b,
+    0xff53_4d42,
+    0xfe53_4d42,
+    0x00c3_6400,
+    0x5346_414f,
+    0x7375_7245,
+    0x6573_5546,
+];
+
+/// How often an operation failing with ESTALE is tried again.
+const STALE_RETRIES: u32 = 3;
+const STALE_DELAY: Duration = Duration::from_millis(100);
+
+/// How the filesystem of the state directory is told apart, the `state-fs`
+/// setting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Detection {
+    /// Asked of the kernel with statfs.
+    Auto,
+    Local,
+    Network,
+}
+
+impl Detection {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Detection::Auto),
+            "local" => Ok(Detection::Local),
+            "network" => Ok(Detection::Network),
+            other => bail!("state-fs must be auto, local or network, not {}.", other),
+        }
+    }
+}
+
+static STATE_ON_NETWORK: Lazy<bool> = Lazy::new(|| {
+    let network = on_network(config::get().state_fs, Path::new(CURRENT_VERSION_DIRECTORY));
+    if network {
+        debug!(
+            "{} is on a network filesystem, locking with a lock file rather than flock.",
+            CURRENT_VERSION_DIRECTORY
+        );
+    }
+    network
+});
+
+/// Whether the state directory is on a network filesystem.
+pub fn state_on_network() -> bool {
+    *STATE_ON_NETWORK
+}
+
+/// Whether `p` is taken to be on a network filesystem with `detection`.
+pub fn on_network(detection: Detection, p: &Path) -> bool {
+    match detection {
+        Detection::Auto => is_network(p),
+        Detection::Local => false,
+        Detection::Network => true,
+    }
+}
+
+/// Whether `p`, or the closest of its ancestors which exists, is on a
+/// network filesystem. Assumed not when statfs fails.
+fn is_network(p: &Path) -> bool {
+    let dir = match p.ancestors().find(|a| a.exists()) {
+        Some(dir) => dir,
+        None => return false,
+    };
+    let c = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+
+    NETWORK_MAGICS.contains(&(stat.f_type as u32))
+}
+
+/// Runs `f`, trying it again a few times when it fails with ESTALE, as a
+/// file replaced on the NFS server does until the handle is looked up anew.
+pub fn retry_stale<T, F: FnMut() -> io::Result<T>>(mut f: F) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if e.raw_os_error() == Some(libc::ESTALE) && attempt < STALE_RETRIES => {
+                attempt += 1;
+                debug!(
+                    "Stale file handle, retrying ({}/{}) ...",
+                    attempt, STALE_RETRIES
+                );
+                thread::sleep(STALE_DELAY);
+            }
+            r => return r,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{config, debug};
+
+static NETRC: OnceCell<Option<Netrc>> = OnceCell::new();
+
+pub struct Credentials {
+    pub login: String,
+    pub password: String,
+}
+
+/// Never shows the password, so credentials can be logged by mistake.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Credentials {{ login: {:?}, password: <redacted> }}",
+            self.login
+        )
+    }
+}
+
+struct Netrc {
+    path: PathBuf,
+    machines: HashMap<String, Credentials>,
+    default: Option<Credentials>,
+}
+
+/// `$NETRC`, or `~/.netrc`.
+fn path() -> Option<PathBuf> {
+    std::env::var_os("NETRC")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".netrc")))
+}
+
+/// Reads the netrc file on first use. A missing file is not an error, an
+/// unreadable or malformed one is.
+fn netrc() -> Result<Option<&'static Netrc>> {
+    if !config::get().netrc {
+        return Ok(None);
+    }
+    NETRC
+        .get_or_try_init(|| -> Result<Option<Netrc>> {
+            let path = match path() {
+                Some(p) if p.exists() => p,
+                _ => return Ok(None),
+            };
+            let s = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read {}: {}.", path.display(), e))?;
+            let netrc = parse(&s).map_err(|e| anyhow!("Invalid {}: {}", path.display(), e))?;
+            debug!(
+                "Read credentials for {} hosts from {}.",
+                netrc.machines.len(),
+                path.display()
+            );
+
+            Ok(Some(Netrc { path, ..netrc }))
+        })
+        .map(Option::as_ref)
+}
+
+/// Credentials of `host`, falling back to the `default` entry, for proxies.
+pub fn proxy_credentials(host: &str) -> Result<Option<&'static Credentials>> {
+    Ok(netrc()?.and_then(|n| {
+        let c = n
+            .machines
+            .get(&host.to_ascii_lowercase())
+            .or(n.default.as_ref());
+        if c.is_some() {
+            debug!(
+                "Using credentials from {} for proxy {}.",
+                n.path.display(),
+                host
+            );
+        }
+        c
+    }))
+}
+
+/// Credentials listed for exactly `host`, for mirrors. The `default` entry is
+/// not used here, it would hand the password to every download host.
+pub fn host_credentials(host: &str) -> Result<Option<&'static Credentials>> {
+    Ok(netrc()?.and_then(|n| n.machines.get(&host.to_ascii_lowercase())))
+}
+
+/// Parses the tokens of a netrc file. Errors name the offending line but
+/// never its values, which may be passwords.
+fn parse(s: &str) -> Result<Netrc> {
+    let mut tokens = Vec::new();
+    let mut in_macdef = false;
+    for (i, line) in s.lines().enumerate() {
+        // A macro definition runs up to the next empty line.
+        if in_macdef {
+            in_macdef = !line.trim().is_empty();
+            continue;
+        }
+        if line.split_whitespace().next() == Some("macdef") {
+            in_macdef = true;
+            continue;
+        }
+        let line_tokens = line.split_whitespace().take_while(|t| !t.starts_with('#'));
+        tokens.extend(line_tokens.map(|t| (i + 1, t)));
+    }
+
+    let mut machines = HashMap::new();
+    let mut default = None;
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some((line, token)) = tokens.next() {
+        let machine = match token {
+            "machine" => match tokens.next() {
+                Some((_, name)) => Some(name.to_ascii_lowercase()),
+                None => bail!("line {}: machine without a name.", line),
+            },
+            "default" => None,
+            _ => bail!("line {}: expected machine or default.", line),
+        };
+        let mut login = None;
+        let mut password = None;
+        while let Some((line, key)) = tokens.next_if(|(_, t)| !matches!(*t, "machine" | "default"))
+        {
+            let value = match tokens.next() {
+                Some((_, value)) => value.to_owned(),
+                None => bail!("line {}: {} without a value.", line, key),
+            };
+            match key {
+                "login" => login = Some(value),
+                "password" => password = Some(value),
+                "account" => (),
+                _ => bail!("line {}: unknown keyword.", line),
+            }
+        }
+        // Entries without a password are of no use for basic auth.
+        if let (Some(login), Some(password)) = (login, password) {
+            let credentials = Credentials { login, password };
+            match machine {
+                Some(machine) => {
+                    machines.entry(machine).or_insert(credentials);
+                }
+                None => default = Some(credentials),
+            }
+        }
+    }
+
+    Ok(Netrc {
+        path: PathBuf::new(),
+        machines,
+        default,
+    })
+}
@@ -0,0 +1,62 @@
+use anyhow::Result;
+use std::path::Path;
+
+use console::style;
+
+use crate::info;
+
+const FLAG_DIRECTORY: &str = "/run/vsdown";
+const FLAG_PATH: &str = "/run/vsdown/update-available";
+const SNIPPET_PATH: &str = "/etc/profile.d/vsdown-update-notice.sh";
+
+/// Kept to a file test and a `cat` as it runs for every login shell.
+const SNIPPET: &str = r#"# Installed by vsdown generate-login-notice, remove with --remove.
+case $- in *i*) [ -r /run/vsdown/update-available ] && cat /run/vsdown/update-available ;; esac
+"#;
+
+/// Writes the flag file read by the login snippet when an update is pending,
+/// and removes it otherwise.
+pub fn write_flag(pending: Option<&str>) -> Result<()> {
+    match pending {
+        Some(version) => {
+            std::fs::create_dir_all(FLAG_DIRECTORY)?;
+            std::fs::write(
+                FLAG_PATH,
+                format!(
+                    "VS Code {} is available; run 'sudo vsdown install'\n",
+                    version
+                ),
+            )?;
+        }
+        None => clear_flag()?,
+    }
+
+    Ok(())
+}
+
+pub fn clear_flag() -> Result<()> {
+    if Path::new(FLAG_PATH).exists() {
+        std::fs::remove_file(FLAG_PATH)?;
+    }
+
+    Ok(())
+}
+
+/// Installs (or with `remove`, uninstalls) the profile.d snippet printing the
+/// notice in interactive shells.
+pub fn generate(remove: bool) -> Result<()> {
+    if remove {
+        if Path::new(SNIPPET_PATH).exists() {
+            std::fs::remove_file(SNIPPET_PATH)?;
+            info!("Removed {}.", SNIPPET_PATH);
+        }
+        return Ok(());
+    }
+    std::fs::write(SNIPPET_PATH, SNIPPET)?;
+    info!(
+        "Installed {}, run `vsdown check --write-flag` periodically to keep the notice current.",
+        SNIPPET_PATH
+    );
+
+    Ok(())
+}
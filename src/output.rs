@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Where a `--json` result goes: stdout, a file given with `--output`, or both.
+pub struct JsonOutput {
+    stdout: bool,
+    file: Option<Destination>,
+}
+
+struct Destination {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: File,
+}
+
+impl JsonOutput {
+    /// Creates the temporary file next to `path` right away, so a destination
+    /// that cannot be written fails the command before it does any work.
+    pub fn open(path: Option<&Path>, stdout: bool) -> Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let mut tmp = OsString::from(path.as_os_str());
+                tmp.push(".tmp");
+                let tmp = PathBuf::from(tmp);
+                let file = File::create(&tmp)
+                    .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+                Some(Destination {
+                    path: path.to_owned(),
+                    tmp,
+                    file,
+                })
+            }
+            None => None,
+        };
+
+        Ok(JsonOutput { stdout, file })
+    }
+
+    /// Prints `value` and moves it into place in the `--output` file.
+    pub fn write<T: Serialize>(self, value: &T) -> Result<()> {
+        let s = serde_json::to_string(value)?;
+        if self.stdout {
+            println!("{}", s);
+        }
+        self.finish(&s)
+    }
+
+    /// Leaves `{"error": message}` in the `--output` file, so it holds valid
+    /// JSON even when the command failed. Nothing goes to stdout, where the
+    /// error has already been logged.
+    pub fn write_error(self, message: &str) -> Result<()> {
+        let s = serde_json::json!({ "error": message }).to_string();
+        self.finish(&s)
+    }
+
+    fn finish(self, s: &str) -> Result<()> {
+        if let Some(mut d) = self.file {
+            let result = d
+                .file
+                .write_all(s.as_bytes())
+                .and_then(|_| d.file.write_all(b"\n"))
+                .and_then(|_| d.file.sync_all())
+                .and_then(|_| std::fs::rename(&d.tmp, &d.path));
+            if let Err(e) = result {
+                std::fs::remove_file(&d.tmp).ok();
+                return Err(anyhow!("Failed to write {}: {}", d.path.display(), e));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+use std::{path::Path, process::Command};
+
+/// Queries asking a package manager which package owns a path, tried in
+/// order until one of the tools is installed.
+const OWNER_QUERIES: &[(&str, &[&str])] = &[
+    ("dpkg-query", &["-S"]),
+    ("rpm", &["-q", "--qf", "%{NAME}\\n", "-f"]),
+    ("pacman", &["-Qqo"]),
+];
+
+/// The package owning `p`, if the system package manager knows of one.
+pub fn owner(p: &Path) -> Option<String> {
+    for (tool, args) in OWNER_QUERIES {
+        let output = match Command::new(tool).args(*args).arg(p).output() {
+            Ok(output) => output,
+            Err(_) => continue,
+        };
+        if !output.status.success() {
+            return None;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let line = stdout.lines().next()?;
+        // dpkg-query prints "package[, package...]: path".
+        let name = match line.split_once(": ") {
+            Some((packages, _)) if *tool == "dpkg-query" => packages,
+            _ => line,
+        };
+        return Some(name.trim().to_string()).filter(|n| !n.is_empty());
+    }
+
+    None
+}
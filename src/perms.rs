@@ -0,0 +1,127 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use crate::checker::{self, INSTALL_DIR};
+use crate::{info, manifest, state, warn};
+
+const DIRECTORY_MODE: u32 = 0o755;
+const EXECUTABLE_MODE: u32 = 0o755;
+const RESOURCE_MODE: u32 = 0o644;
+/// chrome-sandbox once `install` has set it up.
+const SANDBOX_MODE: u32 = 0o4755;
+
+/// Re-applies the expected permissions to the installed tree: the modes the
+/// manifest recorded, or 0755 for directories and executables and 0644 for
+/// everything else where it recorded none. chrome-sandbox is set up again if
+/// it was at install time. Prints every change; with `dry_run` nothing is
+/// changed. Returns the number of changed paths.
+pub fn fix(dry_run: bool) -> Result<usize> {
+    let root = Path::new(INSTALL_DIR);
+    if !root.is_dir() {
+        return Err(checker::not_installed_at(INSTALL_DIR));
+    }
+    let recorded = match manifest::read() {
+        Ok(Some(m)) => m
+            .files
+            .into_iter()
+            .filter_map(|(p, r)| r.mode.map(|mode| (p, mode)))
+            .collect(),
+        Ok(None) => BTreeMap::new(),
+        Err(e) => {
+            warn!("{} Falling back to default permissions.", e);
+            BTreeMap::new()
+        }
+    };
+    if recorded.is_empty() {
+        info!("No permissions were recorded at install time, applying the defaults.");
+    }
+
+    let mut changed = 0;
+    fix_directory(root, Path::new(""), &recorded, dry_run, &mut changed)?;
+    let sandbox = root.join("chrome-sandbox");
+    if state::sandbox_setup_enabled() && sandbox.exists() {
+        let metadata = std::fs::metadata(&sandbox)?;
+        if metadata.uid() != 0 || metadata.mode() & 0o7777 != SANDBOX_MODE {
+            report(&sandbox, metadata.mode(), SANDBOX_MODE, dry_run);
+            if !dry_run {
+                checker::setup_sandbox()
+                    .map_err(|e| anyhow!("Failed to set up chrome-sandbox: {}.", e))?;
+            }
+            changed += 1;
+        }
+    }
+
+    Ok(changed)
+}
+
+fn fix_directory(
+    root: &Path,
+    rel: &Path,
+    recorded: &BTreeMap<String, u32>,
+    dry_run: bool,
+    changed: &mut usize,
+) -> Result<()> {
+    let mut entries = std::fs::read_dir(root.join(rel))?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let rel = rel.join(entry.file_name());
+        let p = root.join(&rel);
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() || rel == Path::new("chrome-sandbox") {
+            continue;
+        }
+        let mode = entry.metadata()?.permissions().mode() & 0o7777;
+        let expected = if file_type.is_dir() {
+            DIRECTORY_MODE
+        } else {
+            match recorded.get(&manifest::encode_path(&rel)) {
+                Some(&mode) => mode,
+                None if is_executable(&p) => EXECUTABLE_MODE,
+                None => RESOURCE_MODE,
+            }
+        };
+        if mode != expected {
+            report(&p, mode, expected, dry_run);
+            if !dry_run {
+                std::fs::set_permissions(&p, std::fs::Permissions::from_mode(expected))
+                    .map_err(|e| anyhow!("Failed to change the mode of {}: {}.", p.display(), e))?;
+            }
+            *changed += 1;
+        }
+        // Directories are fixed first so that their contents can be read.
+        if file_type.is_dir() {
+            fix_directory(root, &rel, recorded, dry_run, changed)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// ELF binaries and scripts.
+fn is_executable(p: &Path) -> bool {
+    let mut magic = [0; 4];
+    match std::fs::File::open(p).and_then(|mut f| f.read_exact(&mut magic)) {
+        Ok(()) => &magic == b"\x7fELF" || magic.starts_with(b"#!"),
+        Err(_) => false,
+    }
+}
+
+fn report(p: &Path, from: u32, to: u32, dry_run: bool) {
+    println!(
+        "{} {} ({:04o} -> {:04o})",
+        if dry_run {
+            style("would fix:").yellow().bold()
+        } else {
+            style("fixed:").green().bold()
+        },
+        p.display(),
+        from & 0o7777,
+        to
+    );
+}
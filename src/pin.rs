@@ -0,0 +1,183 @@
+use anyhow::{anyhow, bail, Result};
+use reqwest::{blocking::Response, tls::TlsInfo};
+use std::collections::HashMap;
+
+use crate::{checksum, config};
+
+/// Parses `pins` entries of the form `HOST=sha256:HEX` into the accepted
+/// SPKI digests of each host. A host may be listed several times so a new
+/// key can be pinned before the old one is retired.
+pub fn parse(entries: &[String]) -> Result<HashMap<String, Vec<String>>> {
+    let mut pins: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in entries {
+        let (host, digest) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid pin {}: expected HOST=sha256:HEX.", entry))?;
+        let digest =
+            checksum::parse_sha256(digest).map_err(|e| anyhow!("Invalid pin {}: {}.", entry, e))?;
+        pins.entry(host.trim().to_ascii_lowercase())
+            .or_default()
+            .push(digest);
+    }
+
+    Ok(pins)
+}
+
+/// Fails unless the certificate `r` was served with carries one of the keys
+/// `pins` lists for its host. Hosts without pins are not checked.
+pub fn check(r: &Response, pins: &HashMap<String, Vec<String>>) -> Result<()> {
+    let host = match r.url().host_str() {
+        Some(host) => host.to_ascii_lowercase(),
+        None => return Ok(()),
+    };
+    let pins = match pins.get(&host) {
+        Some(pins) => pins,
+        None => return Ok(()),
+    };
+    if r.url().scheme() != "https" {
+        bail!(
+            "Refusing plain HTTP response from {}, which has pinned certificate keys.",
+            host
+        );
+    }
+    let digest = r
+        .extensions()
+        .get::<TlsInfo>()
+        .and_then(|i| i.peer_certificate())
+        .and_then(spki)
+        .map(checksum::sha256_bytes)
+        .ok_or_else(|| {
+            anyhow!(
+                "Cannot read the certificate key of {} to check its pin.",
+                host
+            )
+        })?;
+    if !pins.contains(&digest) {
+        bail!(
+            "The certificate key of {} (sha256:{}) matches none of its pins. If the host has rotated its key, add the new pin to the pins of {}, or remove the pins of {} to disable pinning for it.",
+            host,
+            digest,
+            config::CONFIG_PATH,
+            host
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the DER SubjectPublicKeyInfo of a DER X.509 certificate.
+pub fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, _, cert) = element(cert)?;
+    let (_, _, mut tbs) = element(cert)?;
+    // The version is optional and explicitly tagged [0].
+    if tbs.first() == Some(&0xa0) {
+        let (_, whole, _) = element(tbs)?;
+        tbs = &tbs[whole.len()..];
+    }
+    // Skip serialNumber, signature, issuer, validity and subject.
+    for _ in 0..5 {
+        let (_, whole, _) = element(tbs)?;
+        tbs = &tbs[whole.len()..];
+    }
+    let (tag, whole, _) = element(tbs)?;
+
+    (tag == 0x30).then(|| whole)
+}
+
+/// Splits off the DER element at the start of `buf`, returning its tag, the
+/// whole element and its contents.
+fn element(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *buf.first()?;
+    let first = *buf.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let mut len = 0usize;
+        for b in buf.get(2..2 + n)? {
+            len = len << 8 | *b as usize;
+        }
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+
+    Some((tag, buf.get(..end)?, buf.get(header..end)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate() -> rcgen::Certificate {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn finds_the_key() {
+        let cert = certificate();
+        let der = cert.serialize_der().unwrap();
+        let key = spki(&der).unwrap();
+        assert_eq!(key[0], 0x30);
+        assert!(key.ends_with(cert.get_key_pair().public_key_raw()));
+    }
+
+    #[test]
+    fn rejects_truncated_certificates() {
+        let der = certificate().serialize_der().unwrap();
+        for n in 0..der.len() {
+            assert_eq!(spki(&der[..n]), None, "cut at {}", n);
+        }
+    }
+
+    #[test]
+    fn survives_corrupted_certificates() {
+        let der = certificate().serialize_der().unwrap();
+        for i in 0..der.len() {
+            for b in [0x00, 0x01, 0x7f, 0x80, 0x81, 0x84, 0x85, 0xa0, 0xff] {
+                let mut corrupted = der.clone();
+                corrupted[i] = b;
+                // Anything found is a part of the input, or nothing is.
+                if let Some(key) = spki(&corrupted) {
+                    assert!(key.len() <= corrupted.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_elements() {
+        assert_eq!(element(&[]), None);
+        assert_eq!(element(&[0x30]), None);
+        // Contents shorter than their length.
+        assert_eq!(element(&[0x30, 0x03, 0x01, 0x02]), None);
+        assert_eq!(element(&[0x30, 0x82, 0x01]), None);
+        assert_eq!(element(&[0x30, 0x82, 0x01, 0x00, 0x00]), None);
+        // Indefinite and overlong lengths.
+        assert_eq!(element(&[0x30, 0x80, 0x00, 0x00]), None);
+        assert_eq!(element(&[0x30, 0x85, 0xff, 0xff, 0xff, 0xff, 0xff]), None);
+        assert_eq!(element(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]), None);
+        assert_eq!(
+            element(&[0x30, 0x02, 0x05, 0x00, 0xff]),
+            Some((0x30, &[0x30, 0x02, 0x05, 0x00][..], &[0x05, 0x00][..]))
+        );
+        assert_eq!(spki(&[0x30, 0x00]), None);
+        assert_eq!(spki(&[0x30, 0x02, 0xa0, 0x00]), None);
+    }
+
+    #[test]
+    fn parses_pins() {
+        let digest = "ab".repeat(32);
+        let pins = parse(&[
+            format!("Example.org=sha256:{}", digest),
+            format!("example.org=sha256:{}", "cd".repeat(32)),
+        ])
+        .unwrap();
+        assert_eq!(pins["example.org"].len(), 2);
+        assert_eq!(pins["example.org"][0], digest);
+        assert!(parse(&["example.org".to_string()]).is_err());
+        assert!(parse(&["example.org=sha256:00".to_string()]).is_err());
+    }
+}
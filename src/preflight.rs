@@ -0,0 +1,173 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::checker::{BIN_PATH, CURRENT_VERSION_DIRECTORY, DIRECTORY_PATH, VSCODE_PATH};
+use crate::{cache, info, units, warn};
+
+/// Free space an install needs next to the tree it replaces.
+const MIN_INSTALL_FREE: u64 = 512 * 1024 * 1024;
+/// Enough for the state files, history and the manifest.
+const MIN_STATE_FREE: u64 = 16 * 1024 * 1024;
+/// Enough for one archive.
+const MIN_CACHE_FREE: u64 = 256 * 1024 * 1024;
+
+/// Checks write access to every location install touches, printing one line
+/// per path. Returns false if any of them is not writable.
+pub fn check_permissions() -> bool {
+    info!("Checking write access to installation paths ...");
+    let mut paths = vec![
+        ("install directory", VSCODE_PATH),
+        ("symlink directory", BIN_PATH),
+    ];
+    for i in DIRECTORY_PATH {
+        paths.push(("metadata directory", *i));
+    }
+    paths.push(("state directory", CURRENT_VERSION_DIRECTORY));
+    let cache_dir = cache::directory();
+    let cache_dir = cache_dir.to_string_lossy();
+    paths.push(("cache directory", &*cache_dir));
+
+    let mut all_writable = true;
+    for (purpose, path) in paths {
+        match probe_writable(Path::new(path)) {
+            Ok(()) => {
+                eprintln!("  {:<20} {} {}", purpose, path, style("writable").green());
+            }
+            Err(e) => {
+                all_writable = false;
+                eprintln!(
+                    "  {:<20} {} {} ({})",
+                    purpose,
+                    path,
+                    style("not writable").red().bold(),
+                    e
+                );
+            }
+        }
+    }
+
+    all_writable
+}
+
+/// Checks that the install prefix, the state directory and the cache
+/// directory are writable and have room, each on its own filesystem. A full
+/// /var otherwise breaks state writes after the install itself succeeded.
+/// The cache is optional, so problems with it are only warned about.
+pub fn check_space() -> Result<()> {
+    let cache_dir = cache::directory();
+    let locations = [
+        (
+            "install directory",
+            PathBuf::from(VSCODE_PATH),
+            MIN_INSTALL_FREE,
+            true,
+        ),
+        (
+            "state directory",
+            PathBuf::from(CURRENT_VERSION_DIRECTORY),
+            MIN_STATE_FREE,
+            true,
+        ),
+        ("cache directory", cache_dir, MIN_CACHE_FREE, false),
+    ];
+    for (purpose, dir, min, required) in locations {
+        let result = probe_writable(&dir)
+            .map_err(|e| anyhow!("The {} {} is not writable: {}.", purpose, dir.display(), e))
+            .and_then(|()| {
+                let (free, mount) = free_space(&dir)?;
+                if free < min {
+                    bail!(
+                        "The {} {} is on {}, which has {} free but needs {}.",
+                        purpose,
+                        dir.display(),
+                        mount.display(),
+                        units::Bytes(free),
+                        units::Bytes(min)
+                    );
+                }
+
+                Ok(())
+            });
+        match result {
+            Err(e) if !required => warn!(
+                "{} The release will not be cached, pass --cache-dir to put the cache elsewhere.",
+                e
+            ),
+            r => r?,
+        }
+    }
+
+    Ok(())
+}
+
+/// The space available to unprivileged users where `path` is or would be
+/// created, along with the mount point of that filesystem.
+fn free_space(path: &Path) -> Result<(u64, PathBuf)> {
+    let dir = existing_ancestor(path)?;
+    let c = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c.as_ptr(), &mut stat) } != 0 {
+        bail!(
+            "Failed to query the free space of {}: {}.",
+            dir.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+    let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+
+    Ok((free, mount_point(dir)))
+}
+
+/// The longest mount point containing `dir`, per /proc/self/mounts.
+fn mount_point(dir: &Path) -> PathBuf {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    std::fs::read_to_string("/proc/self/mounts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        // Spaces in mount points are escaped as \040.
+        .map(|m| PathBuf::from(m.replace("\\040", " ")))
+        .filter(|m| dir.starts_with(m))
+        .max_by_key(|m| m.components().count())
+        .unwrap_or_else(|| PathBuf::from("/"))
+}
+
+fn existing_ancestor(path: &Path) -> Result<&Path> {
+    let mut dir = path;
+    while !dir.exists() {
+        dir = dir
+            .parent()
+            .ok_or_else(|| anyhow!("no existing parent directory"))?;
+    }
+
+    Ok(dir)
+}
+
+fn probe_writable(path: &Path) -> Result<()> {
+    // Directories which do not exist yet are created during installation, so
+    // what matters is whether their closest existing ancestor is writable.
+    let dir = existing_ancestor(path)?;
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    let probe = dir.join(format!(".vsdown-perm-check-{}", std::process::id()));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| {
+            if dir == path {
+                anyhow!("{}", e)
+            } else {
+                anyhow!("{} (checked {})", e, dir.display())
+            }
+        })?;
+    std::fs::remove_file(&probe)?;
+
+    Ok(())
+}
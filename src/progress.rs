@@ -0,0 +1,274 @@
+use clap::ArgEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config,
+    events::{self, Event},
+    units,
+};
+
+/// indicatif is updated at most this often, however small the reads are.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How progress of downloads, hashing and unpacking is shown.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    /// A bar on terminals, plain lines otherwise
+    Auto,
+    /// A bar with throughput and ETA
+    Bar,
+    /// A spinner with the amount processed so far
+    Spinner,
+    /// A line at every 10%, for logs which can't handle carriage returns
+    Plain,
+    /// Nothing
+    None,
+}
+
+/// Percentage step between lines of the plain style.
+const PLAIN_STEP: u64 = 10;
+
+fn style() -> Style {
+    match config::get().progress_style {
+        Style::Auto if console::Term::stderr().is_term() => Style::Bar,
+        Style::Auto => Style::Plain,
+        s => s,
+    }
+}
+
+/// Creates a progress bar of `len` bytes rendered in the configured style,
+/// `template` being used for the bar style.
+pub fn bar(len: u64, template: &str) -> ProgressBar {
+    match style() {
+        Style::Bar => {
+            let bar = ProgressBar::new(len);
+            bar.set_style(ProgressStyle::default_bar().template(&units::template(template)));
+            bar
+        }
+        Style::Spinner => {
+            let bar = ProgressBar::new(len);
+            bar.set_style(
+                ProgressStyle::default_spinner().template(&units::template("{spinner} {bytes}")),
+            );
+            bar
+        }
+        // Plain lines and events are emitted by ThrottledProgress, which
+        // needs the length.
+        _ => hidden(len),
+    }
+}
+
+/// A progress bar of `len` which is never drawn, for when something else
+/// writes to the terminal meanwhile. Events and plain lines are still
+/// emitted through it.
+pub fn hidden(len: u64) -> ProgressBar {
+    let bar = ProgressBar::hidden();
+    bar.set_length(len);
+    bar
+}
+
+/// Creates a progress bar of `len` files rendered in the configured style,
+/// for use with `ThrottledProgress::files()`.
+pub fn file_bar(len: u64) -> ProgressBar {
+    match style() {
+        Style::Bar => {
+            let bar = ProgressBar::new(len);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner} [{bar:25.cyan/blue}] {pos}/{len} files (eta {eta})"),
+            );
+            bar
+        }
+        Style::Spinner => {
+            let bar = ProgressBar::new(len);
+            bar.set_style(ProgressStyle::default_spinner().template("{spinner} {pos} files"));
+            bar
+        }
+        _ => {
+            let bar = ProgressBar::hidden();
+            bar.set_length(len);
+            bar
+        }
+    }
+}
+
+/// Accumulates progress increments and forwards them to the bar in batches,
+/// so fast streams with small reads don't spend their time in the bar.
+pub struct ThrottledProgress<'a> {
+    bar: &'a ProgressBar,
+    /// Names the task in `--progress-json` events.
+    task: &'static str,
+    pending: u64,
+    last_flush: Instant,
+    /// The last percentage printed in the plain style, None otherwise.
+    plain: Option<u64>,
+    /// Counting files rather than bytes.
+    files: bool,
+}
+
+impl<'a> ThrottledProgress<'a> {
+    pub fn new(bar: &'a ProgressBar, task: &'static str) -> Self {
+        Self {
+            bar,
+            task,
+            pending: 0,
+            last_flush: Instant::now(),
+            plain: (style() == Style::Plain).then(|| 0),
+            files: false,
+        }
+    }
+
+    /// Like `new()`, for a bar from `file_bar()`.
+    pub fn files(bar: &'a ProgressBar, task: &'static str) -> Self {
+        Self {
+            files: true,
+            ..Self::new(bar, task)
+        }
+    }
+
+    pub fn inc(&mut self, delta: u64) {
+        self.pending += delta;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.pending > 0 {
+            self.bar.inc(self.pending);
+            self.pending = 0;
+        }
+        self.last_flush = Instant::now();
+        if events::enabled() {
+            let len = self.bar.length();
+            events::emit(&Event::Progress {
+                task: self.task.to_string(),
+                done: self.bar.position(),
+                total: (len > 0).then(|| len),
+            });
+        }
+        if let Some(last) = self.plain {
+            let len = self.bar.length();
+            if len == 0 {
+                return;
+            }
+            let percent = (self.bar.position() * 100 / len).min(100);
+            if percent >= last + PLAIN_STEP {
+                let percent = percent - percent % PLAIN_STEP;
+                if self.files {
+                    eprintln!(
+                        "  {}% ({} of {} files)",
+                        percent,
+                        units::Count(self.bar.position()),
+                        units::Count(len)
+                    );
+                } else {
+                    eprintln!(
+                        "  {}% ({} of {})",
+                        percent,
+                        units::Bytes(self.bar.position()),
+                        units::Bytes(len)
+                    );
+                }
+                self.plain = Some(percent);
+            }
+        }
+    }
+}
+
+impl Drop for ThrottledProgress<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Sleeps for `delay`, counting down next to `what` on a spinner where bars
+/// are shown.
+pub fn countdown(what: &str, delay: Duration) {
+    if !matches!(style(), Style::Bar | Style::Spinner) {
+        thread::sleep(delay);
+        return;
+    }
+    let spinner = ProgressBar::new_spinner();
+    let end = Instant::now() + delay;
+    while let Some(left) = end.checked_duration_since(Instant::now()) {
+        if left.is_zero() {
+            break;
+        }
+        let seconds = (left.as_millis() + 999) / 1000;
+        spinner.set_message(format!("{}, retrying in {}s ...", what, seconds));
+        spinner.tick();
+        thread::sleep(left.min(Duration::from_secs(1)));
+    }
+    spinner.finish_and_clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progress_streams::ProgressReader;
+    use std::io::Read;
+
+    /// `left` zero bytes, read `chunk` at a time however large the buffer.
+    struct Trickle {
+        left: u64,
+        chunk: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.chunk).min(self.left as usize);
+            buf[..n].fill(0);
+            self.left -= n as u64;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn bounds_bar_updates() {
+        const LEN: u64 = 64 << 20;
+        let bar = hidden(LEN);
+        let start = Instant::now();
+        let mut throttle = ThrottledProgress::new(&bar, "download");
+        let (mut callbacks, mut updates, mut last) = (0u64, 0u64, 0);
+        let mut reader = ProgressReader::new(
+            Trickle {
+                left: LEN,
+                chunk: 16,
+            },
+            |n: usize| {
+                callbacks += 1;
+                throttle.inc(n as u64);
+                if bar.position() != last {
+                    last = bar.position();
+                    updates += 1;
+                }
+            },
+        );
+        std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+        drop(reader);
+        let elapsed = start.elapsed();
+        drop(throttle);
+
+        assert_eq!(callbacks, LEN / 16);
+        // At most one update per FLUSH_INTERVAL, the rest left to the drop.
+        let bound = (elapsed.as_millis() / FLUSH_INTERVAL.as_millis()) as u64;
+        assert!(updates <= bound, "{} updates in {:?}", updates, elapsed);
+        assert_eq!(bar.position(), LEN);
+    }
+
+    #[test]
+    fn flushes_on_drop() {
+        let bar = hidden(100);
+        {
+            let mut throttle = ThrottledProgress::files(&bar, "remove");
+            throttle.inc(40);
+            throttle.inc(2);
+        }
+        assert_eq!(bar.position(), 42);
+    }
+}
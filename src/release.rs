@@ -0,0 +1,286 @@
+use anyhow::Result;
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{fmt, thread, time::Duration};
+
+use crate::checker;
+use crate::timings::{self, Phase};
+use crate::{debug, http, info, state, units, warn};
+
+/// Metadata of one build, `{version}` being a product version or "latest".
+pub const UPDATE_API_URL: &str =
+    "https://update.code.visualstudio.com/api/versions/{version}/{os}/stable";
+/// Download os values of the Linux archives Microsoft publishes.
+const PLATFORMS: &[&str] = &["linux-x64", "linux-arm64", "linux-armhf"];
+/// Attempts at a lookup answered with 5xx. A 404 is an answer, not retried.
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How many versions `--suggest` lists.
+const SUGGESTIONS: usize = 5;
+
+/// The update service does not publish the requested version, which no
+/// retry will change.
+#[derive(Debug)]
+pub struct NotFound {
+    pub version: String,
+    /// None when no platform has it.
+    pub os: Option<String>,
+    /// The closest published versions, with `--suggest`.
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.os {
+            Some(os) => write!(
+                f,
+                "Visual Studio Code {} is not published upstream for {}.",
+                self.version, os
+            )?,
+            None => write!(
+                f,
+                "Visual Studio Code {} is not published upstream.",
+                self.version
+            )?,
+        }
+        if !self.suggestions.is_empty() {
+            write!(
+                f,
+                " The closest published versions are: {}.",
+                self.suggestions.join(", ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// A build as described by the update API.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateApiRelease {
+    pub url: String,
+    /// The commit the build was made from.
+    pub version: String,
+    pub product_version: String,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    #[serde(default)]
+    pub sha256hash: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ReleaseInfo {
+    pub version: String,
+    pub commit: Option<String>,
+    /// Release date as YYYY-MM-DD (UTC).
+    pub released: Option<String>,
+    pub release_notes: String,
+    pub downloads: Vec<Download>,
+}
+
+#[derive(Serialize)]
+pub struct Download {
+    pub os: String,
+    pub url: String,
+    pub size_bytes: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+/// Looks up the build of `version` for `os`, failing with `NotFound` if
+/// there is none.
+pub fn query(version: &str, os: &str) -> Result<UpdateApiRelease> {
+    timings::measure(Phase::Metadata, || lookup(version, os))
+}
+
+fn lookup(version: &str, os: &str) -> Result<UpdateApiRelease> {
+    let url = UPDATE_API_URL
+        .replace("{version}", version)
+        .replace("{os}", os);
+    let mut attempt = 0;
+    let r = loop {
+        let r = http::send_with_retry_after(http::client()?.get(&url))?;
+        if !r.status().is_server_error() || attempt + 1 >= RETRIES {
+            break r;
+        }
+        let delay = RETRY_DELAY * 2u32.pow(attempt);
+        attempt += 1;
+        warn!(
+            "The update service responded with {}, retrying in {}s ({}/{}) ...",
+            r.status(),
+            delay.as_secs(),
+            attempt,
+            RETRIES - 1
+        );
+        http::discard(r);
+        thread::sleep(delay);
+    };
+    if r.status() == reqwest::StatusCode::NOT_FOUND {
+        http::discard(r);
+        return Err(NotFound {
+            version: version.to_string(),
+            os: Some(os.to_string()),
+            suggestions: Vec::new(),
+        }
+        .into());
+    }
+
+    http::json(r.error_for_status()?)
+}
+
+/// Like `query()`, listing the closest published versions in the error with
+/// `suggest`.
+pub fn resolve(version: &str, os: &str, suggest: bool) -> Result<UpdateApiRelease> {
+    query(version, os).map_err(|e| match e.downcast::<NotFound>() {
+        Ok(mut e) => {
+            if suggest {
+                e.suggestions = suggestions(version);
+            }
+            e.into()
+        }
+        Err(e) => e,
+    })
+}
+
+/// The stable versions closest to `version`, newest first. Lookup failures
+/// only mean no suggestions.
+fn suggestions(version: &str) -> Vec<String> {
+    let versions = match checker::get_stable_versions() {
+        Ok(versions) => versions,
+        Err(e) => {
+            debug!("Could not look up the published versions: {}", e);
+            return Vec::new();
+        }
+    };
+    let wanted = parse_loose(version);
+    // Distance per component, so a typo in the patch level ranks the other
+    // patch releases of the same minor version first.
+    let distance = |parsed: &[u64]| {
+        wanted
+            .iter()
+            .zip(parsed.iter().chain(std::iter::repeat(&0)))
+            .map(|(a, b)| a.abs_diff(*b))
+            .collect::<Vec<_>>()
+    };
+    let mut closest = versions
+        .into_iter()
+        .map(|v| (parse_loose(&v), v))
+        .filter(|(parsed, _)| !parsed.is_empty())
+        .collect::<Vec<_>>();
+    closest.sort_by(|a, b| distance(&a.0).cmp(&distance(&b.0)).then(b.0.cmp(&a.0)));
+    closest.truncate(SUGGESTIONS);
+    closest.sort_by(|a, b| b.0.cmp(&a.0));
+
+    closest.into_iter().map(|(_, v)| v).collect()
+}
+
+/// The leading numeric components of `version`, so typos such as "1.85.x"
+/// still rank by what parses.
+fn parse_loose(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map_while(|p| p.parse::<u64>().ok())
+        .collect()
+}
+
+/// Collects the metadata of `version`, the latest release by default, for
+/// every Linux platform. Only HEAD requests are made for the archives.
+pub fn info(version: Option<&str>, suggest: bool) -> Result<ReleaseInfo> {
+    let version = match version {
+        Some(v) => v.to_string(),
+        None => checker::get_lastest_version()?,
+    };
+    info!("Looking up Visual Studio Code {} ...", version);
+    let mut builds = Vec::new();
+    let mut last_error = None;
+    for os in PLATFORMS {
+        match query(&version, os) {
+            Ok(build) => builds.push((os, build)),
+            Err(e) if e.is::<NotFound>() => debug!("{}", e),
+            Err(e) => {
+                debug!("{}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+    let first = match (builds.first(), last_error) {
+        (Some(first), _) => first,
+        // A failure worth retrying unless the service said no everywhere.
+        (None, Some(e)) => return Err(e),
+        (None, None) => {
+            return Err(NotFound {
+                version: version.clone(),
+                os: None,
+                suggestions: if suggest {
+                    suggestions(&version)
+                } else {
+                    Vec::new()
+                },
+            }
+            .into())
+        }
+    };
+    let commit = first.1.version.clone();
+    let (y, m, d) = state::civil_date(first.1.timestamp / 1000);
+
+    Ok(ReleaseInfo {
+        release_notes: release_notes_url(&version),
+        commit: Some(commit),
+        released: Some(format!("{:04}-{:02}-{:02}", y, m, d)),
+        downloads: builds
+            .into_iter()
+            .map(|(os, build)| {
+                let size = match checker::get_download_info(&build.url) {
+                    Ok((_, size)) => size,
+                    Err(e) => {
+                        debug!("Could not resolve the size of {}: {}", build.url, e);
+                        None
+                    }
+                };
+                Download {
+                    os: os.to_string(),
+                    url: build.url,
+                    size_bytes: size,
+                    sha256: build.sha256hash,
+                }
+            })
+            .collect(),
+        version,
+    })
+}
+
+/// Release notes are published per minor version, e.g. 1.85.2 at
+/// https://code.visualstudio.com/updates/v1_85.
+fn release_notes_url(version: &str) -> String {
+    let mut parts = version.split('.');
+    match (parts.next(), parts.next()) {
+        (Some(major), Some(minor)) => {
+            format!("https://code.visualstudio.com/updates/v{}_{}", major, minor)
+        }
+        _ => "https://code.visualstudio.com/updates".to_string(),
+    }
+}
+
+pub fn print_info(info: &ReleaseInfo) {
+    let or_unknown = |v: &Option<String>| v.clone().unwrap_or_else(|| "unknown".to_string());
+    println!(
+        "{}",
+        style(format!("Visual Studio Code {}", info.version)).bold()
+    );
+    println!("  Commit:        {}", or_unknown(&info.commit));
+    println!("  Released:      {}", or_unknown(&info.released));
+    println!("  Release notes: {}", info.release_notes);
+    for d in &info.downloads {
+        println!("  {}", style(&d.os).bold());
+        println!("    URL:    {}", d.url);
+        println!(
+            "    Size:   {}",
+            d.size_bytes
+                .map(|s| units::Bytes(s).to_string())
+                .unwrap_or_else(|| "unknown".to_string())
+        );
+        println!("    SHA256: {}", or_unknown(&d.sha256));
+    }
+}
@@ -0,0 +1,57 @@
+use console::style;
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::{checker, gc, units, user};
+
+/// User data of Visual Studio Code, relative to the home directory. `remove`
+/// never touches these.
+const USER_DATA: &[&str] = &[".config/Code", ".vscode", ".vscode-cli", ".cache/Code"];
+
+#[derive(Serialize)]
+pub struct Item {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct RemoveReport {
+    pub would_remove: Vec<Item>,
+    pub would_keep: Vec<Item>,
+}
+
+/// What `remove` would delete and the user data it would leave behind.
+pub fn report() -> RemoveReport {
+    let item = |path: PathBuf| Item {
+        size_bytes: gc::disk_usage(&path),
+        path,
+    };
+    let would_keep = match user::target() {
+        Ok(user) => USER_DATA
+            .iter()
+            .filter_map(|p| user.in_home(p).ok())
+            .filter(|p| p.exists())
+            .map(item)
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    RemoveReport {
+        would_remove: checker::managed_paths().into_iter().map(item).collect(),
+        would_keep,
+    }
+}
+
+pub fn print_report(report: &RemoveReport) {
+    println!("{}", style("Would remove:").bold());
+    for i in &report.would_remove {
+        println!("  {} ({})", i.path.display(), units::Bytes(i.size_bytes));
+    }
+    println!("{}", style("Would keep (user data):").bold());
+    if report.would_keep.is_empty() {
+        println!("  (none found)");
+    }
+    for i in &report.would_keep {
+        println!("  {} ({})", i.path.display(), units::Bytes(i.size_bytes));
+    }
+}
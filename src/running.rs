@@ -0,0 +1,121 @@
+use anyhow::Result;
+use console::style;
+use std::{
+    fmt::{self, Display},
+    path::Path,
+};
+
+use crate::{checker::INSTALL_DIR, logger, user, warn};
+
+/// A process running an executable of the installed tree.
+pub struct Instance {
+    pub pid: u32,
+    pub user: String,
+}
+
+/// Returned by `guard()` when it refused to go on, so that the caller can
+/// exit with a status of its own.
+#[derive(Debug)]
+pub struct EditorRunning {
+    pub what: String,
+    pub pids: Vec<u32>,
+    pub flag: &'static str,
+}
+
+impl Display for EditorRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pids = self.pids.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        write!(
+            f,
+            "Not {} while Visual Studio Code is running (PID {}). Close it first, or pass {} to go on anyway.",
+            self.what,
+            pids.join(", "),
+            self.flag
+        )
+    }
+}
+
+impl std::error::Error for EditorRunning {}
+
+/// Every process whose executable lies in the installed tree, found through
+/// /proc. Processes of other users are only visible to root.
+pub fn instances() -> Vec<Instance> {
+    let dir = match std::fs::read_dir("/proc") {
+        Ok(dir) => dir,
+        Err(_) => return Vec::new(),
+    };
+    let mut found = dir
+        .filter_map(|i| i.ok())
+        .filter_map(|i| i.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| *pid != std::process::id() && runs_installed_tree(*pid))
+        .map(|pid| Instance {
+            pid,
+            user: owner(pid).unwrap_or_else(|| "?".to_string()),
+        })
+        .collect::<Vec<_>>();
+    found.sort_by_key(|i| i.pid);
+
+    found
+}
+
+fn runs_installed_tree(pid: u32) -> bool {
+    // The link reads "... (deleted)" once an upgrade replaced the file, which
+    // still starts with the install dir.
+    match std::fs::read_link(format!("/proc/{}/exe", pid)) {
+        Ok(exe) => exe.starts_with(INSTALL_DIR),
+        // Without access to exe, fall back to argv[0].
+        Err(_) => std::fs::read(format!("/proc/{}/cmdline", pid))
+            .ok()
+            .and_then(|c| {
+                let argv0 = c.split(|b| *b == 0).next()?.to_vec();
+                String::from_utf8(argv0).ok()
+            })
+            .map_or(false, |argv0| Path::new(&argv0).starts_with(INSTALL_DIR)),
+    }
+}
+
+fn owner(pid: u32) -> Option<String> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let uid = status
+        .lines()
+        .find_map(|l| l.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse::<libc::uid_t>()
+        .ok()?;
+
+    Some(user::by_uid(uid).map_or_else(|| uid.to_string(), |u| u.name))
+}
+
+/// Refuses `what` (e.g. "upgrading") while the editor is running, which a
+/// live session may not survive. `force`, set by the command line `flag`,
+/// goes on with a warning; otherwise someone at the terminal is asked.
+pub fn guard(what: &str, force: bool, flag: &'static str) -> Result<()> {
+    let found = instances();
+    if found.is_empty() {
+        return Ok(());
+    }
+    for i in &found {
+        warn!(
+            "Visual Studio Code is running as PID {} (user {}).",
+            i.pid, i.user
+        );
+    }
+    if force {
+        warn!("Going on {} anyway, running instances may misbehave.", what);
+        return Ok(());
+    }
+    if logger::confirm(&format!(
+        "Go on {} anyway? Running instances may misbehave.",
+        what
+    )) {
+        return Ok(());
+    }
+
+    Err(EditorRunning {
+        what: what.to_string(),
+        pids: found.iter().map(|i| i.pid).collect(),
+        flag,
+    }
+    .into())
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::user;
+
+/// Where desktop metadata files go when installed system-wide.
+pub const SYSTEM_DATA_DIR: &str = "/usr/share";
+
+/// Where the desktop entry, icon and AppStream metadata are installed. The
+/// editor tree itself always lives in /usr/lib/vscode.
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Under /usr/share, for every user
+    System,
+    /// Under ~/.local/share of the user running vsdown, that of $SUDO_USER under sudo
+    User,
+}
+
+/// The data directory metadata of `scope` goes to.
+pub fn data_dir(scope: Scope) -> Result<PathBuf> {
+    match scope {
+        Scope::System => Ok(PathBuf::from(SYSTEM_DATA_DIR)),
+        Scope::User => user_data_dir(),
+    }
+}
+
+/// `$XDG_DATA_HOME` of the target user, only taken from the environment
+/// when that is vsdown's own rather than that of root under sudo.
+fn user_data_dir() -> Result<PathBuf> {
+    let user = user::target()?;
+    if !user.sudo {
+        if let Some(dir) = std::env::var_os("XDG_DATA_HOME").map(PathBuf::from) {
+            // The spec says to ignore relative paths.
+            if dir.is_absolute() {
+                return Ok(dir);
+            }
+        }
+    }
+
+    user.in_home(".local/share")
+}
+
+/// Creates `dir` and its missing parents. Below a user data directory they
+/// are handed to the owner of the closest existing ancestor, so running under
+/// sudo does not leave root-owned directories in the user's home.
+pub fn create_dir(dir: &Path, data_dir: &Path) -> Result<()> {
+    if data_dir == Path::new(SYSTEM_DATA_DIR) {
+        std::fs::create_dir_all(dir)?;
+        return Ok(());
+    }
+    let mut missing = Vec::new();
+    let mut p = dir;
+    while !p.exists() {
+        missing.push(p.to_path_buf());
+        p = match p.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+    let owner = user::owner_of(p)?;
+    std::fs::create_dir_all(dir)?;
+    for p in missing {
+        std::os::unix::fs::chown(&p, Some(owner.uid), Some(owner.gid))?;
+    }
+
+    Ok(())
+}
+
+/// Hands `p`, a file just installed below a user data directory, to the
+/// owner of the directory it is in.
+pub fn adopt(p: &Path, data_dir: &Path) -> Result<()> {
+    if data_dir == Path::new(SYSTEM_DATA_DIR) {
+        return Ok(());
+    }
+    let parent = p.parent().unwrap_or(data_dir);
+    let owner = user::owner_of(parent)?;
+    std::os::unix::fs::lchown(p, Some(owner.uid), Some(owner.gid))?;
+
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{cmp::Ordering, env::consts::ARCH, path::Path};
+
+use crate::checker::{
+    self, CheckOptions, InstallOptions, UpdateStatus, BIN_PATH, CURRENT_VERSION_DIRECTORY,
+};
+use crate::exclude::Exclude;
+use crate::hooks::{self, Stage};
+use crate::{info, warn};
+
+/// The standalone VS Code CLI, which serves remote tunnels, is kept apart
+/// from the desktop installation so either can be installed alone.
+pub const SERVER_DIR: &str = "/usr/lib/vscode-server";
+const SERVER_LINK_NAME: &str = "code-tunnel";
+const SERVER_VERSION_FILENAME: &str = "server_version";
+
+fn server_os() -> Result<&'static str> {
+    let os = match ARCH {
+        "x86_64" => "cli-alpine-x64",
+        "aarch64" => "cli-alpine-arm64",
+        "arm" => "cli-linux-armhf",
+        _ => bail!("Unfortunately, the Visual Studio Code CLI is not available for your device's architecture."),
+    };
+
+    Ok(os)
+}
+
+fn version_path() -> String {
+    format!("{}{}", CURRENT_VERSION_DIRECTORY, SERVER_VERSION_FILENAME)
+}
+
+pub fn installed_version() -> Option<String> {
+    std::fs::read_to_string(version_path())
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Whether the CLI tree or its recorded version is present.
+pub fn is_installed() -> bool {
+    Path::new(SERVER_DIR).exists() || Path::new(&version_path()).exists()
+}
+
+/// Compares the installed CLI with the latest release. The CLI is released
+/// along with the desktop editor, so both share the version check.
+pub fn check_update(_options: &CheckOptions) -> Result<UpdateStatus> {
+    let latest = checker::latest_version()?;
+    let latest_version = latest.version;
+    let current_version = installed_version();
+    let ordering = current_version
+        .as_deref()
+        .and_then(|c| checker::compare_versions(c, &latest_version));
+
+    Ok(UpdateStatus {
+        update_available: current_version.is_some()
+            && match ordering {
+                Some(o) => o == Ordering::Less,
+                None => current_version.as_deref() != Some(latest_version.as_str()),
+            },
+        ahead: ordering == Some(Ordering::Greater),
+        current_version,
+        latest_version,
+        version_source: latest.source,
+        current_commit: None,
+        latest_commit: None,
+        url: None,
+        size_bytes: None,
+        explain: None,
+        // The CLI is not cached, so there is nothing to roll back to.
+        retained: Vec::new(),
+    })
+}
+
+/// Installs the CLI to `SERVER_DIR` with a `code-tunnel` launcher, unless the
+/// latest version already is (or `force` is set).
+pub fn install(options: &InstallOptions, force: bool) -> Result<()> {
+    let os = server_os()?;
+    if !force && options.origin().is_none() && !options.offline {
+        let latest = checker::get_lastest_version()?;
+        if installed_version().as_deref() == Some(latest.as_str()) {
+            info!(
+                "You have already installed the latest Visual Studio Code CLI ({})!",
+                latest
+            );
+            return Ok(());
+        }
+    }
+    let checker::Release { buf, version, .. } = checker::fetch_release(os, options)?;
+    let env = [
+        ("VSDOWN_VERSION", version.as_str()),
+        ("VSDOWN_INSTALL_DIR", SERVER_DIR),
+        ("VSDOWN_VARIANT", "server"),
+    ];
+    hooks::run(Stage::PreInstall, &env)?;
+
+    let staging = format!("{}.new", SERVER_DIR);
+    if Path::new(&staging).exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    checker::unpack(
+        &buf,
+        Path::new(&staging),
+        options.unpack_jobs,
+        &Exclude::default(),
+        None,
+    )?;
+    if !Path::new(&staging).join("code").is_file() {
+        let _ = std::fs::remove_dir_all(&staging);
+        bail!("The downloaded Visual Studio Code CLI archive does not contain a code executable.");
+    }
+    remove_files()?;
+    std::fs::rename(&staging, SERVER_DIR)?;
+    let link = Path::new(BIN_PATH).join(SERVER_LINK_NAME);
+    std::os::unix::fs::symlink(Path::new(SERVER_DIR).join("code"), &link).map_err(|e| {
+        anyhow!(
+            "Could not create symlink {} for the Visual Studio Code CLI! {}",
+            link.display(),
+            e
+        )
+    })?;
+    std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+    std::fs::write(version_path(), &version)?;
+    hooks::run(Stage::PostInstall, &env)?;
+    info!(
+        "Visual Studio Code CLI {} has been installed, run `{} tunnel` to start a remote tunnel.",
+        version, SERVER_LINK_NAME
+    );
+
+    Ok(())
+}
+
+pub fn remove() -> Result<()> {
+    let version = installed_version().unwrap_or_default();
+    let env = [
+        ("VSDOWN_VERSION", version.as_str()),
+        ("VSDOWN_INSTALL_DIR", SERVER_DIR),
+        ("VSDOWN_VARIANT", "server"),
+    ];
+    hooks::run(Stage::PreRemove, &env)?;
+    remove_files()?;
+    if Path::new(&version_path()).exists() {
+        std::fs::remove_file(version_path())?;
+    }
+    hooks::run(Stage::PostRemove, &env)?;
+
+    Ok(())
+}
+
+fn remove_files() -> Result<()> {
+    let link = Path::new(BIN_PATH).join(SERVER_LINK_NAME);
+    match std::fs::read_link(&link) {
+        Ok(target) if target.starts_with(SERVER_DIR) => std::fs::remove_file(&link)?,
+        Ok(_) => {
+            warn!(
+                "{} does not point to the Visual Studio Code CLI, leaving it alone.",
+                link.display()
+            );
+        }
+        Err(_) => {}
+    }
+    if Path::new(SERVER_DIR).exists() {
+        std::fs::remove_dir_all(SERVER_DIR)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,855 @@
+use anyhow::{anyhow, bail, Result};
+use clap::ArgEnum;
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::checker::{CURRENT_VERSION_DIRECTORY, DEFAULT_BIN_NAME, INSTALL_DIR};
+use crate::scope::{Scope, SYSTEM_DATA_DIR};
+use crate::timings::{self, Phase};
+use crate::{config, debug, netfs, warn};
+
+const LAST_SUCCESS_FILENAME: &str = ".last-success";
+const ORIGIN_FILENAME: &str = "origin";
+const REMOTE_VERSIONS_FILENAME: &str = "remote_versions.json";
+const LAST_CHECK_FILENAME: &str = "last-check.json";
+const IN_PROGRESS_FILENAME: &str = "in-progress.json";
+const REMOVAL_FILENAME: &str = "removal.json";
+const PENDING_SWAP_FILENAME: &str = "pending-swap.json";
+const NO_SANDBOX_FILENAME: &str = "no-sandbox";
+const BIN_NAME_FILENAME: &str = "bin_name";
+const CLI_NAME_FILENAME: &str = "cli_name";
+const COEXIST_FILENAME: &str = "coexist";
+const METADATA_DIR_FILENAME: &str = "metadata_dir";
+const CURRENT_VERSION_FILENAME: &str = "current_version";
+const STATE_FILENAME: &str = "state.json";
+/// What older vsdown versions recorded as the version when none was
+/// installed, read as no version.
+const NOT_INSTALLED_SENTINEL: &str = "None";
+/// Bumped whenever a field of `State` changes meaning.
+const STATE_FORMAT_VERSION: u32 = 1;
+
+/// Where the state of the installed release is recorded.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A single state.json, migrated from the legacy files on first use
+    Json,
+    /// current_version, bin_name, cli_name, no-sandbox, coexist, metadata_dir and origin, for older vsdown
+    Legacy,
+}
+
+/// Everything recorded about the installed release, kept in state.json.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct State {
+    /// `STATE_FORMAT_VERSION` of the vsdown which wrote the file.
+    pub format: u32,
+    pub channel: String,
+    /// None once removed.
+    pub version: Option<String>,
+    pub install_dir: String,
+    /// None when `--no-symlink` left the launcher to the administrator.
+    pub bin_name: Option<String>,
+    /// Name of the symlink to the CLI helper in `BIN_PATH`, None without
+    /// `--cli-symlink`.
+    pub cli_name: Option<String>,
+    /// Whether chrome-sandbox is set up, and so the launcher is a symlink
+    /// rather than the --no-sandbox wrapper.
+    pub sandbox: bool,
+    /// Where the release came from when it was not a regular download.
+    pub origin: Option<String>,
+    /// Whether the launcher and desktop files were installed under names of
+    /// their own, next to a packaged Visual Studio Code.
+    pub coexist: bool,
+    /// Where the desktop metadata was installed: /usr/share, or a user's
+    /// data directory with `--metadata-scope user`.
+    pub metadata_dir: String,
+    /// When `version` was installed, in Unix seconds.
+    pub installed_at: Option<u64>,
+    /// When `verify` last found the installation intact, in Unix seconds.
+    pub last_verified: Option<u64>,
+    /// When the file was last written, in Unix seconds.
+    pub updated_at: u64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            format: STATE_FORMAT_VERSION,
+            channel: "desktop".to_string(),
+            version: None,
+            install_dir: INSTALL_DIR.to_string(),
+            bin_name: Some(DEFAULT_BIN_NAME.to_string()),
+            cli_name: None,
+            sandbox: true,
+            origin: None,
+            coexist: false,
+            metadata_dir: SYSTEM_DATA_DIR.to_string(),
+            installed_at: None,
+            last_verified: None,
+            updated_at: 0,
+        }
+    }
+}
+
+fn state_path() -> PathBuf {
+    Path::new(CURRENT_VERSION_DIRECTORY).join(STATE_FILENAME)
+}
+
+fn legacy_path(name: &str) -> PathBuf {
+    Path::new(CURRENT_VERSION_DIRECTORY).join(name)
+}
+
+/// The recorded state. With the json format, a missing state.json is
+/// migrated from the legacy files, which are still read when it cannot be.
+pub fn load() -> State {
+    if config::get().state_format == Format::Legacy {
+        return legacy();
+    }
+    match read(state_path()) {
+        Ok(b) => match serde_json::from_slice::<State>(&b) {
+            Ok(state) if state.format <= STATE_FORMAT_VERSION => return without_sentinel(state),
+            Ok(state) => warn!(
+                "{} was written in state format {} by a newer vsdown, reading the legacy files instead.",
+                state_path().display(),
+                state.format
+            ),
+            Err(e) => warn!(
+                "Failed to parse {}, reading the legacy files instead: {}",
+                state_path().display(),
+                e
+            ),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let state = legacy();
+            if legacy_path(CURRENT_VERSION_FILENAME).exists() {
+                match save(&state) {
+                    Ok(()) => debug!("Migrated the legacy state files to {}.", state_path().display()),
+                    Err(e) => debug!("Could not migrate the legacy state files: {}", e),
+                }
+            }
+            return state;
+        }
+        Err(e) => warn!(
+            "Failed to read {}, reading the legacy files instead: {}",
+            state_path().display(),
+            e
+        ),
+    }
+
+    legacy()
+}
+
+/// The version in a legacy current_version file, which has stray
+/// whitespace, and none in place of `NOT_INSTALLED_SENTINEL`.
+fn legacy_version(s: &str) -> Option<String> {
+    Some(s.replace('\n', "").replace(' ', ""))
+        .filter(|s| !s.is_empty() && s != NOT_INSTALLED_SENTINEL)
+}
+
+fn without_sentinel(mut state: State) -> State {
+    state.version = state.version.filter(|v| v != NOT_INSTALLED_SENTINEL);
+
+    state
+}
+
+/// Whether state.json has taken over from the legacy files, which are then
+/// no longer read.
+pub fn migrated() -> bool {
+    config::get().state_format == Format::Json
+        && read(state_path())
+            .ok()
+            .and_then(|b| serde_json::from_slice::<State>(&b).ok())
+            .map_or(false, |s| s.format <= STATE_FORMAT_VERSION)
+}
+
+/// The state as recorded by vsdown before state.json.
+fn legacy() -> State {
+    let version = read_to_string(legacy_path(CURRENT_VERSION_FILENAME))
+        .ok()
+        .and_then(|s| legacy_version(&s));
+    let bin_name = match read_to_string(legacy_path(BIN_NAME_FILENAME)) {
+        Ok(s) if s.trim().is_empty() => None,
+        Ok(s) => Some(s.trim().to_string()),
+        Err(_) => Some(DEFAULT_BIN_NAME.to_string()),
+    };
+    let cli_name = read_to_string(legacy_path(CLI_NAME_FILENAME))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+    let origin = read_to_string(legacy_path(ORIGIN_FILENAME))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+    let metadata_dir = read_to_string(legacy_path(METADATA_DIR_FILENAME))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| SYSTEM_DATA_DIR.to_string());
+
+    State {
+        version,
+        bin_name,
+        cli_name,
+        sandbox: !legacy_path(NO_SANDBOX_FILENAME).exists(),
+        origin,
+        coexist: legacy_path(COEXIST_FILENAME).exists(),
+        metadata_dir,
+        ..Default::default()
+    }
+}
+
+fn save(state: &State) -> Result<()> {
+    let mut state = state.clone();
+    state.format = STATE_FORMAT_VERSION;
+    state.updated_at = now();
+    write_atomic(&state_path(), &serde_json::to_vec_pretty(&state)?)
+        .map_err(|e| anyhow!("Failed to write {}: {}", state_path().display(), e))
+}
+
+/// Changes the recorded state with `f`, writing it back atomically.
+fn update<F: FnOnce(&mut State)>(f: F) -> Result<()> {
+    if let Some(format) = read(state_path())
+        .ok()
+        .and_then(|b| serde_json::from_slice::<State>(&b).ok())
+        .map(|s| s.format)
+        .filter(|f| *f > STATE_FORMAT_VERSION)
+    {
+        bail!(
+            "{} was written in state format {} by a newer vsdown, refusing to overwrite it.",
+            state_path().display(),
+            format
+        );
+    }
+    let mut state = load();
+    f(&mut state);
+
+    save(&state)
+}
+
+/// The version recorded as installed, None when nothing is.
+pub fn recorded_version() -> Option<String> {
+    load().version
+}
+
+/// The file `recorded_version()` reads.
+pub fn version_source() -> String {
+    match config::get().state_format {
+        Format::Json => state_path().display().to_string(),
+        Format::Legacy => legacy_path(CURRENT_VERSION_FILENAME).display().to_string(),
+    }
+}
+
+/// Records `version` as installed.
+pub fn write_version(version: &str) -> Result<()> {
+    match config::get().state_format {
+        Format::Json => update(|s| {
+            if s.version.as_deref() != Some(version) {
+                s.installed_at = Some(now());
+                s.last_verified = None;
+            }
+            s.version = Some(version.to_string());
+        }),
+        Format::Legacy => {
+            std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+            std::fs::write(legacy_path(CURRENT_VERSION_FILENAME), version)?;
+
+            Ok(())
+        }
+    }
+}
+
+/// Records that `verify` found the installation intact just now. Only the
+/// json format tracks this.
+pub fn write_last_verified() -> Result<()> {
+    if config::get().state_format == Format::Legacy {
+        return Ok(());
+    }
+
+    update(|s| s.last_verified = Some(now()))
+}
+
+/// Forgets the installed version, keeping the choices later installs
+/// reuse. The legacy version file goes as well, so an older vsdown does not
+/// take it for an install.
+pub fn clear_version() -> Result<()> {
+    if config::get().state_format == Format::Json {
+        update(|s| {
+            s.version = None;
+            s.installed_at = None;
+        })?;
+    }
+    let p = legacy_path(CURRENT_VERSION_FILENAME);
+    if p.is_file() {
+        std::fs::remove_file(p)?;
+    }
+
+    Ok(())
+}
+
+/// The legacy version file, which `clear_version()` removes if present.
+pub fn legacy_version_path() -> PathBuf {
+    legacy_path(CURRENT_VERSION_FILENAME)
+}
+
+#[derive(Serialize, Deserialize)]
+struct RemoteVersions {
+    /// When the list was fetched, in Unix seconds.
+    timestamp: u64,
+    versions: Vec<String>,
+}
+
+/// The outcome of the last `vsdown check`, for `check --cached-only`.
+#[derive(Serialize, Deserialize)]
+pub struct LastCheck {
+    /// When the check ran, in Unix seconds.
+    pub timestamp: u64,
+    /// The version the check found installed, None when there was none.
+    pub installed: Option<String>,
+    pub latest: String,
+    pub update_available: bool,
+}
+
+/// How far an install got, in order.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstallStage {
+    /// The archive is available, in the cache unless it came from a URL.
+    Downloaded,
+    /// The archive is unpacked next to the installed tree.
+    Extracted,
+    /// The installed tree has been replaced with the unpacked one.
+    Replaced,
+    /// The launcher, desktop entry and icon are in place.
+    Linked,
+}
+
+impl InstallStage {
+    pub fn description(&self) -> &'static str {
+        match self {
+            InstallStage::Downloaded => "after downloading the release",
+            InstallStage::Extracted => "after unpacking the release",
+            InstallStage::Replaced => "after replacing the installed files",
+            InstallStage::Linked => "after linking the launcher and desktop files",
+        }
+    }
+}
+
+/// The install being carried out by process `pid`.
+#[derive(Serialize, Deserialize)]
+pub struct InProgress {
+    pub version: String,
+    pub os: String,
+    pub origin: Option<String>,
+    pub stage: InstallStage,
+    pub pid: u32,
+    /// SHA-256 of the archive being unpacked, and how many of its entries
+    /// are, so an interrupted extraction can continue where it stopped.
+    #[serde(default)]
+    pub archive_sha256: Option<String>,
+    #[serde(default)]
+    pub extracted_entries: u64,
+    /// What the install was asked to do, for resuming it the same way.
+    /// Missing from journals of older vsdown versions.
+    #[serde(default)]
+    pub options: Option<InstallSettings>,
+}
+
+/// The options of an install which decide what it installs and how, as
+/// resuming it has to keep them.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct InstallSettings {
+    /// `--exclude` patterns, on top of the configured ones.
+    pub exclude: Vec<String>,
+    pub skip_compat_check: bool,
+    pub sandbox_setup: Option<bool>,
+    pub bin_name: Option<String>,
+    pub no_symlink: bool,
+    pub cli_name: Option<String>,
+    pub no_cli_symlink: bool,
+    pub coexist: bool,
+    pub metadata_scope: Option<Scope>,
+    pub unpack_jobs: usize,
+}
+
+fn in_progress_path() -> PathBuf {
+    Path::new(CURRENT_VERSION_DIRECTORY).join(IN_PROGRESS_FILENAME)
+}
+
+/// Records that this process reached `stage` installing `version`. The
+/// extraction progress and the install settings are kept past
+/// `InstallStage::Downloaded`.
+pub fn write_in_progress(
+    version: &str,
+    os: &str,
+    origin: Option<&str>,
+    stage: InstallStage,
+) -> Result<()> {
+    std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+    let previous = read(in_progress_path())
+        .ok()
+        .and_then(|s| serde_json::from_slice::<InProgress>(&s).ok())
+        .filter(|op| stage != InstallStage::Downloaded && op.version == version && op.os == os);
+    let op = InProgress {
+        version: version.to_string(),
+        os: os.to_string(),
+        origin: origin.map(|s| s.to_string()),
+        stage,
+        pid: std::process::id(),
+        archive_sha256: previous.as_ref().and_then(|op| op.archive_sha256.clone()),
+        extracted_entries: previous.as_ref().map_or(0, |op| op.extracted_entries),
+        options: previous.and_then(|op| op.options),
+    };
+    write_atomic(&in_progress_path(), &serde_json::to_vec(&op)?)
+}
+
+/// Records that the first `entries` entries of the archive hashing to
+/// `sha256` are unpacked.
+pub fn write_extract_progress(sha256: &str, entries: u64) -> Result<()> {
+    let mut op = serde_json::from_slice::<InProgress>(&read(in_progress_path())?)?;
+    op.archive_sha256 = Some(sha256.to_string());
+    op.extracted_entries = entries;
+
+    write_atomic(&in_progress_path(), &serde_json::to_vec(&op)?)
+}
+
+/// Records the settings the install in progress was started with.
+pub fn write_install_settings(settings: &InstallSettings) -> Result<()> {
+    let mut op = serde_json::from_slice::<InProgress>(&read(in_progress_path())?)?;
+    op.options = Some(settings.clone());
+
+    write_atomic(&in_progress_path(), &serde_json::to_vec(&op)?)
+}
+
+/// Replaces `p` with `contents` through a temporary file, so readers never
+/// see it half written. Both the file and the rename are synced before this
+/// returns, so a power cut cannot leave `p` empty or pointing at the old one.
+pub fn write_atomic(p: &Path, contents: &[u8]) -> Result<()> {
+    let parent = match p.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(parent)?;
+    // Per process, so two writers never interleave in the same temporary.
+    let mut tmp = p.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    timings::measure(Phase::StateWrite, || {
+        netfs::retry_stale(|| {
+            let mut f = File::create(&tmp)?;
+            f.write_all(contents)?;
+            f.sync_all()
+        })?;
+        netfs::retry_stale(|| std::fs::rename(&tmp, p))?;
+        netfs::retry_stale(|| File::open(parent)?.sync_all())
+    })?;
+    timings::add_bytes(Phase::StateWrite, contents.len() as u64);
+
+    Ok(())
+}
+
+/// `std::fs::read`, retried on ESTALE for state directories on NFS.
+fn read<P: AsRef<Path>>(p: P) -> std::io::Result<Vec<u8>> {
+    netfs::retry_stale(|| std::fs::read(p.as_ref()))
+}
+
+fn read_to_string<P: AsRef<Path>>(p: P) -> std::io::Result<String> {
+    netfs::retry_stale(|| std::fs::read_to_string(p.as_ref()))
+}
+
+/// The install recorded in progress, if the process doing it has gone away.
+pub fn interrupted_install() -> Option<InProgress> {
+    let s = read(format!(
+        "{}{}",
+        CURRENT_VERSION_DIRECTORY, IN_PROGRESS_FILENAME
+    ))
+    .ok()?;
+    let op = serde_json::from_slice::<InProgress>(&s).ok()?;
+    if op.pid != std::process::id() && Path::new(&format!("/proc/{}", op.pid)).exists() {
+        return None;
+    }
+
+    Some(op)
+}
+
+pub fn clear_in_progress() -> Result<()> {
+    let p = format!("{}{}", CURRENT_VERSION_DIRECTORY, IN_PROGRESS_FILENAME);
+    if Path::new(&p).exists() {
+        std::fs::remove_file(p)?;
+    }
+
+    Ok(())
+}
+
+/// An unpacked release which could not replace the installed tree, and
+/// the choices of the install which unpacked it, for `vsdown finish-upgrade`.
+#[derive(Serialize, Deserialize)]
+pub struct PendingSwap {
+    pub version: String,
+    pub os: String,
+    pub origin: Option<String>,
+    pub sha256: Option<String>,
+    pub hash_source: String,
+    pub sandbox: bool,
+    pub coexist: bool,
+    pub data_dir: PathBuf,
+    pub bin_name: Option<String>,
+    #[serde(default)]
+    pub cli_name: Option<String>,
+    /// Excluded files and their sizes, for the manifest.
+    pub omitted: BTreeMap<String, u64>,
+}
+
+pub fn write_pending_swap(pending: &PendingSwap) -> Result<()> {
+    write_atomic(
+        &Path::new(CURRENT_VERSION_DIRECTORY).join(PENDING_SWAP_FILENAME),
+        &serde_json::to_vec(pending)?,
+    )
+}
+
+pub fn pending_swap() -> Option<PendingSwap> {
+    let s = read(Path::new(CURRENT_VERSION_DIRECTORY).join(PENDING_SWAP_FILENAME)).ok()?;
+
+    serde_json::from_slice(&s).ok()
+}
+
+pub fn clear_pending_swap() -> Result<()> {
+    let p = Path::new(CURRENT_VERSION_DIRECTORY).join(PENDING_SWAP_FILENAME);
+    if p.exists() {
+        std::fs::remove_file(p)?;
+    }
+
+    Ok(())
+}
+
+/// How far process `pid` got removing the tree at `path`.
+#[derive(Serialize, Deserialize)]
+pub struct Removal {
+    pub path: String,
+    /// Files and symlinks in the tree when the removal started.
+    pub total: u64,
+    pub removed: u64,
+    pub pid: u32,
+}
+
+pub fn write_removal(removal: &Removal) -> Result<()> {
+    write_atomic(
+        &Path::new(CURRENT_VERSION_DIRECTORY).join(REMOVAL_FILENAME),
+        &serde_json::to_vec(removal)?,
+    )
+}
+
+/// The removal recorded by `write_removal()`, if the process doing it has
+/// gone away.
+pub fn interrupted_removal() -> Option<Removal> {
+    let s = read(Path::new(CURRENT_VERSION_DIRECTORY).join(REMOVAL_FILENAME)).ok()?;
+    let removal = serde_json::from_slice::<Removal>(&s).ok()?;
+    if removal.pid != std::process::id() && Path::new(&format!("/proc/{}", removal.pid)).exists() {
+        return None;
+    }
+
+    Some(removal)
+}
+
+pub fn clear_removal() -> Result<()> {
+    let p = Path::new(CURRENT_VERSION_DIRECTORY).join(REMOVAL_FILENAME);
+    if p.exists() {
+        std::fs::remove_file(p)?;
+    }
+
+    Ok(())
+}
+
+/// Records whether the installed release had its sandbox set up, so later
+/// upgrades keep the choice.
+pub fn write_sandbox_setup(enabled: bool) -> Result<()> {
+    if config::get().state_format == Format::Json {
+        return update(|s| s.sandbox = enabled);
+    }
+    let p = legacy_path(NO_SANDBOX_FILENAME);
+    if enabled {
+        if p.exists() {
+            std::fs::remove_file(p)?;
+        }
+    } else {
+        std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+        std::fs::write(p, "")?;
+    }
+
+    Ok(())
+}
+
+pub fn sandbox_setup_enabled() -> bool {
+    load().sandbox
+}
+
+/// Records whether the installed release coexists with a packaged one.
+pub fn write_coexist(coexist: bool) -> Result<()> {
+    if config::get().state_format == Format::Json {
+        return update(|s| s.coexist = coexist);
+    }
+    let p = legacy_path(COEXIST_FILENAME);
+    if coexist {
+        std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+        std::fs::write(p, "")?;
+    } else if p.exists() {
+        std::fs::remove_file(p)?;
+    }
+
+    Ok(())
+}
+
+pub fn coexist() -> bool {
+    load().coexist
+}
+
+/// Records the data directory the desktop metadata is installed below.
+pub fn write_metadata_dir(dir: &Path) -> Result<()> {
+    let dir = dir.display().to_string();
+    if config::get().state_format == Format::Json {
+        return update(|s| s.metadata_dir = dir);
+    }
+    let p = legacy_path(METADATA_DIR_FILENAME);
+    if dir == SYSTEM_DATA_DIR {
+        if p.exists() {
+            std::fs::remove_file(p)?;
+        }
+    } else {
+        std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+        std::fs::write(p, dir)?;
+    }
+
+    Ok(())
+}
+
+/// The directory recorded by `write_metadata_dir()`, /usr/share when
+/// nothing was recorded.
+pub fn metadata_dir() -> PathBuf {
+    PathBuf::from(load().metadata_dir)
+}
+
+/// Records the name of the launcher installed in `BIN_PATH`, None when
+/// `--no-symlink` left it to the administrator.
+pub fn write_bin_name(name: Option<&str>) -> Result<()> {
+    if config::get().state_format == Format::Json {
+        return update(|s| s.bin_name = name.map(|n| n.to_string()));
+    }
+    std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+    std::fs::write(legacy_path(BIN_NAME_FILENAME), name.unwrap_or_default())?;
+
+    Ok(())
+}
+
+/// The launcher name recorded by `write_bin_name()`, `DEFAULT_BIN_NAME`
+/// when nothing was recorded.
+pub fn bin_name() -> Option<String> {
+    load().bin_name
+}
+
+/// Records the name of the CLI helper symlink in `BIN_PATH`, None when
+/// there is none.
+pub fn write_cli_name(name: Option<&str>) -> Result<()> {
+    if config::get().state_format == Format::Json {
+        return update(|s| s.cli_name = name.map(|n| n.to_string()));
+    }
+    let p = legacy_path(CLI_NAME_FILENAME);
+    match name {
+        Some(name) => {
+            std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+            std::fs::write(p, name)?;
+        }
+        None if p.exists() => std::fs::remove_file(p)?,
+        None => (),
+    }
+
+    Ok(())
+}
+
+pub fn cli_name() -> Option<String> {
+    load().cli_name
+}
+
+/// Records a successful install of `version` in the success marker, a
+/// shell-sourceable file of `VERSION=`, `TIMESTAMP=` (Unix seconds), `SHA256=`,
+/// `HASH_SOURCE=` and `COMMIT=` lines, `HASH_SOURCE` telling what the archive
+/// with that hash was verified against. `COMMIT` is empty if the build did not
+/// name its commit.
+pub fn write_success_marker(
+    version: &str,
+    sha256: &str,
+    hash_source: &str,
+    commit: Option<&str>,
+) -> Result<()> {
+    let marker = format!(
+        "VERSION={}\nTIMESTAMP={}\nSHA256={}\nHASH_SOURCE={}\nCOMMIT={}\n",
+        version,
+        now(),
+        sha256,
+        hash_source,
+        commit.unwrap_or_default()
+    );
+
+    write_atomic(
+        &Path::new(CURRENT_VERSION_DIRECTORY).join(LAST_SUCCESS_FILENAME),
+        marker.as_bytes(),
+    )
+}
+
+/// The version recorded in the success marker, if there is one.
+pub fn success_marker_version() -> Option<String> {
+    success_marker_field("VERSION")
+}
+
+/// The commit recorded in the success marker, if it named one.
+pub fn success_marker_commit() -> Option<String> {
+    success_marker_field("COMMIT").filter(|c| !c.is_empty())
+}
+
+fn success_marker_field(key: &str) -> Option<String> {
+    let s = read_to_string(format!(
+        "{}{}",
+        CURRENT_VERSION_DIRECTORY, LAST_SUCCESS_FILENAME
+    ))
+    .ok()?;
+    let fields = s
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .collect::<HashMap<_, _>>();
+
+    fields.get(key).map(|v| v.to_string())
+}
+
+/// Records where the installed release came from when it was not a regular
+/// download, e.g. `--from-url`, so it can be audited later.
+pub fn write_origin(origin: Option<&str>) -> Result<()> {
+    if config::get().state_format == Format::Json {
+        return update(|s| s.origin = origin.map(|o| o.to_string()));
+    }
+    let p = legacy_path(ORIGIN_FILENAME);
+    match origin {
+        Some(origin) => {
+            std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+            std::fs::write(p, format!("{}\n", origin))?;
+        }
+        None if p.exists() => std::fs::remove_file(p)?,
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Records the release versions last reported upstream.
+pub fn write_remote_versions(versions: &[String]) -> Result<()> {
+    if versions.is_empty() {
+        return Ok(());
+    }
+    let remote = RemoteVersions {
+        timestamp: now(),
+        versions: versions.to_vec(),
+    };
+
+    write_atomic(
+        &Path::new(CURRENT_VERSION_DIRECTORY).join(REMOTE_VERSIONS_FILENAME),
+        &serde_json::to_vec(&remote)?,
+    )
+}
+
+/// The versions recorded by `write_remote_versions()`, with their timestamp.
+pub fn remote_versions() -> Option<(u64, Vec<String>)> {
+    let s = read(format!(
+        "{}{}",
+        CURRENT_VERSION_DIRECTORY, REMOTE_VERSIONS_FILENAME
+    ))
+    .ok()?;
+    let remote = serde_json::from_slice::<RemoteVersions>(&s).ok()?;
+
+    Some((remote.timestamp, remote.versions))
+}
+
+/// Records the outcome of a check.
+pub fn write_last_check(check: &LastCheck) -> Result<()> {
+    std::fs::create_dir_all(CURRENT_VERSION_DIRECTORY)?;
+    write_atomic(
+        &Path::new(CURRENT_VERSION_DIRECTORY).join(LAST_CHECK_FILENAME),
+        &serde_json::to_vec(check)?,
+    )
+}
+
+/// What `write_last_check()` recorded, if anything.
+pub fn last_check() -> Option<LastCheck> {
+    let s = read(Path::new(CURRENT_VERSION_DIRECTORY).join(LAST_CHECK_FILENAME)).ok()?;
+
+    parse_last_check(&s)
+}
+
+/// The contents of the last check file, None when they are corrupt.
+pub fn parse_last_check(s: &[u8]) -> Option<LastCheck> {
+    serde_json::from_slice(s).ok()
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The UTC year, month and day of Unix time `ts`.
+pub fn civil_date(ts: u64) -> (i64, i64, i64) {
+    // Howard Hinnant's civil_from_days.
+    let z = (ts / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_sentinel_as_not_installed() {
+        assert_eq!(legacy_version("1.80.0\n").as_deref(), Some("1.80.0"));
+        assert_eq!(legacy_version(" 1.80.0 \n\n").as_deref(), Some("1.80.0"));
+        assert_eq!(legacy_version("None\n"), None);
+        assert_eq!(legacy_version("None"), None);
+        assert_eq!(legacy_version("\n"), None);
+        assert_eq!(legacy_version(""), None);
+
+        let state = |version: Option<&str>| {
+            let mut json = serde_json::to_value(State::default()).unwrap();
+            json["version"] = serde_json::json!(version);
+            without_sentinel(serde_json::from_value::<State>(json).unwrap())
+        };
+        assert_eq!(state(Some("None")).version, None);
+        assert_eq!(state(None).version, None);
+        assert_eq!(state(Some("1.80.0")).version.as_deref(), Some("1.80.0"));
+    }
+
+    #[test]
+    fn writes_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("nested/state.json");
+        write_atomic(&p, b"old").unwrap();
+        write_atomic(&p, b"new").unwrap();
+        assert_eq!(std::fs::read(&p).unwrap(), b"new");
+        let names: Vec<_> = std::fs::read_dir(p.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["state.json"]);
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::checker::CURRENT_VERSION_DIRECTORY;
+use crate::table::{self, Format, Table};
+use crate::{debug, state};
+
+const STATS_FILENAME: &str = "stats.json";
+
+/// Counters of one calendar month (UTC).
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Counters {
+    pub downloads_attempted: u64,
+    pub downloads_completed: u64,
+    /// Everything received, failed and resumed attempts included.
+    pub bytes_downloaded: u64,
+    /// Installs served from the cache instead of the network.
+    pub cache_hits: u64,
+    pub bytes_saved_by_cache: u64,
+    /// Installs skipped as the latest or requested version was installed.
+    pub up_to_date_skips: u64,
+}
+
+/// Updates the counters of the current month. Statistics are not worth
+/// failing an operation for, so errors are only logged.
+pub fn record<F: FnOnce(&mut Counters)>(f: F) {
+    let mut stats = read();
+    f(stats.entry(month(state::now())).or_default());
+    let result = serde_json::to_vec(&stats)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| state::write_atomic(&path(), &s));
+    if let Err(e) = result {
+        debug!("Failed to update the statistics: {}", e);
+    }
+}
+
+/// Counters by month, as `YYYY-MM`.
+pub fn read() -> BTreeMap<String, Counters> {
+    std::fs::read(path())
+        .ok()
+        .and_then(|s| serde_json::from_slice(&s).ok())
+        .unwrap_or_default()
+}
+
+pub fn reset() -> Result<()> {
+    let p = path();
+    if p.exists() {
+        std::fs::remove_file(p)?;
+    }
+
+    Ok(())
+}
+
+/// Columns of `stats`, in the order `--format plain` keeps. Byte counts are
+/// exact there.
+const COLUMNS: &[&str] = &[
+    "MONTH",
+    "ATTEMPTED",
+    "COMPLETED",
+    "DOWNLOADED",
+    "CACHE HITS",
+    "SAVED",
+    "SKIPPED",
+];
+
+pub fn print(stats: &BTreeMap<String, Counters>, format: Format) {
+    if stats.is_empty() {
+        if format == Format::Table {
+            println!("No statistics have been recorded yet.");
+        }
+        return;
+    }
+    let mut table = Table::new(COLUMNS);
+    for (month, c) in stats {
+        table.row(vec![
+            month.clone(),
+            c.downloads_attempted.to_string(),
+            c.downloads_completed.to_string(),
+            table::bytes(c.bytes_downloaded, format),
+            c.cache_hits.to_string(),
+            table::bytes(c.bytes_saved_by_cache, format),
+            c.up_to_date_skips.to_string(),
+        ]);
+    }
+    table.print(format);
+}
+
+fn path() -> PathBuf {
+    Path::new(CURRENT_VERSION_DIRECTORY).join(STATS_FILENAME)
+}
+
+/// Formats the UTC month of Unix time `ts` as `YYYY-MM`.
+fn month(ts: u64) -> String {
+    let (y, m, _) = state::civil_date(ts);
+
+    format!("{:04}-{:02}", y, m)
+}
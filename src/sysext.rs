@@ -0,0 +1,179 @@
+use anyhow::{anyhow, bail, Context, Result};
+use console::style;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::checker::{self, InstallOptions, PATH_KV};
+use crate::exclude::Exclude;
+use crate::{info, warn};
+
+/// Filesystems a sysext image can be built with, in order of preference.
+const IMAGE_TOOLS: &[(&str, &str)] = &[("erofs", "mkfs.erofs"), ("squashfs", "mksquashfs")];
+
+/// Builds a systemd-sysext extension containing Visual Studio Code at
+/// `output`, without touching the running system. Outputs ending in `.raw`
+/// become filesystem images, anything else a directory tree.
+pub fn build_sysext(output: &Path, fs: Option<&str>, options: &InstallOptions) -> Result<()> {
+    if output.exists() {
+        bail!("{} already exists.", output.display());
+    }
+    let is_image = output.extension().map(|e| e == "raw").unwrap_or(false);
+    let name = output
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow!("Invalid output path {}.", output.display()))?
+        .to_string();
+    let tool = if is_image {
+        Some(find_image_tool(fs)?)
+    } else {
+        if fs.is_some() {
+            warn!("--fs only applies to .raw image outputs, building a directory.");
+        }
+        None
+    };
+
+    let (release, arch) = checker::prepare_release(options)?;
+    let checker::Release { buf, version, .. } = release;
+    let tree = if is_image {
+        output.with_extension("raw.d")
+    } else {
+        output.to_path_buf()
+    };
+    if tree.exists() {
+        bail!("Staging directory {} already exists.", tree.display());
+    }
+    let result = populate_tree(&tree, &buf, &arch, &version, &name).and_then(|_| match tool {
+        Some((fs, mkfs)) => build_image(&tree, output, fs, mkfs),
+        None => Ok(()),
+    });
+    if is_image || result.is_err() {
+        let _ = std::fs::remove_dir_all(&tree);
+    }
+    result?;
+
+    info!(
+        "Built systemd-sysext extension {} with Visual Studio Code {}.",
+        output.display(),
+        version
+    );
+    info!("To merge it into the running system:");
+    eprintln!("  cp -a {} /var/lib/extensions/", output.display());
+    eprintln!("  systemd-sysext merge");
+
+    Ok(())
+}
+
+fn populate_tree(tree: &Path, buf: &[u8], arch: &str, version: &str, name: &str) -> Result<()> {
+    let lib = tree.join("usr/lib");
+    std::fs::create_dir_all(&lib)?;
+    checker::unpack(buf, &lib, 1, &Exclude::default(), None)?;
+    std::fs::rename(lib.join(format!("VSCode-{}", arch)), lib.join("vscode"))
+        .context("Failed to move the unpacked release into place")?;
+
+    let bin = tree.join("usr/bin");
+    std::fs::create_dir_all(&bin)?;
+    std::os::unix::fs::symlink("/usr/lib/vscode/code", bin.join("vscode"))?;
+
+    info!("Adding AppStream metadata, desktop entry, and MIME type handler ...");
+    for (p, b) in PATH_KV {
+        let p = tree.join(p.trim_start_matches('/'));
+        if let Some(parent) = p.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&p, b).map_err(|e| anyhow!("Failed to write {}: {}.", p.display(), e))?;
+    }
+
+    let os_release = read_os_release()?;
+    let id = os_release
+        .get("ID")
+        .ok_or_else(|| anyhow!("The host os-release does not define ID."))?;
+    let mut release = format!("ID={}\n", id);
+    match os_release.get("VERSION_ID") {
+        Some(v) => release.push_str(&format!("VERSION_ID={}\n", v)),
+        // Rolling distributions such as AOSC OS may not set VERSION_ID.
+        None => release.push_str("# The host os-release does not define VERSION_ID.\n"),
+    }
+    release.push_str(&format!("VSDOWN_VSCODE_VERSION={}\n", version));
+    let release_dir = lib.join("extension-release.d");
+    std::fs::create_dir_all(&release_dir)?;
+    std::fs::write(
+        release_dir.join(format!("extension-release.{}", name)),
+        release,
+    )?;
+
+    Ok(())
+}
+
+fn build_image(tree: &Path, output: &Path, fs: &str, mkfs: &str) -> Result<()> {
+    info!("Building {} image with {} ...", fs, mkfs);
+    let mut command = Command::new(mkfs);
+    match fs {
+        "erofs" => command.arg(output).arg(tree),
+        _ => command
+            .arg(tree)
+            .arg(output)
+            .args(["-noappend", "-all-root"]),
+    };
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("Failed to run {}: {}.", mkfs, e))?;
+    if !status.success() {
+        bail!("{} failed with {}.", mkfs, status);
+    }
+
+    Ok(())
+}
+
+fn find_image_tool(fs: Option<&str>) -> Result<(&'static str, &'static str)> {
+    let candidates = IMAGE_TOOLS
+        .iter()
+        .filter(|(name, _)| fs.map(|f| f == *name).unwrap_or(true))
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        bail!(
+            "Unsupported sysext filesystem {}, expected erofs or squashfs.",
+            fs.unwrap_or_default()
+        );
+    }
+    for &&(name, mkfs) in &candidates {
+        if find_in_path(mkfs).is_some() {
+            return Ok((name, mkfs));
+        }
+    }
+
+    bail!(
+        "Building a sysext image requires {}, but it was not found in PATH. Pass an output path without .raw to build a directory instead.",
+        candidates
+            .iter()
+            .map(|(_, mkfs)| *mkfs)
+            .collect::<Vec<_>>()
+            .join(" or ")
+    )
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+}
+
+pub fn read_os_release() -> Result<HashMap<String, String>> {
+    let s = std::fs::read_to_string("/etc/os-release")
+        .or_else(|_| std::fs::read_to_string("/usr/lib/os-release"))
+        .context("Failed to read the host os-release")?;
+
+    Ok(s.lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| {
+            (
+                k.trim().to_string(),
+                v.trim().trim_matches('"').trim_matches('\'').to_string(),
+            )
+        })
+        .collect())
+}
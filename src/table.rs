@@ -0,0 +1,188 @@
+use clap::ArgEnum;
+use console::{measure_text_width, pad_str, strip_ansi_codes, truncate_str, Alignment};
+use std::borrow::Cow;
+
+use crate::units;
+
+/// Spaces between the columns of `Format::Table`.
+const GAP: usize = 2;
+/// Narrowest a column is shrunk to when the terminal is too narrow.
+const MIN_WIDTH: usize = 6;
+
+/// How the tabular subcommands print their results.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Aligned columns with a header, fitted to the terminal
+    Table,
+    /// Tab-separated columns without a header, whose order is kept stable for scripts
+    Plain,
+    /// The same JSON as --json
+    Json,
+}
+
+/// Rows of cells under fixed headers. Cells may be styled, which only
+/// `Format::Table` keeps.
+pub struct Table {
+    headers: &'static [&'static str],
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: &'static [&'static str]) -> Self {
+        Self {
+            headers,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<String>) {
+        debug_assert_eq!(cells.len(), self.headers.len());
+        self.rows.push(cells);
+    }
+
+    /// Prints the table as `format`, which must not be `Format::Json`.
+    pub fn print(&self, format: Format) {
+        let width = console::Term::stdout()
+            .size_checked()
+            .map(|(_, cols)| cols as usize);
+        print!("{}", self.render(format, width));
+    }
+
+    /// Renders the table as `format`, shrinking the widest columns of a
+    /// `Format::Table` until it fits in `width`, if given.
+    pub fn render(&self, format: Format, width: Option<usize>) -> String {
+        let mut s = String::new();
+        if format == Format::Plain {
+            for row in &self.rows {
+                let cells = row.iter().map(|c| plain(c)).collect::<Vec<_>>();
+                s.push_str(&cells.join("\t"));
+                s.push('\n');
+            }
+            return s;
+        }
+        let headers = self.headers.iter().map(|h| h.to_string()).collect();
+        let lines = std::iter::once(&headers)
+            .chain(&self.rows)
+            .collect::<Vec<_>>();
+        let mut widths = (0..self.headers.len())
+            .map(|i| {
+                lines
+                    .iter()
+                    .map(|l| measure_text_width(&l[i]))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+        if let Some(width) = width {
+            let gaps = GAP * widths.len().saturating_sub(1);
+            while widths.iter().sum::<usize>() + gaps > width {
+                let (i, w) = widths
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .max_by_key(|(_, w)| *w)
+                    .unwrap_or_default();
+                if w <= MIN_WIDTH {
+                    break;
+                }
+                widths[i] = w - 1;
+            }
+        }
+        for line in lines {
+            let last = line.len() - 1;
+            for (i, cell) in line.iter().enumerate() {
+                // Some console versions truncate cells which just fit.
+                let cell = if measure_text_width(cell) > widths[i] {
+                    truncate_str(cell, widths[i], "…")
+                } else {
+                    Cow::Borrowed(cell.as_str())
+                };
+                if i == last {
+                    s.push_str(&cell);
+                } else {
+                    s.push_str(&pad_str(&cell, widths[i], Alignment::Left, None));
+                    s.push_str(&" ".repeat(GAP));
+                }
+            }
+            s.push('\n');
+        }
+
+        s
+    }
+}
+
+/// A cell as `Format::Plain` prints it: unstyled, on one line and without
+/// tabs, so columns can be split on tabs.
+fn plain(cell: &str) -> String {
+    strip_ansi_codes(cell).replace(|c| c == '\t' || c == '\n', " ")
+}
+
+/// A byte count, exact in `Format::Plain` for scripts.
+pub fn bytes(n: u64, format: Format) -> String {
+    match format {
+        Format::Plain => n.to_string(),
+        _ => units::Bytes(n).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn releases() -> Table {
+        let mut table = Table::new(&["VERSION", "DATE", "SIZE"]);
+        table.row(vec![
+            "\x1b[32m1.80.0\x1b[0m".to_string(),
+            "2023-07-12".to_string(),
+            "95.30 MiB".to_string(),
+        ]);
+        table.row(vec![
+            "1.79.2".to_string(),
+            "2023-06-14".to_string(),
+            "94.12 MiB".to_string(),
+        ]);
+        table
+    }
+
+    #[test]
+    fn renders_wide() {
+        let wide = "VERSION  DATE        SIZE\n\
+                    \x1b[32m1.80.0\x1b[0m   2023-07-12  95.30 MiB\n\
+                    1.79.2   2023-06-14  94.12 MiB\n";
+        assert_eq!(releases().render(Format::Table, None), wide);
+        assert_eq!(releases().render(Format::Table, Some(30)), wide);
+        assert_eq!(releases().render(Format::Table, Some(200)), wide);
+    }
+
+    #[test]
+    fn renders_narrow() {
+        assert_eq!(
+            releases().render(Format::Table, Some(24)),
+            "VERSION  DATE     SIZE\n\
+             \x1b[32m1.80.0\x1b[0m   2023-0…  95.30…\n\
+             1.79.2   2023-0…  94.12…\n"
+        );
+        // Columns are not shrunk past MIN_WIDTH, however narrow.
+        let narrowest = "VERSI…  DATE    SIZE\n\
+                         \x1b[32m1.80.0\x1b[0m  2023-…  95.30…\n\
+                         1.79.2  2023-…  94.12…\n";
+        assert_eq!(releases().render(Format::Table, Some(10)), narrowest);
+        assert_eq!(releases().render(Format::Table, Some(0)), narrowest);
+    }
+
+    #[test]
+    fn renders_plain() {
+        let mut table = releases();
+        table.row(vec![
+            "1.0.0".to_string(),
+            "a\tb\nc".to_string(),
+            "0 B".to_string(),
+        ]);
+        assert_eq!(
+            table.render(Format::Plain, Some(10)),
+            "1.80.0\t2023-07-12\t95.30 MiB\n\
+             1.79.2\t2023-06-14\t94.12 MiB\n\
+             1.0.0\ta b c\t0 B\n"
+        );
+    }
+}
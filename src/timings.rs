@@ -0,0 +1,213 @@
+use once_cell::sync::Lazy;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::units;
+
+/// The parts of an operation `--timings` breaks its run time down into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Looking up versions and builds.
+    Metadata,
+    /// From sending a download request to its response headers.
+    Connect,
+    Download,
+    Hash,
+    Extract,
+    /// Swapping the tree in and installing the launcher and metadata.
+    Install,
+    /// Hooks run after installing or removing.
+    PostInstall,
+    StateWrite,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Metadata => "metadata",
+            Phase::Connect => "connect",
+            Phase::Download => "download",
+            Phase::Hash => "hash",
+            Phase::Extract => "extract",
+            Phase::Install => "install",
+            Phase::PostInstall => "post-install",
+            Phase::StateWrite => "state-write",
+        }
+    }
+}
+
+/// The time spent in a phase, and the bytes it read or wrote for the I/O
+/// phases.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Total {
+    pub elapsed: Duration,
+    pub bytes: Option<u64>,
+}
+
+static TOTALS: Lazy<Mutex<BTreeMap<Phase, Total>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+thread_local! {
+    /// Time spent in phases nested in each phase being measured on this
+    /// thread, innermost last.
+    static NESTED: RefCell<Vec<Duration>> = RefCell::new(Vec::new());
+}
+
+/// Runs `f` as part of `phase`. Time spent in phases measured inside it is
+/// counted only for those, so the phases add up to the run time.
+pub fn measure<T, F: FnOnce() -> T>(phase: Phase, f: F) -> T {
+    measure_into(&TOTALS, phase, f)
+}
+
+fn measure_into<T, F: FnOnce() -> T>(
+    totals: &Mutex<BTreeMap<Phase, Total>>,
+    phase: Phase,
+    f: F,
+) -> T {
+    NESTED.with(|n| n.borrow_mut().push(Duration::ZERO));
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    let nested = NESTED.with(|n| {
+        let mut n = n.borrow_mut();
+        let nested = n.pop().unwrap_or_default();
+        if let Some(parent) = n.last_mut() {
+            *parent += elapsed;
+        }
+        nested
+    });
+    totals.lock().unwrap().entry(phase).or_default().elapsed += elapsed.saturating_sub(nested);
+
+    result
+}
+
+/// Counts `n` bytes toward the throughput of `phase`.
+pub fn add_bytes(phase: Phase, n: u64) {
+    let mut totals = TOTALS.lock().unwrap();
+    let total = totals.entry(phase).or_default();
+    total.bytes = Some(total.bytes.unwrap_or(0) + n);
+}
+
+/// Every phase which ran, once each, in the order they run in.
+pub fn report() -> Vec<(Phase, Total)> {
+    TOTALS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(phase, total)| (*phase, *total))
+        .collect()
+}
+
+/// The milliseconds spent in each phase, as the history records them.
+pub fn millis() -> BTreeMap<String, u64> {
+    report()
+        .into_iter()
+        .map(|(phase, total)| (phase.name().to_string(), total.elapsed.as_millis() as u64))
+        .collect()
+}
+
+/// Prints the breakdown on stderr, where it stays out of `--json` output.
+pub fn print() {
+    for line in lines(&report()) {
+        eprintln!("{} {}", console::style("timings:").cyan().bold(), line);
+    }
+}
+
+/// A line for each phase of `report`, then one with their total, or none
+/// when nothing was measured.
+fn lines(report: &[(Phase, Total)]) -> Vec<String> {
+    if report.is_empty() {
+        return Vec::new();
+    }
+    let total = report.iter().map(|(_, t)| t.elapsed).sum::<Duration>();
+    let mut lines = Vec::new();
+    for (phase, t) in report {
+        let mut line = format!(
+            "{:<13} {:>8}",
+            phase.name(),
+            units::Elapsed(t.elapsed).to_string()
+        );
+        if let Some(bytes) = t.bytes {
+            let rate = bytes as f64 / t.elapsed.as_secs_f64().max(f64::EPSILON);
+            line.push_str(&format!(
+                "  {} at {}",
+                units::Bytes(bytes),
+                units::Rate(rate)
+            ));
+        }
+        lines.push(line);
+    }
+    lines.push(format!(
+        "{:<13} {:>8}",
+        "total",
+        units::Elapsed(total).to_string()
+    ));
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn nested_phases_add_up() {
+        // Not TOTALS, which the other tests hashing files add to.
+        let totals = Mutex::new(BTreeMap::new());
+        let start = Instant::now();
+        measure_into(&totals, Phase::Download, || {
+            sleep(Duration::from_millis(30));
+            measure_into(&totals, Phase::Connect, || sleep(Duration::from_millis(20)));
+            measure_into(&totals, Phase::Connect, || sleep(Duration::from_millis(20)));
+        });
+        let wall = start.elapsed();
+        let report = totals.into_inner().unwrap().into_iter().collect::<Vec<_>>();
+
+        let phases = report.iter().map(|(p, _)| *p).collect::<Vec<_>>();
+        assert_eq!(phases, [Phase::Connect, Phase::Download]);
+        let (connect, download) = (report[0].1.elapsed, report[1].1.elapsed);
+        assert!(connect >= Duration::from_millis(40), "{:?}", connect);
+        // Without the time spent connecting.
+        assert!(download >= Duration::from_millis(30), "{:?}", download);
+        assert!(connect + download <= wall);
+
+        let printed = lines(&report);
+        assert_eq!(printed.len(), 3);
+        assert!(printed[0].starts_with("connect "), "{}", printed[0]);
+        assert!(printed[1].starts_with("download "), "{}", printed[1]);
+        assert_eq!(
+            printed[2],
+            format!(
+                "{:<13} {:>8}",
+                "total",
+                units::Elapsed(connect + download).to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn shows_throughput() {
+        let report = [(
+            Phase::Download,
+            Total {
+                elapsed: Duration::from_secs(2),
+                bytes: Some(4 << 20),
+            },
+        )];
+        let printed = lines(&report);
+        assert_eq!(
+            printed[0],
+            format!(
+                "download            2s  {} at {}/s",
+                units::Bytes(4 << 20),
+                units::Bytes(2 << 20)
+            )
+        );
+        assert_eq!(printed[1], "total               2s");
+        assert!(lines(&[]).is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use std::{fmt, time::Duration};
+
+use crate::config;
+
+/// Which multiples byte counts are shown in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum System {
+    /// KiB, MiB and GiB: powers of 1024, as indicatif and du -h use.
+    Binary,
+    /// kB, MB and GB: powers of 1000, as drive vendors and network speeds use.
+    Decimal,
+}
+
+impl System {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "binary" => Ok(System::Binary),
+            "decimal" => Ok(System::Decimal),
+            other => bail!("units must be binary or decimal, not {}.", other),
+        }
+    }
+
+    fn base(self) -> f64 {
+        match self {
+            System::Binary => 1024.0,
+            System::Decimal => 1000.0,
+        }
+    }
+
+    fn prefixes(self) -> [&'static str; 6] {
+        match self {
+            System::Binary => ["B", "KiB", "MiB", "GiB", "TiB", "PiB"],
+            System::Decimal => ["B", "kB", "MB", "GB", "TB", "PB"],
+        }
+    }
+}
+
+/// How the locale writes numbers.
+struct Separators {
+    /// Between groups of three digits, empty for none.
+    group: &'static str,
+    decimal: char,
+}
+
+/// Taken from LC_ALL, LC_NUMERIC or LANG, the first which is set, as the C
+/// library would. Only the language is looked at.
+static SEPARATORS: Lazy<Separators> = Lazy::new(|| separators(&locale(|v| std::env::var(v).ok())));
+
+/// The numeric locale, by looking up the variables with `var`.
+fn locale<F: Fn(&str) -> Option<String>>(var: F) -> String {
+    ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .iter()
+        .find_map(|v| var(v).filter(|s| !s.is_empty()))
+        .unwrap_or_default()
+}
+
+fn separators(locale: &str) -> Separators {
+    let language = locale
+        .split(|c| c == '_' || c == '.' || c == '@')
+        .next()
+        .unwrap_or_default();
+    let (group, decimal) = match language {
+        "" | "C" | "POSIX" => ("", '.'),
+        "da" | "de" | "el" | "es" | "id" | "it" | "nl" | "pt" | "tr" => (".", ','),
+        // A no-break space, so numbers are not wrapped apart.
+        "bg" | "cs" | "fi" | "fr" | "hu" | "nb" | "nn" | "pl" | "ru" | "sk" | "sv" | "uk" => {
+            ("\u{a0}", ',')
+        }
+        _ => (",", '.'),
+    };
+
+    Separators { group, decimal }
+}
+
+fn group_digits(n: u64, group: &str) -> String {
+    let digits = n.to_string();
+    let mut s = String::with_capacity(digits.len() + digits.len() / 3 * group.len());
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            s.push_str(group);
+        }
+        s.push(c);
+    }
+
+    s
+}
+
+fn fmt_bytes(n: u64, system: System, sep: &Separators) -> String {
+    let base = system.base();
+    let prefixes = system.prefixes();
+    if (n as f64) < base {
+        return format!("{} {}", group_digits(n, sep.group), prefixes[0]);
+    }
+    let mut value = n as f64;
+    let mut i = 0;
+    while value >= base && i < prefixes.len() - 1 {
+        value /= base;
+        i += 1;
+    }
+    let number = format!("{:.2}", value).replace('.', &sep.decimal.to_string());
+
+    format!("{} {}", number, prefixes[i])
+}
+
+fn fmt_duration(d: Duration, sep: &Separators) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0 => format!("{:.1}s", d.as_secs_f64()).replace('.', &sep.decimal.to_string()),
+        1..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// A byte count in the configured `units`, e.g. 1.50 MiB, with the decimal
+/// mark of the locale.
+pub struct Bytes(pub u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&fmt_bytes(self.0, config::get().units, &SEPARATORS))
+    }
+}
+
+/// A transfer rate in bytes per second, e.g. 12.30 MiB/s.
+pub struct Rate(pub f64);
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = fmt_bytes(self.0.max(0.0) as u64, config::get().units, &SEPARATORS);
+        f.pad(&format!("{}/s", bytes))
+    }
+}
+
+/// A plain count with the digit grouping of the locale, e.g. 12,345.
+pub struct Count(pub u64);
+
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&group_digits(self.0, SEPARATORS.group))
+    }
+}
+
+/// A duration as shown in summaries and ETAs, to the second below an hour
+/// and to the minute above: 0.4s, 42s, 3m 05s, 1h 02m.
+pub struct Elapsed(pub Duration);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&fmt_duration(self.0, &SEPARATORS))
+    }
+}
+
+/// `template` with its byte keys switched to the configured `units`.
+/// indicatif renders those itself, in the same units but with a plain '.'
+/// as the decimal mark.
+pub fn template(template: &str) -> String {
+    let prefix = match config::get().units {
+        System::Binary => "binary_",
+        System::Decimal => "decimal_",
+    };
+    ["bytes}", "total_bytes}", "bytes_per_sec}"]
+        .iter()
+        .fold(template.to_string(), |t, key| {
+            t.replace(&format!("{{{}", key), &format!("{{{}{}", prefix, key))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(n: u64, system: System, locale: &str) -> String {
+        fmt_bytes(n, system, &separators(locale))
+    }
+
+    fn duration(millis: u64, locale: &str) -> String {
+        fmt_duration(Duration::from_millis(millis), &separators(locale))
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(bytes(0, System::Binary, "C"), "0 B");
+        assert_eq!(bytes(1023, System::Binary, "C"), "1023 B");
+        assert_eq!(bytes(1024, System::Binary, "C"), "1.00 KiB");
+        assert_eq!(bytes(1024, System::Decimal, "C"), "1.02 kB");
+        assert_eq!(bytes(999, System::Decimal, "C"), "999 B");
+        assert_eq!(bytes(1000, System::Decimal, "C"), "1.00 kB");
+        assert_eq!(bytes(1_572_864, System::Binary, "C"), "1.50 MiB");
+        assert_eq!(bytes(1_500_000, System::Decimal, "C"), "1.50 MB");
+        assert_eq!(bytes(3 << 30, System::Binary, "C"), "3.00 GiB");
+        assert_eq!(bytes(u64::MAX, System::Binary, "C"), "16384.00 PiB");
+    }
+
+    #[test]
+    fn formats_bytes_for_the_locale() {
+        assert_eq!(bytes(1023, System::Binary, "de_DE.UTF-8"), "1.023 B");
+        assert_eq!(bytes(1_572_864, System::Binary, "de_DE.UTF-8"), "1,50 MiB");
+        assert_eq!(bytes(1023, System::Binary, "fr_FR.UTF-8"), "1\u{a0}023 B");
+        assert_eq!(bytes(1_500_000, System::Decimal, "fr_FR"), "1,50 MB");
+        assert_eq!(bytes(1023, System::Binary, "en_US.UTF-8"), "1,023 B");
+        assert_eq!(bytes(1023, System::Binary, "C.UTF-8"), "1023 B");
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(duration(400, "C"), "0.4s");
+        assert_eq!(duration(400, "de_DE"), "0,4s");
+        assert_eq!(duration(0, "C"), "0.0s");
+        assert_eq!(duration(42_000, "C"), "42s");
+        assert_eq!(duration(42_900, "C"), "42s");
+        assert_eq!(duration(60_000, "C"), "1m 00s");
+        assert_eq!(duration(185_000, "C"), "3m 05s");
+        assert_eq!(duration(3_599_000, "C"), "59m 59s");
+        assert_eq!(duration(3_720_000, "C"), "1h 02m");
+        assert_eq!(duration(90_000_000, "C"), "25h 00m");
+    }
+
+    #[test]
+    fn groups_digits() {
+        assert_eq!(group_digits(0, ","), "0");
+        assert_eq!(group_digits(999, ","), "999");
+        assert_eq!(group_digits(1000, ","), "1,000");
+        assert_eq!(group_digits(1_234_567, "."), "1.234.567");
+        assert_eq!(group_digits(123_456, "\u{a0}"), "123\u{a0}456");
+        assert_eq!(group_digits(1_234_567, ""), "1234567");
+    }
+
+    #[test]
+    fn picks_separators() {
+        for (locale, group, decimal) in [
+            ("", "", '.'),
+            ("C", "", '.'),
+            ("POSIX", "", '.'),
+            ("de_DE.UTF-8", ".", ','),
+            ("de_CH@euro", ".", ','),
+            ("fr_FR.UTF-8", "\u{a0}", ','),
+            ("ru", "\u{a0}", ','),
+            ("en_US.UTF-8", ",", '.'),
+            ("ja_JP", ",", '.'),
+        ] {
+            let sep = separators(locale);
+            assert_eq!((sep.group, sep.decimal), (group, decimal), "{}", locale);
+        }
+    }
+
+    #[test]
+    fn finds_the_locale() {
+        let vars = |set: &'static [(&'static str, &'static str)]| {
+            move |v: &str| {
+                set.iter()
+                    .find(|(k, _)| *k == v)
+                    .map(|(_, val)| val.to_string())
+            }
+        };
+        assert_eq!(locale(vars(&[])), "");
+        assert_eq!(locale(vars(&[("LANG", "de_DE")])), "de_DE");
+        assert_eq!(
+            locale(vars(&[("LANG", "de_DE"), ("LC_NUMERIC", "fr_FR")])),
+            "fr_FR"
+        );
+        assert_eq!(
+            locale(vars(&[
+                ("LANG", "de_DE"),
+                ("LC_NUMERIC", "fr_FR"),
+                ("LC_ALL", "C")
+            ])),
+            "C"
+        );
+        // Set but empty counts as unset.
+        assert_eq!(locale(vars(&[("LANG", "de_DE"), ("LC_ALL", "")])), "de_DE");
+    }
+}
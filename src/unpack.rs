@@ -0,0 +1,807 @@
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{Read, Write},
+    os::unix::fs::{lchown, MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use crate::exclude::Exclude;
+use crate::{debug, info, logger, state};
+
+/// Workers used when `--concurrent-unpack` is given without a count.
+const DEFAULT_JOBS: usize = 4;
+
+/// Files queued per worker before reading the archive blocks.
+const QUEUE_DEPTH: usize = 2;
+
+/// NAME_MAX, the longest file name Linux filesystems take.
+const MAX_COMPONENT_LEN: usize = 255;
+/// PATH_MAX less the terminating NUL, the longest path the kernel takes.
+const MAX_PATH_LEN: usize = 4095;
+/// Paths in rejection messages are cut to this many characters.
+const MAX_SHOWN_LEN: usize = 80;
+/// How often a journaled unpack records its progress.
+const JOURNAL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An unpack whose progress is recorded in the install journal, see
+/// `state::write_extract_progress()`.
+pub struct Journal<'a> {
+    /// SHA-256 of the archive.
+    pub sha256: String,
+    /// Entries an interrupted unpack of the same archive completed, which
+    /// are only written again if they changed since.
+    pub done: u64,
+    /// Given the number of entries completed instead of the install
+    /// journal, if set.
+    pub sink: Option<&'a dyn Fn(u64)>,
+}
+
+impl Journal<'_> {
+    fn record(&self, entries: u64) {
+        if let Some(sink) = self.sink {
+            return sink(entries);
+        }
+        if let Err(e) = state::write_extract_progress(&self.sha256, entries) {
+            debug!("Could not record the extraction progress: {}", e);
+        }
+    }
+}
+
+/// The number of workers for `--concurrent-unpack` without a count: one per
+/// CPU, up to `DEFAULT_JOBS`.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(DEFAULT_JOBS)
+}
+
+/// Attributes restored from the tar header, as `tar::Archive` does with
+/// permissions, ownerships and modification times preserved.
+struct Attributes {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+}
+
+impl Attributes {
+    fn apply(&self, file: &File, path: &Path) -> std::io::Result<()> {
+        // Changing the owner clears setuid bits, so it goes first.
+        lchown(path, Some(self.uid), Some(self.gid))?;
+        file.set_permissions(std::fs::Permissions::from_mode(self.mode & 0o7777))?;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(self.mtime))?;
+
+        Ok(())
+    }
+}
+
+struct FileJob {
+    /// Of the entry in the archive.
+    index: u64,
+    path: PathBuf,
+    data: Vec<u8>,
+    attributes: Attributes,
+}
+
+impl FileJob {
+    fn write(self) -> Result<()> {
+        let path = &self.path;
+        let result = (|| -> std::io::Result<()> {
+            // Replace rather than write through whatever is there, which may
+            // be read-only or a symlink.
+            if std::fs::symlink_metadata(path).is_ok() {
+                std::fs::remove_file(path)?;
+            }
+            let mut f = File::create(path)?;
+            f.write_all(&self.data)?;
+            self.attributes.apply(&f, path)
+        })();
+
+        result.map_err(|e| anyhow!("Failed to unpack {}: {}.", path.display(), e))
+    }
+}
+
+/// An archive entry `--exclude` kept from being unpacked.
+pub struct Skipped {
+    /// Relative to the top of the archive.
+    pub path: PathBuf,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// Whether `entry` at `rel` is to be left out: excluded itself, or a hard
+/// link to an excluded file, which would have nothing to link to.
+fn is_excluded<R: Read>(entry: &tar::Entry<R>, rel: &Path, exclude: &Exclude) -> Result<bool> {
+    if exclude.is_empty() {
+        return Ok(false);
+    }
+    if exclude.is_excluded(rel) {
+        return Ok(true);
+    }
+    if entry.header().entry_type().is_hard_link() {
+        if let Some(target) = entry.link_name()? {
+            return Ok(exclude.is_excluded(&target));
+        }
+    }
+
+    Ok(false)
+}
+
+/// Unpacks `archive` into `dir` entry by entry like `tar::Archive::unpack()`,
+/// leaving out what `exclude` matches. Directories are unpacked last, as
+/// their permissions may not let their contents be written. With a
+/// `journal`, the progress is recorded as it goes.
+pub fn serial<R: Read>(
+    archive: &mut tar::Archive<R>,
+    dir: &Path,
+    exclude: &Exclude,
+    journal: Option<&Journal>,
+) -> Result<Vec<Skipped>> {
+    std::fs::create_dir_all(dir)?;
+    let mut skipped = Vec::new();
+    let mut dirs = Vec::new();
+    let done = journal.map_or(0, |j| j.done);
+    let mut kept = 0;
+    let mut recorded = Instant::now();
+    let mut count = 0;
+    for (i, entry) in archive.entries()?.enumerate() {
+        count = i as u64 + 1;
+        if let Some(journal) = journal {
+            if recorded.elapsed() >= JOURNAL_INTERVAL {
+                journal.record(i as u64);
+                recorded = Instant::now();
+            }
+        }
+        let mut entry = entry?;
+        let rel = entry.path()?.into_owned();
+        if is_excluded(&entry, &rel, exclude)? {
+            skipped.push(Skipped {
+                size: entry.size(),
+                is_dir: entry.header().entry_type().is_dir(),
+                path: rel,
+            });
+            continue;
+        }
+        check_length(dir, &rel)?;
+        log_unpacked(&rel);
+        if entry.header().entry_type().is_dir() {
+            dirs.push(entry);
+        } else if (i as u64) < done && is_intact(&entry, dir, &rel)? {
+            kept += 1;
+        } else {
+            entry.unpack_in(dir)?;
+        }
+    }
+    for mut entry in dirs {
+        entry.unpack_in(dir)?;
+    }
+    if let Some(journal) = journal {
+        journal.record(count);
+    }
+    if kept > 0 {
+        info!(
+            "Kept {} entries an interrupted extraction had unpacked.",
+            kept
+        );
+    }
+
+    Ok(skipped)
+}
+
+/// Whether `rel` in `dir` still is what unpacking `entry` made it: a file
+/// of its size and modification time, a symlink to its target, or a hard
+/// link to its target. Contents are not read, slow storage being why this
+/// is asked.
+fn is_intact<R: Read>(entry: &tar::Entry<R>, dir: &Path, rel: &Path) -> Result<bool> {
+    let path = dir.join(rel);
+    let metadata = match std::fs::symlink_metadata(&path) {
+        Ok(m) => m,
+        Err(_) => return Ok(false),
+    };
+    let header = entry.header();
+    let kind = header.entry_type();
+    if kind.is_symlink() {
+        return Ok(metadata.file_type().is_symlink()
+            && std::fs::read_link(&path).ok().as_deref() == entry.link_name()?.as_deref());
+    }
+    if kind.is_hard_link() {
+        // A target unpacked again since is a file of its own.
+        let target = match entry.link_name()? {
+            Some(target) => std::fs::symlink_metadata(dir.join(target)).ok(),
+            None => None,
+        };
+        return Ok(target.map_or(false, |t| {
+            t.dev() == metadata.dev() && t.ino() == metadata.ino()
+        }));
+    }
+
+    Ok(kind.is_file()
+        && metadata.is_file()
+        && metadata.len() == entry.size()
+        && metadata.mtime() == header.mtime()? as i64)
+}
+
+/// Unpacks `archive` into `dir`, decompressing and reading entries on this
+/// thread while `jobs` workers write the regular files. Directories are
+/// created in archive order before anything is written into them, and get
+/// their permissions last so read-only ones can still be filled. Returns the
+/// entries `exclude` left out. The progress is recorded in `journal` as in
+/// `serial()`, up to the first entry a worker has not written yet, though
+/// only `serial()` can continue from it.
+pub fn concurrent<R: Read>(
+    archive: &mut tar::Archive<R>,
+    dir: &Path,
+    jobs: usize,
+    exclude: &Exclude,
+    journal: Option<&Journal>,
+) -> Result<Vec<Skipped>> {
+    std::fs::create_dir_all(dir)?;
+    let (tx, rx) = mpsc::sync_channel::<FileJob>(jobs * QUEUE_DEPTH);
+    let rx = Arc::new(Mutex::new(rx));
+    // Indices of the files queued and not written yet.
+    let pending = Arc::new(Mutex::new(BTreeSet::new()));
+    let workers = (0..jobs)
+        .map(|_| {
+            let (rx, pending) = (Arc::clone(&rx), Arc::clone(&pending));
+            thread::spawn(move || -> Result<()> {
+                loop {
+                    let job = {
+                        let rx = rx
+                            .lock()
+                            .map_err(|_| anyhow!("another unpack worker failed"))?;
+                        rx.recv()
+                    };
+                    match job {
+                        Ok(job) => {
+                            let index = job.index;
+                            job.write()?;
+                            pending
+                                .lock()
+                                .map_err(|_| anyhow!("another unpack worker failed"))?
+                                .remove(&index);
+                        }
+                        Err(_) => return Ok(()),
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(rx);
+
+    let mut dirs = Vec::new();
+    let mut hard_links = Vec::new();
+    let mut skipped = Vec::new();
+    let mut recorded = Instant::now();
+    let mut count = 0;
+    let result = (|| -> Result<()> {
+        for (i, entry) in archive.entries()?.enumerate() {
+            count = i as u64 + 1;
+            if let Some(journal) = journal {
+                if recorded.elapsed() >= JOURNAL_INTERVAL {
+                    let queued = pending
+                        .lock()
+                        .map_err(|_| anyhow!("an unpack worker failed"))?;
+                    journal.record(queued.first().copied().unwrap_or(i as u64));
+                    recorded = Instant::now();
+                }
+            }
+            let mut entry = entry?;
+            let rel = entry.path()?.into_owned();
+            if is_excluded(&entry, &rel, exclude)? {
+                skipped.push(Skipped {
+                    size: entry.size(),
+                    is_dir: entry.header().entry_type().is_dir(),
+                    path: rel,
+                });
+                continue;
+            }
+            check_path(dir, &rel)?;
+            check_length(dir, &rel)?;
+            log_unpacked(&rel);
+            let path = dir.join(&rel);
+            let header = entry.header();
+            let attributes = Attributes {
+                mode: header.mode()?,
+                uid: header.uid()? as u32,
+                gid: header.gid()? as u32,
+                mtime: header.mtime()?,
+            };
+            let entry_type = header.entry_type();
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if entry_type.is_dir() {
+                std::fs::create_dir_all(&path)?;
+                dirs.push((path, attributes));
+            } else if entry_type.is_file() {
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data)?;
+                pending
+                    .lock()
+                    .map_err(|_| anyhow!("an unpack worker failed"))?
+                    .insert(i as u64);
+                tx.send(FileJob {
+                    index: i as u64,
+                    path,
+                    data,
+                    attributes,
+                })
+                .map_err(|_| anyhow!("the unpack workers stopped"))?;
+            } else if entry_type.is_symlink() {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("{} is a symlink without a target", rel.display()))?
+                    .into_owned();
+                if std::fs::symlink_metadata(&path).is_ok() {
+                    std::fs::remove_file(&path)?;
+                }
+                std::os::unix::fs::symlink(&target, &path)?;
+                lchown(&path, Some(attributes.uid), Some(attributes.gid))?;
+            } else if entry_type.is_hard_link() {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("{} is a hard link without a target", rel.display()))?
+                    .into_owned();
+                check_path(dir, &target)?;
+                // The target may still be queued, link once everything is written.
+                pending
+                    .lock()
+                    .map_err(|_| anyhow!("an unpack worker failed"))?
+                    .insert(i as u64);
+                hard_links.push((dir.join(target), path));
+            } else {
+                debug!(
+                    "Skipping {}, which is of an unsupported type.",
+                    rel.display()
+                );
+            }
+        }
+
+        Ok(())
+    })();
+    drop(tx);
+
+    let mut error = result.err();
+    for worker in workers {
+        let r = worker
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("an unpack worker panicked")));
+        if let Err(e) = r {
+            error.get_or_insert(e);
+        }
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    for (target, path) in hard_links {
+        if std::fs::symlink_metadata(&path).is_ok() {
+            std::fs::remove_file(&path)?;
+        }
+        std::fs::hard_link(&target, &path)?;
+    }
+    // Deepest first, so restoring a parent's mtime is not undone by its children.
+    for (path, attributes) in dirs.iter().rev() {
+        let f = File::open(path)?;
+        attributes
+            .apply(&f, path)
+            .map_err(|e| anyhow!("Failed to unpack {}: {}.", path.display(), e))?;
+    }
+    if let Some(journal) = journal {
+        journal.record(count);
+    }
+
+    Ok(skipped)
+}
+
+/// Logs `rel` as extracted with `--verbose-unpack`. With concurrent
+/// unpacking, it may not be written yet.
+fn log_unpacked(rel: &Path) {
+    if logger::is_verbose_unpack() {
+        info!("unpack: {}", rel.display());
+    }
+}
+
+/// Refuses entries whose names no Linux filesystem could hold, rather than
+/// leaving the tree half extracted at whichever the kernel then refuses.
+fn check_length(dir: &Path, rel: &Path) -> Result<()> {
+    if let Some(c) = rel
+        .components()
+        .find(|c| c.as_os_str().len() > MAX_COMPONENT_LEN)
+    {
+        bail!(
+            "Refusing to unpack {}, the name {} is {} bytes long, over the limit of {}.",
+            shorten(rel),
+            shorten(Path::new(c.as_os_str())),
+            c.as_os_str().len(),
+            MAX_COMPONENT_LEN
+        );
+    }
+    let len = dir.join(rel).as_os_str().len();
+    if len > MAX_PATH_LEN {
+        bail!(
+            "Refusing to unpack {}, its path is {} bytes long, over the limit of {}.",
+            shorten(rel),
+            len,
+            MAX_PATH_LEN
+        );
+    }
+
+    Ok(())
+}
+
+/// `p` for messages, cut to `MAX_SHOWN_LEN` characters. Bytes which are not
+/// UTF-8 are shown as replacement characters.
+fn shorten(p: &Path) -> String {
+    let s = p.to_string_lossy();
+    match s.char_indices().nth(MAX_SHOWN_LEN) {
+        Some((i, _)) => format!("{}...", &s[..i]),
+        None => s.into_owned(),
+    }
+}
+
+/// Refuses entries which would end up outside `dir`, be it through `..`, an
+/// absolute path or a symlink unpacked earlier.
+fn check_path(dir: &Path, rel: &Path) -> Result<()> {
+    let mut p = dir.to_path_buf();
+    let mut components = rel.components().peekable();
+    while let Some(c) = components.next() {
+        match c {
+            Component::Normal(c) => p.push(c),
+            Component::CurDir => continue,
+            _ => bail!(
+                "Refusing to unpack {}, which points outside the target directory.",
+                rel.display()
+            ),
+        }
+        let is_last = components.peek().is_none();
+        if !is_last
+            && std::fs::symlink_metadata(&p)
+                .map(|m| m.file_type().is_symlink())
+                .unwrap_or(false)
+        {
+            bail!(
+                "Refusing to unpack {} through the symlink {}.",
+                rel.display(),
+                p.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use std::{cell::RefCell, ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    use crate::{manifest, verify};
+
+    const MTIME: u64 = 1_600_000_000;
+
+    /// An entry of a test archive, by its path in the archive.
+    pub enum Spec<'a> {
+        Dir(&'a [u8], u32),
+        File(&'a [u8], &'a [u8], u32),
+        Symlink(&'a [u8], &'a str),
+        HardLink(&'a [u8], &'a str),
+    }
+
+    /// A gzipped tar of `specs`, owned by the user running the tests.
+    pub fn archive(specs: &[Spec]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for spec in specs {
+            let mut header = tar::Header::new_gnu();
+            header.set_uid(unsafe { libc::getuid() } as u64);
+            header.set_gid(unsafe { libc::getgid() } as u64);
+            header.set_mtime(MTIME);
+            let (path, data, kind, mode) = match spec {
+                Spec::Dir(p, mode) => (*p, &b""[..], tar::EntryType::Directory, *mode),
+                Spec::File(p, data, mode) => (*p, *data, tar::EntryType::Regular, *mode),
+                Spec::Symlink(p, target) => {
+                    header.set_link_name(target).unwrap();
+                    (*p, &b""[..], tar::EntryType::Symlink, 0o777)
+                }
+                Spec::HardLink(p, target) => {
+                    header.set_link_name(target).unwrap();
+                    (*p, &b""[..], tar::EntryType::Link, 0o644)
+                }
+            };
+            header.set_entry_type(kind);
+            header.set_mode(mode);
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, Path::new(OsStr::from_bytes(path)), data)
+                .unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// Opens `buf` as `checker::unpack()` does.
+    pub fn open(buf: &[u8]) -> tar::Archive<GzDecoder<&[u8]>> {
+        let mut tar = tar::Archive::new(GzDecoder::new(buf));
+        tar.set_preserve_permissions(true);
+        tar.set_preserve_ownerships(true);
+        tar
+    }
+
+    fn release() -> Vec<u8> {
+        archive(&[
+            Spec::Dir(b"VSCode-linux-x64", 0o755),
+            Spec::Dir(b"VSCode-linux-x64/bin", 0o750),
+            Spec::File(b"VSCode-linux-x64/code", b"#!/bin/sh\n", 0o755),
+            Spec::File(b"VSCode-linux-x64/bin/code-cli", b"cli", 0o711),
+            Spec::File(b"VSCode-linux-x64/LICENSE", b"MIT", 0o644),
+            Spec::File(b"VSCode-linux-x64/secret", b"xyz", 0o600),
+            Spec::Symlink(b"VSCode-linux-x64/bin/code", "../code"),
+            Spec::HardLink(b"VSCode-linux-x64/LICENSE.txt", "VSCode-linux-x64/LICENSE"),
+        ])
+    }
+
+    /// The type, permissions, owner, mtime and contents or target of
+    /// everything under `root`, by relative path.
+    fn snapshot(root: &Path) -> Vec<(PathBuf, String)> {
+        let mut entries = Vec::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                let m = std::fs::symlink_metadata(&path).unwrap();
+                let rel = path.strip_prefix(root).unwrap().to_path_buf();
+                // tar::Entry::unpack_in() restores the mtimes of regular
+                // files only, so those of the others are not compared.
+                let described = if m.file_type().is_symlink() {
+                    format!(
+                        "link {:?} {}:{}",
+                        std::fs::read_link(&path).unwrap(),
+                        m.uid(),
+                        m.gid()
+                    )
+                } else if m.is_dir() {
+                    pending.push(path.clone());
+                    format!("dir {:o} {}:{}", m.mode() & 0o7777, m.uid(), m.gid())
+                } else {
+                    format!(
+                        "file {:o} {}:{} {} {:?}",
+                        m.mode() & 0o7777,
+                        m.uid(),
+                        m.gid(),
+                        m.mtime(),
+                        std::fs::read(&path).unwrap()
+                    )
+                };
+                entries.push((rel, described));
+            }
+        }
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn concurrent_matches_serial() {
+        let buf = release();
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        serial(&mut open(&buf), a.path(), &Exclude::default(), None).unwrap();
+        concurrent(&mut open(&buf), b.path(), 4, &Exclude::default(), None).unwrap();
+
+        let (serially, concurrently) = (snapshot(a.path()), snapshot(b.path()));
+        assert_eq!(serially.len(), 8);
+        assert_eq!(serially, concurrently);
+        assert!(serially.iter().any(
+            |(p, d)| p == Path::new("VSCode-linux-x64/code") && d.contains(&MTIME.to_string())
+        ));
+    }
+
+    /// A rough benchmark rather than a criterion one, which could not reach
+    /// this module of a binary crate. Ignored as its timings depend on the
+    /// disk: run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn concurrent_against_serial() {
+        let names = (0..2000)
+            .map(|i| format!("VSCode-linux-x64/f{}", i).into_bytes())
+            .collect::<Vec<_>>();
+        let data = vec![b'x'; 64 << 10];
+        let mut specs = vec![Spec::Dir(b"VSCode-linux-x64", 0o755)];
+        specs.extend(names.iter().map(|n| Spec::File(n, &data, 0o644)));
+        let buf = archive(&specs);
+
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let start = Instant::now();
+        serial(&mut open(&buf), a.path(), &Exclude::default(), None).unwrap();
+        let serially = start.elapsed();
+        let start = Instant::now();
+        concurrent(
+            &mut open(&buf),
+            b.path(),
+            DEFAULT_JOBS,
+            &Exclude::default(),
+            None,
+        )
+        .unwrap();
+        let concurrently = start.elapsed();
+        eprintln!(
+            "serial {:?}, concurrent with {} jobs {:?}",
+            serially, DEFAULT_JOBS, concurrently
+        );
+        assert_eq!(snapshot(a.path()), snapshot(b.path()));
+    }
+
+    #[test]
+    fn journals_both_ways() {
+        let buf = release();
+        let entries = open(&buf).entries().unwrap().count() as u64;
+        let recorded = RefCell::new(Vec::new());
+        let record = |entries: u64| recorded.borrow_mut().push(entries);
+        let journal = Journal {
+            sha256: "0".repeat(64),
+            done: 0,
+            sink: Some(&record),
+        };
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        serial(
+            &mut open(&buf),
+            a.path(),
+            &Exclude::default(),
+            Some(&journal),
+        )
+        .unwrap();
+        concurrent(
+            &mut open(&buf),
+            b.path(),
+            2,
+            &Exclude::default(),
+            Some(&journal),
+        )
+        .unwrap();
+        assert_eq!(*recorded.borrow(), [entries, entries]);
+    }
+
+    #[test]
+    fn resumed_extraction_verifies() {
+        let buf = release();
+        let dir = tempfile::tempdir().unwrap();
+        serial(&mut open(&buf), dir.path(), &Exclude::default(), None).unwrap();
+        let root = dir.path().join("VSCode-linux-x64");
+        let recorded = manifest::build(&root, "1.0.0", 1).unwrap();
+
+        // Interrupted with one file not written yet and another half written.
+        std::fs::remove_file(root.join("bin/code-cli")).unwrap();
+        std::fs::write(root.join("secret"), b"x").unwrap();
+        let journal = Journal {
+            sha256: "0".repeat(64),
+            done: 8,
+            sink: Some(&|_| {}),
+        };
+        serial(
+            &mut open(&buf),
+            dir.path(),
+            &Exclude::default(),
+            Some(&journal),
+        )
+        .unwrap();
+
+        let report = verify::compare(&root, &recorded, 1).unwrap();
+        assert!(report.is_clean(), "{:?}", report.modified);
+        assert_eq!(report.checked, recorded.files.len());
+    }
+
+    #[test]
+    fn keeps_intact_entries_when_resuming() {
+        let buf = release();
+        let dir = tempfile::tempdir().unwrap();
+        serial(&mut open(&buf), dir.path(), &Exclude::default(), None).unwrap();
+        // Same size and mtime, so taken as what the interrupted run wrote.
+        let code = dir.path().join("VSCode-linux-x64/code");
+        let kept = b"#!/bin/ss\n";
+        std::fs::write(&code, kept).unwrap();
+        File::open(&code)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(MTIME))
+            .unwrap();
+        let journal = Journal {
+            sha256: "0".repeat(64),
+            done: 8,
+            sink: Some(&|_| {}),
+        };
+        serial(
+            &mut open(&buf),
+            dir.path(),
+            &Exclude::default(),
+            Some(&journal),
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&code).unwrap(), kept);
+
+        // Without a journal everything is written again.
+        serial(&mut open(&buf), dir.path(), &Exclude::default(), None).unwrap();
+        assert_eq!(std::fs::read(&code).unwrap(), b"#!/bin/sh\n");
+    }
+
+    #[test]
+    fn unpacks_non_utf8_names() {
+        let name = b"VSCode-linux-x64/caf\xe9 \\x41.txt";
+        let buf = archive(&[
+            Spec::Dir(b"VSCode-linux-x64", 0o755),
+            Spec::File(name, b"x", 0o644),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        serial(&mut open(&buf), dir.path(), &Exclude::default(), None).unwrap();
+        let path = dir.path().join(OsStr::from_bytes(name));
+        assert_eq!(std::fs::read(&path).unwrap(), b"x");
+
+        let root = dir.path().join("VSCode-linux-x64");
+        let recorded = manifest::build(&root, "1.0.0", 1).unwrap();
+        let key = manifest::encode_path(Path::new(OsStr::from_bytes(b"caf\xe9 \\x41.txt")));
+        assert_eq!(key, "caf\\xe9 \\\\x41.txt");
+        assert!(recorded.files.contains_key(&key));
+        assert!(verify::compare(&root, &recorded, 1).unwrap().is_clean());
+    }
+
+    /// Unpacks an archive holding a file at `path` both ways, returning the
+    /// errors.
+    fn rejections(path: &[u8]) -> Vec<String> {
+        let buf = archive(&[
+            Spec::Dir(b"VSCode-linux-x64", 0o755),
+            Spec::File(path, b"x", 0o644),
+        ]);
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let serially = serial(&mut open(&buf), a.path(), &Exclude::default(), None);
+        let concurrently = concurrent(&mut open(&buf), b.path(), 2, &Exclude::default(), None);
+
+        [serially.err(), concurrently.err()]
+            .into_iter()
+            .map(|e| e.expect("the entry was unpacked").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn rejects_overlong_names() {
+        let mut path = b"VSCode-linux-x64/".to_vec();
+        path.extend([b'a'; MAX_COMPONENT_LEN + 1]);
+        for e in rejections(&path) {
+            assert!(
+                e.starts_with("Refusing to unpack VSCode-linux-x64/aaa"),
+                "{}",
+                e
+            );
+            assert!(
+                e.contains("is 256 bytes long, over the limit of 255."),
+                "{}",
+                e
+            );
+        }
+
+        // Not UTF-8 either, which the message shows replaced.
+        let mut path = b"VSCode-linux-x64/".to_vec();
+        path.extend([0xff; MAX_COMPONENT_LEN + 1]);
+        for e in rejections(&path) {
+            assert!(e.contains("\u{fffd}..."), "{}", e);
+            assert!(e.contains("over the limit of 255."), "{}", e);
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_paths() {
+        let mut path = b"VSCode-linux-x64".to_vec();
+        while path.len() <= 4096 {
+            path.push(b'/');
+            path.extend([b'a'; 200]);
+        }
+        for e in rejections(&path) {
+            assert!(e.contains("bytes long, over the limit of 4095."), "{}", e);
+            // The path is cut short in the message.
+            assert!(e.len() < 200, "{}", e);
+        }
+    }
+}
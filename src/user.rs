@@ -0,0 +1,216 @@
+use anyhow::{anyhow, bail, Result};
+use std::{
+    ffi::{CStr, CString, OsString},
+    os::unix::{fs::MetadataExt, process::CommandExt},
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+/// The user per-user side effects are for: the one who ran `sudo vsdown`
+/// rather than root, otherwise whoever runs vsdown.
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub home: PathBuf,
+    /// Whether this is `$SUDO_USER`, whose environment is not the one
+    /// vsdown got.
+    pub sudo: bool,
+}
+
+impl User {
+    /// `rel`, a relative path free of `..`, below the home directory.
+    pub fn in_home(&self, rel: &str) -> Result<PathBuf> {
+        let rel = Path::new(rel);
+        if rel
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            bail!(
+                "{} is not a path inside the home directory of {}.",
+                rel.display(),
+                self.name
+            );
+        }
+
+        Ok(self.home.join(rel))
+    }
+
+    /// `program`, to be run as this user with their home and name in the
+    /// environment, when that is not who runs vsdown.
+    pub fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        if self.uid != unsafe { libc::geteuid() } {
+            command
+                .uid(self.uid)
+                .gid(self.gid)
+                .env("HOME", &self.home)
+                .env("USER", &self.name)
+                .env("LOGNAME", &self.name);
+        }
+
+        command
+    }
+}
+
+/// The target user: `$SUDO_USER` when vsdown runs as root under sudo, unless
+/// that is root itself, otherwise the invoking user.
+pub fn target() -> Result<User> {
+    let ids = unsafe { (libc::geteuid(), libc::getuid(), libc::getgid()) };
+    target_with(|v| std::env::var_os(v), ids)
+}
+
+/// `target()` with the environment looked up with `var`, running with the
+/// effective uid, uid and gid `ids`.
+fn target_with<F: Fn(&str) -> Option<OsString>>(
+    var: F,
+    (euid, uid, gid): (libc::uid_t, libc::uid_t, libc::gid_t),
+) -> Result<User> {
+    let sudo_user = var("SUDO_USER")
+        .and_then(|u| u.into_string().ok())
+        .filter(|u| !u.is_empty());
+    if let Some(name) = sudo_user {
+        if euid == 0 && name != "root" {
+            let user = by_name(&name)
+                .ok_or_else(|| anyhow!("Could not find the user {} from $SUDO_USER.", name))?;
+            return Ok(User { sudo: true, ..user });
+        }
+    }
+    let mut user = by_uid(uid).unwrap_or_else(|| User {
+        name: uid.to_string(),
+        uid,
+        gid,
+        home: PathBuf::new(),
+        sudo: false,
+    });
+    // Our own environment is authoritative for the invoking user.
+    if let Some(home) = var("HOME").filter(|h| !h.is_empty()) {
+        user.home = PathBuf::from(home);
+    }
+    if user.home.as_os_str().is_empty() {
+        bail!("$HOME is not set and {} has no home directory.", user.name);
+    }
+
+    Ok(user)
+}
+
+/// The owner of `p`, for acting on files which may belong to an earlier
+/// target user, e.g. when a timer upgrades an install made under sudo.
+pub fn owner_of(p: &Path) -> Result<User> {
+    let m = std::fs::metadata(p)?;
+
+    Ok(by_uid(m.uid()).unwrap_or_else(|| User {
+        name: m.uid().to_string(),
+        uid: m.uid(),
+        gid: m.gid(),
+        home: PathBuf::new(),
+        sudo: false,
+    }))
+}
+
+pub fn by_name(name: &str) -> Option<User> {
+    let name = CString::new(name).ok()?;
+
+    from_passwd(unsafe { libc::getpwnam(name.as_ptr()) })
+}
+
+pub fn by_uid(uid: libc::uid_t) -> Option<User> {
+    from_passwd(unsafe { libc::getpwuid(uid) })
+}
+
+fn from_passwd(pw: *mut libc::passwd) -> Option<User> {
+    if pw.is_null() {
+        return None;
+    }
+    let (name, dir) = unsafe { (CStr::from_ptr((*pw).pw_name), CStr::from_ptr((*pw).pw_dir)) };
+
+    Some(User {
+        name: name.to_string_lossy().into_owned(),
+        uid: unsafe { (*pw).pw_uid },
+        gid: unsafe { (*pw).pw_gid },
+        home: PathBuf::from(&*dir.to_string_lossy()),
+        sudo: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// No uid of /etc/passwd.
+    const UNKNOWN: libc::uid_t = 4_000_000;
+
+    fn target_in(
+        vars: &[(&str, &str)],
+        ids: (libc::uid_t, libc::uid_t, libc::gid_t),
+    ) -> Result<User> {
+        target_with(
+            |v| {
+                vars.iter()
+                    .find(|(k, _)| *k == v)
+                    .map(|(_, val)| OsString::from(val))
+            },
+            ids,
+        )
+    }
+
+    #[test]
+    fn sudo_user() {
+        let nobody = by_name("nobody").expect("a nobody user");
+        let vars = [("SUDO_USER", "nobody"), ("HOME", "/root")];
+        let user = target_in(&vars, (0, 0, 0)).unwrap();
+        // With their own home, not root's.
+        assert_eq!(
+            user,
+            User {
+                sudo: true,
+                ..nobody
+            }
+        );
+
+        let e = target_in(&[("SUDO_USER", "no-such-vsdown-user")], (0, 0, 0)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Could not find the user no-such-vsdown-user from $SUDO_USER."
+        );
+    }
+
+    #[test]
+    fn sudo_as_root() {
+        let vars = [("SUDO_USER", "root"), ("HOME", "/tmp/home")];
+        let user = target_in(&vars, (0, 0, 0)).unwrap();
+        assert_eq!(user.name, "root");
+        assert_eq!(user.uid, 0);
+        assert_eq!(user.home, Path::new("/tmp/home"));
+        assert!(!user.sudo);
+    }
+
+    #[test]
+    fn invoking_user() {
+        let user = target_in(&[("HOME", "/tmp/home")], (0, 0, 0)).unwrap();
+        assert_eq!((user.name.as_str(), user.uid), ("root", 0));
+        assert_eq!(user.home, Path::new("/tmp/home"));
+        assert!(!user.sudo);
+
+        // $SUDO_USER is only believed of root, and not when empty.
+        let vars = [("SUDO_USER", "nobody"), ("HOME", "/tmp/home")];
+        let user = target_in(&vars, (UNKNOWN, UNKNOWN, 100)).unwrap();
+        assert_eq!(user.name, UNKNOWN.to_string());
+        assert_eq!((user.uid, user.gid), (UNKNOWN, 100));
+        assert!(!user.sudo);
+        let vars = [("SUDO_USER", ""), ("HOME", "/tmp/home")];
+        assert_eq!(target_in(&vars, (0, 0, 0)).unwrap().name, "root");
+    }
+
+    #[test]
+    fn needs_a_home() {
+        let user = target_in(&[], (0, 0, 0)).unwrap();
+        assert_eq!(user.home, Path::new(&by_uid(0).unwrap().home));
+        let e = target_in(&[("HOME", "")], (UNKNOWN, UNKNOWN, UNKNOWN)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("$HOME is not set and {} has no home directory.", UNKNOWN)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use console::style;
+use std::{
+    ffi::CString,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+use crate::checker::{self, INSTALL_DIR};
+use crate::{debug, info, logger, warn};
+
+/// Debian-style switch for unprivileged user namespaces, 0 when disabled.
+const USERNS_CLONE: &str = "/proc/sys/kernel/unprivileged_userns_clone";
+/// 0 disables user namespaces for everyone.
+const MAX_USER_NAMESPACES: &str = "/proc/sys/user/max_user_namespaces";
+/// AppArmor confining unprivileged user namespaces to profiles allowing them.
+const APPARMOR_RESTRICT: &str = "/proc/sys/kernel/apparmor_restrict_unprivileged_userns";
+
+/// What decides whether Chromium's sandbox can start.
+pub struct Probe {
+    /// Whether unprivileged processes may create user namespaces, which the
+    /// sandbox prefers over the setuid helper.
+    pub userns: bool,
+    /// The sysctl which disabled them, if one did.
+    pub disabled_by: Option<&'static str>,
+    /// Whether chrome-sandbox is setuid root on a filesystem honouring it.
+    pub helper: bool,
+    /// Why it is not, if it is not.
+    pub helper_problem: Option<String>,
+}
+
+fn read_sysctl(p: &str) -> Option<u64> {
+    std::fs::read_to_string(p).ok()?.trim().parse().ok()
+}
+
+/// Reads the kernel settings and checks the installed sandbox helper.
+pub fn probe() -> Probe {
+    let disabled_by = if read_sysctl(USERNS_CLONE) == Some(0) {
+        Some(USERNS_CLONE)
+    } else if read_sysctl(MAX_USER_NAMESPACES) == Some(0) {
+        Some(MAX_USER_NAMESPACES)
+    } else if read_sysctl(APPARMOR_RESTRICT) == Some(1) {
+        Some(APPARMOR_RESTRICT)
+    } else {
+        None
+    };
+    let helper_problem = helper_problem(&Path::new(INSTALL_DIR).join("chrome-sandbox"));
+
+    Probe {
+        userns: disabled_by.is_none(),
+        disabled_by,
+        helper: helper_problem.is_none(),
+        helper_problem,
+    }
+}
+
+fn helper_problem(p: &Path) -> Option<String> {
+    let m = match std::fs::symlink_metadata(p) {
+        Ok(m) => m,
+        Err(_) => return Some(format!("{} is missing", p.display())),
+    };
+    if !m.is_file() || m.uid() != 0 || m.mode() & 0o4000 == 0 {
+        return Some(format!("{} is not setuid root", p.display()));
+    }
+    let c = CString::new(p.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c.as_ptr(), &mut stat) } == 0 && stat.f_flag & libc::ST_NOSUID != 0 {
+        return Some(format!("{} is on a filesystem mounted nosuid", p.display()));
+    }
+
+    None
+}
+
+/// Explains after an install which of the sandbox modes Visual Studio Code
+/// will launch in, and offers to switch the launcher to --no-sandbox when
+/// neither works.
+pub fn report(sandbox: bool) {
+    let probe = probe();
+    if !sandbox {
+        debug!("The launcher runs Visual Studio Code with --no-sandbox.");
+        return;
+    }
+    if probe.userns {
+        debug!("Unprivileged user namespaces are available to the sandbox.");
+        return;
+    }
+    let disabled_by = probe.disabled_by.unwrap_or_default();
+    if probe.helper {
+        info!(
+            "Unprivileged user namespaces are disabled ({}), Visual Studio Code will use the setuid chrome-sandbox helper instead.",
+            disabled_by
+        );
+        return;
+    }
+    warn!(
+        "Unprivileged user namespaces are disabled ({}) and {}, so Visual Studio Code will fail to start its sandbox.",
+        disabled_by,
+        probe.helper_problem.as_deref().unwrap_or_default()
+    );
+    warn!("Either enable them, or run it with --no-sandbox, which weakens its security.");
+    if !logger::confirm("Switch the launcher and desktop entries to --no-sandbox?") {
+        info!("Run `vsdown install --force --no-sandbox-setup` to switch later.");
+        return;
+    }
+    match checker::switch_to_no_sandbox() {
+        Ok(()) => info!("Visual Studio Code will now run with --no-sandbox."),
+        Err(e) => warn!("Failed to switch to --no-sandbox: {}", e),
+    }
+}
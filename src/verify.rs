@@ -0,0 +1,331 @@
+use anyhow::{bail, Result};
+use console::style;
+use serde::Serialize;
+use std::path::Path;
+
+use crate::checker::{self, INSTALL_DIR};
+use crate::manifest::Manifest;
+use crate::{cache, debug, diff, info, manifest, state, units, warn};
+
+/// Differences between the installed tree and its manifest, each list
+/// sorted by path.
+#[derive(Serialize)]
+pub struct Report {
+    pub version: String,
+    pub checked: usize,
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    pub unexpected: Vec<String>,
+    /// Unchanged files whose permissions differ from the recorded ones.
+    pub permissions: Vec<String>,
+    /// Commits recorded at install time and named by the installed
+    /// product.json, which should be the same.
+    pub recorded_commit: Option<String>,
+    pub installed_commit: Option<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.modified.is_empty()
+            && self.missing.is_empty()
+            && self.unexpected.is_empty()
+            && self.permissions.is_empty()
+            && !self.commit_mismatch()
+    }
+
+    pub fn commit_mismatch(&self) -> bool {
+        matches!(
+            (&self.recorded_commit, &self.installed_commit),
+            (Some(r), Some(i)) if r != i
+        )
+    }
+}
+
+/// Re-hashes the installed tree on `jobs` threads and compares it with the
+/// manifest recorded when installing it.
+pub fn verify(jobs: usize) -> Result<Report> {
+    let manifest = match manifest::read()? {
+        Some(m) => m,
+        None => bail!(
+            "There is no manifest of the installed files to verify them against. {}",
+            manifest::MISSING_HINT
+        ),
+    };
+    info!(
+        "Verifying {} files of Visual Studio Code {} ...",
+        units::Count(manifest.files.len() as u64),
+        manifest.version
+    );
+    if !manifest.omitted.is_empty() {
+        info!(
+            "{} files of the release were excluded when installing and are not checked.",
+            manifest.omitted.len()
+        );
+    }
+    let mut report = compare(Path::new(INSTALL_DIR), &manifest, jobs)?;
+    report.recorded_commit = state::success_marker_commit();
+    report.installed_commit = checker::get_installed_commit();
+    if report.is_clean() {
+        if let Err(e) = state::write_last_verified() {
+            debug!("Could not record the verification: {}", e);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Compares the tree at `root` with `manifest`, hashing on `jobs` threads.
+/// The commits are left for the caller to fill in.
+pub fn compare(root: &Path, manifest: &Manifest, jobs: usize) -> Result<Report> {
+    let mut present = Vec::new();
+    if root.is_dir() {
+        manifest::walk(root, Path::new(""), &mut present)?;
+    }
+    let expected = manifest.files.keys().cloned().collect::<Vec<_>>();
+    let records = manifest::records(root, &expected, jobs);
+
+    let mut report = Report {
+        version: manifest.version.clone(),
+        checked: records.len(),
+        modified: Vec::new(),
+        missing: Vec::new(),
+        unexpected: present
+            .into_iter()
+            .filter(|p| !manifest.files.contains_key(p))
+            .collect(),
+        permissions: Vec::new(),
+        recorded_commit: None,
+        installed_commit: None,
+    };
+    for (p, record) in records {
+        let recorded = &manifest.files[&p];
+        match record {
+            Ok(r) if r.same_contents(recorded) => {
+                if recorded.mode.is_some() && r.mode != recorded.mode {
+                    report.permissions.push(p);
+                }
+            }
+            Ok(_) => report.modified.push(p),
+            Err(_) if std::fs::symlink_metadata(root.join(manifest::decode_path(&p))).is_err() => {
+                report.missing.push(p)
+            }
+            Err(e) => {
+                warn!("{}", e);
+                report.modified.push(p);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Differences between the installed tree and the file list of its release
+/// archive, each list sorted by path.
+#[derive(Serialize)]
+pub struct UpstreamReport {
+    pub version: String,
+    pub checked: usize,
+    /// Installed files the release does not have.
+    pub added: Vec<String>,
+    /// Files of the release which are not installed, other than excluded ones.
+    pub removed: Vec<String>,
+    /// Files installed with another size, or as another kind, than released.
+    pub changed: Vec<String>,
+    /// Files of the release excluded when installing.
+    pub excluded: usize,
+}
+
+impl UpstreamReport {
+    pub fn is_clean(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares the file list and sizes of the installed tree with those of
+/// the release archive of the installed version, which catches partial
+/// extractions and excludes which drifted. No file is hashed on either side,
+/// only the cached archive as a whole is checked once. Returns
+/// None when there is no such archive to compare with, as only the cache
+/// has it: releases do not come with a file list of their own.
+pub fn verify_upstream() -> Result<Option<UpstreamReport>> {
+    let version = match state::recorded_version() {
+        Some(v) => v,
+        None => checker::get_installed_version()?,
+    };
+    let entry = match cache::lookup(&version, &checker::get_arch()?) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    info!(
+        "Reading the file list of Visual Studio Code {} ...",
+        version
+    );
+    let upstream = diff::listing(&cache::load(&entry, false)?)?;
+    let omitted = manifest::read()
+        .ok()
+        .flatten()
+        .map(|m| m.omitted)
+        .unwrap_or_default();
+    let root = Path::new(INSTALL_DIR);
+    let mut present = Vec::new();
+    if root.is_dir() {
+        manifest::walk(root, Path::new(""), &mut present)?;
+    }
+
+    let mut report = UpstreamReport {
+        version,
+        checked: present.len(),
+        added: Vec::new(),
+        removed: upstream
+            .keys()
+            .filter(|p| !omitted.contains_key(*p))
+            .filter(|p| std::fs::symlink_metadata(root.join(manifest::decode_path(p))).is_err())
+            .cloned()
+            .collect(),
+        changed: Vec::new(),
+        excluded: omitted.len(),
+    };
+    for p in present {
+        let released = match upstream.get(&p) {
+            Some(r) => r,
+            None => {
+                report.added.push(p);
+                continue;
+            }
+        };
+        let installed = root.join(manifest::decode_path(&p));
+        let same = match &released.link {
+            Some(target) => std::fs::read_link(&installed)
+                .map_or(false, |l| manifest::encode_path(&l) == *target),
+            None => std::fs::symlink_metadata(&installed)
+                .map_or(false, |m| m.is_file() && m.len() == released.size),
+        };
+        if !same {
+            report.changed.push(p);
+        }
+    }
+
+    Ok(Some(report))
+}
+
+pub fn print_upstream_report(report: &UpstreamReport) {
+    for p in &report.added {
+        println!("{} {}", style("added:").cyan().bold(), p);
+    }
+    for p in &report.removed {
+        println!("{} {}", style("removed:").red().bold(), p);
+    }
+    for p in &report.changed {
+        println!("{} {}", style("changed:").yellow().bold(), p);
+    }
+    if report.is_clean() {
+        info!(
+            "All {} files of Visual Studio Code {} match the released file list.",
+            units::Count(report.checked as u64),
+            report.version
+        );
+    } else {
+        warn!(
+            "{} added, {} removed and {} changed files compared with the release.",
+            report.added.len(),
+            report.removed.len(),
+            report.changed.len()
+        );
+    }
+}
+
+/// Records the installed tree as it is now in a new manifest, for installs
+/// without one or whose manifest was lost. What is on disk is taken as
+/// correct, so local changes are no longer reported afterwards.
+pub fn rebuild_manifest(jobs: usize) -> Result<usize> {
+    let root = Path::new(INSTALL_DIR);
+    if !root.is_dir() {
+        bail!(
+            "There is no Visual Studio Code at {} to record.",
+            INSTALL_DIR
+        );
+    }
+    let version = match state::recorded_version() {
+        Some(v) => v,
+        None => checker::get_installed_version()?,
+    };
+    // Files an install left out on purpose cannot be told from those lost
+    // since, so only a previous manifest knows them.
+    let omitted = manifest::read()
+        .ok()
+        .flatten()
+        .map(|m| m.omitted)
+        .unwrap_or_default();
+    info!(
+        "Recording the installed files of Visual Studio Code {} ...",
+        version
+    );
+    let mut m = manifest::build(root, &version, jobs)?;
+    m.omitted = omitted;
+    manifest::write(&m)?;
+
+    Ok(m.files.len())
+}
+
+/// Reminds to run `verify` once the installation has gone `days` days
+/// without being found intact, counted from the install if it never was.
+pub fn remind_if_due(days: u64) {
+    let state = state::load();
+    if state.version.is_none() {
+        return;
+    }
+    let since = match state.last_verified.or(state.installed_at) {
+        Some(since) => since,
+        None => return,
+    };
+    let elapsed = state::now().saturating_sub(since) / (24 * 60 * 60);
+    if elapsed >= days {
+        warn!(
+            "The installation has not been verified in {} days, run `vsdown verify`.",
+            elapsed
+        );
+    }
+}
+
+pub fn print_report(report: &Report) {
+    for p in &report.modified {
+        println!("{} {}", style("modified:").yellow().bold(), p);
+    }
+    for p in &report.missing {
+        println!("{} {}", style("missing:").red().bold(), p);
+    }
+    for p in &report.unexpected {
+        println!("{} {}", style("unexpected:").cyan().bold(), p);
+    }
+    for p in &report.permissions {
+        println!("{} {}", style("permissions:").magenta().bold(), p);
+    }
+    if report.commit_mismatch() {
+        println!(
+            "{} installed commit {} (product.json), recorded commit {}",
+            style("inconsistent:").red().bold(),
+            report.installed_commit.as_deref().unwrap_or_default(),
+            report.recorded_commit.as_deref().unwrap_or_default()
+        );
+    }
+    if report.is_clean() {
+        info!(
+            "All {} files of Visual Studio Code {} match the manifest.",
+            units::Count(report.checked as u64),
+            report.version
+        );
+    } else {
+        warn!(
+            "{} modified, {} missing and {} unexpected files.",
+            report.modified.len(),
+            report.missing.len(),
+            report.unexpected.len()
+        );
+        if !report.permissions.is_empty() {
+            warn!(
+                "{} files have changed permissions, run `vsdown fix-perms` to restore them.",
+                report.permissions.len()
+            );
+        }
+    }
+}
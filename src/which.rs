@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::checker::{get_current_version, INSTALL_DIR};
+use crate::color_bool;
+
+/// Upper bound on followed links, in case of symlink loops.
+const MAX_HOPS: usize = 32;
+
+#[derive(Serialize)]
+pub struct Hop {
+    pub path: PathBuf,
+    /// How this path was reached: "path" for the PATH lookup, "symlink" or
+    /// "wrapper" (a launcher script executing the next hop).
+    pub kind: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Resolution {
+    pub name: String,
+    pub hops: Vec<Hop>,
+    pub target: PathBuf,
+    pub version: Option<String>,
+    pub managed: bool,
+    pub channel: Option<&'static str>,
+}
+
+/// Resolves `name` the way a shell would and follows it to the final binary.
+pub fn resolve(name: &str) -> Result<Resolution> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let first = std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+        .ok_or_else(|| anyhow!("{} was not found in PATH.", name))?;
+
+    let mut hops = vec![Hop {
+        path: first.clone(),
+        kind: "path",
+    }];
+    let mut current = first;
+    while hops.len() < MAX_HOPS {
+        if let Ok(target) = std::fs::read_link(&current) {
+            let target = match current.parent() {
+                Some(parent) => parent.join(target),
+                None => target,
+            };
+            hops.push(Hop {
+                path: target.clone(),
+                kind: "symlink",
+            });
+            current = target;
+        } else if let Some(target) = wrapper_target(&current) {
+            hops.push(Hop {
+                path: target.clone(),
+                kind: "wrapper",
+            });
+            current = target;
+        } else {
+            break;
+        }
+    }
+
+    let target = current.canonicalize().unwrap_or(current);
+    let version = target
+        .parent()
+        .and_then(|dir| package_version(&dir.join("resources/app/package.json")));
+    let managed = target.starts_with(INSTALL_DIR) && get_current_version().is_ok();
+
+    Ok(Resolution {
+        name: name.to_string(),
+        hops,
+        target,
+        version,
+        managed,
+        channel: if managed { Some("stable") } else { None },
+    })
+}
+
+/// Finds the binary a launcher script starts: either VS Code's own
+/// `bin/code` CLI wrapper, which runs `../code`, or the first absolute path
+/// passed to `exec`.
+fn wrapper_target(p: &Path) -> Option<PathBuf> {
+    let s = std::fs::read(p).ok()?;
+    if !s.starts_with(b"#!") || s.len() > 64 * 1024 {
+        return None;
+    }
+    let s = String::from_utf8_lossy(&s);
+    if let Some(bin) = p.parent() {
+        let electron = bin.parent().map(|root| root.join("code"));
+        if bin.file_name().map(|n| n == "bin").unwrap_or(false) && s.contains("ELECTRON") {
+            if let Some(electron) = electron.filter(|e| e.is_file()) {
+                return Some(electron);
+            }
+        }
+    }
+
+    s.lines()
+        .filter_map(|l| l.trim().strip_prefix("exec "))
+        .filter_map(|l| l.split_whitespace().next())
+        .map(|t| t.trim_matches(|c| c == '"' || c == '\''))
+        .find(|t| t.starts_with('/'))
+        .map(PathBuf::from)
+        .filter(|t| t != p)
+}
+
+fn package_version(p: &Path) -> Option<String> {
+    let s = std::fs::read_to_string(p).ok()?;
+    let v = serde_json::from_str::<serde_json::Value>(&s).ok()?;
+
+    v.get("version")?.as_str().map(|s| s.to_string())
+}
+
+pub fn print_resolution(r: &Resolution) {
+    let chain = r
+        .hops
+        .iter()
+        .map(|h| format!("{} ({})", h.path.display(), h.kind))
+        .collect::<Vec<_>>()
+        .join(" -> ");
+    println!("{}: {}", r.name, chain);
+    println!("Target: {}", r.target.display());
+    println!("Version: {}", r.version.as_deref().unwrap_or("unknown"));
+    match r.channel {
+        Some(channel) => println!(
+            "Managed by vsdown: {} ({})",
+            color_bool!(r.managed),
+            channel
+        ),
+        None => println!("Managed by vsdown: {}", color_bool!(r.managed)),
+    }
+}
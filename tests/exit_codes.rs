@@ -0,0 +1,84 @@
+//! Exit codes of the vsdown binary reachable without an installation, root,
+//! the network or the state of the machine running the tests. The others,
+//! which depend on `/var/lib/vsdown`, are covered by the unit tests of
+//! `main` and `checker`.
+
+use std::process::Command;
+
+fn vsdown(args: &[&str]) -> (i32, String) {
+    vsdown_with(args, &[])
+}
+
+/// Runs vsdown with the proxy variables replaced by `env`.
+fn vsdown_with(args: &[&str], env: &[(&str, &str)]) -> (i32, String) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_vsdown"));
+    for var in [
+        "http_proxy",
+        "HTTP_PROXY",
+        "https_proxy",
+        "HTTPS_PROXY",
+        "all_proxy",
+        "ALL_PROXY",
+        "no_proxy",
+        "NO_PROXY",
+    ] {
+        command.env_remove(var);
+    }
+    let output = command
+        .args(args)
+        .envs(env.iter().copied())
+        .output()
+        .unwrap();
+
+    (
+        output.status.code().unwrap(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn unreachable_version_source_is_network_unavailable() {
+    // Nothing listens on port 1 of the loopback, so the version source
+    // cannot be reached through it whether or not there is a network.
+    let (code, stderr) = vsdown_with(
+        &[
+            "check",
+            "--no-netrc",
+            "--channel-version-source",
+            "update-api",
+            "--wait-online",
+            "0",
+        ],
+        &[("HTTPS_PROXY", "http://127.0.0.1:1")],
+    );
+    assert_eq!(code, 75, "{}", stderr);
+    assert!(
+        stderr.contains("The network did not become available within 0 seconds."),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn help_lists_every_exit_code() {
+    let help = Command::new(env!("CARGO_BIN_EXE_vsdown"))
+        .args(["install", "--help"])
+        .output()
+        .unwrap();
+    assert!(help.status.success());
+    let help = String::from_utf8_lossy(&help.stdout);
+    for code in ["2", "3", "5", "10", "75"] {
+        assert!(help.contains(&format!("    {:<5} ", code)), "{}", code);
+    }
+}
+
+#[test]
+fn invalid_arguments_fail() {
+    let (code, stderr) = vsdown(&["check", "--on-up-to-date", "256"]);
+    assert_ne!(code, 0);
+    assert!(
+        stderr.contains("256 is not an exit code from 0 to 255"),
+        "{}",
+        stderr
+    );
+}